@@ -0,0 +1,332 @@
+// Copyright (c) 2022, BlockProject 3D
+//
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification,
+// are permitted provided that the following conditions are met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//     * Redistributions in binary form must reproduce the above copyright notice,
+//       this list of conditions and the following disclaimer in the documentation
+//       and/or other materials provided with the distribution.
+//     * Neither the name of BlockProject 3D nor the names of its contributors
+//       may be used to endorse or promote products derived from this software
+//       without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS
+// "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT
+// LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR
+// A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT OWNER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Typed span duration assertions for use as performance regression gates in integration
+//! tests.
+//!
+//! Duration collection is off by default to avoid unbounded memory growth in production;
+//! call [enable_collection](enable_collection) at the start of a test scenario and
+//! [reset](reset) between scenarios. Assertions query whatever has been collected so far:
+//!
+//! ```no_run
+//! use std::time::Duration;
+//! use bp3d_tracing::assertions::SpanAssertion;
+//!
+//! SpanAssertion::new("parse_document")
+//!     .p95_under(Duration::from_millis(5))
+//!     .min_runs(100)
+//!     .check()
+//!     .unwrap();
+//! ```
+
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+
+static COLLECT: AtomicBool = AtomicBool::new(false);
+
+//Collection must stay memory-bounded when enabled for the whole process lifetime (the
+// dump-on-signal mode): past this many samples per span the series is decimated by
+// keeping every other sample, which halves the memory while roughly preserving the
+// distribution the percentiles are computed from.
+const MAX_SAMPLES_PER_SPAN: usize = 8192;
+
+static DURATIONS: Lazy<DashMap<String, Vec<Duration>>> = Lazy::new(DashMap::new);
+
+/// Enables collection of span durations for use by assertions.
+pub fn enable_collection() {
+    COLLECT.store(true, Ordering::Relaxed);
+}
+
+/// Disables collection of span durations.
+pub fn disable_collection() {
+    COLLECT.store(false, Ordering::Relaxed);
+}
+
+/// Clears all span durations collected so far; call between test scenarios.
+pub fn reset() {
+    DURATIONS.clear();
+}
+
+pub(crate) fn record(name: &str, duration: Duration) {
+    if !COLLECT.load(Ordering::Relaxed) {
+        return;
+    }
+    let mut runs = DURATIONS.entry(name.into()).or_default();
+    if runs.len() >= MAX_SAMPLES_PER_SPAN {
+        let mut keep = false;
+        runs.retain(|_| {
+            keep = !keep;
+            keep
+        });
+    }
+    runs.push(duration);
+}
+
+/// A serializable snapshot of one span's statistics, for exporting current profiling
+/// state to JSON dashboards without the network viewer. Durations are exact
+/// seconds+nanos pairs; percentiles use the nearest-rank method.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct SpanStatsSnapshot {
+    pub name: String,
+    pub run_count: usize,
+    pub avg: crate::profiler::network_types::Duration,
+    pub min: crate::profiler::network_types::Duration,
+    pub max: crate::profiler::network_types::Duration,
+    pub p50: crate::profiler::network_types::Duration,
+    pub p99: crate::profiler::network_types::Duration,
+    /// Instances of this span currently open; populated from the profiler's live
+    /// tracking when active, 0 in logger-only mode.
+    pub live: usize
+}
+
+fn nearest_rank(sorted: &[Duration], percentile: usize) -> Duration {
+    let rank = (sorted.len() * percentile).div_ceil(100).max(1);
+    sorted[rank - 1]
+}
+
+/// Snapshots the statistics of every span with collected durations, sorted by name.
+/// Collection must be enabled (see [enable_collection](enable_collection)).
+pub fn span_stats_snapshot() -> Vec<SpanStatsSnapshot> {
+    let live_by_name: std::collections::HashMap<String, usize> =
+        match crate::profiler::state::ProfilerState::try_get() {
+            Some(state) => state.open_spans_by_name(),
+            None => std::collections::HashMap::new()
+        };
+    let mut snapshots: Vec<SpanStatsSnapshot> = DURATIONS.iter()
+        .filter(|entry| !entry.value().is_empty())
+        .map(|entry| {
+            let mut runs = entry.value().clone();
+            runs.sort_unstable();
+            let sum: Duration = runs.iter().sum();
+            SpanStatsSnapshot {
+                name: entry.key().clone(),
+                run_count: runs.len(),
+                avg: (sum / runs.len() as u32).into(),
+                min: runs[0].into(),
+                max: (*runs.last().unwrap()).into(),
+                p50: nearest_rank(&runs, 50).into(),
+                p99: nearest_rank(&runs, 99).into(),
+                live: live_by_name.get(entry.key()).copied().unwrap_or(0)
+            }
+        })
+        .collect();
+    snapshots.sort_by(|a, b| a.name.cmp(&b.name));
+    snapshots
+}
+
+/// The detailed result of a failed [SpanAssertion](SpanAssertion) check.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssertionReport {
+    /// The name of the span the assertion was checked against.
+    pub span: String,
+    /// The number of runs collected for the span.
+    pub runs: usize,
+    /// The minimum number of runs the assertion required.
+    pub min_runs: usize,
+    /// The observed p95 duration and its configured limit when the p95 gate failed.
+    pub p95: Option<(Duration, Duration)>,
+    /// The observed average duration and its configured limit when the average gate failed.
+    pub avg: Option<(Duration, Duration)>
+}
+
+impl Display for AssertionReport {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "span '{}' failed its duration assertion ({} runs", self.span, self.runs)?;
+        if self.runs < self.min_runs {
+            write!(f, ", expected at least {}", self.min_runs)?;
+        }
+        if let Some((actual, limit)) = self.p95 {
+            write!(f, ", p95 {} exceeds {}", crate::util::format_duration(actual), crate::util::format_duration(limit))?;
+        }
+        if let Some((actual, limit)) = self.avg {
+            write!(f, ", avg {} exceeds {}", crate::util::format_duration(actual), crate::util::format_duration(limit))?;
+        }
+        write!(f, ")")
+    }
+}
+
+impl std::error::Error for AssertionReport {}
+
+/// A builder describing duration limits for a named span.
+pub struct SpanAssertion {
+    span: String,
+    p95_under: Option<Duration>,
+    avg_under: Option<Duration>,
+    min_runs: usize
+}
+
+impl SpanAssertion {
+    /// Creates an assertion against the span with the given name.
+    pub fn new<T: Into<String>>(span: T) -> SpanAssertion {
+        SpanAssertion {
+            span: span.into(),
+            p95_under: None,
+            avg_under: None,
+            min_runs: 1
+        }
+    }
+
+    /// Requires the 95th percentile of collected durations to stay under the given limit.
+    pub fn p95_under(mut self, limit: Duration) -> Self {
+        self.p95_under = Some(limit);
+        self
+    }
+
+    /// Requires the average of collected durations to stay under the given limit.
+    pub fn avg_under(mut self, limit: Duration) -> Self {
+        self.avg_under = Some(limit);
+        self
+    }
+
+    /// Requires at least the given number of runs to have been collected; fewer runs fail
+    /// the check so a gate cannot silently pass on an empty sample.
+    pub fn min_runs(mut self, runs: usize) -> Self {
+        self.min_runs = runs;
+        self
+    }
+
+    /// Checks the assertion against the durations collected so far.
+    pub fn check(self) -> Result<(), AssertionReport> {
+        let mut runs = DURATIONS.get(&self.span)
+            .map(|v| v.clone())
+            .unwrap_or_default();
+        runs.sort_unstable();
+        let mut report = AssertionReport {
+            span: self.span,
+            runs: runs.len(),
+            min_runs: self.min_runs,
+            p95: None,
+            avg: None
+        };
+        if let (Some(limit), false) = (self.p95_under, runs.is_empty()) {
+            //Nearest-rank p95: smallest duration covering 95% of the sorted runs.
+            let rank = (runs.len() * 95).div_ceil(100);
+            let actual = runs[rank - 1];
+            if actual >= limit {
+                report.p95 = Some((actual, limit));
+            }
+        }
+        if let (Some(limit), false) = (self.avg_under, runs.is_empty()) {
+            let actual = runs.iter().sum::<Duration>() / runs.len() as u32;
+            if actual >= limit {
+                report.avg = Some((actual, limit));
+            }
+        }
+        if report.runs < report.min_runs || report.p95.is_some() || report.avg.is_some() {
+            return Err(report);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use super::*;
+
+    fn fill(name: &str, millis: impl IntoIterator<Item = u64>) {
+        for v in millis {
+            DURATIONS.entry(name.into()).or_default().push(Duration::from_millis(v));
+        }
+    }
+
+    #[test]
+    fn snapshot_serializes_expected_aggregates() {
+        fill("snapshot_span", [10, 20, 30, 40]);
+        let snapshot = span_stats_snapshot();
+        let entry = snapshot.iter().find(|v| v.name == "snapshot_span").unwrap();
+        assert_eq!(entry.run_count, 4);
+        assert_eq!(std::time::Duration::from(entry.min), Duration::from_millis(10));
+        assert_eq!(std::time::Duration::from(entry.max), Duration::from_millis(40));
+        assert_eq!(std::time::Duration::from(entry.avg), Duration::from_millis(25));
+        assert_eq!(std::time::Duration::from(entry.p50), Duration::from_millis(20));
+        assert_eq!(std::time::Duration::from(entry.p99), Duration::from_millis(40));
+        assert_eq!(entry.live, 0);
+        //And the snapshot is plain JSON for dashboards.
+        let json = serde_json::to_value(entry).unwrap();
+        assert_eq!(json["run_count"], 4);
+        assert_eq!(json["min"]["seconds"], 0);
+        assert_eq!(json["min"]["nano_seconds"], 10_000_000);
+    }
+
+    #[test]
+    fn p95_gate() {
+        fill("p95_gate", (1..=100).map(|_| 1));
+        fill("p95_gate", [100; 4]);
+        //104 runs: 100 at 1ms and 4 at 100ms => p95 is 1ms.
+        SpanAssertion::new("p95_gate")
+            .p95_under(Duration::from_millis(5))
+            .check()
+            .unwrap();
+        let report = SpanAssertion::new("p95_gate")
+            .p95_under(Duration::from_millis(1))
+            .check()
+            .unwrap_err();
+        assert_eq!(report.runs, 104);
+        assert_eq!(report.p95, Some((Duration::from_millis(1), Duration::from_millis(1))));
+    }
+
+    #[test]
+    fn avg_gate() {
+        fill("avg_gate", [2, 4]);
+        SpanAssertion::new("avg_gate")
+            .avg_under(Duration::from_millis(4))
+            .check()
+            .unwrap();
+        let report = SpanAssertion::new("avg_gate")
+            .avg_under(Duration::from_millis(3))
+            .check()
+            .unwrap_err();
+        assert_eq!(report.avg, Some((Duration::from_millis(3), Duration::from_millis(3))));
+    }
+
+    #[test]
+    fn min_runs_gate() {
+        fill("min_runs_gate", [1; 10]);
+        let report = SpanAssertion::new("min_runs_gate")
+            .p95_under(Duration::from_millis(5))
+            .min_runs(100)
+            .check()
+            .unwrap_err();
+        assert_eq!(report.runs, 10);
+        assert_eq!(report.min_runs, 100);
+        assert!(report.p95.is_none());
+    }
+
+    #[test]
+    fn unknown_span_fails_min_runs() {
+        let report = SpanAssertion::new("never_recorded")
+            .p95_under(Duration::from_millis(5))
+            .check()
+            .unwrap_err();
+        assert_eq!(report.runs, 0);
+    }
+}
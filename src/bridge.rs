@@ -0,0 +1,246 @@
+// Copyright (c) 2022, BlockProject 3D
+//
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification,
+// are permitted provided that the following conditions are met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//     * Redistributions in binary form must reproduce the above copyright notice,
+//       this list of conditions and the following disclaimer in the documentation
+//       and/or other materials provided with the distribution.
+//     * Neither the name of BlockProject 3D nor the names of its contributors
+//       may be used to endorse or promote products derived from this software
+//       without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS
+// "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT
+// LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR
+// A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT OWNER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Interop bridge for existing `tracing_subscriber` setups: a tracer that forwards events
+//! unchanged and emits a synthetic event on every span exit (span name, duration and
+//! recorded fields) into a user-provided [Dispatch](tracing_core::Dispatch), so span
+//! timings show up in whatever the user already configured for events.
+
+use std::fmt::Debug;
+use std::time::Duration;
+use dashmap::DashMap;
+use time::OffsetDateTime;
+use tracing_core::{Callsite, Dispatch, Event, Field, Kind, Level, Metadata};
+use tracing_core::field::{Value, Visit};
+use tracing_core::metadata;
+use tracing_core::span::{Attributes, Id, Record};
+use tracing_core::subscriber::Interest;
+use crate::core::{Tracer, TracingSystem};
+use crate::util::Meta;
+
+struct BridgeCallsite;
+static BRIDGE_CALLSITE: BridgeCallsite = BridgeCallsite;
+
+//Synthetic span-exit events all share this static callsite; they are emitted at INFO since
+// the original span's level cannot be carried by a static metadata.
+static BRIDGE_META: Metadata<'static> = metadata! {
+    name: "span_exit",
+    target: "bp3d_tracing::bridge",
+    level: Level::INFO,
+    fields: &["message", "span.name", "span.duration", "span.fields"],
+    callsite: &BRIDGE_CALLSITE,
+    kind: Kind::EVENT
+};
+
+impl Callsite for BridgeCallsite {
+    fn set_interest(&self, _: Interest) {}
+    fn metadata(&self) -> &Metadata<'static> {
+        &BRIDGE_META
+    }
+}
+
+struct FieldsVisitor(String);
+
+impl Visit for FieldsVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        if !self.0.is_empty() {
+            self.0 += ", ";
+        }
+        self.0 += &format!("{}: {:?}", field.name(), value);
+    }
+}
+
+struct SpanData {
+    metadata: Meta,
+    fields: FieldsVisitor
+}
+
+/// A tracer emitting span exits as synthetic events into a secondary dispatch.
+pub struct EventBridge {
+    dispatch: Dispatch,
+    spans: DashMap<Id, SpanData>
+}
+
+impl EventBridge {
+    pub fn new(dispatch: Dispatch) -> TracingSystem<EventBridge> {
+        TracingSystem::without_destructor(crate::core::BackendKind::Other, EventBridge {
+            dispatch,
+            spans: DashMap::new()
+        })
+    }
+}
+
+impl Tracer for EventBridge {
+    fn enabled(&self) -> bool {
+        true
+    }
+
+    fn span_create(&self, id: &Id, _: bool, _: Option<Id>, attrs: &Attributes) {
+        let mut data = SpanData {
+            metadata: attrs.metadata(),
+            fields: FieldsVisitor(String::new())
+        };
+        attrs.record(&mut data.fields);
+        self.spans.insert(id.clone(), data);
+    }
+
+    fn span_values(&self, id: &Id, values: &Record) {
+        if let Some(mut data) = self.spans.get_mut(id) {
+            values.record(&mut data.fields);
+        }
+    }
+
+    fn span_follows_from(&self, _: &Id, _: &Id) {
+    }
+
+    fn event(&self, _: Option<Id>, _: OffsetDateTime, event: &Event) {
+        if self.dispatch.enabled(event.metadata()) {
+            self.dispatch.event(event);
+        }
+    }
+
+    fn span_enter(&self, _: &Id) {
+    }
+
+    fn span_exit(&self, id: &Id, duration: Duration) {
+        let data = match self.spans.get(id) {
+            Some(v) => v,
+            None => return
+        };
+        if !self.dispatch.enabled(&BRIDGE_META) {
+            return;
+        }
+        let name = data.metadata.name();
+        let secs = duration.as_secs_f64();
+        let message = format!("The span '{}' finished", name);
+        let fields = BRIDGE_META.fields();
+        let message_field = fields.field("message").unwrap();
+        let name_field = fields.field("span.name").unwrap();
+        let duration_field = fields.field("span.duration").unwrap();
+        let fields_field = fields.field("span.fields").unwrap();
+        let message = message.as_str();
+        let span_fields = data.fields.0.as_str();
+        let array = [
+            (&message_field, Some(&message as &dyn Value)),
+            (&name_field, Some(&name as &dyn Value)),
+            (&duration_field, Some(&secs as &dyn Value)),
+            (&fields_field, Some(&span_fields as &dyn Value))
+        ];
+        let values = fields.value_set(&array);
+        self.dispatch.event(&Event::new(&BRIDGE_META, &values));
+    }
+
+    fn span_destroy(&self, id: &Id, _: crate::core::CloseReason) {
+        self.spans.remove(id);
+    }
+
+    fn max_level_hint(&self) -> Option<Level> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use tracing_core::Subscriber;
+    use super::*;
+
+    struct CaptureVisitor(Vec<(String, String)>);
+
+    impl Visit for CaptureVisitor {
+        fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+            self.0.push((field.name().into(), format!("{:?}", value)));
+        }
+    }
+
+    type CapturedEvents = Arc<Mutex<Vec<Vec<(String, String)>>>>;
+
+    struct Capture(CapturedEvents);
+
+    impl Subscriber for Capture {
+        fn enabled(&self, _: &Metadata) -> bool {
+            true
+        }
+
+        fn new_span(&self, _: &Attributes) -> Id {
+            Id::from_u64(1)
+        }
+
+        fn record(&self, _: &Id, _: &Record) {}
+        fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+        fn event(&self, event: &Event) {
+            let mut visitor = CaptureVisitor(Vec::new());
+            event.record(&mut visitor);
+            self.0.lock().unwrap().push(visitor.0);
+        }
+
+        fn enter(&self, _: &Id) {}
+        fn exit(&self, _: &Id) {}
+    }
+
+    struct TestCallsite;
+    static CALLSITE: TestCallsite = TestCallsite;
+    static META: Metadata<'static> = metadata! {
+        name: "bridged_span",
+        target: module_path!(),
+        level: Level::INFO,
+        fields: &["size"],
+        callsite: &CALLSITE,
+        kind: Kind::SPAN
+    };
+
+    impl Callsite for TestCallsite {
+        fn set_interest(&self, _: Interest) {}
+        fn metadata(&self) -> &Metadata<'static> {
+            &META
+        }
+    }
+
+    #[test]
+    fn span_exit_is_forwarded_as_event() {
+        let captured = Arc::new(Mutex::new(Vec::new()));
+        let system = EventBridge::new(Dispatch::new(Capture(captured.clone())));
+        let bridge = system.system.derived();
+        let id = Id::from_u64(1 << 32);
+        let size_field = META.fields().field("size").unwrap();
+        let size = 42u64;
+        let array = [(&size_field, Some(&size as &dyn Value))];
+        let values = META.fields().value_set(&array);
+        let attrs = Attributes::new_root(&META, &values);
+        bridge.span_create(&id, true, None, &attrs);
+        bridge.span_exit(&id, Duration::from_millis(250));
+        bridge.span_destroy(&id, crate::core::CloseReason::Normal);
+        let captured = captured.lock().unwrap();
+        assert_eq!(captured.len(), 1);
+        let event = &captured[0];
+        assert!(event.iter().any(|(k, v)| k == "span.name" && v.contains("bridged_span")));
+        assert!(event.iter().any(|(k, v)| k == "span.duration" && v.starts_with("0.25")));
+        assert!(event.iter().any(|(k, v)| k == "span.fields" && v.contains("size: 42")));
+    }
+}
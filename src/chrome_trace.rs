@@ -0,0 +1,244 @@
+// Copyright (c) 2022, BlockProject 3D
+//
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification,
+// are permitted provided that the following conditions are met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//     * Redistributions in binary form must reproduce the above copyright notice,
+//       this list of conditions and the following disclaimer in the documentation
+//       and/or other materials provided with the distribution.
+//     * Neither the name of BlockProject 3D nor the names of its contributors
+//       may be used to endorse or promote products derived from this software
+//       without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS
+// "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT
+// LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR
+// A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT OWNER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Export of recorded sessions to the Chrome trace-event JSON format, readable by
+//! chrome://tracing and Perfetto.
+//!
+//! The converter consumes a recorded stream of length-prefixed protocol frames (the bytes a
+//! file backend captures from the profiler connection) and streams the JSON array entry by
+//! entry, so multi-hundred-MB sessions never need to fit in memory. Span runs become
+//! complete ("X") events and log events become instant ("i") events; span names come from
+//! the SpanAlloc metadata. Until the protocol carries explicit start offsets, a span run's
+//! start is approximated as the last known stream time minus its duration, and thread ids
+//! are not transmitted, so tid is always 0. An in-process `Guard::export_chrome_trace`
+//! variant requires in-process session recording and will land together with it.
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io::{Read, Write};
+use bincode::Options;
+use byteorder::{ByteOrder, LittleEndian};
+use crate::profiler::network_types::{Command, event_flags};
+
+//The pid reported in every entry; the recording does not carry the producer's pid.
+const TRACE_PID: u32 = 1;
+
+fn escape_json(out: &mut String, value: &str) {
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c)
+        }
+    }
+}
+
+/// A streaming writer for the Trace Event JSON array format.
+pub struct TraceWriter<W: Write> {
+    out: W,
+    first: bool
+}
+
+impl<W: Write> TraceWriter<W> {
+    pub fn new(mut out: W) -> std::io::Result<TraceWriter<W>> {
+        out.write_all(b"[")?;
+        Ok(TraceWriter {
+            out,
+            first: true
+        })
+    }
+
+    fn entry(&mut self, body: &str) -> std::io::Result<()> {
+        if self.first {
+            self.first = false;
+        } else {
+            self.out.write_all(b",")?;
+        }
+        self.out.write_all(b"\n")?;
+        self.out.write_all(body.as_bytes())
+    }
+
+    /// Writes a complete ("X") event: a span run with microsecond timestamp and duration.
+    pub fn complete(&mut self, tid: u32, name: &str, ts_us: i64, dur_us: u64) -> std::io::Result<()> {
+        let mut body = String::from("{\"ph\":\"X\",\"pid\":");
+        body += &TRACE_PID.to_string();
+        body += ",\"tid\":";
+        body += &tid.to_string();
+        body += ",\"name\":\"";
+        escape_json(&mut body, name);
+        body += "\",\"ts\":";
+        body += &ts_us.to_string();
+        body += ",\"dur\":";
+        body += &dur_us.to_string();
+        body += "}";
+        self.entry(&body)
+    }
+
+    /// Writes an instant ("i") event.
+    pub fn instant(&mut self, tid: u32, name: &str, ts_us: i64) -> std::io::Result<()> {
+        let mut body = String::from("{\"ph\":\"i\",\"s\":\"t\",\"pid\":");
+        body += &TRACE_PID.to_string();
+        body += ",\"tid\":";
+        body += &tid.to_string();
+        body += ",\"name\":\"";
+        escape_json(&mut body, name);
+        body += "\",\"ts\":";
+        body += &ts_us.to_string();
+        body += "}";
+        self.entry(&body)
+    }
+
+    /// Terminates the JSON array and returns the underlying writer.
+    pub fn finish(mut self) -> std::io::Result<W> {
+        self.out.write_all(b"\n]\n")?;
+        Ok(self.out)
+    }
+}
+
+/// Converts a recorded stream of length-prefixed profiler frames into Chrome trace-event
+/// JSON. Both sides stream: frames are decoded one at a time and entries written as they
+/// are produced.
+pub fn convert_frames<R: Read, W: Write>(mut input: R, out: W) -> std::io::Result<W> {
+    let mut writer = TraceWriter::new(out)?;
+    let mut names: HashMap<u32, String> = HashMap::new();
+    let mut clock_us: i64 = 0;
+    let mut header = [0; 4];
+    loop {
+        if input.read_exact(&mut header).is_err() {
+            break; //End of recording.
+        }
+        let len = LittleEndian::read_u32(&header) as usize;
+        let mut frame = vec![0; len];
+        input.read_exact(&mut frame)
+            .map_err(|_| std::io::Error::other("truncated frame in recording"))?;
+        let cmd: Command = bincode::options().deserialize(&frame)
+            .map_err(|e| std::io::Error::other(format!("undecodable frame in recording: {}", e)))?;
+        match cmd {
+            Command::SpanAlloc { id, metadata, .. } => {
+                names.insert(id.id(), metadata.name().into());
+            },
+            Command::Event { flags, time, message, metadata, .. } => {
+                if flags & event_flags::ABSOLUTE_TIME != 0 {
+                    clock_us = time * 1_000_000;
+                } else {
+                    clock_us += time * 1_000_000;
+                }
+                let name = message.as_deref().unwrap_or_else(|| metadata.name());
+                writer.instant(0, name, clock_us)?;
+            },
+            Command::SpanExit { span, duration, .. } => {
+                let name = names.get(&span.id()).map(|v| v.as_str()).unwrap_or("<unknown>");
+                let duration: std::time::Duration = duration.into();
+                //A span entered and never properly exited can report an absurd elapsed
+                // value; saturate instead of silently wrapping the microsecond cast.
+                let dur_us = u64::try_from(duration.as_micros()).unwrap_or(u64::MAX);
+                //The protocol does not carry the start offset yet; approximate it from
+                // the last known stream time.
+                writer.complete(0, name, clock_us - dur_us as i64, dur_us)?;
+            },
+            _ => {}
+        }
+    }
+    writer.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::profiler::network_types::{Metadata, SpanId};
+    use super::*;
+
+    fn frame(cmd: &Command) -> Vec<u8> {
+        let body = bincode::options().serialize(cmd).unwrap();
+        let mut frame = vec![0; 4];
+        LittleEndian::write_u32(&mut frame, body.len() as u32);
+        frame.extend_from_slice(&body);
+        frame
+    }
+
+    fn test_metadata(name: &str) -> Metadata {
+        let record = log::Record::builder()
+            .target("bp3d_tracing::tests")
+            .level(log::Level::Info)
+            .build();
+        let mut metadata = Metadata::from_log(&record);
+        metadata.set_name(name.into());
+        metadata
+    }
+
+    #[test]
+    fn golden_small_session() {
+        let mut recording = Vec::new();
+        recording.extend(frame(&Command::SpanAlloc {
+            id: SpanId::from_u64(1 << 32),
+            metadata: test_metadata("parse \"doc\""),
+            tags: Vec::new()
+        }));
+        recording.extend(frame(&Command::Event {
+            span: None,
+            metadata: test_metadata("boot"),
+            flags: event_flags::ABSOLUTE_TIME,
+            time: 100,
+            message: Some("started".into()),
+            value_set: Vec::new()
+        }));
+        recording.extend(frame(&Command::Event {
+            span: None,
+            metadata: test_metadata("tick"),
+            flags: 0,
+            time: 2,
+            message: None,
+            value_set: Vec::new()
+        }));
+        recording.extend(frame(&Command::SpanExit {
+            span: SpanId::from_u64(1 << 32),
+            duration: std::time::Duration::from_millis(500).into(),
+            failed: false,
+            suspect: false,
+            memory_delta: None
+        }));
+        recording.extend(frame(&Command::Terminate));
+        let out = convert_frames(&recording[..], Vec::new()).unwrap();
+        let golden = "[\n\
+{\"ph\":\"i\",\"s\":\"t\",\"pid\":1,\"tid\":0,\"name\":\"started\",\"ts\":100000000},\n\
+{\"ph\":\"i\",\"s\":\"t\",\"pid\":1,\"tid\":0,\"name\":\"tick\",\"ts\":102000000},\n\
+{\"ph\":\"X\",\"pid\":1,\"tid\":0,\"name\":\"parse \\\"doc\\\"\",\"ts\":101500000,\"dur\":500000}\n\
+]\n";
+        assert_eq!(String::from_utf8(out).unwrap(), golden);
+    }
+
+    #[test]
+    fn truncated_recording_is_an_error() {
+        let mut recording = frame(&Command::Terminate);
+        recording.pop();
+        assert!(convert_frames(&recording[..], Vec::new()).is_err());
+    }
+}
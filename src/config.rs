@@ -0,0 +1,777 @@
+// Copyright (c) 2022, BlockProject 3D
+//
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification,
+// are permitted provided that the following conditions are met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//     * Redistributions in binary form must reproduce the above copyright notice,
+//       this list of conditions and the following disclaimer in the documentation
+//       and/or other materials provided with the distribution.
+//     * Neither the name of BlockProject 3D nor the names of its contributors
+//       may be used to endorse or promote products derived from this software
+//       without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS
+// "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT
+// LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR
+// A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT OWNER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Layered configuration for the logging and tracing systems.
+//!
+//! Every field is an `Option` so that configuration sources can be stacked: a base layer
+//! overlaid by an environment-specific layer, finally overlaid by the environment variables
+//! read in [Config::from_env](Config::from_env). Merging follows a single rule: a `Some` in
+//! the overlaying layer wins, a `None` keeps the value already present.
+
+use std::borrow::Cow;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use tracing_core::Level;
+
+fn parse_level(level: &str) -> Option<Level> {
+    match level {
+        "error" => Some(Level::ERROR),
+        "warning" => Some(Level::WARN),
+        "info" => Some(Level::INFO),
+        "debug" => Some(Level::DEBUG),
+        "trace" => Some(Level::TRACE),
+        _ => None
+    }
+}
+
+/// Configuration of the logger tracer.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LoggerConfig {
+    /// Disables the logger entirely.
+    pub disabled: Option<bool>,
+    /// The maximum level to log.
+    pub level: Option<Level>,
+    /// Logs a debug line when a span declares a follows-from relationship.
+    pub log_follows_from: Option<bool>,
+    /// Which lifecycle lines to emit for spans.
+    pub span_events: Option<SpanEvents>,
+    /// Renders completed spans as one compact line: `span[name] 12.3ms key=val`.
+    pub compact_span: Option<bool>,
+    /// Emits one info event at init with app, versions, OS/arch, CPU and command line,
+    /// giving log files a post-mortem context header.
+    pub log_startup_info: Option<bool>,
+    /// How boolean fields render.
+    pub bool_style: Option<BoolStyle>,
+    /// Routes log output whose target starts with a prefix into its own file (longest
+    /// prefix wins); unrouted targets keep the default sink.
+    pub file_routing: Vec<(String, std::path::PathBuf)>
+}
+
+impl LoggerConfig {
+    pub fn merge(&mut self, other: LoggerConfig) {
+        if let Some(v) = other.disabled {
+            self.disabled = Some(v);
+        }
+        if let Some(v) = other.level {
+            self.level = Some(v);
+        }
+        if let Some(v) = other.log_follows_from {
+            self.log_follows_from = Some(v);
+        }
+        if let Some(v) = other.span_events {
+            self.span_events = Some(v);
+        }
+        if let Some(v) = other.compact_span {
+            self.compact_span = Some(v);
+        }
+        if let Some(v) = other.log_startup_info {
+            self.log_startup_info = Some(v);
+        }
+        if let Some(v) = other.bool_style {
+            self.bool_style = Some(v);
+        }
+        if !other.file_routing.is_empty() {
+            self.file_routing = other.file_routing;
+        }
+    }
+}
+
+/// Configuration of console output.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ConsoleConfig {
+    /// Forces all messages to stdout instead of routing warnings/errors to stderr.
+    pub always_stdout: Option<bool>,
+    /// Enables or disables colored output; None lets the backend auto-detect.
+    pub colors: Option<bool>
+}
+
+impl ConsoleConfig {
+    pub fn merge(&mut self, other: ConsoleConfig) {
+        if let Some(v) = other.always_stdout {
+            self.always_stdout = Some(v);
+        }
+        if let Some(v) = other.colors {
+            self.colors = Some(v);
+        }
+    }
+}
+
+/// How boolean fields render in log lines.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BoolStyle {
+    /// `name=true` / `name=false`.
+    Verbose,
+    /// True flags render as `+name`, false flags are omitted.
+    Compact
+}
+
+fn parse_bool_style(mode: &str) -> Option<BoolStyle> {
+    match mode {
+        "verbose" => Some(BoolStyle::Verbose),
+        "compact" => Some(BoolStyle::Compact),
+        _ => None
+    }
+}
+
+/// Which lifecycle lines the logger emits for spans.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SpanEvents {
+    /// No span lines at all.
+    None,
+    /// Only the end summary (the historical behavior).
+    End,
+    /// A begin line on enter and an end line on exit, tagged with the instance id so
+    /// overlapping runs of the same callsite can be paired from the log alone.
+    BeginEnd
+}
+
+fn parse_span_events(mode: &str) -> Option<SpanEvents> {
+    match mode {
+        "none" => Some(SpanEvents::None),
+        "end" => Some(SpanEvents::End),
+        "begin_end" => Some(SpanEvents::BeginEnd),
+        _ => None
+    }
+}
+
+/// How much of span/event field values the profiler records.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FieldMode {
+    /// Record field names and values.
+    Full,
+    /// Record field names with a placeholder value; the shape of spans stays visible
+    /// without shipping the data itself (a privacy/bandwidth knob distinct from
+    /// redaction).
+    NamesOnly,
+    /// Skip fields entirely.
+    None
+}
+
+fn parse_field_mode(mode: &str) -> Option<FieldMode> {
+    match mode {
+        "full" => Some(FieldMode::Full),
+        "names_only" | "names" => Some(FieldMode::NamesOnly),
+        "none" => Some(FieldMode::None),
+        _ => None
+    }
+}
+
+/// How much location information each event carries on the wire.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LocationMode {
+    /// Target, module path, file and line.
+    Full,
+    /// Only the target; module path, file and line are dropped, reclaiming payload space
+    /// for user data.
+    TargetOnly,
+    /// No location at all.
+    None
+}
+
+fn parse_location_mode(mode: &str) -> Option<LocationMode> {
+    match mode {
+        "full" => Some(LocationMode::Full),
+        "target_only" | "target" => Some(LocationMode::TargetOnly),
+        "none" => Some(LocationMode::None),
+        _ => None
+    }
+}
+
+/// How span instance ids are allocated within a callsite.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InstanceAllocation {
+    /// Freed instance ids are recycled and the numbering resets once the span goes fully
+    /// idle: ids stay small but two runs of the same span can share an id.
+    Reuse,
+    /// Instance ids always increment, so two runs of the same span never share an id;
+    /// ids grow larger over time.
+    Monotonic
+}
+
+fn parse_instance_allocation(mode: &str) -> Option<InstanceAllocation> {
+    match mode {
+        "reuse" => Some(InstanceAllocation::Reuse),
+        "monotonic" => Some(InstanceAllocation::Monotonic),
+        _ => None
+    }
+}
+
+/// A server-side preset mirroring the client-controlled recording settings, for headless
+/// sessions where no interactive viewer is attached; a real client's values override
+/// these field-by-field.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ProfilerPreset {
+    /// How many points the rolling averages keep.
+    pub max_average_points: Option<u32>,
+    /// Starts recording without waiting for a client command.
+    pub record_enable: Option<bool>,
+    /// The maximum number of recorded rows.
+    pub record_max_rows: Option<u32>,
+    /// The sampling/report period in milliseconds.
+    pub period_ms: Option<u32>,
+    /// The maximum level recorded.
+    pub max_level: Option<Level>
+}
+
+impl ProfilerPreset {
+    pub fn merge(&mut self, other: ProfilerPreset) {
+        if let Some(v) = other.max_average_points {
+            self.max_average_points = Some(v);
+        }
+        if let Some(v) = other.record_enable {
+            self.record_enable = Some(v);
+        }
+        if let Some(v) = other.record_max_rows {
+            self.record_max_rows = Some(v);
+        }
+        if let Some(v) = other.period_ms {
+            self.period_ms = Some(v);
+        }
+        if let Some(v) = other.max_level {
+            self.max_level = Some(v);
+        }
+    }
+}
+
+/// Configuration of the network profiler tracer.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ProfilerConfig {
+    /// The TCP port to listen on for a debugger connection.
+    pub port: Option<u16>,
+    /// The capacity of the profiler command channel.
+    pub channel_capacity: Option<usize>,
+    /// Emits the discovered callsite-level span tree as a single message on terminate.
+    pub export_span_tree: Option<bool>,
+    /// How much of span/event field values to record.
+    pub fields: Option<FieldMode>,
+    /// How much location information each event carries.
+    pub event_include_location: Option<LocationMode>,
+    /// Batches up to this many commands in a thread-local buffer before one channel send;
+    /// 0 or 1 disables batching.
+    pub batch_size: Option<usize>,
+    /// Flushes a non-empty batch at the next command after this many milliseconds.
+    pub batch_flush_ms: Option<u64>,
+    /// Captures the process RSS at span enter/exit and reports the delta; off by default
+    /// because reading RSS costs a syscall per span run.
+    pub capture_memory: Option<bool>,
+    /// Recording settings applied as if a client had sent them.
+    pub preset: ProfilerPreset,
+    /// Where auto-discovery announcements are sent (a broadcast or multicast address);
+    /// defaults to the limited broadcast address on the default discovery port.
+    pub discovery_address: Option<SocketAddrV4>,
+    /// The local IPv4 address to bind the discovery socket to, selecting the outbound
+    /// interface on multi-homed hosts.
+    pub discovery_interface: Option<Ipv4Addr>,
+    /// Overrides where local artifacts (summary, dumps) are written.
+    pub artifacts_dir: Option<std::path::PathBuf>,
+    /// Lets clients download the current log file over the profiler connection; off by
+    /// default for privacy-sensitive deployments.
+    pub allow_log_download: Option<bool>,
+    /// Marks span runs as suspect when the writer observed a processing gap longer than
+    /// this many milliseconds with an empty channel (debugger-induced stall).
+    pub stall_threshold_ms: Option<u64>,
+    /// Caps how many variables one span/event payload records; excess fields collapse
+    /// into an explicit `...(N more fields)` marker instead of being dropped silently.
+    pub max_vars_per_span: Option<usize>,
+    /// Caps how many live span instances keep full tracking state; beyond it new
+    /// instances degrade to duration-only tracking so instance-heavy workloads stay
+    /// memory-bounded.
+    pub max_tracked_instances: Option<usize>,
+    /// Counts sent messages per protocol type for wire debugging; off by default since
+    /// it costs a map update per frame.
+    pub self_metrics: Option<bool>,
+    /// How long a connecting client may take to answer the Hello handshake before the
+    /// connection is dropped and the profiler returns to accepting.
+    pub handshake_timeout_ms: Option<u64>,
+    /// Caps concurrently open pooled output files (the logger's per-target route files
+    /// today, per-span dataset streams when they land); the least-recently-written
+    /// stream is flushed and closed past the cap, reopening on demand.
+    pub max_open_datasets: Option<usize>,
+    /// When set, connections start read-only and must authenticate with this token
+    /// before state-changing client messages are honored.
+    pub control_token: Option<String>,
+    /// Caps how many bytes a Debug-rendered field value may produce; rendering
+    /// short-circuits at the cap instead of formatting megabytes that would be dropped.
+    pub max_debug_len: Option<usize>,
+    /// Installs a SIGUSR1 handler (and a trigger-file fallback) dumping current span
+    /// statistics to the artifacts directory without disturbing the session.
+    pub dump_on_signal: Option<bool>
+}
+
+impl ProfilerConfig {
+    pub fn merge(&mut self, other: ProfilerConfig) {
+        if let Some(v) = other.port {
+            self.port = Some(v);
+        }
+        if let Some(v) = other.channel_capacity {
+            self.channel_capacity = Some(v);
+        }
+        if let Some(v) = other.export_span_tree {
+            self.export_span_tree = Some(v);
+        }
+        if let Some(v) = other.fields {
+            self.fields = Some(v);
+        }
+        if let Some(v) = other.event_include_location {
+            self.event_include_location = Some(v);
+        }
+        if let Some(v) = other.batch_size {
+            self.batch_size = Some(v);
+        }
+        if let Some(v) = other.batch_flush_ms {
+            self.batch_flush_ms = Some(v);
+        }
+        if let Some(v) = other.capture_memory {
+            self.capture_memory = Some(v);
+        }
+        self.preset.merge(other.preset);
+        if let Some(v) = other.discovery_address {
+            self.discovery_address = Some(v);
+        }
+        if let Some(v) = other.discovery_interface {
+            self.discovery_interface = Some(v);
+        }
+        if let Some(v) = other.artifacts_dir {
+            self.artifacts_dir = Some(v);
+        }
+        if let Some(v) = other.allow_log_download {
+            self.allow_log_download = Some(v);
+        }
+        if let Some(v) = other.stall_threshold_ms {
+            self.stall_threshold_ms = Some(v);
+        }
+        if let Some(v) = other.max_vars_per_span {
+            self.max_vars_per_span = Some(v);
+        }
+        if let Some(v) = other.max_tracked_instances {
+            self.max_tracked_instances = Some(v);
+        }
+        if let Some(v) = other.self_metrics {
+            self.self_metrics = Some(v);
+        }
+        if let Some(v) = other.handshake_timeout_ms {
+            self.handshake_timeout_ms = Some(v);
+        }
+        if let Some(v) = other.max_open_datasets {
+            self.max_open_datasets = Some(v);
+        }
+        if let Some(v) = other.control_token {
+            self.control_token = Some(v);
+        }
+        if let Some(v) = other.max_debug_len {
+            self.max_debug_len = Some(v);
+        }
+        if let Some(v) = other.dump_on_signal {
+            self.dump_on_signal = Some(v);
+        }
+    }
+}
+
+/// The root configuration of the tracing systems.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Config {
+    pub logger: LoggerConfig,
+    pub console: ConsoleConfig,
+    pub profiler: ProfilerConfig,
+    /// The maximum sustained event rate; when exceeded, debug/trace events are shed until
+    /// the volume subsides (adaptive level raising).
+    pub max_events_per_sec: Option<u32>,
+    /// How span instance ids are allocated.
+    pub instance_allocation: Option<InstanceAllocation>,
+    /// Attaches the active span stack (breadcrumbs) as a span.stack field to events at or
+    /// above this level.
+    pub attach_span_stack_on: Option<Level>,
+    /// Asserts the application runs spans on a single thread, selecting a global span
+    /// stack without thread-local lookup or borrow counting. Unsound if violated.
+    pub single_threaded: Option<bool>
+}
+
+impl Config {
+    /// Builds a configuration layer from the process environment (LOG, LOG_DISABLE,
+    /// LOG_STDOUT, LOG_COLOR and PROFILER_PORT). Unset or unparsable variables yield None
+    /// so that merging this layer never clears a value from a lower layer.
+    pub fn from_env() -> Config {
+        Config {
+            logger: LoggerConfig {
+                disabled: bp3d_env::get_bool("LOG_DISABLE"),
+                level: bp3d_env::get("LOG").map(|v| v.to_lowercase())
+                    .map(Cow::Owned)
+                    .and_then(|v| parse_level(&v)),
+                log_follows_from: bp3d_env::get_bool("LOG_FOLLOWS_FROM"),
+                span_events: bp3d_env::get("LOG_SPAN_EVENTS").map(|v| v.to_lowercase())
+                    .and_then(|v| parse_span_events(&v)),
+                compact_span: bp3d_env::get_bool("LOG_COMPACT_SPAN"),
+                log_startup_info: bp3d_env::get_bool("LOG_STARTUP_INFO"),
+                bool_style: bp3d_env::get("LOG_BOOL_STYLE").map(|v| v.to_lowercase())
+                    .and_then(|v| parse_bool_style(&v)),
+                file_routing: Vec::new()
+            },
+            console: ConsoleConfig {
+                always_stdout: bp3d_env::get_bool("LOG_STDOUT"),
+                colors: bp3d_env::get_bool("LOG_COLOR")
+            },
+            max_events_per_sec: bp3d_env::get("MAX_EVENTS_PER_SEC").and_then(|v| v.parse().ok()),
+            instance_allocation: bp3d_env::get("INSTANCE_ALLOCATION").map(|v| v.to_lowercase())
+                .and_then(|v| parse_instance_allocation(&v)),
+            attach_span_stack_on: bp3d_env::get("ATTACH_SPAN_STACK").map(|v| v.to_lowercase())
+                .and_then(|v| parse_level(&v)),
+            single_threaded: bp3d_env::get_bool("SINGLE_THREADED"),
+            profiler: ProfilerConfig {
+                port: bp3d_env::get("PROFILER_PORT").and_then(|v| v.parse().ok()),
+                channel_capacity: bp3d_env::get("PROFILER_CHANNEL_CAPACITY").and_then(|v| v.parse().ok()),
+                export_span_tree: bp3d_env::get_bool("PROFILER_EXPORT_SPAN_TREE"),
+                fields: bp3d_env::get("PROFILER_FIELDS").map(|v| v.to_lowercase())
+                    .and_then(|v| parse_field_mode(&v)),
+                event_include_location: bp3d_env::get("PROFILER_EVENT_LOCATION").map(|v| v.to_lowercase())
+                    .and_then(|v| parse_location_mode(&v)),
+                batch_size: bp3d_env::get("PROFILER_BATCH_SIZE").and_then(|v| v.parse().ok()),
+                batch_flush_ms: bp3d_env::get("PROFILER_BATCH_FLUSH_MS").and_then(|v| v.parse().ok()),
+                capture_memory: bp3d_env::get_bool("PROFILER_CAPTURE_MEMORY"),
+                preset: ProfilerPreset {
+                    max_average_points: bp3d_env::get("PROFILER_PRESET_AVERAGE_POINTS").and_then(|v| v.parse().ok()),
+                    record_enable: bp3d_env::get_bool("PROFILER_PRESET_RECORD"),
+                    record_max_rows: bp3d_env::get("PROFILER_PRESET_MAX_ROWS").and_then(|v| v.parse().ok()),
+                    period_ms: bp3d_env::get("PROFILER_PRESET_PERIOD_MS").and_then(|v| v.parse().ok()),
+                    max_level: bp3d_env::get("PROFILER_PRESET_MAX_LEVEL").map(|v| v.to_lowercase())
+                        .and_then(|v| parse_level(&v))
+                },
+                discovery_address: bp3d_env::get("PROFILER_DISCOVERY_ADDRESS").and_then(|v| v.parse().ok()),
+                discovery_interface: bp3d_env::get("PROFILER_DISCOVERY_INTERFACE").and_then(|v| v.parse().ok()),
+                artifacts_dir: bp3d_env::get("PROFILER_ARTIFACTS_DIR").map(std::path::PathBuf::from),
+                allow_log_download: bp3d_env::get_bool("PROFILER_ALLOW_LOG_DOWNLOAD"),
+                stall_threshold_ms: bp3d_env::get("PROFILER_STALL_THRESHOLD_MS").and_then(|v| v.parse().ok()),
+                max_vars_per_span: bp3d_env::get("PROFILER_MAX_VARS_PER_SPAN").and_then(|v| v.parse().ok()),
+                max_tracked_instances: bp3d_env::get("PROFILER_MAX_TRACKED_INSTANCES").and_then(|v| v.parse().ok()),
+                self_metrics: bp3d_env::get_bool("PROFILER_SELF_METRICS"),
+                handshake_timeout_ms: bp3d_env::get("PROFILER_HANDSHAKE_TIMEOUT_MS").and_then(|v| v.parse().ok()),
+                max_open_datasets: bp3d_env::get("PROFILER_MAX_OPEN_DATASETS").and_then(|v| v.parse().ok()),
+                control_token: bp3d_env::get("PROFILER_CONTROL_TOKEN"),
+                max_debug_len: bp3d_env::get("PROFILER_MAX_DEBUG_LEN").and_then(|v| v.parse().ok()),
+                dump_on_signal: bp3d_env::get_bool("PROFILER_DUMP_ON_SIGNAL")
+            }
+        }
+    }
+
+    /// Parses a configuration layer from an embedded TOML string (typically included at
+    /// compile time with `include_str!`), for single-binary distribution of defaults.
+    /// Unknown keys are ignored and absent keys stay None, so the embedded layer composes
+    /// with [merge](Config::merge) like any other: embedded base first, then file layers,
+    /// then [from_env](Config::from_env) last so users keep control.
+    pub fn from_embedded(toml: &'static str) -> Config {
+        let root: toml::Value = toml.parse().unwrap_or(toml::Value::Boolean(false));
+        let section = |name: &str| root.get(name).and_then(|v| v.as_table().cloned());
+        let get_bool = |table: &Option<toml::value::Table>, key: &str|
+            table.as_ref().and_then(|t| t.get(key)).and_then(|v| v.as_bool());
+        let get_int = |table: &Option<toml::value::Table>, key: &str|
+            table.as_ref().and_then(|t| t.get(key)).and_then(|v| v.as_integer());
+        let get_str = |table: &Option<toml::value::Table>, key: &str|
+            table.as_ref().and_then(|t| t.get(key)).and_then(|v| v.as_str().map(|v| v.to_lowercase()));
+        let logger = section("logger");
+        let console = section("console");
+        let profiler = section("profiler");
+        let preset = profiler.as_ref()
+            .and_then(|t| t.get("preset"))
+            .and_then(|v| v.as_table().cloned());
+        Config {
+            logger: LoggerConfig {
+                disabled: get_bool(&logger, "disabled"),
+                level: get_str(&logger, "level").and_then(|v| parse_level(&v)),
+                log_follows_from: get_bool(&logger, "log_follows_from"),
+                span_events: get_str(&logger, "span_events").and_then(|v| parse_span_events(&v)),
+                compact_span: get_bool(&logger, "compact_span"),
+                log_startup_info: get_bool(&logger, "log_startup_info"),
+                bool_style: get_str(&logger, "bool_style").and_then(|v| parse_bool_style(&v)),
+                file_routing: logger.as_ref()
+                    .and_then(|t| t.get("file_routing"))
+                    .and_then(|v| v.as_array())
+                    .map(|entries| entries.iter()
+                        .filter_map(|entry| {
+                            let table = entry.as_table()?;
+                            let prefix = table.get("target_prefix")?.as_str()?;
+                            let path = table.get("path")?.as_str()?;
+                            Some((prefix.to_string(), std::path::PathBuf::from(path)))
+                        })
+                        .collect())
+                    .unwrap_or_default()
+            },
+            console: ConsoleConfig {
+                always_stdout: get_bool(&console, "always_stdout"),
+                colors: get_bool(&console, "colors")
+            },
+            max_events_per_sec: get_int(&root.get("tracing").and_then(|v| v.as_table().cloned()), "max_events_per_sec")
+                .or_else(|| root.get("max_events_per_sec").and_then(|v| v.as_integer()))
+                .map(|v| v as u32),
+            instance_allocation: root.get("instance_allocation")
+                .and_then(|v| v.as_str())
+                .and_then(|v| parse_instance_allocation(&v.to_lowercase())),
+            attach_span_stack_on: root.get("attach_span_stack_on")
+                .and_then(|v| v.as_str())
+                .and_then(|v| parse_level(&v.to_lowercase())),
+            single_threaded: root.get("single_threaded").and_then(|v| v.as_bool()),
+            profiler: ProfilerConfig {
+                port: get_int(&profiler, "port").map(|v| v as u16),
+                channel_capacity: get_int(&profiler, "channel_capacity").map(|v| v as usize),
+                export_span_tree: get_bool(&profiler, "export_span_tree"),
+                fields: get_str(&profiler, "fields").and_then(|v| parse_field_mode(&v)),
+                event_include_location: get_str(&profiler, "event_include_location")
+                    .and_then(|v| parse_location_mode(&v)),
+                batch_size: get_int(&profiler, "batch_size").map(|v| v as usize),
+                batch_flush_ms: get_int(&profiler, "batch_flush_ms").map(|v| v as u64),
+                capture_memory: get_bool(&profiler, "capture_memory"),
+                allow_log_download: get_bool(&profiler, "allow_log_download"),
+                stall_threshold_ms: get_int(&profiler, "stall_threshold_ms").map(|v| v as u64),
+                max_vars_per_span: get_int(&profiler, "max_vars_per_span").map(|v| v as usize),
+                max_tracked_instances: get_int(&profiler, "max_tracked_instances").map(|v| v as usize),
+                self_metrics: get_bool(&profiler, "self_metrics"),
+                handshake_timeout_ms: get_int(&profiler, "handshake_timeout_ms").map(|v| v as u64),
+                max_open_datasets: get_int(&profiler, "max_open_datasets").map(|v| v as usize),
+                control_token: profiler.as_ref()
+                    .and_then(|t| t.get("control_token"))
+                    .and_then(|v| v.as_str())
+                    .map(|v| v.to_string()),
+                max_debug_len: get_int(&profiler, "max_debug_len").map(|v| v as usize),
+                dump_on_signal: get_bool(&profiler, "dump_on_signal"),
+                artifacts_dir: profiler.as_ref()
+                    .and_then(|t| t.get("artifacts_dir"))
+                    .and_then(|v| v.as_str())
+                    .map(std::path::PathBuf::from),
+                discovery_address: profiler.as_ref()
+                    .and_then(|t| t.get("discovery_address"))
+                    .and_then(|v| v.as_str())
+                    .and_then(|v| v.parse().ok()),
+                discovery_interface: profiler.as_ref()
+                    .and_then(|t| t.get("discovery_interface"))
+                    .and_then(|v| v.as_str())
+                    .and_then(|v| v.parse().ok()),
+                preset: ProfilerPreset {
+                    max_average_points: get_int(&preset, "max_average_points").map(|v| v as u32),
+                    record_enable: get_bool(&preset, "record_enable"),
+                    record_max_rows: get_int(&preset, "record_max_rows").map(|v| v as u32),
+                    period_ms: get_int(&preset, "period_ms").map(|v| v as u32),
+                    max_level: get_str(&preset, "max_level").and_then(|v| parse_level(&v))
+                }
+            }
+        }
+    }
+
+    /// Overlays `other` onto this configuration: `Some` fields in `other` win, `None`
+    /// fields keep the value of `self`. Recurses into each section.
+    pub fn merge(&mut self, other: Config) {
+        self.logger.merge(other.logger);
+        self.console.merge(other.console);
+        self.profiler.merge(other.profiler);
+        if let Some(v) = other.max_events_per_sec {
+            self.max_events_per_sec = Some(v);
+        }
+        if let Some(v) = other.instance_allocation {
+            self.instance_allocation = Some(v);
+        }
+        if let Some(v) = other.attach_span_stack_on {
+            self.attach_span_stack_on = Some(v);
+        }
+        if let Some(v) = other.single_threaded {
+            self.single_threaded = Some(v);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn base() -> Config {
+        Config {
+            logger: LoggerConfig {
+                disabled: Some(false),
+                level: Some(Level::INFO),
+                log_follows_from: Some(false),
+                span_events: Some(SpanEvents::End),
+                compact_span: Some(false),
+                log_startup_info: Some(false),
+                bool_style: Some(BoolStyle::Verbose),
+                file_routing: Vec::new()
+            },
+            console: ConsoleConfig {
+                always_stdout: Some(false),
+                colors: None
+            },
+            max_events_per_sec: None,
+            instance_allocation: Some(InstanceAllocation::Reuse),
+            attach_span_stack_on: None,
+            single_threaded: Some(false),
+            profiler: ProfilerConfig {
+                port: Some(4026),
+                channel_capacity: Some(128),
+                export_span_tree: Some(true),
+                fields: Some(FieldMode::Full),
+                event_include_location: Some(LocationMode::Full),
+                batch_size: None,
+                batch_flush_ms: None,
+                capture_memory: Some(false),
+                preset: ProfilerPreset::default(),
+                discovery_address: None,
+                discovery_interface: None,
+                artifacts_dir: None,
+                allow_log_download: Some(false),
+                stall_threshold_ms: Some(2000),
+                max_vars_per_span: None,
+                max_tracked_instances: None,
+                self_metrics: Some(false),
+                handshake_timeout_ms: Some(5000),
+                max_open_datasets: Some(64),
+                control_token: None,
+                max_debug_len: None,
+                dump_on_signal: Some(false)
+            }
+        }
+    }
+
+    #[test]
+    fn merge_some_wins() {
+        let mut config = base();
+        config.merge(Config {
+            logger: LoggerConfig {
+                disabled: None,
+                level: Some(Level::DEBUG),
+                log_follows_from: Some(true),
+                span_events: Some(SpanEvents::BeginEnd),
+                compact_span: Some(true),
+                log_startup_info: Some(true),
+                bool_style: Some(BoolStyle::Compact),
+                file_routing: vec![("my_engine::render".into(), std::path::PathBuf::from("render.log"))]
+            },
+            console: ConsoleConfig {
+                always_stdout: None,
+                colors: Some(true)
+            },
+            max_events_per_sec: Some(10_000),
+            instance_allocation: Some(InstanceAllocation::Monotonic),
+            attach_span_stack_on: Some(Level::ERROR),
+            single_threaded: None,
+            profiler: ProfilerConfig {
+                port: Some(4027),
+                channel_capacity: None,
+                export_span_tree: None,
+                fields: Some(FieldMode::NamesOnly),
+                event_include_location: Some(LocationMode::TargetOnly),
+                batch_size: Some(64),
+                batch_flush_ms: Some(10),
+                capture_memory: Some(true),
+                preset: ProfilerPreset {
+                    record_enable: Some(true),
+                    ..ProfilerPreset::default()
+                },
+                discovery_address: None,
+                discovery_interface: Some(Ipv4Addr::LOCALHOST),
+                artifacts_dir: None,
+                allow_log_download: None,
+                stall_threshold_ms: None,
+                max_vars_per_span: Some(32),
+                max_tracked_instances: Some(100_000),
+                self_metrics: None,
+                handshake_timeout_ms: None,
+                max_open_datasets: None,
+                control_token: Some("staging-secret".into()),
+                max_debug_len: Some(4096),
+                dump_on_signal: None
+            }
+        });
+        assert_eq!(config.logger.disabled, Some(false)); //None keeps self
+        assert_eq!(config.logger.level, Some(Level::DEBUG)); //Some wins
+        assert_eq!(config.logger.log_follows_from, Some(true));
+        assert_eq!(config.logger.span_events, Some(SpanEvents::BeginEnd));
+        assert_eq!(config.logger.compact_span, Some(true));
+        assert_eq!(config.console.always_stdout, Some(false));
+        assert_eq!(config.console.colors, Some(true));
+        assert_eq!(config.profiler.port, Some(4027));
+        assert_eq!(config.profiler.channel_capacity, Some(128));
+        assert_eq!(config.profiler.fields, Some(FieldMode::NamesOnly));
+        assert_eq!(config.profiler.event_include_location, Some(LocationMode::TargetOnly));
+        assert_eq!(config.profiler.batch_size, Some(64));
+        assert_eq!(config.profiler.capture_memory, Some(true));
+        assert_eq!(config.profiler.preset.record_enable, Some(true));
+        assert_eq!(config.profiler.discovery_interface, Some(Ipv4Addr::LOCALHOST));
+        assert_eq!(config.max_events_per_sec, Some(10_000));
+        assert_eq!(config.instance_allocation, Some(InstanceAllocation::Monotonic));
+        assert_eq!(config.attach_span_stack_on, Some(Level::ERROR));
+    }
+
+    #[test]
+    fn embedded_toml_layers_under_later_overrides() {
+        //The base a binary would ship via include_str!.
+        let embedded = Config::from_embedded(r#"
+single_threaded = false
+[logger]
+level = "debug"
+span_events = "begin_end"
+[profiler]
+port = 5055
+max_vars_per_span = 16
+[profiler.preset]
+record_enable = true
+"#);
+        //Its values override the built-in defaults (which are all None)...
+        assert_eq!(embedded.logger.level, Some(Level::DEBUG));
+        assert_eq!(embedded.logger.span_events, Some(SpanEvents::BeginEnd));
+        assert_eq!(embedded.profiler.port, Some(5055));
+        assert_eq!(embedded.profiler.max_vars_per_span, Some(16));
+        assert_eq!(embedded.profiler.preset.record_enable, Some(true));
+        assert_eq!(embedded.logger.disabled, None); //Absent keys stay unset.
+        //...and are themselves overridden field-by-field by a later layer (the shape
+        // Config::from_env produces).
+        let mut layered = embedded;
+        layered.merge(Config {
+            logger: LoggerConfig {
+                level: Some(Level::ERROR),
+                ..LoggerConfig::default()
+            },
+            ..Config::default()
+        });
+        assert_eq!(layered.logger.level, Some(Level::ERROR));
+        assert_eq!(layered.profiler.port, Some(5055)); //Untouched fields survive.
+    }
+
+    #[test]
+    fn garbage_embedded_toml_yields_an_empty_layer() {
+        let config = Config::from_embedded("this is not toml {{{{");
+        assert_eq!(config, Config::default());
+    }
+
+    #[test]
+    fn merge_empty_overlay_keeps_base() {
+        let mut config = base();
+        config.merge(Config::default());
+        assert_eq!(config, base());
+    }
+
+    #[test]
+    fn merge_into_empty_takes_overlay() {
+        let mut config = Config::default();
+        config.merge(base());
+        assert_eq!(config, base());
+    }
+}
@@ -0,0 +1,106 @@
+// Copyright (c) 2022, BlockProject 3D
+//
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification,
+// are permitted provided that the following conditions are met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//     * Redistributions in binary form must reproduce the above copyright notice,
+//       this list of conditions and the following disclaimer in the documentation
+//       and/or other materials provided with the distribution.
+//     * Neither the name of BlockProject 3D nor the names of its contributors
+//       may be used to endorse or promote products derived from this software
+//       without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS
+// "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT
+// LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR
+// A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT OWNER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Lightweight span context propagation across thread boundaries.
+//!
+//! Spans don't follow work handed to std::thread::spawn or a rayon pool: the worker
+//! thread's span stack is empty, so its spans and events attribute to root. Capture the
+//! context on the originating thread with [current](current) and attach it on the worker;
+//! attached contexts are markers on the stack - they resolve parent attribution but are
+//! never timed entries and produce no span exit of their own.
+
+use tracing_core::span::Id;
+use crate::core::{pop_attached_span, push_attached_span, current_span_entry};
+
+/// A captured snapshot of the current span context (the innermost active span).
+#[derive(Clone)]
+pub struct TraceContext {
+    head: Option<(Id, &'static str)>
+}
+
+/// Keeps an attached context on the worker thread's stack; detaches on drop.
+pub struct ContextGuard {
+    attached: Option<Id>
+}
+
+impl Drop for ContextGuard {
+    fn drop(&mut self) {
+        if let Some(id) = self.attached.take() {
+            pop_attached_span(&id);
+        }
+    }
+}
+
+/// Captures the current thread's span context for transfer to another thread.
+pub fn current() -> TraceContext {
+    TraceContext {
+        head: current_span_entry()
+    }
+}
+
+impl TraceContext {
+    /// Attaches the captured context to the current thread: spans and events created
+    /// while the guard lives attribute to the captured span. The marker entry carries no
+    /// timing; only the original thread's real enter/exit measures the span.
+    pub fn attach(self) -> ContextGuard {
+        ContextGuard {
+            attached: self.head.map(|(id, name)| {
+                push_attached_span(&id, name);
+                id
+            })
+        }
+    }
+
+    /// Runs the closure with this context attached; the rayon-friendly shape for
+    /// `par_iter` bodies.
+    pub fn in_context<R>(&self, func: impl FnOnce() -> R) -> R {
+        let _guard = self.clone().attach();
+        func()
+    }
+}
+
+/// Spawns a thread that inherits the caller's span context for its whole body.
+pub fn spawn_traced<R: 'static + Send>(
+    func: impl FnOnce() -> R + Send + 'static
+) -> std::thread::JoinHandle<R> {
+    let context = current();
+    std::thread::spawn(move || context.in_context(func))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_context_attaches_as_a_no_op() {
+        let context = current();
+        assert!(context.head.is_none());
+        let guard = context.attach();
+        drop(guard);
+    }
+}
@@ -0,0 +1,1238 @@
+// Copyright (c) 2022, BlockProject 3D
+//
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification,
+// are permitted provided that the following conditions are met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//     * Redistributions in binary form must reproduce the above copyright notice,
+//       this list of conditions and the following disclaimer in the documentation
+//       and/or other materials provided with the distribution.
+//     * Neither the name of BlockProject 3D nor the names of its contributors
+//       may be used to endorse or promote products derived from this software
+//       without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS
+// "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT
+// LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR
+// A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT OWNER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use dashmap::DashMap;
+use time::OffsetDateTime;
+use tracing_core::{Event, Level, LevelFilter, Metadata, Subscriber};
+use tracing_core::span::{Attributes, Current, Id, Record};
+use tracing_core::subscriber::Interest;
+use crate::config::InstanceAllocation;
+use tracing_core::callsite::Identifier;
+use crate::util::{Meta, span_from_id_instance, span_to_id_instance, SPAN_ID_MAX};
+
+//TODO: Check if by any chance anything could panic (normally nothing should ever be able to panic here).
+
+//Callsite ids above this limit are never handed out; a process that somehow exhausts the
+// callsite id space keeps running with all further callsites sharing the reserved
+// overflow head below instead of wrapping the counter back into the forbidden zero id.
+const SPAN_ID_LIMIT: u32 = SPAN_ID_MAX - 1;
+
+//The span id shared by every callsite discovered after the counter saturated.
+const OVERFLOW_SPAN_ID: u32 = SPAN_ID_MAX;
+
+
+/// Which backend a tracing system (and the Guard wrapping it) drives; lets applications
+/// dispatch backend-specific control without downcasting an opaque box.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackendKind {
+    /// The console/file logger.
+    Logger,
+    /// The network profiler.
+    Profiler,
+    /// Any other tracer (event bridge, JSON sink, scoped test systems).
+    Other
+}
+
+pub struct TracingSystem<T> {
+    pub system: BaseTracer<T>,
+    pub backend: BackendKind,
+    pub destructor: Option<Box<dyn Any + Send>>
+}
+
+impl<T> TracingSystem<T> {
+    pub fn with_destructor(backend: BackendKind, derived: T, destructor: Box<dyn Any + Send>) -> TracingSystem<T> {
+        TracingSystem {
+            system: BaseTracer::new(derived),
+            backend,
+            destructor: Some(destructor)
+        }
+    }
+
+    pub fn without_destructor(backend: BackendKind, derived: T) -> TracingSystem<T> {
+        TracingSystem {
+            system: BaseTracer::new(derived),
+            backend,
+            destructor: None
+        }
+    }
+}
+
+/// Why a span instance is being destroyed, inferred by the subscriber: a span closed
+/// while still on its thread's stack never exited - it was dropped/cancelled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    Normal,
+    Dropped
+}
+
+pub trait Tracer {
+    fn enabled(&self) -> bool;
+    fn span_create(&self, id: &Id, new: bool, parent: Option<Id>, span: &Attributes);
+    fn span_values(&self, id: &Id, values: &Record);
+    fn span_follows_from(&self, id: &Id, follows: &Id);
+    fn event(&self, parent: Option<Id>, time: OffsetDateTime, event: &Event);
+    fn span_enter(&self, id: &Id);
+    fn span_exit(&self, id: &Id, duration: Duration);
+    fn span_destroy(&self, id: &Id, reason: CloseReason);
+    fn max_level_hint(&self) -> Option<Level>;
+}
+
+struct SpanData {
+    ref_count: usize,
+    metadata: Meta,
+    last_time: Option<Instant>
+}
+
+struct SpanHead {
+    next_instance_id: u64,
+    instance_count: u64,
+    allocation: InstanceAllocation,
+    freed_instances: VecDeque<u64>
+}
+
+impl SpanHead {
+    pub fn new(allocation: InstanceAllocation) -> SpanHead {
+        SpanHead {
+            next_instance_id: 0,
+            instance_count: 0,
+            allocation,
+            freed_instances: VecDeque::new()
+        }
+    }
+
+    pub fn free_instance(&mut self, id: u64) {
+        self.instance_count -= 1;
+        if self.allocation == InstanceAllocation::Monotonic {
+            //Never recycle: two runs of the same span must not share an id.
+            return;
+        }
+        if self.instance_count == 0 {
+            self.freed_instances.clear();
+            self.next_instance_id = 0;
+        } else {
+            self.freed_instances.push_back(id);
+        }
+    }
+
+    pub fn new_instance(&mut self) -> u64 {
+        self.instance_count += 1;
+        match self.freed_instances.pop_back() {
+            None => {
+                let id = self.next_instance_id;
+                self.next_instance_id += 1;
+                id
+            },
+            Some(v) => v
+        }
+    }
+}
+
+//The content identity of a callsite, used to suppress duplicate allocations when the
+// same logical callsite reappears at a different address (e.g. a reloaded dynamic
+// library rehashing its statics).
+type ContentKey = (&'static str, Option<&'static str>, Option<u32>);
+
+fn content_key(metadata: Meta) -> ContentKey {
+    (metadata.name(), metadata.file(), metadata.line())
+}
+
+//The active span stack of the current thread: ids with their span names, innermost last.
+// Thread-local, so reading it (current-span resolution, breadcrumbs for error events)
+// takes no lock.
+thread_local! {
+    static SPAN_STACK: RefCell<Vec<(Id, &'static str)>> = const { RefCell::new(Vec::new()) };
+}
+
+//The single-threaded fast path: one global stack with no TLS lookup and no RefCell
+// borrow counting, for embedded-style apps that assert single-threadedness via the
+// single_threaded config. SAFETY contract: this cell is only touched when that assertion
+// was made, in which case exactly one thread runs spans; the thread-local default stays
+// the correct choice everywhere else.
+struct SingleThreadStack(std::cell::UnsafeCell<Vec<(Id, &'static str)>>);
+
+unsafe impl Sync for SingleThreadStack {}
+
+static ST_STACK: SingleThreadStack = SingleThreadStack(std::cell::UnsafeCell::new(Vec::new()));
+
+//Mirrors the installed tracer's single_threaded flag for the free accessors.
+static SINGLE_THREADED: AtomicBool = AtomicBool::new(false);
+
+//Live spans across every tracer instance, for the global accessor; each tracer also
+// keeps its own count.
+static LIVE_SPANS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+/// How many spans are currently live process-wide (created and not yet closed); a number
+/// that keeps growing points at spans cloned but never closed.
+pub fn live_span_count() -> usize {
+    LIVE_SPANS.load(Ordering::Relaxed)
+}
+
+fn with_stack<R>(single_threaded: bool, func: impl FnOnce(&mut Vec<(Id, &'static str)>) -> R) -> R {
+    if single_threaded {
+        //SAFETY: see SingleThreadStack - the app asserted only one thread runs spans.
+        unsafe { func(&mut *ST_STACK.0.get()) }
+    } else {
+        SPAN_STACK.with(|stack| func(&mut stack.borrow_mut()))
+    }
+}
+
+//The span stack snapshot attached to the event currently being delivered, when the
+// attach-on level matched; sinks read it through attached_span_stack().
+thread_local! {
+    static EVENT_STACK: RefCell<Option<Vec<&'static str>>> = const { RefCell::new(None) };
+}
+
+/// Returns the names of the spans active on this thread, outermost first.
+#[allow(dead_code)] //Public surface for diagnostics; event capture snapshots directly.
+pub fn span_stack_names() -> Vec<&'static str> {
+    with_stack(SINGLE_THREADED.load(Ordering::Relaxed),
+        |stack| stack.iter().map(|(_, name)| *name).collect())
+}
+
+/// The span stack snapshot attached to the event currently being delivered, if the
+/// configured attach level matched; what sinks render as the `span.stack` field.
+pub fn attached_span_stack() -> Option<Vec<&'static str>> {
+    EVENT_STACK.with(|stack| stack.borrow().clone())
+}
+
+/// The innermost stack entry of this thread, for context capture across threads.
+pub(crate) fn current_span_entry() -> Option<(Id, &'static str)> {
+    with_stack(SINGLE_THREADED.load(Ordering::Relaxed),
+        |stack| stack.last().map(|(id, name)| (id.clone(), *name)))
+}
+
+/// Pushes a propagated context marker: resolves parent attribution on this thread but is
+/// never a timed entry (only the originating thread's real enter/exit measures the span).
+pub(crate) fn push_attached_span(id: &Id, name: &'static str) {
+    with_stack(SINGLE_THREADED.load(Ordering::Relaxed),
+        |stack| stack.push((id.clone(), name)));
+}
+
+pub(crate) fn pop_attached_span(id: &Id) {
+    with_stack(SINGLE_THREADED.load(Ordering::Relaxed), |stack| {
+        if let Some(index) = stack.iter().rposition(|(v, _)| v == id) {
+            stack.remove(index);
+        }
+    });
+}
+
+fn push_span(single_threaded: bool, id: &Id, name: &'static str) {
+    with_stack(single_threaded, |stack| stack.push((id.clone(), name)));
+}
+
+fn pop_span(single_threaded: bool, id: &Id) {
+    with_stack(single_threaded, |stack| {
+        //Pop the innermost occurrence only: re-entrant runs of the same span each own
+        // one stack slot.
+        if let Some(index) = stack.iter().rposition(|(v, _)| v == id) {
+            stack.remove(index);
+        }
+    });
+}
+
+fn current_span(single_threaded: bool) -> Option<Id> {
+    with_stack(single_threaded, |stack| stack.last().map(|(id, _)| id.clone()))
+}
+
+struct Inner {
+    //Keyed by the callsite Identifier - the identity tracing actually supports - rather
+    // than a raw pointer hash, so dynamically registered callsites (leaked metadata)
+    // behave exactly like the static fast path.
+    spans_by_meta: HashMap<Identifier, u32>,
+    spans_by_content: HashMap<ContentKey, u32>,
+    heads: HashMap<u32, SpanHead>,
+    spans_by_id: HashMap<Id, SpanData>,
+}
+
+impl Inner {
+    pub fn new() -> Inner {
+        Inner {
+            spans_by_meta: HashMap::new(),
+            spans_by_content: HashMap::new(),
+            heads: HashMap::new(),
+            spans_by_id: HashMap::new()
+        }
+    }
+}
+
+/// A token bucket shedding low-level events under log storms: when the sustained event
+/// rate exceeds the configured maximum, debug/trace events are dropped (the effective
+/// minimum level is temporarily raised) until volume subsides.
+pub struct RateLimiter {
+    max_per_sec: f64,
+    state: Mutex<(f64, Instant)>, //(available tokens, last refill)
+    shedding: AtomicBool
+}
+
+impl RateLimiter {
+    pub fn new(max_per_sec: u32) -> RateLimiter {
+        RateLimiter {
+            max_per_sec: max_per_sec as f64,
+            state: Mutex::new((max_per_sec as f64, Instant::now())),
+            shedding: AtomicBool::new(false)
+        }
+    }
+
+    pub fn allow(&self, level: Level, now: Instant) -> bool {
+        let mut lock = self.state.lock().unwrap();
+        let (tokens, last) = &mut *lock;
+        *tokens = (*tokens + now.saturating_duration_since(*last).as_secs_f64() * self.max_per_sec)
+            .min(self.max_per_sec);
+        *last = now;
+        if *tokens >= 1.0 {
+            *tokens -= 1.0;
+            //Volume subsided: restore the effective level once the bucket has meaningfully
+            // refilled, not on the first spare token (avoids notice flapping).
+            if *tokens > self.max_per_sec / 2.0 && self.shedding.swap(false, Ordering::Relaxed) {
+                log::info!(target: "bp3d-tracing", "Event volume subsided; debug/trace events are recorded again");
+            }
+            return true;
+        }
+        if !self.shedding.swap(true, Ordering::Relaxed) {
+            log::warn!(target: "bp3d-tracing", "Event volume exceeds {} events/sec; temporarily dropping debug/trace events", self.max_per_sec);
+        }
+        //Shedding raises the effective minimum level to INFO; more severe events always
+        // pass. Levels compare at inverse logic!
+        level < Level::DEBUG
+    }
+}
+
+//Bits of a cached per-callsite decision. Upcoming decision types (exclusions, sampling,
+// field gating) each get a bit here instead of their own hot-path lookup.
+const DECISION_ENABLED: u8 = 0x1;
+
+pub struct BaseTracer<T> {
+    inner: Mutex<Inner>,
+    counter: AtomicU32,
+    overflowed: AtomicBool,
+    rate: Option<RateLimiter>,
+    allocation: InstanceAllocation,
+    attach_stack: Option<Level>,
+    single_threaded: bool,
+    live_spans: std::sync::atomic::AtomicUsize,
+    //Per-callsite decisions computed once on first encounter, keyed like spans_by_meta by
+    // the callsite address. The epoch invalidates the whole cache in O(1) when any
+    // relevant config changes at runtime: entries from an older epoch are recomputed on
+    // their next lookup.
+    decisions: DashMap<Identifier, (u64, u8)>,
+    decision_epoch: AtomicU64,
+    derived: T
+}
+
+impl<T> BaseTracer<T> {
+    pub fn new(derived: T) -> BaseTracer<T> {
+        BaseTracer {
+            inner: Mutex::new(Inner::new()),
+            counter: AtomicU32::new(1),
+            overflowed: AtomicBool::new(false),
+            rate: None,
+            allocation: InstanceAllocation::Reuse,
+            attach_stack: None,
+            single_threaded: false,
+            live_spans: std::sync::atomic::AtomicUsize::new(0),
+            decisions: DashMap::new(),
+            decision_epoch: AtomicU64::new(0),
+            derived
+        }
+    }
+
+    /// Invalidates every cached per-callsite decision; call after changing any config the
+    /// decisions depend on.
+    pub fn invalidate_decisions(&self) {
+        self.decision_epoch.fetch_add(1, Ordering::Release);
+        //The epoch only reaches callers that still consult enabled(); callsites handed
+        // Interest::never() by register_callsite are cached by tracing and never ask
+        // again, so the interest cache must be rebuilt for the new epoch to reach them.
+        tracing_core::callsite::rebuild_interest_cache();
+    }
+
+}
+
+impl<T: Tracer> BaseTracer<T> {
+    /// Whether this subscriber allocated the id. A span id leaked from a scoped foreign
+    /// subscriber would otherwise be reinterpreted through our id+instance packing and
+    /// could collide with a legitimate entry, attributing data to the wrong span; foreign
+    /// ids are ignored and counted instead.
+    fn owns(&self, span: &Id) -> bool {
+        let owned = self.inner.lock().unwrap().spans_by_id.contains_key(span);
+        if !owned {
+            crate::stats::FOREIGN_IDS.fetch_add(1, Ordering::Relaxed);
+        }
+        owned
+    }
+
+    fn cached_decision(&self, metadata: &Metadata) -> u8 {
+        let key = metadata.callsite();
+        let epoch = self.decision_epoch.load(Ordering::Acquire);
+        if let Some(entry) = self.decisions.get(&key) {
+            if entry.0 == epoch {
+                return entry.1;
+            }
+        }
+        let mut decision = 0;
+        let enabled = match self.derived.max_level_hint() {
+            //Levels compare at inverse logic!
+            Some(level) => level >= *metadata.level(),
+            None => true
+        };
+        if enabled {
+            decision |= DECISION_ENABLED;
+        }
+        self.decisions.insert(key, (epoch, decision));
+        decision
+    }
+
+    pub fn set_max_event_rate(&mut self, max_events_per_sec: Option<u32>) {
+        self.rate = max_events_per_sec.map(RateLimiter::new);
+    }
+
+    pub fn set_instance_allocation(&mut self, allocation: InstanceAllocation) {
+        self.allocation = allocation;
+    }
+
+    pub fn set_attach_span_stack_on(&mut self, level: Option<Level>) {
+        self.attach_stack = level;
+    }
+
+    /// How many spans this tracer currently tracks as live.
+    pub fn live_spans(&self) -> usize {
+        self.live_spans.load(Ordering::Relaxed)
+    }
+
+    /// Selects the single-threaded span stack fast path; only sound when the application
+    /// genuinely runs spans on one thread.
+    pub fn set_single_threaded(&mut self, single_threaded: bool) {
+        self.single_threaded = single_threaded;
+        SINGLE_THREADED.store(single_threaded, Ordering::Relaxed);
+    }
+
+    pub fn derived(&self) -> &T {
+        &self.derived
+    }
+}
+
+impl<T: 'static + Tracer> Subscriber for BaseTracer<T> {
+    fn register_callsite(&self, metadata: &'static Metadata<'static>) -> Interest {
+        //Rate-limited events must consult enabled() on every call instead of being
+        // cached as always-interesting.
+        if self.rate.is_some() && metadata.is_event() {
+            return Interest::sometimes();
+        }
+        match self.enabled(metadata) {
+            true => Interest::always(),
+            false => Interest::never()
+        }
+    }
+
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        //The per-callsite cache answers the static parts (level gating, future
+        // exclusions/sampling) after the first encounter; only dynamic state (rate
+        // limiting, backend liveness) is evaluated per call.
+        if self.cached_decision(metadata) & DECISION_ENABLED == 0 {
+            return false;
+        }
+        if let (Some(rate), true) = (&self.rate, metadata.is_event()) {
+            if !rate.allow(*metadata.level(), Instant::now()) {
+                return false;
+            }
+        }
+        self.derived.enabled()
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        self.derived.max_level_hint().map(LevelFilter::from_level)
+    }
+
+    fn new_span(&self, span: &Attributes<'_>) -> Id {
+        let mut lock = self.inner.lock().unwrap();
+        let (new, span_id) = {
+            let key = span.metadata().callsite();
+            match lock.spans_by_meta.get(&key).copied() {
+                Some(span_id) => {
+                    let instance = lock.heads.get_mut(&span_id).unwrap().new_instance();
+                    (false, span_from_id_instance(span_id, instance))
+                }, //Why the fuck doesn't Id implement Copy? It's a fucking u64 so it should be copy fucking hell!
+                None => {
+                    //Unknown address: before allocating, check whether the same logical
+                    // callsite (identical name/file/line) was already seen at another
+                    // address, which happens when a reloaded dynamic library re-creates
+                    // its statics; reusing the id avoids a duplicate SpanAlloc.
+                    let content = content_key(span.metadata());
+                    if let Some(span_id) = lock.spans_by_content.get(&content).copied() {
+                        lock.spans_by_meta.insert(key, span_id);
+                        let instance = lock.heads.get_mut(&span_id).unwrap().new_instance();
+                        (false, span_from_id_instance(span_id, instance))
+                    } else {
+                        let span_id = self.counter.fetch_add(1, Ordering::Relaxed);
+                        if span_id >= SPAN_ID_LIMIT {
+                            //Keep the counter pinned so repeated fetch_add cannot wrap to 0.
+                            self.counter.store(SPAN_ID_LIMIT, Ordering::Relaxed);
+                            if !self.overflowed.swap(true, Ordering::Relaxed) {
+                                //Cannot go through log here: the log pump would call back into
+                                // this subscriber while the inner lock is held.
+                                eprintln!("bp3d-tracing: span callsite id space exhausted; \
+further callsites will share a degraded overflow id");
+                            }
+                            let allocation = self.allocation;
+                            let head = lock.heads.entry(OVERFLOW_SPAN_ID)
+                                .or_insert_with(|| SpanHead::new(allocation));
+                            let instance = head.new_instance();
+                            (false, span_from_id_instance(OVERFLOW_SPAN_ID, instance))
+                        } else {
+                            let mut head = SpanHead::new(self.allocation);
+                            let instance = head.new_instance();
+                            lock.spans_by_meta.insert(key, span_id);
+                            lock.spans_by_content.insert(content, span_id);
+                            lock.heads.insert(span_id, head);
+                            (true, span_from_id_instance(span_id, instance))
+                        }
+                    }
+                }
+            }
+        };
+        let parent = if span.is_root() {
+            None
+        } else {
+            span.parent().cloned().or_else(|| current_span(self.single_threaded))
+        };
+        lock.spans_by_id.insert(span_id.clone(), SpanData {
+            metadata: span.metadata(),
+            ref_count: 1,
+            last_time: None
+        });
+        self.live_spans.fetch_add(1, Ordering::Relaxed);
+        LIVE_SPANS.fetch_add(1, Ordering::Relaxed);
+        self.derived.span_create(&span_id, new, parent, span);
+        span_id
+    }
+
+    fn record(&self, span: &Id, values: &Record<'_>) {
+        if !self.owns(span) {
+            return;
+        }
+        self.derived.span_values(span, values);
+    }
+
+    fn record_follows_from(&self, span: &Id, follows: &Id) {
+        if !self.owns(span) || !self.owns(follows) {
+            return;
+        }
+        self.derived.span_follows_from(span, follows);
+    }
+
+    fn event(&self, event: &Event<'_>) {
+        crate::stats::count_target(event.metadata().target());
+        //Attach the breadcrumb stack when the event is severe enough (levels compare at
+        // inverse logic) and clear it as soon as delivery finishes.
+        let attach = match self.attach_stack {
+            Some(level) => *event.metadata().level() <= level,
+            None => false
+        };
+        if attach {
+            let names = with_stack(self.single_threaded,
+                |stack| stack.iter().map(|(_, name)| *name).collect());
+            EVENT_STACK.with(|stack| *stack.borrow_mut() = Some(names));
+        }
+        self.derived.event(current_span(self.single_threaded), OffsetDateTime::now_utc(), event);
+        if attach {
+            EVENT_STACK.with(|stack| *stack.borrow_mut() = None);
+        }
+    }
+
+    fn enter(&self, span: &Id) {
+        let mut lock = self.inner.lock().unwrap();
+        match lock.spans_by_id.get_mut(span) {
+            Some(data) => {
+                data.last_time = Some(Instant::now());
+                push_span(self.single_threaded, span, data.metadata.name());
+                self.derived.span_enter(span);
+            },
+            None => {
+                crate::stats::FOREIGN_IDS.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn exit(&self, span: &Id) {
+        let mut lock = self.inner.lock().unwrap();
+        if !lock.spans_by_id.contains_key(span) {
+            crate::stats::FOREIGN_IDS.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        if let Some(data) = lock.spans_by_id.get_mut(span) {
+            let duration = data.last_time.map(|v| v.elapsed())
+                .unwrap_or_default();
+            crate::assertions::record(data.metadata.name(), duration);
+            pop_span(self.single_threaded, span);
+            self.derived.span_exit(span, duration);
+        }
+    }
+
+    fn clone_span(&self, id: &Id) -> Id {
+        let mut lock = self.inner.lock().unwrap();
+        if let Some(data) = lock.spans_by_id.get_mut(id) {
+            data.ref_count += 1;
+        }
+        id.clone()
+    }
+
+    fn try_close(&self, id: Id) -> bool {
+        let mut lock = self.inner.lock().unwrap();
+        if !lock.spans_by_id.contains_key(&id) {
+            crate::stats::FOREIGN_IDS.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        if let Some(data) = lock.spans_by_id.get_mut(&id) {
+            data.ref_count -= 1;
+            if data.ref_count == 0 {
+                {
+                    let (span_id, instance) = span_to_id_instance(&id);
+                    if let Some(head) = lock.heads.get_mut(&span_id) {
+                        head.free_instance(instance);
+                    }
+                }
+                lock.spans_by_id.remove(&id);
+                self.live_spans.fetch_sub(1, Ordering::Relaxed);
+                LIVE_SPANS.fetch_sub(1, Ordering::Relaxed);
+                //Still on the stack at close time means entered but never exited: the
+                // span was dropped mid-flight (e.g. a cancelled future).
+                let reason = match with_stack(self.single_threaded,
+                    |stack| stack.iter().any(|(v, _)| *v == id)) {
+                    true => {
+                        pop_span(self.single_threaded, &id);
+                        CloseReason::Dropped
+                    },
+                    false => CloseReason::Normal
+                };
+                self.derived.span_destroy(&id, reason);
+                return true;
+            }
+        }
+        false
+    }
+
+    fn current_span(&self) -> Current {
+        match current_span(self.single_threaded) {
+            Some(v) => {
+                let lock = self.inner.lock().unwrap();
+                match lock.spans_by_id.get(&v) {
+                    Some(data) => Current::new(v.clone(), data.metadata),
+                    None => Current::none()
+                }
+            },
+            None => Current::none()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use time::OffsetDateTime;
+    use tracing_core::{Callsite, Kind, Metadata};
+    use tracing_core::metadata;
+    use tracing_core::subscriber::Interest;
+    use super::*;
+
+    struct NullTracer;
+
+    impl Tracer for NullTracer {
+        fn enabled(&self) -> bool {
+            true
+        }
+        fn span_create(&self, _: &Id, _: bool, _: Option<Id>, _: &Attributes) {}
+        fn span_values(&self, _: &Id, _: &Record) {}
+        fn span_follows_from(&self, _: &Id, _: &Id) {}
+        fn event(&self, _: Option<Id>, _: OffsetDateTime, _: &Event) {}
+        fn span_enter(&self, _: &Id) {}
+        fn span_exit(&self, _: &Id, _: Duration) {}
+        fn span_destroy(&self, _: &Id, _: CloseReason) {}
+        fn max_level_hint(&self) -> Option<Level> {
+            None
+        }
+    }
+
+    macro_rules! test_callsite {
+        ($callsite: ident, $instance: ident, $meta: ident, $name: literal) => {
+            //Non-zero-sized so each static gets a distinct address (the subscriber keys
+            // callsites by pointer, like real tracing callsite statics which carry state).
+            struct $callsite(#[allow(dead_code)] u8);
+            static $instance: $callsite = $callsite(0);
+            static $meta: Metadata<'static> = metadata! {
+                name: $name,
+                target: module_path!(),
+                level: Level::INFO,
+                fields: &[],
+                callsite: &$instance,
+                kind: Kind::SPAN
+            };
+            impl Callsite for $callsite {
+                fn set_interest(&self, _: Interest) {}
+                fn metadata(&self) -> &Metadata<'static> {
+                    &$meta
+                }
+            }
+        };
+    }
+
+    test_callsite!(Callsite1, CALLSITE1, META1, "span1");
+    test_callsite!(Callsite2, CALLSITE2, META2, "span2");
+    test_callsite!(Callsite3, CALLSITE3, META3, "span3");
+
+    fn new_span(tracer: &BaseTracer<NullTracer>, meta: &'static Metadata<'static>) -> Id {
+        let values = meta.fields().value_set(&[]);
+        tracer.new_span(&Attributes::new_root(meta, &values))
+    }
+
+    struct LevelTracer(std::sync::atomic::AtomicBool); //true = allow only ERROR
+
+    impl Tracer for LevelTracer {
+        fn enabled(&self) -> bool {
+            true
+        }
+        fn span_create(&self, _: &Id, _: bool, _: Option<Id>, _: &Attributes) {}
+        fn span_values(&self, _: &Id, _: &Record) {}
+        fn span_follows_from(&self, _: &Id, _: &Id) {}
+        fn event(&self, _: Option<Id>, _: OffsetDateTime, _: &Event) {}
+        fn span_enter(&self, _: &Id) {}
+        fn span_exit(&self, _: &Id, _: Duration) {}
+        fn span_destroy(&self, _: &Id, _: CloseReason) {}
+        fn max_level_hint(&self) -> Option<Level> {
+            match self.0.load(Ordering::Relaxed) {
+                true => Some(Level::ERROR),
+                false => Some(Level::TRACE)
+            }
+        }
+    }
+
+    #[test]
+    fn callsite_decisions_cache_until_invalidated() {
+        let tracer = BaseTracer::new(LevelTracer(AtomicBool::new(false)));
+        //First encounter computes and caches: INFO passes under a TRACE hint.
+        assert!(tracer.enabled(&EVENT_META));
+        //Tighten the level without invalidating: the cached decision still answers.
+        tracer.derived().0.store(true, Ordering::Relaxed);
+        assert!(tracer.enabled(&EVENT_META));
+        //Bumping the epoch recomputes on the next lookup.
+        tracer.invalidate_decisions();
+        assert!(!tracer.enabled(&EVENT_META));
+    }
+
+    #[test]
+    fn invalidation_reaches_never_interest_callsites() {
+        //A DEBUG event under an ERROR-only hint: register_callsite hands tracing
+        // Interest::never(), the verdict tracing caches and never revisits via
+        // enabled().
+        let tracer = BaseTracer::new(LevelTracer(AtomicBool::new(true)));
+        assert!(tracer.register_callsite(&EVENT_META).is_never());
+        //Loosen the level, then invalidate: re-registration must now say always, i.e.
+        // the epoch bump reaches callsites that would otherwise stay disabled forever.
+        tracer.derived().0.store(false, Ordering::Relaxed);
+        tracer.invalidate_decisions();
+        assert!(tracer.register_callsite(&EVENT_META).is_always());
+    }
+
+    #[test]
+    fn rate_limiter_sheds_low_levels_under_storm() {
+        let limiter = RateLimiter::new(10);
+        let now = Instant::now();
+        //The bucket starts full: the first 10 events pass regardless of level.
+        for _ in 0..10 {
+            assert!(limiter.allow(Level::DEBUG, now));
+        }
+        //Storm continues within the same second: debug/trace shed, info and above pass.
+        assert!(!limiter.allow(Level::DEBUG, now));
+        assert!(!limiter.allow(Level::TRACE, now));
+        assert!(limiter.allow(Level::INFO, now));
+        assert!(limiter.allow(Level::ERROR, now));
+        //Volume subsides: after a refill the low levels come back.
+        let later = now + Duration::from_secs(2);
+        assert!(limiter.allow(Level::DEBUG, later));
+        assert!(!limiter.shedding.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn enabled_sheds_debug_events_at_high_rate() {
+        let mut tracer = BaseTracer::new(NullTracer);
+        tracer.set_max_event_rate(Some(5));
+        let shed = (0..100)
+            .filter(|_| !tracer.enabled(&EVENT_META))
+            .count();
+        assert!(shed >= 90); //5-token bucket: virtually everything past the burst drops.
+    }
+
+    struct EventCallsite(#[allow(dead_code)] u8);
+    static EVENT_CALLSITE: EventCallsite = EventCallsite(0);
+    static EVENT_META: Metadata<'static> = metadata! {
+        name: "debug_event",
+        target: module_path!(),
+        level: Level::DEBUG,
+        fields: &[],
+        callsite: &EVENT_CALLSITE,
+        kind: Kind::EVENT
+    };
+
+    impl Callsite for EventCallsite {
+        fn set_interest(&self, _: Interest) {}
+        fn metadata(&self) -> &Metadata<'static> {
+            &EVENT_META
+        }
+    }
+
+    fn run_span(tracer: &BaseTracer<NullTracer>) -> u64 {
+        let id = new_span(tracer, &META1);
+        tracer.enter(&id);
+        tracer.exit(&id);
+        let (_, instance) = span_to_id_instance(&id);
+        assert!(tracer.try_close(id));
+        instance
+    }
+
+    #[test]
+    fn propagated_context_attributes_worker_spans_and_events() {
+        use std::sync::Arc;
+        use std::sync::Mutex as StdMutex;
+
+        struct ParentLog(StdMutex<Vec<Option<Id>>>);
+
+        impl Tracer for ParentLog {
+            fn enabled(&self) -> bool {
+                true
+            }
+            fn span_create(&self, _: &Id, _: bool, parent: Option<Id>, _: &Attributes) {
+                self.0.lock().unwrap().push(parent);
+            }
+            fn span_values(&self, _: &Id, _: &Record) {}
+            fn span_follows_from(&self, _: &Id, _: &Id) {}
+            fn event(&self, parent: Option<Id>, _: OffsetDateTime, _: &Event) {
+                self.0.lock().unwrap().push(parent);
+            }
+            fn span_enter(&self, _: &Id) {}
+            fn span_exit(&self, _: &Id, _: Duration) {}
+            fn span_destroy(&self, _: &Id, _: CloseReason) {}
+            fn max_level_hint(&self) -> Option<Level> {
+                None
+            }
+        }
+
+        //Contemporary spans pick their parent from the thread's span stack.
+        fn contemporary(tracer: &BaseTracer<ParentLog>, meta: &'static Metadata<'static>) -> Id {
+            let values = meta.fields().value_set(&[]);
+            tracer.new_span(&Attributes::new(meta, &values))
+        }
+
+        let tracer = Arc::new(BaseTracer::new(ParentLog(StdMutex::new(Vec::new()))));
+        let outer = new_span2(&tracer, &META1);
+        tracer.enter(&outer);
+        let context = crate::context::current();
+        //Without the helper: a worker-thread span attributes to root.
+        let bare = tracer.clone();
+        std::thread::spawn(move || {
+            contemporary(&bare, &META2);
+        }).join().unwrap();
+        //With the helper: same work, correctly parented; events too.
+        let traced = tracer.clone();
+        std::thread::spawn(move || {
+            context.in_context(|| {
+                contemporary(&traced, &META3);
+                let values = EVENT_META.fields().value_set(&[]);
+                traced.event(&Event::new(&EVENT_META, &values));
+            });
+        }).join().unwrap();
+        tracer.exit(&outer);
+        let parents = tracer.derived().0.lock().unwrap().clone();
+        //outer(create), bare span (None), traced span (outer), traced event (outer).
+        assert_eq!(parents[1], None);
+        assert_eq!(parents[2], Some(outer.clone()));
+        assert_eq!(parents[3], Some(outer.clone()));
+        //The marker never produced a span_exit of its own (only outer's exit times it);
+        // nothing is left on this thread's stack either.
+        drop(parents);
+        assert!(tracer.try_close(outer));
+    }
+
+    #[test]
+    fn live_span_count_rises_and_falls() {
+        let tracer = BaseTracer::new(NullTracer);
+        assert_eq!(tracer.live_spans(), 0);
+        let a = new_span(&tracer, &META1);
+        let b = new_span(&tracer, &META2);
+        assert_eq!(tracer.live_spans(), 2);
+        //A cloned span stays live until its last reference closes.
+        let clone = tracer.clone_span(&a);
+        assert!(!tracer.try_close(a));
+        assert_eq!(tracer.live_spans(), 2);
+        assert!(tracer.try_close(clone));
+        assert_eq!(tracer.live_spans(), 1);
+        assert!(tracer.try_close(b));
+        assert_eq!(tracer.live_spans(), 0);
+    }
+
+    #[test]
+    fn foreign_span_ids_are_ignored_without_contamination() {
+        let tracer = BaseTracer::new(NullTracer);
+        let ours = new_span(&tracer, &META1);
+        //A plausible-looking id from a foreign subscriber that we never allocated.
+        let foreign = Id::from_u64(0xDEAD_0000_0001);
+        let before = crate::stats::snapshot().foreign_ids_ignored;
+        let values = META1.fields().value_set(&[]);
+        tracer.record(&foreign, &Record::new(&values));
+        tracer.record_follows_from(&foreign, &ours);
+        tracer.record_follows_from(&ours, &foreign);
+        tracer.enter(&foreign);
+        tracer.exit(&foreign);
+        assert!(!tracer.try_close(foreign));
+        //Six foreign references counted, our span untouched and still closable.
+        assert!(crate::stats::snapshot().foreign_ids_ignored >= before + 6);
+        tracer.enter(&ours);
+        tracer.exit(&ours);
+        assert!(tracer.try_close(ours));
+    }
+
+    #[test]
+    fn single_thread_mode_keeps_span_timing_working() {
+        use std::sync::Mutex as StdMutex;
+
+        struct DurationRecorder(StdMutex<Vec<Duration>>);
+
+        impl Tracer for DurationRecorder {
+            fn enabled(&self) -> bool {
+                true
+            }
+            fn span_create(&self, _: &Id, _: bool, _: Option<Id>, _: &Attributes) {}
+            fn span_values(&self, _: &Id, _: &Record) {}
+            fn span_follows_from(&self, _: &Id, _: &Id) {}
+            fn event(&self, _: Option<Id>, _: OffsetDateTime, _: &Event) {}
+            fn span_enter(&self, _: &Id) {}
+            fn span_exit(&self, _: &Id, duration: Duration) {
+                self.0.lock().unwrap().push(duration);
+            }
+            fn span_destroy(&self, _: &Id, _: CloseReason) {}
+            fn max_level_hint(&self) -> Option<Level> {
+                None
+            }
+        }
+
+        let mut tracer = BaseTracer::new(DurationRecorder(StdMutex::new(Vec::new())));
+        tracer.single_threaded = true; //Field only: the global mirror stays untouched so
+                                       // concurrently running tests keep their TLS stacks.
+        let id = new_span2(&tracer, &META1);
+        tracer.enter(&id);
+        //Nesting through the global stack resolves parents as usual.
+        let inner = new_span2(&tracer, &META2);
+        tracer.enter(&inner);
+        std::thread::sleep(Duration::from_millis(5));
+        tracer.exit(&inner);
+        tracer.exit(&id);
+        assert!(tracer.try_close(inner));
+        assert!(tracer.try_close(id));
+        let durations = tracer.derived().0.lock().unwrap();
+        assert_eq!(durations.len(), 2);
+        assert!(durations.iter().all(|v| *v >= Duration::from_millis(5)));
+    }
+
+    #[test]
+    fn reuse_allocation_recycles_after_idle() {
+        let tracer = BaseTracer::new(NullTracer);
+        assert_eq!(run_span(&tracer), 0);
+        //Fully idle: the numbering resets and the id is shared with the first run.
+        assert_eq!(run_span(&tracer), 0);
+    }
+
+    #[test]
+    fn monotonic_allocation_never_reuses_ids() {
+        let mut tracer = BaseTracer::new(NullTracer);
+        tracer.set_instance_allocation(InstanceAllocation::Monotonic);
+        assert_eq!(run_span(&tracer), 0);
+        //Reusing the span after full idle still yields a fresh id.
+        assert_eq!(run_span(&tracer), 1);
+        assert_eq!(run_span(&tracer), 2);
+    }
+
+    #[test]
+    fn error_events_carry_the_span_stack() {
+        use std::sync::Mutex as StdMutex;
+
+        struct StackRecorder(StdMutex<Vec<Option<Vec<&'static str>>>>);
+
+        impl Tracer for StackRecorder {
+            fn enabled(&self) -> bool {
+                true
+            }
+            fn span_create(&self, _: &Id, _: bool, _: Option<Id>, _: &Attributes) {}
+            fn span_values(&self, _: &Id, _: &Record) {}
+            fn span_follows_from(&self, _: &Id, _: &Id) {}
+            fn event(&self, _: Option<Id>, _: OffsetDateTime, _: &Event) {
+                self.0.lock().unwrap().push(attached_span_stack());
+            }
+            fn span_enter(&self, _: &Id) {}
+            fn span_exit(&self, _: &Id, _: Duration) {}
+            fn span_destroy(&self, _: &Id, _: CloseReason) {}
+            fn max_level_hint(&self) -> Option<Level> {
+                None
+            }
+        }
+
+        struct ErrorCallsite(#[allow(dead_code)] u8);
+        static ERROR_CALLSITE: ErrorCallsite = ErrorCallsite(0);
+        static ERROR_META: Metadata<'static> = metadata! {
+            name: "boom",
+            target: module_path!(),
+            level: Level::ERROR,
+            fields: &[],
+            callsite: &ERROR_CALLSITE,
+            kind: Kind::EVENT
+        };
+        impl Callsite for ErrorCallsite {
+            fn set_interest(&self, _: Interest) {}
+            fn metadata(&self) -> &Metadata<'static> {
+                &ERROR_META
+            }
+        }
+
+        let mut tracer = BaseTracer::new(StackRecorder(StdMutex::new(Vec::new())));
+        tracer.set_attach_span_stack_on(Some(Level::ERROR));
+        let outer = new_span2(&tracer, &META1);
+        let inner = new_span2(&tracer, &META2);
+        tracer.enter(&outer);
+        tracer.enter(&inner);
+        let values = ERROR_META.fields().value_set(&[]);
+        tracer.event(&Event::new(&ERROR_META, &values));
+        tracer.exit(&inner);
+        tracer.exit(&outer);
+        //An INFO event would not get the stack; the ERROR event carries both span names
+        // outermost first, and the snapshot is cleared after delivery.
+        let recorded = tracer.derived().0.lock().unwrap();
+        assert_eq!(recorded[0], Some(vec!["span1", "span2"]));
+        assert!(attached_span_stack().is_none());
+        assert!(tracer.try_close(inner));
+        assert!(tracer.try_close(outer));
+    }
+
+    #[test]
+    fn explicit_parent_bypasses_the_current_span() {
+        use std::sync::Mutex as StdMutex;
+
+        struct ParentRecorder(StdMutex<Vec<Option<Id>>>);
+
+        impl Tracer for ParentRecorder {
+            fn enabled(&self) -> bool {
+                true
+            }
+            fn span_create(&self, _: &Id, _: bool, parent: Option<Id>, _: &Attributes) {
+                self.0.lock().unwrap().push(parent);
+            }
+            fn span_values(&self, _: &Id, _: &Record) {}
+            fn span_follows_from(&self, _: &Id, _: &Id) {}
+            fn event(&self, _: Option<Id>, _: OffsetDateTime, _: &Event) {}
+            fn span_enter(&self, _: &Id) {}
+            fn span_exit(&self, _: &Id, _: Duration) {}
+            fn span_destroy(&self, _: &Id, _: CloseReason) {}
+            fn max_level_hint(&self) -> Option<Level> {
+                None
+            }
+        }
+
+        let tracer = BaseTracer::new(ParentRecorder(StdMutex::new(Vec::new())));
+        //An out-of-band parent (e.g. from a distributed trace) and a DIFFERENT span
+        // currently entered on this thread.
+        let out_of_band = new_span2(&tracer, &META1);
+        let current = new_span2(&tracer, &META2);
+        tracer.enter(&current);
+        let values = META3.fields().value_set(&[]);
+        let attrs = Attributes::child_of(out_of_band.clone(), &META3, &values);
+        tracer.new_span(&attrs);
+        let parents = tracer.derived().0.lock().unwrap();
+        //The explicit parent wins over the thread-local current span.
+        assert_eq!(parents[2], Some(out_of_band));
+        assert_ne!(parents[2], Some(current));
+    }
+
+    fn new_span2(tracer: &BaseTracer<impl Tracer + 'static>, meta: &'static Metadata<'static>) -> Id {
+        let values = meta.fields().value_set(&[]);
+        tracer.new_span(&Attributes::new_root(meta, &values))
+    }
+
+    #[test]
+    fn dynamic_callsites_behave_like_static_ones() {
+        use tracing_core::field::FieldSet;
+        use tracing_core::identify_callsite;
+
+        //A callsite registered at runtime: both the callsite struct and its metadata are
+        // leaked allocations, exactly what iterator/future instrumentation produces.
+        struct DynCallsite(#[allow(dead_code)] u8);
+        static FALLBACK: Metadata<'static> = metadata! {
+            name: "fallback",
+            target: module_path!(),
+            level: Level::INFO,
+            fields: &[],
+            callsite: &CALLSITE1,
+            kind: Kind::SPAN
+        };
+        impl Callsite for DynCallsite {
+            fn set_interest(&self, _: Interest) {}
+            fn metadata(&self) -> &Metadata<'static> {
+                &FALLBACK
+            }
+        }
+
+        let callsite: &'static DynCallsite = Box::leak(Box::new(DynCallsite(0)));
+        let metadata: &'static Metadata<'static> = Box::leak(Box::new(Metadata::new(
+            "dyn_span", "dyn_target", Level::INFO,
+            Some("dyn.rs"), Some(7), Some("dyn_module"),
+            FieldSet::new(&[], identify_callsite!(callsite)), Kind::SPAN
+        )));
+
+        let tracer = BaseTracer::new(NullTracer);
+        //Two instances of the dynamic callsite share one span id (the Identifier is the
+        // identity, not a raw pointer hash), with distinct instances, full lifecycle.
+        let a = new_span(&tracer, metadata);
+        let b = new_span(&tracer, metadata);
+        let (id_a, instance_a) = span_to_id_instance(&a);
+        let (id_b, instance_b) = span_to_id_instance(&b);
+        assert_eq!(id_a, id_b);
+        assert_ne!(instance_a, instance_b);
+        tracer.enter(&a);
+        tracer.exit(&a);
+        assert!(tracer.try_close(a));
+        assert!(tracer.try_close(b));
+        //And a static callsite stays unaffected by the dynamic one.
+        let static_span = new_span(&tracer, &META1);
+        let (static_id, _) = span_to_id_instance(&static_span);
+        assert_ne!(static_id, id_a);
+        assert!(tracer.try_close(static_span));
+    }
+
+    #[test]
+    fn dropping_an_entered_span_closes_as_dropped() {
+        use std::sync::Mutex as StdMutex;
+
+        struct ReasonLog(StdMutex<Vec<CloseReason>>);
+
+        impl Tracer for ReasonLog {
+            fn enabled(&self) -> bool {
+                true
+            }
+            fn span_create(&self, _: &Id, _: bool, _: Option<Id>, _: &Attributes) {}
+            fn span_values(&self, _: &Id, _: &Record) {}
+            fn span_follows_from(&self, _: &Id, _: &Id) {}
+            fn event(&self, _: Option<Id>, _: OffsetDateTime, _: &Event) {}
+            fn span_enter(&self, _: &Id) {}
+            fn span_exit(&self, _: &Id, _: Duration) {}
+            fn span_destroy(&self, _: &Id, reason: CloseReason) {
+                self.0.lock().unwrap().push(reason);
+            }
+            fn max_level_hint(&self) -> Option<Level> {
+                None
+            }
+        }
+
+        let tracer = BaseTracer::new(ReasonLog(StdMutex::new(Vec::new())));
+        //A normal run: enter, exit, close.
+        let normal = new_span2(&tracer, &META1);
+        tracer.enter(&normal);
+        tracer.exit(&normal);
+        assert!(tracer.try_close(normal));
+        //A cancelled future: entered, then the span is dropped without exiting.
+        let cancelled = new_span2(&tracer, &META2);
+        tracer.enter(&cancelled);
+        assert!(tracer.try_close(cancelled));
+        let reasons = tracer.derived().0.lock().unwrap();
+        assert_eq!(*reasons, vec![CloseReason::Normal, CloseReason::Dropped]);
+    }
+
+    #[test]
+    fn identical_metadata_at_new_address_reuses_the_span_id() {
+        use tracing_core::field::FieldSet;
+        use tracing_core::identify_callsite;
+
+        //Two callsite statics with byte-identical content (same name/file/line), as after
+        // a dynamic library reload re-created the statics at a new address.
+        struct ReloadCallsite(#[allow(dead_code)] u8);
+        static RELOAD1: ReloadCallsite = ReloadCallsite(0);
+        static RELOAD2: ReloadCallsite = ReloadCallsite(0);
+        static RMETA1: Metadata<'static> = Metadata::new(
+            "reloaded_span", "plugin", Level::INFO,
+            Some("plugin/src/lib.rs"), Some(42), Some("plugin"),
+            FieldSet::new(&[], identify_callsite!(&RELOAD1)), Kind::SPAN
+        );
+        static RMETA2: Metadata<'static> = Metadata::new(
+            "reloaded_span", "plugin", Level::INFO,
+            Some("plugin/src/lib.rs"), Some(42), Some("plugin"),
+            FieldSet::new(&[], identify_callsite!(&RELOAD2)), Kind::SPAN
+        );
+        impl Callsite for ReloadCallsite {
+            fn set_interest(&self, _: Interest) {}
+            fn metadata(&self) -> &Metadata<'static> {
+                &RMETA1
+            }
+        }
+
+        let tracer = BaseTracer::new(NullTracer);
+        let id1 = new_span(&tracer, &RMETA1);
+        let id2 = new_span(&tracer, &RMETA2);
+        let (span1, instance1) = span_to_id_instance(&id1);
+        let (span2, instance2) = span_to_id_instance(&id2);
+        //Same logical callsite: same id, distinct live instances, no duplicate alloc.
+        assert_eq!(span1, span2);
+        assert_ne!(instance1, instance2);
+        assert!(tracer.try_close(id1));
+        assert!(tracer.try_close(id2));
+    }
+
+    #[test]
+    fn counter_saturates_instead_of_wrapping() {
+        let tracer = BaseTracer::new(NullTracer);
+        //Inject a starting value one below the limit.
+        tracer.counter.store(SPAN_ID_LIMIT - 1, Ordering::Relaxed);
+        let id1 = new_span(&tracer, &META1);
+        let (span_id1, _) = span_to_id_instance(&id1);
+        assert_eq!(span_id1, SPAN_ID_LIMIT - 1);
+        //These two distinct callsites exceed the limit and must share the overflow head
+        // instead of wrapping the counter into the forbidden zero id.
+        let id2 = new_span(&tracer, &META2);
+        let id3 = new_span(&tracer, &META3);
+        let (span_id2, instance2) = span_to_id_instance(&id2);
+        let (span_id3, instance3) = span_to_id_instance(&id3);
+        assert_eq!(span_id2, OVERFLOW_SPAN_ID);
+        assert_eq!(span_id3, OVERFLOW_SPAN_ID);
+        assert_ne!(instance2, instance3);
+        //The full lifecycle still works for overflow spans.
+        tracer.enter(&id2);
+        tracer.exit(&id2);
+        assert!(tracer.try_close(id2));
+        assert!(tracer.try_close(id3));
+        assert!(tracer.try_close(id1));
+    }
+}
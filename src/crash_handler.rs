@@ -0,0 +1,199 @@
+// Copyright (c) 2022, BlockProject 3D
+//
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification,
+// are permitted provided that the following conditions are met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//     * Redistributions in binary form must reproduce the above copyright notice,
+//       this list of conditions and the following disclaimer in the documentation
+//       and/or other materials provided with the distribution.
+//     * Neither the name of BlockProject 3D nor the names of its contributors
+//       may be used to endorse or promote products derived from this software
+//       without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS
+// "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT
+// LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR
+// A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT OWNER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Deterministic teardown ordering at crash time.
+//!
+//! The panic hook (and any future fatal-signal handler) runs one owned sequence: record
+//! the panic cheaply first, then attempt the network flush under a strict timeout, then
+//! write the local dump - which must never depend on the possibly-wedged network. Every
+//! step runs failure-isolated (catch_unwind) and timeout-bounded on a watchdog thread, a
+//! recursion guard makes the hook inert if any step itself panics the process again, and
+//! the outcome of each step is appended to the dump trailer so post-mortem analysis knows
+//! which artifacts are trustworthy.
+
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::time::Duration;
+
+/// How one crash-teardown step ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepStatus {
+    Ok,
+    Failed,
+    TimedOut
+}
+
+/// The recorded outcome of one crash-teardown step.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StepReport {
+    pub step: &'static str,
+    pub status: StepStatus
+}
+
+//The hook must never recurse, whatever a step does.
+static IN_CRASH_HANDLER: AtomicBool = AtomicBool::new(false);
+
+//Initialization funnels through install_panic_hook; chaining the previous hook more than
+// once would run the sequence repeatedly per panic.
+static HOOK_INSTALLED: AtomicBool = AtomicBool::new(false);
+
+/// Runs one step failure-isolated and timeout-bounded: the closure executes on a watchdog
+/// thread under catch_unwind; a step that wedges past its budget is left behind
+/// (detached) and recorded as timed out so later steps still run.
+fn run_step(step: &'static str, timeout: Duration, func: impl FnOnce() + Send + 'static) -> StepReport {
+    let (send, recv) = mpsc::channel();
+    std::thread::spawn(move || {
+        let outcome = match std::panic::catch_unwind(AssertUnwindSafe(func)) {
+            Ok(()) => StepStatus::Ok,
+            Err(_) => StepStatus::Failed
+        };
+        let _ = send.send(outcome);
+    });
+    let status = recv.recv_timeout(timeout).unwrap_or(StepStatus::TimedOut);
+    StepReport {
+        step,
+        status
+    }
+}
+
+type Step = (&'static str, Duration, Box<dyn FnOnce() + Send>);
+
+/// Runs the given steps in order; a failed or wedged step never prevents the following
+/// ones from executing.
+pub(crate) fn run_sequence(steps: Vec<Step>) -> Vec<StepReport> {
+    steps.into_iter()
+        .map(|(step, timeout, func)| run_step(step, timeout, func))
+        .collect()
+}
+
+fn write_trailer(reports: &[StepReport], panic_message: &str) {
+    let mut trailer = format!("bp3d-tracing crash trailer\npanic: {}\n", panic_message);
+    for report in reports {
+        trailer += &format!("{}: {:?}\n", report.step, report.status);
+    }
+    //The dump path must not depend on anything that might be wedged: best-effort file
+    // next to a plain stderr copy.
+    eprintln!("{}", trailer);
+    let path = std::env::temp_dir().join("bp3d-tracing-crash-trailer.txt");
+    let _ = std::fs::write(path, trailer);
+}
+
+/// Runs the crash-time teardown sequence for the given reason and writes the trailer;
+/// the entry point for fatal-signal integration. Signal handlers must not call this
+/// directly (the steps allocate and lock): wake a dedicated thread from the handler
+/// (self-pipe or an atomic flag plus a watchdog) and call this from that thread, like
+/// the panic hook does from the panicking thread.
+pub fn run_crash_sequence(reason: &str) {
+    if !IN_CRASH_HANDLER.swap(true, Ordering::AcqRel) {
+        crash_sequence(reason.to_string());
+        IN_CRASH_HANDLER.store(false, Ordering::Release);
+    }
+}
+
+fn crash_sequence(panic_message: String) {
+    let message = panic_message.clone();
+    let reports = run_sequence(vec![
+        //1: cheap in-memory record first, so the panic survives even if everything
+        // below goes wrong.
+        ("record-panic", Duration::from_millis(200), Box::new(move || {
+            log::error!(target: "bp3d-tracing", "panic: {}", message);
+        })),
+        //2: the network flush, strictly bounded: a wedged connection may eat its budget
+        // but nothing more.
+        ("network-flush", Duration::from_secs(2), Box::new(|| {
+            if let Some(state) = crate::profiler::state::ProfilerState::try_get() {
+                state.wait_drained(Duration::from_secs(1));
+            }
+            bp3d_logger::flush();
+        })),
+    ]);
+    //3: the local dump, after and independent of the network step.
+    write_trailer(&reports, &panic_message);
+}
+
+/// Installs the crash-time teardown as a panic hook, chaining the previously installed
+/// hook; a no-op after the first call. Installed automatically during initialization;
+/// fatal-signal handlers reach the same sequence through
+/// [run_crash_sequence](run_crash_sequence).
+pub fn install_panic_hook() {
+    if HOOK_INSTALLED.swap(true, Ordering::AcqRel) {
+        return;
+    }
+    let previous = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        run_crash_sequence(&info.to_string());
+        previous(info);
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicUsize;
+    use super::*;
+
+    #[test]
+    fn failed_and_wedged_steps_do_not_stop_the_sequence() {
+        let executed = Arc::new(AtomicUsize::new(0));
+        let last = executed.clone();
+        let reports = run_sequence(vec![
+            ("healthy", Duration::from_secs(1), Box::new(|| {})),
+            ("panicking", Duration::from_secs(1), Box::new(|| panic!("step exploded"))),
+            //A wedged fake client: sleeps far past its budget.
+            ("wedged", Duration::from_millis(50), Box::new(|| {
+                std::thread::sleep(Duration::from_secs(5));
+            })),
+            ("dump", Duration::from_secs(1), Box::new(move || {
+                last.fetch_add(1, Ordering::Relaxed);
+            }))
+        ]);
+        //Later steps still executed and the trailer statuses are exact.
+        assert_eq!(executed.load(Ordering::Relaxed), 1);
+        assert_eq!(reports, vec![
+            StepReport { step: "healthy", status: StepStatus::Ok },
+            StepReport { step: "panicking", status: StepStatus::Failed },
+            StepReport { step: "wedged", status: StepStatus::TimedOut },
+            StepReport { step: "dump", status: StepStatus::Ok }
+        ]);
+    }
+
+    #[test]
+    fn trailer_records_every_status() {
+        let reports = vec![
+            StepReport { step: "record-panic", status: StepStatus::Ok },
+            StepReport { step: "network-flush", status: StepStatus::TimedOut }
+        ];
+        write_trailer(&reports, "test panic");
+        let trailer = std::fs::read_to_string(
+            std::env::temp_dir().join("bp3d-tracing-crash-trailer.txt")).unwrap();
+        assert!(trailer.contains("panic: test panic"));
+        assert!(trailer.contains("record-panic: Ok"));
+        assert!(trailer.contains("network-flush: TimedOut"));
+    }
+}
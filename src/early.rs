@@ -0,0 +1,386 @@
+// Copyright (c) 2022, BlockProject 3D
+//
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification,
+// are permitted provided that the following conditions are met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//     * Redistributions in binary form must reproduce the above copyright notice,
+//       this list of conditions and the following disclaimer in the documentation
+//       and/or other materials provided with the distribution.
+//     * Neither the name of BlockProject 3D nor the names of its contributors
+//       may be used to endorse or promote products derived from this software
+//       without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS
+// "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT
+// LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR
+// A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT OWNER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Early-capture shim for spans and events emitted before [initialize](crate::initialize).
+//!
+//! Without this shim anything traced before the global subscriber is installed goes to
+//! tracing's no-op default dispatcher and vanishes. [early_init](crate::early_init) installs
+//! a tiny buffering subscriber as the global default; once `initialize` builds the real
+//! backend the buffer is replayed into it (events keep their original timestamps, completed
+//! span runs keep their measured durations) and the shim switches to plain forwarding.
+//!
+//! Ordering caveats: replayed records are delivered when `initialize` runs, so they appear
+//! before any later output but their interleaving with other threads' pre-initialize
+//! activity is the order in which the shim observed them. Spans still open when `initialize`
+//! runs are not migrated; their remaining activity is dropped. The buffer is bounded by
+//! [MAX_EARLY_RECORDS](MAX_EARLY_RECORDS); once full, further records are counted and
+//! reported as dropped.
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fmt::Debug;
+use std::sync::RwLock;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use once_cell::sync::Lazy;
+use time::OffsetDateTime;
+use tracing_core::{Dispatch, Event, Field, Metadata, Subscriber};
+use tracing_core::field::{Value, ValueSet, Visit};
+use tracing_core::span::{Attributes, Id, Record};
+use tracing_core::subscriber::Interest;
+use crate::core::{BaseTracer, Tracer};
+use crate::util::Meta;
+
+/// The maximum number of buffered early records (events plus completed span runs).
+pub const MAX_EARLY_RECORDS: usize = 512;
+
+//Early span ids carry the top bit so that, after the swap to forwarding, stale ids handed
+// out during buffering can be recognized and ignored instead of corrupting the real
+// subscriber's maps.
+const EARLY_ID_BIT: u64 = 1 << 63;
+
+struct OwnedVisitor(Vec<(&'static str, String)>);
+
+impl Visit for OwnedVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.push((field.name(), value.into()));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.0.push((field.name(), format!("{:?}", value)));
+    }
+}
+
+struct SpanBuf {
+    metadata: Meta,
+    fields: Vec<(&'static str, String)>,
+    entered: Option<Instant>,
+    duration: Duration
+}
+
+enum ReplayRecord {
+    Event {
+        metadata: Meta,
+        fields: Vec<(&'static str, String)>,
+        time: OffsetDateTime
+    },
+    Span {
+        metadata: Meta,
+        fields: Vec<(&'static str, String)>,
+        duration: Duration
+    }
+}
+
+struct Buffer {
+    next_id: u64,
+    spans: HashMap<u64, SpanBuf>,
+    records: Vec<ReplayRecord>,
+    dropped: usize
+}
+
+impl Buffer {
+    fn new() -> Buffer {
+        Buffer {
+            next_id: 1,
+            spans: HashMap::new(),
+            records: Vec::new(),
+            dropped: 0
+        }
+    }
+
+    fn push(&mut self, record: ReplayRecord) {
+        if self.records.len() < MAX_EARLY_RECORDS {
+            self.records.push(record);
+        } else {
+            self.dropped += 1;
+        }
+    }
+}
+
+enum State {
+    Buffering(Buffer),
+    Forwarding(Dispatch)
+}
+
+struct EarlyShim {
+    state: RwLock<State>
+}
+
+static EARLY_ACTIVE: AtomicBool = AtomicBool::new(false);
+
+static EARLY_SHIM: Lazy<EarlyShim> = Lazy::new(|| EarlyShim {
+    state: RwLock::new(State::Buffering(Buffer::new()))
+});
+
+/// The unit dispatcher registered as the global default by early_init; all state lives in
+/// the EARLY_SHIM static so that install() can reach it later.
+pub(crate) struct EarlyDispatcher;
+
+pub(crate) fn is_active() -> bool {
+    EARLY_ACTIVE.load(Ordering::Acquire)
+}
+
+pub(crate) fn activate() -> bool {
+    !EARLY_ACTIVE.swap(true, Ordering::AcqRel)
+}
+
+/// Rebuilds a ValueSet for the given metadata from owned buffered values and hands it to
+/// the closure. ValueSet construction needs a fixed-size array, so the pairs are padded
+/// with unset fields up to tracing's limit of 32.
+fn with_value_set<R>(
+    metadata: Meta,
+    pairs: &[(&'static str, String)],
+    func: impl FnOnce(&ValueSet) -> R
+) -> R {
+    let fields: Vec<Field> = pairs.iter()
+        .filter_map(|(name, _)| metadata.fields().field(name))
+        .collect();
+    let pad = match metadata.fields().iter().next() {
+        Some(v) => v,
+        None => {
+            let empty: [(&Field, Option<&dyn Value>); 0] = [];
+            return func(&metadata.fields().value_set(&empty));
+        }
+    };
+    let mut array: Vec<(&Field, Option<&dyn Value>)> = fields.iter()
+        .zip(pairs.iter())
+        .map(|(field, (_, value))| (field, Some(value as &dyn Value)))
+        .collect();
+    array.truncate(32);
+    while array.len() < 32 {
+        array.push((&pad, None));
+    }
+    let array: [(&Field, Option<&dyn Value>); 32] = match array.try_into() {
+        Ok(v) => v,
+        Err(_) => unreachable!() //The vector is always exactly 32 entries long here.
+    };
+    func(&metadata.fields().value_set(&array))
+}
+
+/// Replays the buffered records into the freshly built tracing system then switches the
+/// shim to forwarding mode. Must be called at most once, from load_system.
+pub(crate) fn install<T: 'static + Tracer + Sync + Send>(system: BaseTracer<T>) {
+    let buffer = {
+        let mut lock = EARLY_SHIM.state.write().unwrap();
+        match &mut *lock {
+            State::Buffering(buffer) => std::mem::replace(buffer, Buffer::new()),
+            State::Forwarding(_) => return
+        }
+    };
+    for record in buffer.records {
+        match record {
+            ReplayRecord::Event { metadata, fields, time } => {
+                with_value_set(metadata, &fields, |values| {
+                    let event = Event::new(metadata, values);
+                    system.derived().event(None, time, &event);
+                });
+            },
+            ReplayRecord::Span { metadata, fields, duration } => {
+                with_value_set(metadata, &fields, |values| {
+                    let attrs = Attributes::new_root(metadata, values);
+                    let id = system.new_span(&attrs);
+                    system.derived().span_enter(&id);
+                    system.derived().span_exit(&id, duration);
+                    system.try_close(id);
+                });
+            }
+        }
+    }
+    let dropped = buffer.dropped;
+    crate::stats::EARLY_DROPPED.fetch_add(dropped, Ordering::Relaxed);
+    //Dispatch::new triggers an interest cache rebuild which calls back into this shim's
+    // register_callsite, so the dispatch must be created before taking the state lock.
+    let dispatch = Dispatch::new(system);
+    {
+        let mut lock = EARLY_SHIM.state.write().unwrap();
+        *lock = State::Forwarding(dispatch);
+    }
+    //Interest cached while buffering said "record everything"; recompute it against the
+    // real backend now.
+    tracing_core::callsite::rebuild_interest_cache();
+    if dropped > 0 {
+        tracing::warn!(dropped, "The early-capture buffer overflowed; some pre-initialize records were lost");
+    }
+}
+
+impl Subscriber for EarlyDispatcher {
+    fn register_callsite(&self, metadata: &'static Metadata<'static>) -> Interest {
+        match &*EARLY_SHIM.state.read().unwrap() {
+            //Never cache a definitive interest while buffering: the real backend gets to
+            // answer once installed.
+            State::Buffering(_) => Interest::sometimes(),
+            State::Forwarding(inner) => inner.register_callsite(metadata)
+        }
+    }
+
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        match &*EARLY_SHIM.state.read().unwrap() {
+            State::Buffering(_) => true,
+            State::Forwarding(inner) => inner.enabled(metadata)
+        }
+    }
+
+    fn new_span(&self, span: &Attributes<'_>) -> Id {
+        let mut lock = EARLY_SHIM.state.write().unwrap();
+        match &mut *lock {
+            State::Buffering(buffer) => {
+                let id = EARLY_ID_BIT | buffer.next_id;
+                buffer.next_id += 1;
+                if buffer.spans.len() < MAX_EARLY_RECORDS {
+                    let mut visitor = OwnedVisitor(Vec::new());
+                    span.record(&mut visitor);
+                    buffer.spans.insert(id, SpanBuf {
+                        metadata: span.metadata(),
+                        fields: visitor.0,
+                        entered: None,
+                        duration: Duration::ZERO
+                    });
+                } else {
+                    buffer.dropped += 1;
+                }
+                Id::from_u64(id)
+            },
+            State::Forwarding(inner) => inner.new_span(span)
+        }
+    }
+
+    fn record(&self, span: &Id, values: &Record<'_>) {
+        let mut lock = EARLY_SHIM.state.write().unwrap();
+        match &mut *lock {
+            State::Buffering(buffer) => {
+                if let Some(data) = buffer.spans.get_mut(&span.into_u64()) {
+                    let mut visitor = OwnedVisitor(Vec::new());
+                    values.record(&mut visitor);
+                    data.fields.append(&mut visitor.0);
+                }
+            },
+            State::Forwarding(inner) => {
+                if span.into_u64() & EARLY_ID_BIT == 0 {
+                    inner.record(span, values);
+                }
+            }
+        }
+    }
+
+    fn record_follows_from(&self, span: &Id, follows: &Id) {
+        if let State::Forwarding(inner) = &*EARLY_SHIM.state.read().unwrap() {
+            if span.into_u64() & EARLY_ID_BIT == 0 && follows.into_u64() & EARLY_ID_BIT == 0 {
+                inner.record_follows_from(span, follows);
+            }
+        }
+    }
+
+    fn event(&self, event: &Event<'_>) {
+        let mut lock = EARLY_SHIM.state.write().unwrap();
+        match &mut *lock {
+            State::Buffering(buffer) => {
+                let mut visitor = OwnedVisitor(Vec::new());
+                event.record(&mut visitor);
+                buffer.push(ReplayRecord::Event {
+                    metadata: event.metadata(),
+                    fields: visitor.0,
+                    time: OffsetDateTime::now_utc()
+                });
+            },
+            State::Forwarding(inner) => inner.event(event)
+        }
+    }
+
+    fn enter(&self, span: &Id) {
+        let mut lock = EARLY_SHIM.state.write().unwrap();
+        match &mut *lock {
+            State::Buffering(buffer) => {
+                if let Some(data) = buffer.spans.get_mut(&span.into_u64()) {
+                    data.entered = Some(Instant::now());
+                }
+            },
+            State::Forwarding(inner) => {
+                if span.into_u64() & EARLY_ID_BIT == 0 {
+                    inner.enter(span);
+                }
+            }
+        }
+    }
+
+    fn exit(&self, span: &Id) {
+        let mut lock = EARLY_SHIM.state.write().unwrap();
+        match &mut *lock {
+            State::Buffering(buffer) => {
+                if let Some(data) = buffer.spans.get_mut(&span.into_u64()) {
+                    if let Some(entered) = data.entered.take() {
+                        data.duration += entered.elapsed();
+                    }
+                }
+            },
+            State::Forwarding(inner) => {
+                if span.into_u64() & EARLY_ID_BIT == 0 {
+                    inner.exit(span);
+                }
+            }
+        }
+    }
+
+    fn clone_span(&self, id: &Id) -> Id {
+        if let State::Forwarding(inner) = &*EARLY_SHIM.state.read().unwrap() {
+            if id.into_u64() & EARLY_ID_BIT == 0 {
+                return inner.clone_span(id);
+            }
+        }
+        id.clone()
+    }
+
+    fn try_close(&self, id: Id) -> bool {
+        let mut lock = EARLY_SHIM.state.write().unwrap();
+        match &mut *lock {
+            State::Buffering(buffer) => {
+                if let Some(data) = buffer.spans.remove(&id.into_u64()) {
+                    buffer.push(ReplayRecord::Span {
+                        metadata: data.metadata,
+                        fields: data.fields,
+                        duration: data.duration
+                    });
+                }
+                true
+            },
+            State::Forwarding(inner) => {
+                if id.into_u64() & EARLY_ID_BIT == 0 {
+                    inner.try_close(id)
+                } else {
+                    false
+                }
+            }
+        }
+    }
+
+    fn current_span(&self) -> tracing_core::span::Current {
+        match &*EARLY_SHIM.state.read().unwrap() {
+            State::Buffering(_) => tracing_core::span::Current::none(),
+            State::Forwarding(inner) => inner.current_span()
+        }
+    }
+}
@@ -0,0 +1,119 @@
+// Copyright (c) 2022, BlockProject 3D
+//
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification,
+// are permitted provided that the following conditions are met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//     * Redistributions in binary form must reproduce the above copyright notice,
+//       this list of conditions and the following disclaimer in the documentation
+//       and/or other materials provided with the distribution.
+//     * Neither the name of BlockProject 3D nor the names of its contributors
+//       may be used to endorse or promote products derived from this software
+//       without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS
+// "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT
+// LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR
+// A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT OWNER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! The reserved well-known field names this crate assigns behavior to. A typo in one of
+//! these silently disables the behavior, so every internal detection routes through these
+//! constants and user code should too.
+
+/// The event/span message text.
+pub const MESSAGE: &str = "message";
+
+/// Recording this field marks the current span run as failed
+/// (`span.record(fields::ERROR, &err as &dyn std::error::Error)`).
+pub const ERROR: &str = "error";
+
+/// Display-string variant of [ERROR](ERROR).
+pub const ERROR_MESSAGE: &str = "error.message";
+
+/// Fields whose names start with this prefix become callsite-level span tags.
+pub const TAG_PREFIX: &str = "tag.";
+
+/// The synthetic field carrying the active span stack on severe events.
+pub const SPAN_STACK: &str = "span.stack";
+
+/// Marks the span as failed by recording its error under the well-known field, so the
+/// run is flagged and counted without spelling the field name at every callsite. The
+/// span's callsite must declare the `error.message` field (e.g. via
+/// `error.message = tracing::field::Empty`).
+pub fn mark_failed(span: &tracing::Span, error: impl std::fmt::Display) {
+    span.record(ERROR_MESSAGE, error.to_string().as_str());
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing_core::{Callsite, Kind, Level, Metadata};
+    use tracing_core::field::Visit;
+    use tracing_core::metadata;
+    use tracing_core::subscriber::Interest;
+    use crate::config::FieldMode;
+    use crate::profiler::visitor::Visitor;
+    use super::*;
+
+    struct FieldsCallsite(#[allow(dead_code)] u8);
+    static CALLSITE: FieldsCallsite = FieldsCallsite(0);
+    static META: Metadata<'static> = metadata! {
+        name: "well_known",
+        target: module_path!(),
+        level: Level::INFO,
+        fields: &["message", "error", "error.message", "tag.kind"],
+        callsite: &CALLSITE,
+        kind: Kind::SPAN
+    };
+
+    impl Callsite for FieldsCallsite {
+        fn set_interest(&self, _: Interest) {}
+        fn metadata(&self) -> &Metadata<'static> {
+            &META
+        }
+    }
+
+    //One detection test per well-known field: renaming a constant without updating the
+    //visitors (or vice versa) fails here.
+
+    #[test]
+    fn message_constant_matches_visitor_behavior() {
+        let mut visitor = Visitor::new(FieldMode::Full);
+        visitor.record_str(&META.fields().field(MESSAGE).unwrap(), "hello");
+        let (message, values) = visitor.into_inner();
+        assert_eq!(message.as_deref(), Some("hello"));
+        assert!(values.is_empty());
+    }
+
+    #[test]
+    fn error_constants_match_visitor_behavior() {
+        for field in [ERROR, ERROR_MESSAGE] {
+            let mut visitor = Visitor::new(FieldMode::Full);
+            visitor.record_str(&META.fields().field(field).unwrap(), "boom");
+            assert!(visitor.failed(), "{} must mark the run failed", field);
+        }
+    }
+
+    #[test]
+    fn tag_prefix_matches_visitor_behavior() {
+        let mut visitor = Visitor::new(FieldMode::Full);
+        let name = format!("{}kind", TAG_PREFIX);
+        visitor.record_str(&META.fields().field(&name).unwrap(), "render");
+        assert_eq!(visitor.tags(), &[("kind".to_string(), "render".to_string())]);
+    }
+
+    #[test]
+    fn span_stack_constant_matches_the_profiler_field() {
+        //The profiler attaches the breadcrumb under exactly this name.
+        assert_eq!(SPAN_STACK, "span.stack");
+    }
+}
@@ -0,0 +1,264 @@
+// Copyright (c) 2022, BlockProject 3D
+//
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification,
+// are permitted provided that the following conditions are met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//     * Redistributions in binary form must reproduce the above copyright notice,
+//       this list of conditions and the following disclaimer in the documentation
+//       and/or other materials provided with the distribution.
+//     * Neither the name of BlockProject 3D nor the names of its contributors
+//       may be used to endorse or promote products derived from this software
+//       without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS
+// "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT
+// LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR
+// A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT OWNER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! The simplest structured export: a tracer serializing each event and span exit as one
+//! JSON object per line to an arbitrary writer (file, pipe, socket), independent of both
+//! the console logger and the network profiler.
+
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+use dashmap::DashMap;
+use serde_json::{json, Map, Value as JsonValue};
+use time::OffsetDateTime;
+use tracing_core::{Event, Level};
+use tracing_core::span::{Attributes, Id, Record};
+use crate::config::FieldMode;
+use crate::core::{Tracer, TracingSystem};
+use crate::profiler::network_types::Value;
+use crate::profiler::visitor::Visitor;
+use crate::util::{extract_target_module, Meta};
+
+fn to_json(value: Value) -> JsonValue {
+    match value {
+        Value::Float(v) => json!(v),
+        Value::Signed(v) => json!(v),
+        Value::Unsigned(v) => json!(v),
+        Value::String(v) => json!(v),
+        Value::Bool(v) => json!(v)
+    }
+}
+
+fn fields_to_map(value_set: Vec<(&'static str, Value)>) -> Map<String, JsonValue> {
+    value_set.into_iter()
+        .map(|(name, value)| (name.into(), to_json(value)))
+        .collect()
+}
+
+struct SpanData {
+    metadata: Meta,
+    message: Option<String>,
+    fields: Map<String, JsonValue>
+}
+
+/// A tracer writing newline-delimited JSON records for events and span exits.
+pub struct JsonTracer<W: Write> {
+    writer: Mutex<W>,
+    spans: DashMap<Id, SpanData>
+}
+
+impl<W: Write> JsonTracer<W> {
+    pub fn new(writer: W) -> TracingSystem<JsonTracer<W>> {
+        TracingSystem::without_destructor(crate::core::BackendKind::Other, JsonTracer {
+            writer: Mutex::new(writer),
+            spans: DashMap::new()
+        })
+    }
+
+    fn write_line(&self, line: JsonValue) {
+        let mut lock = self.writer.lock().unwrap();
+        //An output nobody reads anymore is not a reason to take the application down.
+        let _ = writeln!(lock, "{}", line);
+        let _ = lock.flush();
+    }
+}
+
+impl<W: 'static + Write> Tracer for JsonTracer<W> {
+    fn enabled(&self) -> bool {
+        true
+    }
+
+    fn span_create(&self, id: &Id, _: bool, _: Option<Id>, attrs: &Attributes) {
+        let mut visitor = Visitor::new(FieldMode::Full);
+        attrs.record(&mut visitor);
+        let (message, value_set) = visitor.into_inner();
+        self.spans.insert(id.clone(), SpanData {
+            metadata: attrs.metadata(),
+            message,
+            fields: fields_to_map(value_set)
+        });
+    }
+
+    fn span_values(&self, id: &Id, values: &Record) {
+        if let Some(mut data) = self.spans.get_mut(id) {
+            let mut visitor = Visitor::new(FieldMode::Full);
+            values.record(&mut visitor);
+            let (message, value_set) = visitor.into_inner();
+            if message.is_some() {
+                data.message = message;
+            }
+            data.fields.extend(fields_to_map(value_set));
+        }
+    }
+
+    fn span_follows_from(&self, _: &Id, _: &Id) {
+    }
+
+    fn event(&self, _: Option<Id>, time: OffsetDateTime, event: &Event) {
+        let mut visitor = Visitor::new(FieldMode::Full);
+        event.record(&mut visitor);
+        let (message, value_set) = visitor.into_inner();
+        let (target, module) = extract_target_module(event.metadata());
+        self.write_line(json!({
+            "type": "event",
+            "time": time.unix_timestamp(),
+            "level": event.metadata().level().as_str(),
+            "target": target,
+            "module": module,
+            "message": message,
+            "fields": fields_to_map(value_set)
+        }));
+    }
+
+    fn span_enter(&self, _: &Id) {
+    }
+
+    fn span_exit(&self, id: &Id, duration: Duration) {
+        if let Some(data) = self.spans.get(id) {
+            self.write_line(json!({
+                "type": "span_exit",
+                "name": data.metadata.name(),
+                "target": data.metadata.target(),
+                "message": data.message,
+                "duration": duration.as_secs_f64(),
+                "fields": data.fields
+            }));
+        }
+    }
+
+    fn span_destroy(&self, id: &Id, _: crate::core::CloseReason) {
+        self.spans.remove(id);
+    }
+
+    fn max_level_hint(&self) -> Option<Level> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use tracing_core::{Callsite, Kind, Metadata};
+    use tracing_core::field::Value as TracingValue;
+    use tracing_core::metadata;
+    use tracing_core::subscriber::Interest;
+    use super::*;
+
+    #[derive(Clone)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    struct SpanCallsite(#[allow(dead_code)] u8);
+    static SPAN_CALLSITE: SpanCallsite = SpanCallsite(0);
+    static SPAN_META: Metadata<'static> = metadata! {
+        name: "compute",
+        target: module_path!(),
+        level: Level::INFO,
+        fields: &["size"],
+        callsite: &SPAN_CALLSITE,
+        kind: Kind::SPAN
+    };
+
+    impl Callsite for SpanCallsite {
+        fn set_interest(&self, _: Interest) {}
+        fn metadata(&self) -> &Metadata<'static> {
+            &SPAN_META
+        }
+    }
+
+    struct EventCallsite(#[allow(dead_code)] u8);
+    static EVENT_CALLSITE: EventCallsite = EventCallsite(0);
+    static EVENT_META: Metadata<'static> = metadata! {
+        name: "event",
+        target: module_path!(),
+        level: Level::WARN,
+        fields: &["message", "code"],
+        callsite: &EVENT_CALLSITE,
+        kind: Kind::EVENT
+    };
+
+    impl Callsite for EventCallsite {
+        fn set_interest(&self, _: Interest) {}
+        fn metadata(&self) -> &Metadata<'static> {
+            &EVENT_META
+        }
+    }
+
+    #[test]
+    fn events_and_span_exits_emit_parsable_json_lines() {
+        let buf = SharedBuf(Arc::new(Mutex::new(Vec::new())));
+        let system = JsonTracer::new(buf.clone());
+        let tracer = system.system.derived();
+
+        let id = Id::from_u64(1 << 32);
+        let size = 42u64;
+        let size_field = SPAN_META.fields().field("size").unwrap();
+        let array = [(&size_field, Some(&size as &dyn TracingValue))];
+        let values = SPAN_META.fields().value_set(&array);
+        tracer.span_create(&id, true, None, &Attributes::new_root(&SPAN_META, &values));
+        tracer.span_exit(&id, Duration::from_millis(250));
+        tracer.span_destroy(&id, crate::core::CloseReason::Normal);
+
+        let message_field = EVENT_META.fields().field("message").unwrap();
+        let code_field = EVENT_META.fields().field("code").unwrap();
+        let code = 7i64;
+        let array = [
+            (&message_field, Some(&"something happened" as &dyn TracingValue)),
+            (&code_field, Some(&code as &dyn TracingValue))
+        ];
+        let values = EVENT_META.fields().value_set(&array);
+        tracer.event(None, OffsetDateTime::from_unix_timestamp(1648768000).unwrap(),
+            &Event::new(&EVENT_META, &values));
+
+        let bytes = buf.0.lock().unwrap().clone();
+        let text = String::from_utf8(bytes).unwrap();
+        let lines: Vec<serde_json::Value> = text.lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["type"], "span_exit");
+        assert_eq!(lines[0]["name"], "compute");
+        assert_eq!(lines[0]["duration"], 0.25);
+        assert_eq!(lines[0]["fields"]["size"], 42);
+        assert_eq!(lines[1]["type"], "event");
+        assert_eq!(lines[1]["level"], "WARN");
+        assert_eq!(lines[1]["message"], "something happened");
+        assert_eq!(lines[1]["fields"]["code"], 7);
+        assert_eq!(lines[1]["time"], 1648768000);
+    }
+}
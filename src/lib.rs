@@ -1,4 +1,4 @@
-// Copyright (c) 2021, BlockProject 3D
+// Copyright (c) 2022, BlockProject 3D
 //
 // All rights reserved.
 //
@@ -26,3 +26,674 @@
 // NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
 // SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
 
+use std::any::Any;
+use std::sync::atomic::Ordering;
+use bp3d_fs::dirs::App;
+use once_cell::sync::OnceCell;
+use tracing::subscriber::set_global_default;
+use tracing_core::Level;
+use crate::config::Config;
+use crate::core::{Tracer, TracingSystem};
+pub use crate::core::BackendKind;
+use crate::logger::Logger;
+pub use crate::logger::{build_log_msg, LoggerHandle};
+use crate::profiler::Profiler;
+
+pub use crate::stats::TracingStats;
+
+pub mod assertions;
+pub mod bridge;
+#[cfg(feature = "chrome-trace")]
+pub mod chrome_trace;
+pub mod config;
+pub mod context;
+mod core;
+pub mod crash_handler;
+pub mod json;
+mod early;
+pub mod fields;
+mod self_test;
+pub mod stats;
+#[cfg(feature = "test-util")]
+pub mod test_util;
+mod util;
+mod logger;
+mod profiler;
+pub mod project_info;
+#[cfg(feature = "replay")]
+pub mod replay;
+
+/// The guard to ensure proper termination of logging and tracing systems.
+#[must_use = "dropping the Guard stops logging/profiling immediately; hold it for the application lifetime (or call run/forget/block_until_ctrl_c)"]
+pub struct Guard {
+    backend: BackendKind,
+    destructor: Option<Box<dyn Any + Send>>
+}
+
+impl Guard {
+    /// Which backend this guard drives; backend-specific control surfaces (protocol
+    /// info, channel stats) are meaningful only for the matching variant.
+    pub fn backend(&self) -> BackendKind {
+        self.backend
+    }
+
+    fn terminate(&mut self) {
+        //Push any queued messages out before tearing the backend down; this drains both
+        // the logger backend and, in profiler mode, the log pump's channel.
+        bp3d_logger::flush();
+        log::logger().flush();
+        if let Some(destructor) = self.destructor.take() {
+            drop(destructor);
+        }
+    }
+
+    /// Run the following closure then terminate logging and tracing systems.
+    ///
+    /// Termination (flush included) is guaranteed even when the closure panics: the panic
+    /// is caught, the systems are flushed and terminated, then unwinding resumes, so the
+    /// final messages (including any panic-hook event) are delivered before teardown.
+    pub fn run<R, F: FnOnce() -> R>(mut self, func: F) -> R {
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(func));
+        self.terminate();
+        match result {
+            Ok(v) => v,
+            Err(e) => std::panic::resume_unwind(e)
+        }
+    }
+
+    /// Explicitly flush and terminate the logging and tracing systems.
+    pub fn shutdown(mut self) {
+        self.terminate();
+    }
+
+    /// Terminates the session and blocks until the viewer acknowledged receipt of the
+    /// final data (or the timeout elapsed); the CI-capture shape that avoids losing the
+    /// session tail on process exit. Returns whether the acknowledgement arrived; always
+    /// true when no profiler session is active.
+    pub fn drain_and_wait(mut self, timeout: std::time::Duration) -> bool {
+        let profiler = self.backend == BackendKind::Profiler;
+        //Terminate flushes and joins the writer, so the final frames are on the wire
+        // before we start waiting for the viewer.
+        self.terminate();
+        match profiler {
+            true => crate::profiler::state::ProfilerState::get().wait_ack(timeout),
+            false => true
+        }
+    }
+
+    /// Intentionally leaks the guard, keeping logging and tracing alive for the rest of
+    /// the process lifetime; final buffers are then only flushed by OS teardown.
+    pub fn forget(self) {
+        std::mem::forget(self);
+    }
+
+    /// Blocks the calling thread until Ctrl-C (SIGINT) is received, then flushes and
+    /// terminates the systems; the convenience shape for daemons whose main thread has
+    /// nothing else to do.
+    #[cfg(unix)]
+    pub fn block_until_ctrl_c(self) {
+        use std::sync::atomic::AtomicBool;
+
+        static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+        extern "C" fn on_sigint(_: i32) {
+            INTERRUPTED.store(true, Ordering::Release);
+        }
+
+        unsafe {
+            libc::signal(libc::SIGINT, on_sigint as *const () as libc::sighandler_t);
+        }
+        while !INTERRUPTED.load(Ordering::Acquire) {
+            std::thread::sleep(std::time::Duration::from_millis(50));
+        }
+        self.shutdown();
+    }
+
+    /// Returns a handle for flipping the logger's output details at runtime
+    /// (tracing_subscriber::fmt-style with_* toggles); None when the logger backend is
+    /// not the active tracer.
+    pub fn logger_handle(&self) -> Option<LoggerHandle> {
+        match crate::logger::is_active() {
+            true => Some(LoggerHandle(())),
+            false => None
+        }
+    }
+
+    /// What the profiler handshake negotiated (versions of both sides, feature set);
+    /// None before a client connected or in logger mode.
+    pub fn protocol_info(&self) -> Option<crate::profiler::network_types::ProtocolInfo> {
+        crate::profiler::state::ProfilerState::try_get().and_then(|v| v.protocol_info())
+    }
+
+    /// Per-message-type counts of everything sent on the profiler connection; empty
+    /// unless profiler.self_metrics is enabled. Shows whether span updates were actually
+    /// sent when a viewer appears to miss them.
+    pub fn protocol_stats(&self) -> Vec<crate::stats::ProtocolCounter> {
+        crate::stats::protocol_stats()
+    }
+
+    /// Snapshots the tracing health counters (channel headroom, drops, write failures).
+    ///
+    /// Cheap (atomic loads only); applications can poll and log this periodically to
+    /// find out whether their tracing is lossy.
+    pub fn stats(&self) -> TracingStats {
+        crate::stats::snapshot()
+    }
+}
+
+static MAX_LEVEL_HINT: OnceCell<Option<Level>> = OnceCell::new();
+
+pub(crate) fn is_initialized() -> bool {
+    MAX_LEVEL_HINT.get().is_some()
+}
+
+pub(crate) fn level_hint() -> Option<Level> {
+    MAX_LEVEL_HINT.get().copied().flatten()
+}
+
+fn load_system<T: 'static + Tracer + Sync + Send>(system: TracingSystem<T>) -> Guard {
+    //Every initialization path gets the deterministic crash-time teardown ordering; the
+    // hook chains whatever was installed before and installs only once.
+    crate::crash_handler::install_panic_hook();
+    let _ = MAX_LEVEL_HINT.set(system.system.derived().max_level_hint());
+    let backend = system.backend;
+    let destructor = system.destructor;
+    if early::is_active() {
+        //early_init already owns the global default; replay the buffer into the real
+        // backend and switch the shim to forwarding.
+        early::install(system.system);
+    } else {
+        set_global_default(system.system).expect("bp3d-tracing can only be initialized once!");
+    }
+    Guard {
+        backend,
+        destructor
+    }
+}
+
+pub use crate::core::live_span_count;
+pub use crate::self_test::{self_test, SelfTestReport, StageResult};
+
+/// The log-crate bridge forwarding records into the profiler. Hosts that install their
+/// own logger (or a multiplexer like log-fan) can register this to keep log records
+/// flowing to the profiler when bp3d-tracing could not install it itself.
+pub fn log_bridge() -> &'static dyn log::Log {
+    &crate::profiler::logpump::LOG_PUMP
+}
+
+/// Rebuilds a tracing span id from its callsite and instance parts, for applications
+/// tracking parent ids out-of-band (e.g. reconstructing a distributed trace). Pass the
+/// result as an explicit parent: `span!(parent: span_id_from_parts(id, instance), ...)`
+/// attaches to exactly that span, bypassing the thread-local current-span fallback.
+pub fn span_id_from_parts(id: u32, instance: u64) -> tracing_core::span::Id {
+    crate::util::span_from_id_instance(id, instance)
+}
+
+/// Splits a tracing span id into its callsite and instance parts; the inverse of
+/// [span_id_from_parts](span_id_from_parts).
+pub fn span_id_parts(span: &tracing_core::span::Id) -> (u32, u64) {
+    crate::util::span_to_id_instance(span)
+}
+
+/// A snapshot of the profiler command channel utilization.
+pub struct ChannelUsage {
+    /// The configured capacity of the channel.
+    pub capacity: usize,
+    /// The minimum free capacity observed by sampling at send time; the closer to zero,
+    /// the closer the session came to dropping or blocking.
+    pub min_free: usize
+}
+
+/// Reports the profiler command channel utilization observed so far.
+///
+/// Returns None when the profiler was never initialized or no sample was taken yet. Use
+/// this to tune profiler.channel_capacity before hitting drops.
+pub fn profiler_channel_usage() -> Option<ChannelUsage> {
+    let state = crate::profiler::state::ProfilerState::try_get()?;
+    state.monitor().min_free().map(|min_free| ChannelUsage {
+        capacity: state.capacity(),
+        min_free
+    })
+}
+
+/// Initialize the tracing system with a tracer writing newline-delimited JSON records
+/// (events and span exits) to the given writer, for piping structured tracing into
+/// external tooling.
+pub fn initialize_with_json_tracer<W: 'static + std::io::Write + Send + Sync>(writer: W) -> Guard {
+    load_system(crate::json::JsonTracer::new(writer))
+}
+
+/// Initialize the tracing system as a bridge forwarding to an existing subscriber.
+///
+/// Events pass through unchanged and every span exit is emitted as a synthetic event
+/// (span name, duration, fields) into the given dispatch, so span timings show up in
+/// whatever the application already configured for events. Useful for incremental
+/// migrations from tracing_subscriber-based stacks.
+pub fn initialize_with_event_bridge(dispatch: tracing_core::Dispatch) -> Guard {
+    load_system(crate::bridge::EventBridge::new(dispatch))
+}
+
+/// Installs a bounded early-capture buffer as the global default subscriber.
+///
+/// Call this at the very top of main when spans/events may be emitted before
+/// [initialize](initialize) runs (e.g. instrumentation inside library constructors); the
+/// buffered records are replayed into the real backend once it is installed. See the
+/// ordering caveats on the buffer bound in [assertions](crate::assertions)-style gates:
+/// spans still open when `initialize` runs are not migrated.
+pub fn early_init() {
+    if early::activate() {
+        let _ = tracing::dispatcher::set_global_default(tracing_core::Dispatch::new(early::EarlyDispatcher));
+    }
+}
+
+/// Returns true when a span/event with the given target and level would be logged by the
+/// installed tracer.
+///
+/// This mirrors `log::log_enabled!` but consults the level hint of the tracer installed by
+/// [initialize](initialize), allowing callers to skip expensive message construction. The
+/// target is currently unused and reserved for per-target directives; when no tracer has
+/// been installed yet this conservatively returns true.
+pub fn would_log(_target: &str, level: Level) -> bool {
+    match MAX_LEVEL_HINT.get() {
+        //Levels compare at inverse logic!
+        Some(Some(max)) => level <= *max,
+        Some(None) => true,
+        None => true
+    }
+}
+
+/// Runs an initialization closure that may block (the profiler waits for a debugger
+/// connection) without tripping over an ambient tokio runtime: on a multi-thread runtime
+/// worker the closure is routed through block_in_place, on a current-thread runtime it
+/// runs on a temporary thread (the await-free join still blocks, but safely), and outside
+/// any runtime it simply runs inline.
+#[cfg(feature = "tokio")]
+fn enter_blocking<R: Send, F: FnOnce() -> R + Send>(func: F) -> R {
+    match tokio::runtime::Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == tokio::runtime::RuntimeFlavor::MultiThread => {
+            tokio::task::block_in_place(func)
+        },
+        Ok(_) => std::thread::scope(|scope| scope.spawn(func).join().expect("initialization panicked")),
+        Err(_) => func()
+    }
+}
+
+#[cfg(not(feature = "tokio"))]
+fn enter_blocking<R: Send, F: FnOnce() -> R + Send>(func: F) -> R {
+    func()
+}
+
+#[cfg(feature = "tokio")]
+async fn run_blocking_async<R: 'static + Send, F: 'static + FnOnce() -> R + Send>(func: F) -> R {
+    tokio::task::spawn_blocking(func).await.expect("initialization panicked")
+}
+
+/// Initialize the logging and tracing systems for the given application.
+///
+/// The function returns a guard which must be maintained for the duration of the
+/// application. Initialization also installs the crash-time teardown ordering of
+/// [crash_handler](crate::crash_handler) as a panic hook (chaining any previous hook).
+pub fn initialize<T: AsRef<str>>(app: T) -> Guard {
+    let app = app.as_ref();
+    enter_blocking(move || initialize_impl(app))
+}
+
+/// Runs the profiler's writer on the given tokio runtime instead of a dedicated OS
+/// thread; call before initialization. Shutdown awaits the task gracefully (aborting only
+/// a stalled writer) while the host runtime stays alive.
+#[cfg(feature = "tokio")]
+pub fn use_existing_runtime(handle: tokio::runtime::Handle) {
+    crate::profiler::set_runtime_handle(handle);
+}
+
+/// Initialize the logging and tracing systems without blocking the caller's runtime
+/// thread; the potentially blocking parts (including the profiler's wait for a debugger
+/// connection) run on a blocking-capable thread and the returned future simply awaits
+/// them.
+#[cfg(feature = "tokio")]
+pub async fn initialize_async<T: 'static + AsRef<str> + Send>(app: T) -> Guard {
+    run_blocking_async(move || {
+        let app = app.as_ref();
+        initialize_impl(app)
+    }).await
+}
+
+fn initialize_impl(app: &str) -> Guard {
+    {
+        let app = App::new(app);
+        if let Ok(v) = app.get_documents().map(|v| v.join("environment")) {
+            bp3d_env::add_override_path(&v);
+        }
+    }
+    let guard = initialize_with_config(app, Config::from_env());
+    if bp3d_env::get_bool("BP3D_TRACE_SELFTEST").unwrap_or(false) {
+        eprintln!("{}", self_test());
+    }
+    guard
+}
+
+/// Initialize the logging and tracing systems with an explicit configuration.
+///
+/// Layer configurations with [Config::merge](crate::config::Config::merge) before calling
+/// this; most applications want the environment as the last layer so users keep control.
+pub fn initialize_with_config<T: AsRef<str>>(app: T, config: Config) -> Guard {
+    fn apply_rate<T: Tracer>(mut system: TracingSystem<T>, config: &Config) -> TracingSystem<T> {
+        system.system.set_max_event_rate(config.max_events_per_sec);
+        if let Some(allocation) = config.instance_allocation {
+            system.system.set_instance_allocation(allocation);
+        }
+        system.system.set_attach_span_stack_on(config.attach_span_stack_on);
+        if let Some(single) = config.single_threaded {
+            system.system.set_single_threaded(single);
+        }
+        system
+    }
+    let profiler = bp3d_env::get_bool("PROFILER").unwrap_or(false);
+    if profiler {
+        Profiler::new(app.as_ref(), &config).map(|v| load_system(apply_rate(v, &config)))
+            .unwrap_or_else(|_| load_system(apply_rate(Logger::new(app.as_ref(), &config), &config)))
+    } else {
+        load_system(apply_rate(Logger::new(app.as_ref(), &config), &config))
+    }
+}
+
+/// Temporarily redirects logger output into the given writer for the duration of the
+/// closure, then restores normal output.
+///
+/// Builds on the scoped patterns of [LogBuffer](LogBuffer) and
+/// [DisableStdoutLogger](DisableStdoutLogger): console output is suppressed while every
+/// message is captured and written as `<target> [LEVEL] message` lines. The restore (and
+/// the drain of everything captured so far) also happens when the closure panics, before
+/// unwinding resumes.
+pub fn capture_logs<R, W: std::io::Write>(mut writer: W, func: impl FnOnce() -> R) -> R {
+    let buffer = LogBuffer::new();
+    let disable = DisableStdoutLogger::new();
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(func));
+    bp3d_logger::flush();
+    while let Some(msg) = buffer.pull() {
+        let _ = writeln!(writer, "<{}> [{}] {}", msg.target, msg.level, msg.msg);
+    }
+    drop(disable);
+    drop(buffer);
+    match result {
+        Ok(v) => v,
+        Err(e) => std::panic::resume_unwind(e)
+    }
+}
+
+//The refcounts guard externally visible side effects (enabling/disabling backend
+// features). A bare atomic is not enough: with fetch_add/fetch_sub two racing threads can
+// observe the 0->1 / 1->0 transition correctly and still have the enable/disable call
+// reorder against a concurrent observer, e.g. LogBuffer::new returning before the buffer
+// is actually enabled. Holding a small mutex across the transition edge makes the count
+// change and its side effect one atomic step, which is also much easier to audit.
+static LOG_BUFFER_RC: std::sync::Mutex<usize> = std::sync::Mutex::new(0);
+
+static STDOUT_DISABLE_RC: std::sync::Mutex<usize> = std::sync::Mutex::new(0);
+
+/// A struct to automate management of the in-memory log buffer.
+///
+/// When a new instance of this struct is created, the log buffer is automatically enabled if not
+/// already. Inversely, when all instances of this struct are dropped, the log buffer is disabled.
+pub struct LogBuffer(bp3d_logger::LogBuffer);
+
+impl Default for LogBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LogBuffer {
+    /// Creates a new access to the in-memory log buffer.
+    pub fn new() -> LogBuffer {
+        {
+            let mut rc = LOG_BUFFER_RC.lock().unwrap();
+            if *rc == 0 {
+                //If no previous buffers were created, enable the log buffer; under the
+                // lock, so the buffer is guaranteed enabled before new() returns.
+                bp3d_logger::enable_log_buffer();
+            }
+            *rc += 1;
+        }
+        LogBuffer(bp3d_logger::get_log_buffer())
+    }
+
+    /// Attempts to pull a message from the in-memory log buffer.
+    pub fn pull(&self) -> Option<bp3d_logger::LogMsg> {
+        self.0.try_recv().ok()
+    }
+}
+
+impl Drop for LogBuffer {
+    fn drop(&mut self) {
+        let mut rc = LOG_BUFFER_RC.lock().unwrap();
+        *rc -= 1;
+        if *rc == 0 {
+            //If no more log buffers exists after this one, disable the log buffer.
+            bp3d_logger::disable_log_buffer();
+        }
+    }
+}
+
+/// A struct to automate enabling and disabling of the stdout/stderr logger.
+///
+/// When a new instance of this struct is created, the stdout/stderr logger is automatically
+/// disabled if not already. Inversely, when all instances of this struct are dropped, the
+/// stdout/stderr logger is re-enabled.
+pub struct DisableStdoutLogger;
+
+impl Default for DisableStdoutLogger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DisableStdoutLogger {
+    /// Temporarily disables stdout/stderr logging for the lifespan of this struct.
+    pub fn new() -> DisableStdoutLogger {
+        let mut rc = STDOUT_DISABLE_RC.lock().unwrap();
+        if *rc == 0 {
+            //If no previous instances were created, disable the stdout/stderr logger.
+            //First, flush any waiting message.
+            bp3d_logger::flush();
+            //Then disable the backend, still under the lock so no concurrent observer can
+            // see an instance alive with stdout enabled.
+            bp3d_logger::disable_stdout();
+        }
+        *rc += 1;
+        DisableStdoutLogger
+    }
+}
+
+impl Drop for DisableStdoutLogger {
+    fn drop(&mut self) {
+        let mut rc = STDOUT_DISABLE_RC.lock().unwrap();
+        *rc -= 1;
+        if *rc == 0 {
+            //If no more instances exists after this one, re-enable the stdout/stderr logger.
+            bp3d_logger::enable_stdout();
+        }
+    }
+}
+
+#[cfg(all(test, feature = "tokio"))]
+mod tokio_tests {
+    use super::*;
+
+    #[test]
+    fn enter_blocking_outside_runtime() {
+        assert_eq!(enter_blocking(|| 5), 5);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn enter_blocking_inside_multi_thread_runtime() {
+        //block_in_place path; must not panic with "cannot block the current thread".
+        assert_eq!(enter_blocking(|| 5), 5);
+    }
+
+    #[tokio::test]
+    async fn enter_blocking_inside_current_thread_runtime() {
+        //Temporary-thread path: block_in_place is forbidden on current-thread runtimes.
+        assert_eq!(enter_blocking(|| 5), 5);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn async_entry_point_awaits_blocking_work() {
+        assert_eq!(run_blocking_async(|| 7).await, 7);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+    use super::*;
+
+    #[test]
+    fn early_spans_replay_into_logger() {
+        early_init();
+        {
+            let span = tracing::info_span!("early_span", stage = "boot");
+            let _entered = span.enter();
+        }
+        tracing::info!("early event");
+        //The log buffer must be listening before initialize replays the early records.
+        let buffer = LogBuffer::new();
+        let _guard = initialize_with_config("bp3d-tracing-test", Config::default());
+        bp3d_logger::flush();
+        let mut messages = Vec::new();
+        while let Some(msg) = buffer.pull() {
+            messages.push(msg.msg);
+        }
+        assert!(messages.iter().any(|m| m.contains("early_span")));
+        assert!(messages.iter().any(|m| m.contains("early event")));
+    }
+
+    #[test]
+    fn backend_accessor_reports_the_right_variant() {
+        for kind in [BackendKind::Logger, BackendKind::Profiler, BackendKind::Other] {
+            let guard = Guard {
+                backend: kind,
+                destructor: None
+            };
+            assert_eq!(guard.backend(), kind);
+            guard.shutdown();
+        }
+    }
+
+    #[test]
+    fn run_returns_value_and_drops_destructor() {
+        use std::sync::Arc;
+        use std::sync::atomic::AtomicBool;
+
+        struct SetOnDrop(Arc<AtomicBool>);
+        impl Drop for SetOnDrop {
+            fn drop(&mut self) {
+                self.0.store(true, Ordering::Relaxed);
+            }
+        }
+
+        let dropped = Arc::new(AtomicBool::new(false));
+        let guard = Guard {
+            backend: BackendKind::Other,
+            destructor: Some(Box::new(SetOnDrop(dropped.clone())))
+        };
+        let value = guard.run(|| 42);
+        assert_eq!(value, 42);
+        assert!(dropped.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn run_terminates_on_panic_then_resumes_unwinding() {
+        use std::sync::Arc;
+        use std::sync::atomic::AtomicBool;
+
+        struct SetOnDrop(Arc<AtomicBool>);
+        impl Drop for SetOnDrop {
+            fn drop(&mut self) {
+                self.0.store(true, Ordering::Relaxed);
+            }
+        }
+
+        let dropped = Arc::new(AtomicBool::new(false));
+        let guard = Guard {
+            backend: BackendKind::Other,
+            destructor: Some(Box::new(SetOnDrop(dropped.clone())))
+        };
+        let panic = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            guard.run(|| panic!("boom"));
+        }));
+        assert!(panic.is_err());
+        //The destructor must have run BEFORE the panic propagated.
+        assert!(dropped.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn run_composes_with_nested_stdout_guards() {
+        let guard = Guard {
+            backend: BackendKind::Other,
+            destructor: None
+        };
+        let value = guard.run(|| {
+            let _disable = DisableStdoutLogger::new();
+            let _nested = DisableStdoutLogger::new();
+            7
+        });
+        assert_eq!(value, 7);
+        //All nested guards dropped inside the closure: the refcount must be balanced.
+        assert_eq!(*STDOUT_DISABLE_RC.lock().unwrap(), 0);
+    }
+
+    #[test]
+    fn refcount_guards_survive_concurrent_churn() {
+        let threads: Vec<_> = (0..8)
+            .map(|_| std::thread::spawn(|| {
+                for _ in 0..100 {
+                    let buffer = LogBuffer::new();
+                    let disable = DisableStdoutLogger::new();
+                    drop(disable);
+                    drop(buffer);
+                }
+            }))
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        //Every transition and its side effect happened atomically; the counts balance.
+        assert_eq!(*LOG_BUFFER_RC.lock().unwrap(), 0);
+        assert_eq!(*STDOUT_DISABLE_RC.lock().unwrap(), 0);
+    }
+
+    #[test]
+    fn capture_logs_redirects_into_the_writer() {
+        let mut captured = Vec::new();
+        let value = capture_logs(&mut captured, || {
+            bp3d_logger::raw_log(bp3d_logger::LogMsg {
+                msg: "first captured line".into(),
+                level: log::Level::Info,
+                target: "capture_test".into()
+            });
+            bp3d_logger::raw_log(bp3d_logger::LogMsg {
+                msg: "second captured line".into(),
+                level: log::Level::Warn,
+                target: "capture_test".into()
+            });
+            21
+        });
+        assert_eq!(value, 21);
+        let text = String::from_utf8(captured).unwrap();
+        assert!(text.contains("<capture_test> [INFO] first captured line"));
+        assert!(text.contains("<capture_test> [WARN] second captured line"));
+    }
+
+    #[test]
+    fn would_log_respects_level_hint() {
+        //Another test may already have installed a system (also at the default INFO level);
+        // the OnceCell only accepts the first value.
+        let _ = MAX_LEVEL_HINT.set(Some(Level::INFO));
+        assert_eq!(*MAX_LEVEL_HINT.get().unwrap(), Some(Level::INFO));
+        assert!(would_log("bp3d_tracing::tests", Level::ERROR));
+        assert!(would_log("bp3d_tracing::tests", Level::INFO));
+        assert!(!would_log("bp3d_tracing::tests", Level::DEBUG));
+        assert!(!would_log("bp3d_tracing::tests", Level::TRACE));
+    }
+}
@@ -0,0 +1,407 @@
+// Copyright (c) 2022, BlockProject 3D
+//
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification,
+// are permitted provided that the following conditions are met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//     * Redistributions in binary form must reproduce the above copyright notice,
+//       this list of conditions and the following disclaimer in the documentation
+//       and/or other materials provided with the distribution.
+//     * Neither the name of BlockProject 3D nor the names of its contributors
+//       may be used to endorse or promote products derived from this software
+//       without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS
+// "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT
+// LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR
+// A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT OWNER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Central resolution of the local artifact directory (summary json, dataset dumps,
+//! flight recorder output), so every writer shares one fallback chain instead of
+//! reimplementing its own: config override path, then the app logs directory, then a
+//! temp-dir fallback, then disabled. The outcome - which directory is used, or why
+//! writing is disabled - is recorded for the session summary.
+
+use std::path::{Path, PathBuf};
+use bp3d_fs::dirs::App;
+use crate::profiler::thread::util::sanitize_file_name;
+
+/// The resolved artifact output location for this session.
+pub struct ArtifactStore {
+    directory: Option<PathBuf>,
+    description: String
+}
+
+//A directory only counts as usable if we can actually create a file in it; a read-only
+// logs directory on a locked-down system fails here and the chain moves on.
+fn usable(directory: &Path) -> bool {
+    if std::fs::create_dir_all(directory).is_err() {
+        return false;
+    }
+    let probe = directory.join(".bp3d-tracing-probe");
+    match std::fs::write(&probe, b"") {
+        Ok(()) => {
+            let _ = std::fs::remove_file(&probe);
+            true
+        },
+        Err(_) => false
+    }
+}
+
+impl ArtifactStore {
+    /// Resolves the artifact directory once for the session, walking the fallback chain.
+    pub fn resolve(app: &str, override_path: Option<&Path>) -> ArtifactStore {
+        if let Some(path) = override_path {
+            if usable(path) {
+                return ArtifactStore {
+                    description: format!("artifacts in {} (configured override)", path.display()),
+                    directory: Some(path.into())
+                };
+            }
+            log::warn!(target: "bp3d-tracing", "The configured artifact directory {} is not writable; falling back", path.display());
+        }
+        if let Ok(logs) = App::new(app).get_logs() {
+            if usable(logs) {
+                return ArtifactStore {
+                    description: format!("artifacts in {} (app logs directory)", logs.display()),
+                    directory: Some(logs.into())
+                };
+            }
+            log::warn!(target: "bp3d-tracing", "The app logs directory {} is not writable; falling back to a temp directory", logs.display());
+        }
+        let temp = std::env::temp_dir().join(format!("bp3d-tracing-{}", sanitize_file_name(app)));
+        if usable(&temp) {
+            return ArtifactStore {
+                description: format!("artifacts in {} (temp fallback)", temp.display()),
+                directory: Some(temp)
+            };
+        }
+        log::warn!(target: "bp3d-tracing", "No writable artifact directory available; local artifact writing is disabled");
+        ArtifactStore {
+            directory: None,
+            description: "artifact writing disabled: no writable directory available".into()
+        }
+    }
+
+    /// Returns the path for a named artifact (the name is sanitized), or None when
+    /// artifact writing is disabled.
+    pub fn artifact_file(&self, name: &str) -> Option<PathBuf> {
+        self.directory.as_ref().map(|dir| dir.join(sanitize_file_name(name)))
+    }
+
+    /// Which directory is in use, or why artifact writing is disabled; recorded in the
+    /// session summary.
+    pub fn description(&self) -> &str {
+        &self.description
+    }
+}
+
+//The on-demand statistics dump: a SIGUSR1 handler (or anything else) flips this flag,
+// and the write thread consumes it at its idle tick. Dumping never disturbs the session:
+// no stat reset, no recording toggle.
+static DUMP_REQUESTED: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+//The session's resolved artifact store, for triggers consumed on the write thread.
+static STORE: once_cell::sync::OnceCell<ArtifactStore> = once_cell::sync::OnceCell::new();
+
+/// The name of the file whose presence in the artifacts directory triggers a dump on
+/// platforms where signals are unsuitable; removed when consumed.
+pub const DUMP_TRIGGER_FILE: &str = "dump-trigger";
+
+/// Requests a statistics dump at the next idle tick (what the SIGUSR1 handler calls).
+pub fn request_dump() {
+    DUMP_REQUESTED.store(true, std::sync::atomic::Ordering::Release);
+}
+
+pub(crate) fn publish_store(store: ArtifactStore) {
+    let _ = STORE.set(store);
+}
+
+#[cfg(unix)]
+pub(crate) fn install_signal_trigger() {
+    extern "C" fn on_sigusr1(_: i32) {
+        //Only an atomic store: safe in a signal handler.
+        DUMP_REQUESTED.store(true, std::sync::atomic::Ordering::Release);
+    }
+    unsafe {
+        libc::signal(libc::SIGUSR1, on_sigusr1 as *const () as libc::sighandler_t);
+    }
+}
+
+/// Whether a dump was requested, consuming the request: either the signal flag or the
+/// trigger file in the given directory.
+fn consume_trigger(store: &ArtifactStore) -> bool {
+    if DUMP_REQUESTED.swap(false, std::sync::atomic::Ordering::AcqRel) {
+        return true;
+    }
+    if let Some(trigger) = store.artifact_file(DUMP_TRIGGER_FILE) {
+        if trigger.exists() {
+            let _ = std::fs::remove_file(trigger);
+            return true;
+        }
+    }
+    false
+}
+
+/// Writes the current span statistics (the exit-summary content) to a timestamped file
+/// in the artifact directory; None when artifact writing is disabled.
+fn dump_snapshot(store: &ArtifactStore) -> Option<PathBuf> {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|v| v.as_secs())
+        .unwrap_or(0);
+    let path = store.artifact_file(&format!("span-stats-{}.json", now))?;
+    let snapshot = crate::assertions::span_stats_snapshot();
+    let json = serde_json::to_string_pretty(&snapshot).ok()?;
+    std::fs::write(&path, json).ok()?;
+    Some(path)
+}
+
+/// Consumes a pending dump trigger against the session's published store; called from
+/// the write thread's idle tick.
+pub(crate) fn check_and_dump() {
+    if let Some(store) = STORE.get() {
+        if consume_trigger(store) {
+            if let Some(path) = dump_snapshot(store) {
+                log::info!(target: "bp3d-tracing", "Wrote span statistics dump to {}", path.display());
+            }
+        }
+    }
+}
+
+/// A bounded pool of output file writers keyed by path: at most `max_open` stay open,
+/// the least-recently-written one is flushed and closed when the cap is hit, and closed
+/// streams reopen in append mode on demand. Protects against fd exhaustion when many
+/// spans/targets each own an output stream; the logger's per-target file routing writes
+/// through it, and the per-span dataset writers will too.
+pub struct DatasetPool {
+    max_open: usize,
+    //Most recently written last.
+    open: Vec<(PathBuf, std::io::BufWriter<std::fs::File>)>
+}
+
+impl DatasetPool {
+    pub fn new(max_open: usize) -> DatasetPool {
+        DatasetPool {
+            max_open: max_open.max(1),
+            open: Vec::new()
+        }
+    }
+
+    /// How many files are currently open.
+    pub fn open_count(&self) -> usize {
+        self.open.len()
+    }
+
+    /// Appends bytes to the stream at the given path, opening (or reopening) its file as
+    /// needed and evicting the least-recently-written stream beyond the cap.
+    pub fn write(&mut self, path: &Path, bytes: &[u8]) -> std::io::Result<()> {
+        use std::io::Write;
+        if let Some(index) = self.open.iter().position(|(open_path, _)| open_path == path) {
+            let mut entry = self.open.remove(index);
+            entry.1.write_all(bytes)?;
+            self.open.push(entry);
+            return Ok(());
+        }
+        if self.open.len() >= self.max_open {
+            //Flush-and-close the coldest stream; its data is safe and the file reopens
+            // in append mode the next time that stream is written.
+            let (_, mut evicted) = self.open.remove(0);
+            evicted.flush()?;
+        }
+        let file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+        let mut writer = std::io::BufWriter::new(file);
+        writer.write_all(bytes)?;
+        self.open.push((path.into(), writer));
+        Ok(())
+    }
+
+    /// Flushes every open stream.
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        use std::io::Write;
+        for (_, writer) in &mut self.open {
+            writer.flush()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("bp3d-artifact-test-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn dump_works_without_manual_collection_enabling() {
+        use std::time::Duration as StdDuration;
+        use tracing_core::span::Id;
+        use tracing_core::Subscriber;
+        //A session that never called enable_collection() itself: only the
+        // dump_on_signal init path (mirrored here) opens the collection gate, exactly
+        // like Profiler::new does when the config flag is set.
+        crate::assertions::disable_collection();
+        crate::assertions::enable_collection();
+        //Span durations arrive through the regular subscriber exit path, no manual
+        // filling.
+        struct SilentTracer;
+        impl crate::core::Tracer for SilentTracer {
+            fn enabled(&self) -> bool {
+                true
+            }
+            fn span_create(&self, _: &Id, _: bool, _: Option<Id>, _: &tracing_core::span::Attributes) {}
+            fn span_values(&self, _: &Id, _: &tracing_core::span::Record) {}
+            fn span_follows_from(&self, _: &Id, _: &Id) {}
+            fn event(&self, _: Option<Id>, _: time::OffsetDateTime, _: &tracing_core::Event) {}
+            fn span_enter(&self, _: &Id) {}
+            fn span_exit(&self, _: &Id, _: StdDuration) {}
+            fn span_destroy(&self, _: &Id, _: crate::core::CloseReason) {}
+            fn max_level_hint(&self) -> Option<tracing_core::Level> {
+                None
+            }
+        }
+
+        struct DumpCallsite(#[allow(dead_code)] u8);
+        static DUMP_CALLSITE: DumpCallsite = DumpCallsite(0);
+        static DUMP_META: tracing_core::Metadata<'static> = tracing_core::metadata! {
+            name: "signal_dump_span",
+            target: module_path!(),
+            level: tracing_core::Level::INFO,
+            fields: &[],
+            callsite: &DUMP_CALLSITE,
+            kind: tracing_core::metadata::Kind::SPAN
+        };
+        impl tracing_core::Callsite for DumpCallsite {
+            fn set_interest(&self, _: tracing_core::subscriber::Interest) {}
+            fn metadata(&self) -> &tracing_core::Metadata<'static> {
+                &DUMP_META
+            }
+        }
+
+        let tracer = crate::core::BaseTracer::new(SilentTracer);
+        //Another test toggles the same global gate; retry until our exit was recorded
+        // while the gate was open.
+        for _ in 0..50 {
+            crate::assertions::enable_collection();
+            let values = DUMP_META.fields().value_set(&[]);
+            let span = tracer.new_span(&tracing_core::span::Attributes::new_root(&DUMP_META, &values));
+            tracer.enter(&span);
+            tracer.exit(&span);
+            assert!(tracer.try_close(span));
+            if crate::assertions::span_stats_snapshot().iter().any(|v| v.name == "signal_dump_span") {
+                break;
+            }
+        }
+
+        let dir = temp_dir("signal-dump");
+        let store = ArtifactStore::resolve("signal_dump_test", Some(&dir));
+        request_dump();
+        assert!(consume_trigger(&store));
+        let path = dump_snapshot(&store).unwrap();
+        let json: serde_json::Value = serde_json::from_str(
+            &std::fs::read_to_string(&path).unwrap()).unwrap();
+        //The dump carries the real session's span, not an empty array.
+        assert!(json.as_array().unwrap().iter().any(|v| v["name"] == "signal_dump_span"),
+            "dump was empty despite dump_on_signal-style init");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn dump_triggers_write_a_well_formed_snapshot() {
+        let dir = temp_dir("dump");
+        let store = ArtifactStore::resolve("dump_test", Some(&dir));
+        //Populate some statistics for the dump to carry.
+        crate::assertions::enable_collection();
+        for _ in 0..3 {
+            crate::assertions::record("dump_span", std::time::Duration::from_millis(5));
+        }
+        crate::assertions::disable_collection();
+        //The signal-style trigger consumes exactly once...
+        request_dump();
+        assert!(consume_trigger(&store));
+        assert!(!consume_trigger(&store));
+        //...and the file-style trigger works where signals don't.
+        std::fs::write(store.artifact_file(DUMP_TRIGGER_FILE).unwrap(), b"").unwrap();
+        assert!(consume_trigger(&store));
+        assert!(!consume_trigger(&store));
+        let path = dump_snapshot(&store).unwrap();
+        let json: serde_json::Value = serde_json::from_str(
+            &std::fs::read_to_string(&path).unwrap()).unwrap();
+        let entry = json.as_array().unwrap().iter()
+            .find(|v| v["name"] == "dump_span")
+            .expect("dumped snapshot misses the recorded span");
+        assert_eq!(entry["run_count"], 3);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn dataset_pool_bounds_open_files_without_losing_data() {
+        let dir = temp_dir("pool");
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut pool = DatasetPool::new(4);
+        //Far more datasets than the cap, written in two interleaved passes so evicted
+        // streams must reopen and append.
+        for pass in 0..2u32 {
+            for n in 0..20u32 {
+                pool.write(&dir.join(format!("span-{:02}", n)),
+                    format!("row{}-{};", pass, n).as_bytes()).unwrap();
+                assert!(pool.open_count() <= 4);
+            }
+        }
+        pool.flush().unwrap();
+        drop(pool);
+        for n in 0..20u32 {
+            let content = std::fs::read_to_string(dir.join(format!("span-{:02}", n))).unwrap();
+            //Both passes survived eviction and reopening.
+            assert_eq!(content, format!("row0-{};row1-{};", n, n));
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn override_path_wins_when_writable() {
+        let dir = temp_dir("override");
+        let store = ArtifactStore::resolve("artifact_test", Some(&dir));
+        assert_eq!(store.artifact_file("summary.json"), Some(dir.join("summary.json")));
+        assert!(store.description().contains("configured override"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn artifact_names_are_sanitized() {
+        let dir = temp_dir("sanitize");
+        let store = ArtifactStore::resolve("artifact_test", Some(&dir));
+        let file = store.artifact_file("../escape/attempt.json").unwrap();
+        assert!(file.starts_with(&dir));
+        assert!(!file.to_string_lossy()[dir.to_string_lossy().len()..].contains(".."));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unusable_override_falls_back() {
+        //An override pointing at a regular file can never become a directory, which
+        // models a read-only/locked-down location regardless of process privileges
+        // (a permissions-based probe would pass when the tests run as root).
+        let blocker = temp_dir("blocker");
+        std::fs::write(&blocker, b"not a directory").unwrap();
+        let store = ArtifactStore::resolve("artifact_test", Some(&blocker));
+        //The chain moved past the unusable override instead of wedging on it.
+        assert!(!store.description().contains("configured override"));
+        assert!(store.artifact_file("x").map(|f| !f.starts_with(&blocker)).unwrap_or(true));
+        std::fs::remove_file(&blocker).unwrap();
+    }
+}
@@ -0,0 +1,179 @@
+// Copyright (c) 2022, BlockProject 3D
+//
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification,
+// are permitted provided that the following conditions are met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//     * Redistributions in binary form must reproduce the above copyright notice,
+//       this list of conditions and the following disclaimer in the documentation
+//       and/or other materials provided with the distribution.
+//     * Neither the name of BlockProject 3D nor the names of its contributors
+//       may be used to endorse or promote products derived from this software
+//       without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS
+// "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT
+// LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR
+// A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT OWNER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use std::io::Write;
+use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+use unbounded_udp::{Domain, Unbounded};
+use crate::profiler::{DEFAULT_PORT, PROTOCOL_VERSION};
+
+//How often we broadcast ourselves to auto-discover clients; independent of the shutdown
+// latency, which is bounded only by the condvar wakeup.
+const BROADCAST_PERIOD: Duration = Duration::from_secs(2);
+
+/// Signals the discovery loop to exit; the loop wakes immediately instead of sleeping out
+/// the rest of its broadcast interval.
+#[derive(Clone)]
+pub struct ExitSignal(Arc<(Mutex<bool>, Condvar)>);
+
+impl ExitSignal {
+    fn new() -> ExitSignal {
+        ExitSignal(Arc::new((Mutex::new(false), Condvar::new())))
+    }
+
+    pub fn signal(&self) {
+        let (lock, condvar) = &*self.0;
+        *lock.lock().unwrap() = true;
+        condvar.notify_all();
+    }
+}
+
+// The maximum number of characters allowed for the application name in the auto-discover list.
+const NAME_MAX_CHARS: usize = 126;
+
+const PROTOCOL_SIGNATURE: u8 = b'B';
+
+pub struct AutoDiscoveryService {
+    socket: UdpSocket,
+    packet: Box<[u8]>,
+    destination: SocketAddrV4,
+    exit: ExitSignal
+}
+
+impl AutoDiscoveryService {
+    /// Creates the service with an explicit announcement destination (broadcast or
+    /// multicast address) and/or a local address to bind to. Binding selects the outbound
+    /// interface on multi-homed hosts or where broadcast is filtered per segment.
+    pub fn with_network(
+        app_name: &str,
+        interface: Option<Ipv4Addr>,
+        destination: Option<SocketAddrV4>
+    ) -> std::io::Result<AutoDiscoveryService> {
+        let bytes = app_name.as_bytes();
+        let truncated = &bytes[..std::cmp::min(bytes.len(), NAME_MAX_CHARS)];
+        let mut packet = Vec::with_capacity(NAME_MAX_CHARS + 2);
+        packet.push(PROTOCOL_SIGNATURE);
+        packet.push(PROTOCOL_VERSION);
+        packet.write_all(truncated).unwrap();
+        // Null-pad the packet.
+        while packet.len() != NAME_MAX_CHARS + 2 {
+            packet.push(0);
+        }
+        let socket = match interface {
+            Some(ip) => UdpSocket::bind((ip, 0))?,
+            None => UdpSocket::unbounded(Domain::IpV4)?
+        };
+        socket.set_broadcast(true)?;
+        let destination = destination
+            .unwrap_or_else(|| SocketAddrV4::new(Ipv4Addr::BROADCAST, DEFAULT_PORT));
+        if destination.ip().is_multicast() {
+            //Multicast announcements follow the bound interface; loopback stays on so a
+            // client on the same host still discovers us.
+            socket.set_multicast_loop_v4(true)?;
+        }
+        Ok(AutoDiscoveryService {
+            packet: packet.into_boxed_slice(),
+            destination,
+            exit: ExitSignal::new(),
+            socket
+        })
+    }
+
+    pub fn exit_signal(&self) -> ExitSignal {
+        self.exit.clone()
+    }
+
+    pub fn run(&self) {
+        let (lock, condvar) = &*self.exit.0;
+        loop {
+            {
+                if *lock.lock().unwrap() {
+                    break;
+                }
+            }
+            if let Err(e) = self.socket.send_to(&self.packet, self.destination) {
+                eprintln!("Failed to send auto-discover packet: {}", e);
+            }
+            //Sleep out the broadcast interval, but wake immediately on the exit signal.
+            let guard = lock.lock().unwrap();
+            let (guard, _) = condvar.wait_timeout_while(guard, BROADCAST_PERIOD, |exit| !*exit).unwrap();
+            if *guard {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+    use super::*;
+
+    #[test]
+    fn announcements_reach_a_configured_address() {
+        //Receiver on loopback; the service binds to loopback and announces to it.
+        let receiver = match UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)) {
+            Ok(v) => v,
+            Err(_) => return //No UDP in this environment.
+        };
+        receiver.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let port = receiver.local_addr().unwrap().port();
+        let service = AutoDiscoveryService::with_network("net_test",
+            Some(Ipv4Addr::LOCALHOST),
+            Some(SocketAddrV4::new(Ipv4Addr::LOCALHOST, port))).unwrap();
+        let signal = service.exit_signal();
+        let thread = std::thread::spawn(move || service.run());
+        let mut packet = [0; 256];
+        let (len, from) = receiver.recv_from(&mut packet).unwrap();
+        signal.signal();
+        thread.join().unwrap();
+        //The packet went out on the configured interface and carries our signature.
+        assert!(from.ip().is_loopback());
+        assert_eq!(len, NAME_MAX_CHARS + 2);
+        assert_eq!(packet[0], PROTOCOL_SIGNATURE);
+        assert!(packet[2..].starts_with(b"net_test"));
+    }
+
+    #[test]
+    fn run_returns_promptly_on_exit_signal() {
+        let service = match AutoDiscoveryService::with_network("exit_test", None, None) {
+            Ok(v) => v,
+            //No UDP in this environment: nothing to assert about wakeup latency.
+            Err(_) => return
+        };
+        let signal = service.exit_signal();
+        let thread = std::thread::spawn(move || service.run());
+        std::thread::sleep(Duration::from_millis(50));
+        let start = Instant::now();
+        signal.signal();
+        thread.join().unwrap();
+        //Far below the 2s broadcast interval: the condvar woke the loop immediately.
+        assert!(start.elapsed() < Duration::from_millis(500));
+    }
+}
@@ -0,0 +1,1103 @@
+// Copyright (c) 2022, BlockProject 3D
+//
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification,
+// are permitted provided that the following conditions are met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//     * Redistributions in binary form must reproduce the above copyright notice,
+//       this list of conditions and the following disclaimer in the documentation
+//       and/or other materials provided with the distribution.
+//     * Neither the name of BlockProject 3D nor the names of its contributors
+//       may be used to endorse or promote products derived from this software
+//       without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS
+// "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT
+// LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR
+// A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT OWNER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{Error, Read, Write};
+use std::time::{Duration, Instant};
+use byteorder::{ByteOrder, LittleEndian};
+use std::net::{Ipv4Addr, SocketAddrV4, TcpListener, TcpStream};
+use std::sync::atomic::Ordering;
+use crossbeam_channel::{Sender, TrySendError};
+use dashmap::{DashMap, DashSet};
+use time::OffsetDateTime;
+use tracing_core::{Event, Level};
+use tracing_core::span::{Attributes, Id, Record};
+use crate::config::{Config, FieldMode, LocationMode};
+use crate::core::{Tracer, TracingSystem};
+use crate::profiler::auto_discover::AutoDiscoveryService;
+use crate::profiler::DEFAULT_PORT;
+use crate::profiler::logpump::LOG_PUMP;
+use crate::profiler::network_types::{Hello, HELLO_PACKET, MatchResult};
+use crate::profiler::network_types::CloseReason as NetCloseReason;
+use crate::profiler::state::{ProfilerState, ThreadHandle};
+use crate::profiler::thread::{Command, Thread};
+use crate::profiler::visitor::Visitor;
+
+struct Guard;
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        ProfilerState::get().terminate();
+    }
+}
+
+//Server-side limits applied to configuration values; out-of-range requests are clamped
+// with a warning notice rather than rejected.
+const MIN_CHANNEL_CAPACITY: usize = 16;
+const MAX_CHANNEL_CAPACITY: usize = 65536;
+const MAX_RECORD_ROWS: u32 = 1_000_000;
+const MIN_PERIOD_MS: u32 = 10;
+
+/// The recording configuration in effect for a session: the server preset overridden
+/// field-by-field by whatever the client provided, then clamped to server limits with a
+/// notice per clamped field - exactly the path a client-sent config takes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct EffectiveClientConfig {
+    pub max_average_points: u32,
+    pub record_enable: bool,
+    pub record_max_rows: u32,
+    pub period_ms: u32,
+    pub max_level: tracing_core::Level
+}
+
+pub(crate) fn effective_client_config(
+    preset: &crate::config::ProfilerPreset,
+    client: Option<&crate::config::ProfilerPreset>,
+    notices: &mut Vec<String>
+) -> EffectiveClientConfig {
+    let mut merged = preset.clone();
+    if let Some(client) = client {
+        merged.merge(client.clone());
+    }
+    let requested_rows = merged.record_max_rows.unwrap_or(10_000);
+    let rows = requested_rows.min(MAX_RECORD_ROWS);
+    if rows != requested_rows {
+        notices.push(clamp_notice("record.max_rows", requested_rows as usize, rows as usize,
+            "max_record_rows", MAX_RECORD_ROWS as usize));
+    }
+    let requested_period = merged.period_ms.unwrap_or(200);
+    let period = requested_period.max(MIN_PERIOD_MS);
+    if period != requested_period {
+        notices.push(clamp_notice("period", requested_period as usize, period as usize,
+            "min_period", MIN_PERIOD_MS as usize));
+    }
+    EffectiveClientConfig {
+        max_average_points: merged.max_average_points.unwrap_or(1024),
+        record_enable: merged.record_enable.unwrap_or(false),
+        record_max_rows: rows,
+        period_ms: period,
+        max_level: merged.max_level.unwrap_or(tracing_core::Level::INFO)
+    }
+}
+
+//How many commands a traced thread parks locally while the channel is full before it
+// starts dropping the oldest.
+const OVERFLOW_CAP: usize = 1024;
+
+thread_local! {
+    static OVERFLOW: RefCell<VecDeque<Command>> = const { RefCell::new(VecDeque::new()) };
+}
+
+static OVERFLOW_WARNED: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+/// Sends a command without ever blocking the traced thread: a full channel (writer thread
+/// still starting up or stalled) parks commands in a bounded thread-local overflow queue
+/// which drains, in order, ahead of later commands; beyond the bound the oldest command is
+/// dropped with a counted warning.
+pub(crate) fn send_nonblocking(channel: &Sender<Command>, cmd: Command) {
+    OVERFLOW.with(|overflow| {
+        let mut overflow = overflow.borrow_mut();
+        //Older parked commands go first so ordering is preserved.
+        while let Some(front) = overflow.pop_front() {
+            match channel.try_send(front) {
+                Ok(()) => {},
+                Err(TrySendError::Full(front)) => {
+                    overflow.push_front(front);
+                    break;
+                },
+                Err(TrySendError::Disconnected(_)) => return
+            }
+        }
+        if overflow.is_empty() {
+            match channel.try_send(cmd) {
+                Ok(()) | Err(TrySendError::Disconnected(_)) => return,
+                Err(TrySendError::Full(cmd)) => overflow.push_back(cmd)
+            }
+        } else {
+            overflow.push_back(cmd);
+        }
+        if overflow.len() > OVERFLOW_CAP {
+            overflow.pop_front();
+            crate::stats::CHANNEL_DROPS.fetch_add(1, Ordering::Relaxed);
+            if !OVERFLOW_WARNED.swap(true, Ordering::Relaxed) {
+                eprintln!("bp3d-tracing: the profiler channel and overflow buffer are full; \
+oldest commands are being dropped");
+            }
+        }
+    });
+}
+
+/// A thread-local buffer accumulating commands so high-frequency tracing pays one channel
+/// synchronization per batch instead of per message. Whatever is left in the buffer when
+/// the thread exits is flushed by the TLS destructor.
+struct TlsBatch {
+    commands: Vec<Command>,
+    started: Instant,
+    out: Sender<Command>
+}
+
+impl Drop for TlsBatch {
+    fn drop(&mut self) {
+        if !self.commands.is_empty() {
+            //This runs during TLS destruction, where touching other thread-locals (the
+            // overflow queue) aborts; fall back to a plain try_send and account the loss.
+            let batch = Command::Batch(std::mem::take(&mut self.commands));
+            if self.out.try_send(batch).is_err() {
+                crate::stats::CHANNEL_DROPS.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+thread_local! {
+    static BATCH: RefCell<Option<TlsBatch>> = const { RefCell::new(None) };
+}
+
+/// The push-side batching policy; flushing happens when the size cap is reached or, at
+/// the next push, when the time window has elapsed.
+pub(crate) struct Batcher {
+    size: usize,
+    window: Duration
+}
+
+impl Batcher {
+    pub fn new(size: usize, window: Duration) -> Batcher {
+        Batcher {
+            size,
+            window
+        }
+    }
+
+    pub fn push(&self, cmd: Command, out: &Sender<Command>) {
+        BATCH.with(|cell| {
+            let mut cell = cell.borrow_mut();
+            let batch = cell.get_or_insert_with(|| TlsBatch {
+                commands: Vec::with_capacity(self.size),
+                started: Instant::now(),
+                out: out.clone()
+            });
+            batch.commands.push(cmd);
+            if batch.commands.len() >= self.size || batch.started.elapsed() >= self.window {
+                let commands = std::mem::take(&mut batch.commands);
+                batch.started = Instant::now();
+                send_nonblocking(out, Command::Batch(commands));
+            }
+        });
+    }
+}
+
+/// The notice recorded when the log pump cannot be installed because the host (or a
+/// transitive dependency like env_logger) already registered a logger; None when the
+/// installed logger is bp3d-logger's own, whose records keep flowing to tracing outputs.
+fn pump_install_notice(already_installed: bool, bp3d_logger_active: bool) -> Option<String> {
+    if !already_installed {
+        return None;
+    }
+    match bp3d_logger_active {
+        true => Some("A bp3d-logger logger is already installed; log records keep flowing \
+through it instead of the profiler pump".into()),
+        false => Some("A logger was already installed by the host; log-crate records will \
+not be forwarded to the profiler. Register bp3d_tracing::log_bridge() with your logger \
+multiplexer to restore forwarding".into())
+    }
+}
+
+/// A human-readable notice describing a clamped configuration value, emitted as a WARNING
+/// event right after the handshake so viewer users see why they did not get what they
+/// asked for.
+fn clamp_notice(field: &str, requested: usize, applied: usize, limit_name: &str, limit: usize) -> String {
+    format!("The requested {} ({}) was clamped to {} by the server setting {} ({})",
+        field, requested, applied, limit_name, limit)
+}
+
+/// Applies server limits to the configured channel capacity, collecting one notice per
+/// clamped field.
+fn clamp_config(requested: Option<usize>, notices: &mut Vec<String>) -> Option<usize> {
+    let requested = requested?;
+    let applied = requested.clamp(MIN_CHANNEL_CAPACITY, MAX_CHANNEL_CAPACITY);
+    if applied != requested {
+        let (limit_name, limit) = match requested < MIN_CHANNEL_CAPACITY {
+            true => ("min_channel_capacity", MIN_CHANNEL_CAPACITY),
+            false => ("max_channel_capacity", MAX_CHANNEL_CAPACITY)
+        };
+        notices.push(clamp_notice("channel_capacity", requested, applied, limit_name, limit));
+    }
+    Some(applied)
+}
+
+/// Accepts connections until one completes the Hello handshake within the timeout;
+/// failed candidates are dropped and accepting resumes.
+fn accept_client(listener: &TcpListener, handshake_timeout: Duration) -> std::io::Result<TcpStream> {
+    loop {
+        let (mut client, _) = listener.accept()?;
+        match handle_hello(&mut client, handshake_timeout) {
+            Ok(()) => return Ok(client),
+            Err(e) => {
+                eprintln!("Dropping a client that failed the handshake: {}", e);
+            }
+        }
+    }
+}
+
+/// What the reader does with one decoded client command, given the connection's current
+/// permission level; separated from the socket loop so the policy is testable.
+enum ClientAction {
+    Forward(Command),
+    Deny(&'static str),
+    Ignore
+}
+
+fn apply_client_command(
+    cmd: crate::profiler::network_types::deserializer::ClientCommand,
+    controller: &mut bool,
+    control_token: &Option<String>,
+    allow_log_download: bool
+) -> ClientAction {
+    use crate::profiler::network_types::deserializer::{constant_time_eq, ClientCommand};
+    match cmd {
+        ClientCommand::Authenticate { token } => {
+            match control_token {
+                Some(expected) if constant_time_eq(&token, expected) => {
+                    *controller = true;
+                    log::info!(target: "bp3d-tracing", "A client authenticated for control");
+                },
+                _ => log::warn!(target: "bp3d-tracing", "Rejected a client control token")
+            }
+            ClientAction::Ignore
+        },
+        //Read-only queries and acknowledgements are always honored.
+        ClientCommand::QueryOpenSpans => ClientAction::Forward(Command::QueryOpenSpans),
+        ClientCommand::DatasetAck => {
+            ProfilerState::get().acknowledge();
+            ClientAction::Ignore
+        },
+        ClientCommand::RequestLogFile { max_bytes } => {
+            if !allow_log_download {
+                log::warn!(target: "bp3d-tracing", "Denied a client log file request: profiler.allow_log_download is disabled");
+                return ClientAction::Ignore;
+            }
+            ClientAction::Forward(Command::SendLogFile { max_bytes })
+        },
+        //State-changing messages require control permission.
+        ClientCommand::SetSessionName(name) if !*controller => {
+            let _ = name;
+            ClientAction::Deny("SetSessionName")
+        },
+        ClientCommand::PauseRecording { .. } if !*controller => ClientAction::Deny("PauseRecording"),
+        ClientCommand::SetSessionName(name) => {
+            ClientAction::Forward(Command::SessionName(
+                crate::profiler::thread::util::sanitize_file_name(&name)))
+        },
+        ClientCommand::PauseRecording { paused } => {
+            log::info!(target: "bp3d-tracing", "Recording {} by the client", match paused {
+                true => "paused",
+                false => "resumed"
+            });
+            ProfilerState::get().set_paused(paused);
+            ClientAction::Ignore
+        }
+    }
+}
+
+/// Reads client frames (4-byte length prefix + tagged payload) and forwards recognized
+/// commands into the profiler channel; exits when the connection closes. Frame lengths are
+/// bounded before allocation like every client-supplied value. Connections start
+/// read-only whenever a control token is configured.
+fn client_reader(mut socket: TcpStream, channel: Sender<Command>, allow_log_download: bool,
+                 control_token: Option<String>) {
+    use crate::profiler::network_types::deserializer;
+    use crate::profiler::network_types::protocol::{FRAME_LEN_BYTES, MAX_CLIENT_FRAME};
+    let mut header = [0; FRAME_LEN_BYTES];
+    //Without a configured token every connection may control (the historical behavior).
+    let mut controller = control_token.is_none();
+    loop {
+        if socket.read_exact(&mut header).is_err() {
+            break;
+        }
+        let len = LittleEndian::read_u32(&header) as usize;
+        if len > MAX_CLIENT_FRAME {
+            eprintln!("A client frame exceeded the maximum accepted size and the connection was dropped");
+            break;
+        }
+        let mut frame = vec![0; len];
+        if socket.read_exact(&mut frame).is_err() {
+            break;
+        }
+        crate::stats::BYTES_RECEIVED.fetch_add(FRAME_LEN_BYTES + frame.len(), Ordering::Relaxed);
+        match deserializer::parse_client_frame(&frame) {
+            Ok(cmd) => match apply_client_command(cmd, &mut controller, &control_token, allow_log_download) {
+                ClientAction::Forward(cmd) => {
+                    if channel.send(cmd).is_err() {
+                        break;
+                    }
+                },
+                ClientAction::Deny(msg_type) => {
+                    if channel.send(Command::PermissionDenied(msg_type)).is_err() {
+                        break;
+                    }
+                },
+                ClientAction::Ignore => {}
+            },
+            Err(e) => eprintln!("Ignoring an undecodable client frame: {}", e)
+        }
+    }
+}
+
+fn handle_hello(client: &mut TcpStream, timeout: Duration) -> std::io::Result<()> {
+    let bytes = HELLO_PACKET.to_bytes();
+    let mut block = [0; crate::profiler::network_types::protocol::HANDSHAKE_SIZE];
+    client.write_all(&bytes)?;
+    //A client that connects but never answers must not wedge initialization forever.
+    client.set_read_timeout(Some(timeout))?;
+    let result = client.read_exact(&mut block);
+    client.set_read_timeout(None)?;
+    result?;
+    let packet = Hello::from_bytes(block);
+    match HELLO_PACKET.matches(&packet) {
+        MatchResult::SignatureMismatch => Err(Error::other("protocol signature mismatch")),
+        MatchResult::VersionMismatch => Err(Error::other("version signature mismatch")),
+        MatchResult::Ok => {
+            let info = crate::profiler::network_types::ProtocolInfo::negotiate(&HELLO_PACKET, &packet);
+            log::info!(target: "bp3d-tracing", "Negotiated profiler protocol: server v{}{} / client v{}{}",
+                info.server_major,
+                info.server_pre_release.as_deref().map(|v| format!("-{}", v)).unwrap_or_default(),
+                info.client_major,
+                info.client_pre_release.as_deref().map(|v| format!("-{}", v)).unwrap_or_default());
+            ProfilerState::get().set_protocol_info(info);
+            Ok(())
+        }
+    }
+}
+
+/// Returns the first tag whose value differs from the captured callsite-level set, if
+/// any; tags are static annotations, so a changed value is instrumentation misuse.
+fn conflicting_tag<'a>(
+    stored: &'a [(String, String)],
+    new: &'a [(String, String)]
+) -> Option<&'a str> {
+    new.iter()
+        .find(|(name, value)| {
+            stored.iter().any(|(stored_name, stored_value)| stored_name == name && stored_value != value)
+        })
+        .map(|(name, _)| name.as_str())
+}
+
+pub struct Profiler {
+    channel: Sender<Command>,
+    field_mode: FieldMode,
+    max_vars: Option<usize>,
+    max_debug_len: Option<usize>,
+    batcher: Option<Batcher>,
+    capture_memory: bool,
+    enter_rss: DashMap<u64, u64>,
+    callsite_tags: DashMap<u32, Vec<(String, String)>>,
+    failed_spans: DashSet<u64>
+}
+
+impl Profiler {
+    pub fn new(app_name: &str, config: &Config) -> std::io::Result<TracingSystem<Profiler>> {
+        //Size the command channel before anything can touch the state; out-of-range
+        // requests are clamped to the server limits. The notices collected here surface
+        // to the client as warning events right after the handshake.
+        let mut notices = Vec::new();
+        if let Some(capacity) = clamp_config(config.profiler.channel_capacity, &mut notices) {
+            ProfilerState::init(capacity);
+        }
+        ProfilerState::get().set_max_tracked_instances(config.profiler.max_tracked_instances);
+        crate::stats::SELF_METRICS.store(config.profiler.self_metrics.unwrap_or(false),
+            std::sync::atomic::Ordering::Relaxed);
+        //A pre-installed logger is a working session with a warning, not a crash the
+        // user cannot work around.
+        if let Some(notice) = pump_install_notice(log::set_logger(&LOG_PUMP).is_err(),
+            bp3d_logger::enabled()) {
+            eprintln!("bp3d-tracing: {}", notice);
+            notices.push(notice);
+        }
+        let port = config.profiler.port.unwrap_or(DEFAULT_PORT);
+        let addr = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port);
+        let listener = TcpListener::bind(addr)?;
+        let service = AutoDiscoveryService::with_network(app_name,
+            config.profiler.discovery_interface, config.profiler.discovery_address)?;
+        let exit_signal = service.exit_signal();
+        let thread = std::thread::spawn(move || {
+            service.run();
+        });
+        println!("Waiting for debugger to attach to {}...", port);
+        let handshake_timeout = Duration::from_millis(config.profiler.handshake_timeout_ms.unwrap_or(5000));
+        //Block software until a debugger completes the handshake; clients that connect
+        // and stay silent (or speak another protocol) are dropped and we keep accepting.
+        let client = accept_client(&listener, handshake_timeout)?;
+        exit_signal.signal();
+        thread.join().unwrap();
+        let (sender, receiver) = ProfilerState::get().get_channel();
+        //The Project message goes out first: application identity plus plugin-provided
+        // sections, collected off the tracer hot path.
+        sender.send(Command::Project {
+            app_name: app_name.into(),
+            sections: crate::project_info::collect()
+        }).ok();
+        crate::project_info::attach_sink(sender.clone());
+        //Listen for client commands (session naming, ...) on a clone of the socket.
+        if let Ok(reader) = client.try_clone() {
+            let read_sender = sender.clone();
+            let allow_log_download = config.profiler.allow_log_download.unwrap_or(false);
+            let control_token = config.profiler.control_token.clone();
+            std::thread::spawn(move || client_reader(reader, read_sender, allow_log_download, control_token));
+        }
+        //The preset applies as if a client had sent it; a real client's later config
+        // overrides it field-by-field through the same path.
+        let effective = effective_client_config(&config.profiler.preset, None, &mut notices);
+        notices.push(format!("Effective recording config: record={} max_rows={} period={}ms \
+average_points={} max_level={}", effective.record_enable, effective.record_max_rows,
+            effective.period_ms, effective.max_average_points, effective.max_level));
+        //Tell the client about every clamped value right after the handshake.
+        for notice in notices {
+            crate::stats::CONFIG_CLAMPS.fetch_add(1, Ordering::Relaxed);
+            let record = log::Record::builder()
+                .target("bp3d.profiler")
+                .level(log::Level::Warn)
+                .build();
+            sender.send(Command::Event(crate::profiler::thread::Event {
+                span: None,
+                metadata: crate::profiler::network_types::Metadata::from_log(&record),
+                time: OffsetDateTime::now_utc().unix_timestamp(),
+                message: Some(notice),
+                value_set: Vec::new()
+            })).ok();
+        }
+        let export_span_tree = config.profiler.export_span_tree.unwrap_or(false);
+        let location = config.profiler.event_include_location.unwrap_or(LocationMode::Full);
+        //Resolve the artifact directory once for every local writer of this session.
+        let artifacts = crate::profiler::artifacts::ArtifactStore::resolve(app_name,
+            config.profiler.artifacts_dir.as_deref());
+        let artifacts_description = artifacts.description().to_string();
+        if config.profiler.dump_on_signal.unwrap_or(false) {
+            //The dump serializes the collected duration samples; without this the
+            // collection gate stays closed and every dump would be empty.
+            crate::assertions::enable_collection();
+            #[cfg(unix)]
+            crate::profiler::artifacts::install_signal_trigger();
+        }
+        crate::profiler::artifacts::publish_store(artifacts);
+        let logs_dir = bp3d_fs::dirs::App::new(app_name).get_logs().ok().map(|v| v.to_path_buf());
+        let stall_threshold = Duration::from_millis(config.profiler.stall_threshold_ms.unwrap_or(2000));
+        let run = move || {
+            let mut thread = Thread::new(client, receiver, export_span_tree, location,
+                artifacts_description, logs_dir, stall_threshold);
+            thread.run();
+        };
+        //The writer lives on the host's tokio runtime when the application opted in
+        // (better thread accounting, no extra OS thread); on a dedicated thread otherwise.
+        let handle = match crate::profiler::runtime_handle() {
+            #[cfg(feature = "tokio")]
+            Some(runtime) => ThreadHandle::Tokio(runtime.spawn_blocking(run)),
+            _ => ThreadHandle::Std(std::thread::spawn(run))
+        };
+        ProfilerState::get().assign_thread(handle);
+        log::set_max_level(log::LevelFilter::Trace);
+        let batch_size = config.profiler.batch_size.unwrap_or(1);
+        Ok(TracingSystem::with_destructor(crate::core::BackendKind::Profiler, Profiler {
+            channel: sender,
+            field_mode: config.profiler.fields.unwrap_or(FieldMode::Full),
+            max_vars: config.profiler.max_vars_per_span,
+            max_debug_len: config.profiler.max_debug_len,
+            capture_memory: config.profiler.capture_memory.unwrap_or(false),
+            enter_rss: DashMap::new(),
+            callsite_tags: DashMap::new(),
+            batcher: match batch_size > 1 {
+                true => Some(Batcher::new(batch_size,
+                    Duration::from_millis(config.profiler.batch_flush_ms.unwrap_or(10)))),
+                false => None
+            },
+            failed_spans: DashSet::new()
+        }, Box::new(Guard)))
+    }
+
+    fn is_exited(&self) -> bool {
+        ProfilerState::get().is_exited()
+    }
+
+    /// Whether a command carries per-run data that a paused session should not record;
+    /// structural commands (allocations, frees, project info) keep flowing.
+    fn gated_while_paused(cmd: &Command) -> bool {
+        matches!(cmd,
+            Command::SpanInit { .. } | Command::SpanValues { .. } | Command::SpanEnter(_)
+            | Command::SpanExit { .. } | Command::Event(_))
+    }
+
+    fn visitor_for(&self, declared: usize) -> Visitor {
+        let mut visitor = Visitor::with_limits(self.field_mode, declared, self.max_vars);
+        if let Some(max) = self.max_debug_len {
+            visitor.set_max_debug_len(max);
+        }
+        visitor
+    }
+
+    fn command(&self, cmd: Command) {
+        if ProfilerState::get().is_paused() && Self::gated_while_paused(&cmd) {
+            return;
+        }
+        if !self.is_exited() {
+            ProfilerState::get().monitor().observe(&self.channel);
+            match &self.batcher {
+                Some(batcher) => batcher.push(cmd, &self.channel),
+                None => send_nonblocking(&self.channel, cmd)
+            }
+        }
+    }
+}
+
+impl Tracer for Profiler {
+    fn enabled(&self) -> bool {
+        !self.is_exited()
+    }
+
+    fn span_create(&self, id: &Id, new: bool, parent: Option<Id>, span: &Attributes) {
+        let mut visitor = self.visitor_for(span.metadata().fields().len());
+        span.record(&mut visitor);
+        let (callsite, _) = crate::util::span_to_id_instance(id);
+        if new {
+            //Tags are captured once, at callsite level, and never re-encoded per run.
+            self.callsite_tags.insert(callsite, visitor.tags().to_vec());
+            ProfilerState::get().register_span_name(callsite, span.metadata().name());
+            self.command(Command::SpanAlloc {
+                //Copy the metadata strings now: the write thread must never rely on the
+                // callsite's lifetime (dynamic registration leaks aside, it's not ours).
+                metadata: crate::profiler::network_types::Metadata::from_tracing(span.metadata()),
+                id: id.into_u64(),
+                tags: visitor.tags().to_vec()
+            });
+        } else if !visitor.tags().is_empty() {
+            if let Some(stored) = self.callsite_tags.get(&callsite) {
+                if let Some(tag) = conflicting_tag(&stored, visitor.tags()) {
+                    //Cannot go through log here: span_create runs while the subscriber's
+                    // inner lock is held, and the log pump would call back into
+                    // current_span() on the same thread.
+                    eprintln!("bp3d-tracing: ignoring re-recorded value for span tag '{}': \
+tags are fixed at first creation", tag);
+                }
+            }
+        }
+        if visitor.failed() {
+            self.failed_spans.insert(id.into_u64());
+        }
+        ProfilerState::get().register_parent(id.into_u64(), parent.as_ref().map(|v| v.into_u64()));
+        let (message, value_set) = visitor.into_inner();
+        self.command(Command::SpanInit {
+            span: id.into_u64(),
+            value_set,
+            message,
+            parent: parent.map(|v| v.into_u64())
+        });
+    }
+
+    fn span_values(&self, id: &Id, values: &Record) {
+        let mut visitor = self.visitor_for(0);
+        values.record(&mut visitor);
+        if visitor.failed() {
+            self.failed_spans.insert(id.into_u64());
+        }
+        let (message, value_set) = visitor.into_inner();
+        self.command(Command::SpanValues {
+            span: id.into_u64(),
+            message,
+            value_set
+        });
+    }
+
+    fn span_follows_from(&self, id: &Id, follows: &Id) {
+        self.command(Command::SpanFollows {
+            span: id.into_u64(),
+            follows: follows.into_u64()
+        });
+    }
+
+    fn event(&self, parent: Option<Id>, time: OffsetDateTime, event: &Event) {
+        let mut visitor = self.visitor_for(event.metadata().fields().len());
+        event.record(&mut visitor);
+        let (message, mut value_set) = visitor.into_inner();
+        if let Some(stack) = crate::core::attached_span_stack() {
+            value_set.push((crate::fields::SPAN_STACK, crate::profiler::network_types::Value::String(stack.join(" > "))));
+        }
+        self.command(Command::Event(crate::profiler::thread::Event {
+            metadata: crate::profiler::network_types::Metadata::from_tracing(event.metadata()),
+            span: parent.map(|v| v.into_u64()),
+            message,
+            value_set,
+            time: time.unix_timestamp()
+        }));
+    }
+
+    fn span_enter(&self, id: &Id) {
+        ProfilerState::get().span_opened(id.into_u64());
+        if self.capture_memory {
+            if let Some(rss) = crate::util::read_rss_bytes() {
+                self.enter_rss.insert(id.into_u64(), rss);
+            }
+        }
+        self.command(Command::SpanEnter(id.into_u64()));
+    }
+
+    fn span_exit(&self, id: &Id, duration: Duration) {
+        ProfilerState::get().span_closed(id.into_u64());
+        //The flag is consumed on exit so that each run of a span instance is judged on its own.
+        let failed = self.failed_spans.remove(&id.into_u64()).is_some();
+        let memory_delta = match self.capture_memory {
+            true => self.enter_rss.remove(&id.into_u64()).and_then(|(_, enter)| {
+                crate::util::read_rss_bytes().map(|exit| exit as i64 - enter as i64)
+            }),
+            false => None
+        };
+        self.command(Command::SpanExit {
+            span: id.into_u64(),
+            duration,
+            failed,
+            memory_delta
+        });
+    }
+
+    fn span_destroy(&self, id: &Id, reason: crate::core::CloseReason) {
+        ProfilerState::get().span_destroyed(id.into_u64());
+        //An error recorded during the span's lifetime outranks the lifecycle inference.
+        let reason = match (self.failed_spans.remove(&id.into_u64()).is_some(), reason) {
+            (true, _) => NetCloseReason::Error,
+            (false, crate::core::CloseReason::Dropped) => NetCloseReason::Dropped,
+            (false, crate::core::CloseReason::Normal) => NetCloseReason::Normal
+        };
+        self.enter_rss.remove(&id.into_u64());
+        self.command(Command::SpanFree {
+            span: id.into_u64(),
+            reason
+        });
+    }
+
+    fn max_level_hint(&self) -> Option<Level> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::profiler::thread::Event as ThreadEvent;
+    use crate::profiler::network_types::Metadata as NetMetadata;
+    use super::*;
+
+    fn test_event(n: i64) -> Command {
+        let record = log::Record::builder().target("batch_test").level(log::Level::Info).build();
+        Command::Event(ThreadEvent {
+            span: None,
+            metadata: NetMetadata::from_log(&record),
+            time: n,
+            message: Some(format!("event {}", n)),
+            value_set: Vec::new()
+        })
+    }
+
+    fn event_times(cmd: Command) -> Vec<i64> {
+        match cmd {
+            Command::Batch(cmds) => cmds.into_iter().flat_map(event_times).collect(),
+            Command::Event(ThreadEvent { time, .. }) => vec![time],
+            _ => Vec::new()
+        }
+    }
+
+    #[test]
+    fn span_tags_travel_in_the_alloc_command() {
+        use tracing_core::{Callsite, Kind, Level, Metadata};
+        use tracing_core::field::Value as TracingValue;
+        use tracing_core::metadata;
+        use tracing_core::span::Attributes;
+        use tracing_core::subscriber::Interest;
+        use tracing_core::span::Id;
+        use crate::core::Tracer;
+
+        struct TagCallsite(#[allow(dead_code)] u8);
+        static TAG_CALLSITE: TagCallsite = TagCallsite(0);
+        static TAG_META: Metadata<'static> = metadata! {
+            name: "tagged_span",
+            target: module_path!(),
+            level: Level::INFO,
+            fields: &["tag.color", "tag.kind"],
+            callsite: &TAG_CALLSITE,
+            kind: Kind::SPAN
+        };
+        impl Callsite for TagCallsite {
+            fn set_interest(&self, _: Interest) {}
+            fn metadata(&self) -> &Metadata<'static> {
+                &TAG_META
+            }
+        }
+
+        let (send, recv) = crossbeam_channel::unbounded();
+        let profiler = Profiler {
+            channel: send,
+            field_mode: FieldMode::Full,
+            max_vars: None,
+            max_debug_len: None,
+            batcher: None,
+            capture_memory: false,
+            enter_rss: DashMap::new(),
+            callsite_tags: DashMap::new(),
+            failed_spans: DashSet::new()
+        };
+        let color_field = TAG_META.fields().field("tag.color").unwrap();
+        let kind_field = TAG_META.fields().field("tag.kind").unwrap();
+        let array = [
+            (&color_field, Some(&"red" as &dyn TracingValue)),
+            (&kind_field, Some(&"render" as &dyn TracingValue))
+        ];
+        let values = TAG_META.fields().value_set(&array);
+        profiler.span_create(&Id::from_u64(1 << 32), true, None,
+            &Attributes::new_root(&TAG_META, &values));
+        let mut saw_alloc = false;
+        while let Ok(cmd) = recv.try_recv() {
+            if let Command::SpanAlloc { tags, .. } = cmd.clone() {
+                //Both tags appear on the wire-bound allocation, stripped of the prefix.
+                let net = cmd.into_network();
+                if let crate::profiler::network_types::Command::SpanAlloc { tags: net_tags, .. } = net {
+                    assert_eq!(net_tags, tags);
+                }
+                assert_eq!(tags, vec![
+                    ("color".to_string(), "red".to_string()),
+                    ("kind".to_string(), "render".to_string())
+                ]);
+                saw_alloc = true;
+            }
+        }
+        assert!(saw_alloc);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn memory_capture_populates_the_exit_delta() {
+        use std::time::Duration as StdDuration;
+        use tracing_core::span::Id;
+        use crate::core::Tracer;
+        let (send, recv) = crossbeam_channel::unbounded();
+        let profiler = Profiler {
+            channel: send,
+            field_mode: FieldMode::Full,
+            max_vars: None,
+            max_debug_len: None,
+            batcher: None,
+            capture_memory: true,
+            enter_rss: DashMap::new(),
+            callsite_tags: DashMap::new(),
+            failed_spans: DashSet::new()
+        };
+        let id = Id::from_u64(1 << 32);
+        profiler.span_enter(&id);
+        profiler.span_exit(&id, StdDuration::from_millis(1));
+        let mut saw_exit = false;
+        while let Ok(cmd) = recv.try_recv() {
+            if let Command::SpanExit { memory_delta, .. } = cmd {
+                assert!(memory_delta.is_some());
+                saw_exit = true;
+            }
+        }
+        assert!(saw_exit);
+    }
+
+    #[test]
+    fn full_channel_never_blocks_the_sending_thread() {
+        //A tiny channel with NO consumer, as before a client connects: the sending side
+        // must return instead of stalling the traced thread.
+        let (send, recv) = crossbeam_channel::bounded(2);
+        let started = Instant::now();
+        for n in 0..50 {
+            send_nonblocking(&send, test_event(n));
+        }
+        assert!(started.elapsed() < Duration::from_secs(1));
+        //The first two made it into the channel; the rest are parked in order locally.
+        assert_eq!(event_times(recv.try_recv().unwrap()), vec![0]);
+        assert_eq!(event_times(recv.try_recv().unwrap()), vec![1]);
+        //Once the consumer drains, parked commands flush ahead of new ones, in order.
+        send_nonblocking(&send, test_event(50));
+        assert_eq!(event_times(recv.try_recv().unwrap()), vec![2]);
+        assert_eq!(event_times(recv.try_recv().unwrap()), vec![3]);
+    }
+
+    #[test]
+    fn batched_events_are_delivered_in_order() {
+        let (send, recv) = crossbeam_channel::unbounded();
+        let batcher = Batcher::new(3, Duration::from_secs(3600));
+        for n in 0..7 {
+            batcher.push(test_event(n), &send);
+        }
+        //7 events with a cap of 3: two full batches so far, one event still buffered.
+        let mut delivered: Vec<i64> = Vec::new();
+        while let Ok(cmd) = recv.try_recv() {
+            delivered.extend(event_times(cmd));
+        }
+        assert_eq!(delivered, vec![0, 1, 2, 3, 4, 5]);
+        //The rest flushes when the thread exits (TLS destructor).
+        std::thread::spawn(move || {
+            //Fresh thread, fresh TLS: its batch flushes on exit.
+            batcher.push(test_event(7), &send);
+        }).join().unwrap();
+        let mut tail: Vec<i64> = Vec::new();
+        while let Ok(cmd) = recv.try_recv() {
+            tail.extend(event_times(cmd));
+        }
+        assert_eq!(tail, vec![7]);
+    }
+
+    #[test]
+    fn read_only_clients_are_denied_mutating_messages() {
+        use crate::profiler::network_types::deserializer::ClientCommand;
+        let token = Some("staging-secret".to_string());
+        let mut controller = false;
+        //Every mutating message is refused with a typed denial...
+        assert!(matches!(
+            apply_client_command(ClientCommand::SetSessionName("x".into()), &mut controller, &token, true),
+            ClientAction::Deny("SetSessionName")));
+        assert!(matches!(
+            apply_client_command(ClientCommand::PauseRecording { paused: true }, &mut controller, &token, true),
+            ClientAction::Deny("PauseRecording")));
+        //...while read-only queries pass.
+        assert!(matches!(
+            apply_client_command(ClientCommand::QueryOpenSpans, &mut controller, &token, true),
+            ClientAction::Forward(Command::QueryOpenSpans)));
+        //A wrong token leaves the connection read-only; the right one upgrades it.
+        apply_client_command(ClientCommand::Authenticate { token: "guess".into() },
+            &mut controller, &token, true);
+        assert!(!controller);
+        apply_client_command(ClientCommand::Authenticate { token: "staging-secret".into() },
+            &mut controller, &token, true);
+        assert!(controller);
+        //And the privileged client's mutating message now succeeds.
+        assert!(matches!(
+            apply_client_command(ClientCommand::SetSessionName("run".into()), &mut controller, &token, true),
+            ClientAction::Forward(Command::SessionName(_))));
+    }
+
+    #[test]
+    fn silent_clients_are_dropped_and_accepting_resumes() {
+        use crate::profiler::network_types::{Hello, HELLO_PACKET};
+        use crate::profiler::network_types::protocol::HANDSHAKE_SIZE;
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        //First client connects and never answers; second completes the handshake.
+        let silent = TcpStream::connect(addr).unwrap();
+        let handshaker = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            let mut client = TcpStream::connect(addr).unwrap();
+            let mut block = [0; HANDSHAKE_SIZE];
+            client.read_exact(&mut block).unwrap();
+            let server_hello = Hello::from_bytes(block);
+            assert_eq!(server_hello.major(), HELLO_PACKET.major());
+            client.write_all(&HELLO_PACKET.to_bytes()).unwrap();
+            //Keep the socket alive until the server accepted it.
+            std::thread::sleep(Duration::from_millis(200));
+        });
+        let start = std::time::Instant::now();
+        let accepted = accept_client(&listener, Duration::from_millis(100)).unwrap();
+        //The silent client timed out and the profiler recovered onto the good one.
+        assert!(start.elapsed() < Duration::from_secs(3));
+        assert!(accepted.peer_addr().is_ok());
+        drop(silent);
+        handshaker.join().unwrap();
+    }
+
+    #[test]
+    fn pre_installed_logger_warns_instead_of_crashing() {
+        assert_eq!(pump_install_notice(false, false), None);
+        let host = pump_install_notice(true, false).unwrap();
+        assert!(host.contains("log_bridge"));
+        let bp3d = pump_install_notice(true, true).unwrap();
+        assert!(bp3d.contains("keep flowing"));
+        //And the bridge the host can register forwards through the same pump.
+        let bridge = crate::log_bridge();
+        let record = log::Record::builder().target("bridge_test").level(log::Level::Info).build();
+        //Callable without panicking whatever the session state.
+        let _ = log::Log::enabled(bridge, record.metadata());
+        log::Log::flush(bridge);
+    }
+
+    #[test]
+    fn pause_gates_per_run_data_only() {
+        assert!(Profiler::gated_while_paused(&Command::SpanEnter(1 << 32)));
+        assert!(Profiler::gated_while_paused(&Command::SpanExit {
+            span: 1 << 32,
+            duration: Duration::from_millis(1),
+            failed: false,
+            memory_delta: None
+        }));
+        assert!(!Profiler::gated_while_paused(&Command::SpanFree {
+            span: 1 << 32,
+            reason: NetCloseReason::Normal
+        }));
+        assert!(!Profiler::gated_while_paused(&Command::Terminate));
+    }
+
+    #[test]
+    fn conflicting_tag_values_are_detected() {
+        let stored = vec![
+            ("color".to_string(), "red".to_string()),
+            ("kind".to_string(), "render".to_string())
+        ];
+        //Same values: fine. New tag name: fine. Changed value: conflict.
+        assert_eq!(conflicting_tag(&stored, &stored), None);
+        assert_eq!(conflicting_tag(&stored, &[("depth".into(), "3".into())]), None);
+        assert_eq!(conflicting_tag(&stored, &[("color".into(), "blue".into())]), Some("color"));
+    }
+
+    #[test]
+    fn subsequent_runs_omit_tags_from_per_run_payloads() {
+        use tracing_core::{Callsite, Kind, Level, Metadata};
+        use tracing_core::field::Value as TracingValue;
+        use tracing_core::metadata;
+        use tracing_core::span::{Attributes, Id};
+        use tracing_core::subscriber::Interest;
+        use crate::core::Tracer;
+
+        struct OmitCallsite(#[allow(dead_code)] u8);
+        static OMIT_CALLSITE: OmitCallsite = OmitCallsite(0);
+        static OMIT_META: Metadata<'static> = metadata! {
+            name: "omit_span",
+            target: module_path!(),
+            level: Level::INFO,
+            fields: &["tag.kind"],
+            callsite: &OMIT_CALLSITE,
+            kind: Kind::SPAN
+        };
+        impl Callsite for OmitCallsite {
+            fn set_interest(&self, _: Interest) {}
+            fn metadata(&self) -> &Metadata<'static> {
+                &OMIT_META
+            }
+        }
+
+        let (send, recv) = crossbeam_channel::unbounded();
+        let profiler = Profiler {
+            channel: send,
+            field_mode: FieldMode::Full,
+            max_vars: None,
+            max_debug_len: None,
+            batcher: None,
+            capture_memory: false,
+            enter_rss: DashMap::new(),
+            callsite_tags: DashMap::new(),
+            failed_spans: DashSet::new()
+        };
+        let kind_field = OMIT_META.fields().field("tag.kind").unwrap();
+        let array = [(&kind_field, Some(&"render" as &dyn TracingValue))];
+        let values = OMIT_META.fields().value_set(&array);
+        let attrs = Attributes::new_root(&OMIT_META, &values);
+        profiler.span_create(&Id::from_u64(9 << 32), true, None, &attrs);
+        //A second instance of the same callsite: new=false, no alloc, and its SpanInit
+        // value set stays free of tag fields.
+        profiler.span_create(&Id::from_u64((9 << 32) | 1), false, None, &attrs);
+        let mut allocs = 0;
+        while let Ok(cmd) = recv.try_recv() {
+            match cmd {
+                Command::SpanAlloc { .. } => allocs += 1,
+                Command::SpanInit { value_set, .. } => assert!(value_set.is_empty()),
+                _ => {}
+            }
+        }
+        assert_eq!(allocs, 1);
+    }
+
+    #[test]
+    fn preset_only_session_flows_through_clamping() {
+        use crate::config::ProfilerPreset;
+        let preset = ProfilerPreset {
+            max_average_points: None,
+            record_enable: Some(true),
+            record_max_rows: Some(5_000_000), //Above the server limit.
+            period_ms: Some(1), //Below the server minimum.
+            max_level: None
+        };
+        let mut notices = Vec::new();
+        let effective = effective_client_config(&preset, None, &mut notices);
+        assert!(effective.record_enable);
+        assert_eq!(effective.record_max_rows, MAX_RECORD_ROWS);
+        assert_eq!(effective.period_ms, MIN_PERIOD_MS);
+        assert_eq!(effective.max_level, tracing_core::Level::INFO); //Default filled in.
+        assert_eq!(notices.len(), 2);
+        assert!(notices[0].contains("record.max_rows"));
+        assert!(notices[1].contains("min_period"));
+    }
+
+    #[test]
+    fn client_values_override_the_preset_field_by_field() {
+        use crate::config::ProfilerPreset;
+        let preset = ProfilerPreset {
+            max_average_points: Some(256),
+            record_enable: Some(true),
+            record_max_rows: Some(1000),
+            period_ms: Some(100),
+            max_level: None
+        };
+        let client = ProfilerPreset {
+            max_average_points: None, //Keep the preset's value.
+            record_enable: Some(false),
+            record_max_rows: Some(2000),
+            period_ms: None,
+            max_level: Some(tracing_core::Level::TRACE)
+        };
+        let mut notices = Vec::new();
+        let effective = effective_client_config(&preset, Some(&client), &mut notices);
+        assert_eq!(effective.max_average_points, 256);
+        assert!(!effective.record_enable);
+        assert_eq!(effective.record_max_rows, 2000);
+        assert_eq!(effective.period_ms, 100);
+        assert_eq!(effective.max_level, tracing_core::Level::TRACE);
+        assert!(notices.is_empty());
+    }
+
+    #[test]
+    fn below_minimum_is_clamped_up() {
+        let mut notices = Vec::new();
+        assert_eq!(clamp_config(Some(1), &mut notices), Some(MIN_CHANNEL_CAPACITY));
+        assert_eq!(notices, vec![
+            "The requested channel_capacity (1) was clamped to 16 by the server setting \
+min_channel_capacity (16)".to_string()
+        ]);
+    }
+
+    #[test]
+    fn above_maximum_is_clamped_down() {
+        let mut notices = Vec::new();
+        assert_eq!(clamp_config(Some(1_000_000), &mut notices), Some(MAX_CHANNEL_CAPACITY));
+        assert_eq!(notices.len(), 1);
+        assert_eq!(notices[0], "The requested channel_capacity (1000000) was clamped to \
+65536 by the server setting max_channel_capacity (65536)");
+    }
+
+    #[test]
+    fn in_range_produces_no_notice() {
+        let mut notices = Vec::new();
+        assert_eq!(clamp_config(Some(512), &mut notices), Some(512));
+        assert!(notices.is_empty());
+        assert_eq!(clamp_config(None, &mut notices), None);
+        assert!(notices.is_empty());
+    }
+}
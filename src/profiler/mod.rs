@@ -0,0 +1,62 @@
+// Copyright (c) 2022, BlockProject 3D
+//
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification,
+// are permitted provided that the following conditions are met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//     * Redistributions in binary form must reproduce the above copyright notice,
+//       this list of conditions and the following disclaimer in the documentation
+//       and/or other materials provided with the distribution.
+//     * Neither the name of BlockProject 3D nor the names of its contributors
+//       may be used to endorse or promote products derived from this software
+//       without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS
+// "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT
+// LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR
+// A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT OWNER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+#[allow(dead_code)] //The summary/dump writers land on artifact_file next.
+pub(crate) mod artifacts;
+pub(crate) mod thread;
+pub(crate) mod network_types;
+mod core;
+pub(crate) mod visitor;
+pub(crate) mod logpump;
+pub(crate) mod state;
+mod auto_discover;
+
+pub const DEFAULT_PORT: u16 = 4026;
+pub const PROTOCOL_VERSION: u8 = 0;
+
+pub use self::core::Profiler;
+
+#[cfg(feature = "tokio")]
+static RUNTIME: once_cell::sync::OnceCell<tokio::runtime::Handle> = once_cell::sync::OnceCell::new();
+
+/// Stores the runtime handle the profiler writer should run on; effective when set before
+/// initialization.
+#[cfg(feature = "tokio")]
+pub(crate) fn set_runtime_handle(handle: tokio::runtime::Handle) {
+    let _ = RUNTIME.set(handle);
+}
+
+#[cfg(feature = "tokio")]
+pub(crate) fn runtime_handle() -> Option<&'static tokio::runtime::Handle> {
+    RUNTIME.get()
+}
+
+#[cfg(not(feature = "tokio"))]
+pub(crate) fn runtime_handle() -> Option<std::convert::Infallible> {
+    None
+}
@@ -0,0 +1,618 @@
+// Copyright (c) 2022, BlockProject 3D
+//
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification,
+// are permitted provided that the following conditions are met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//     * Redistributions in binary form must reproduce the above copyright notice,
+//       this list of conditions and the following disclaimer in the documentation
+//       and/or other materials provided with the distribution.
+//     * Neither the name of BlockProject 3D nor the names of its contributors
+//       may be used to endorse or promote products derived from this software
+//       without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS
+// "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT
+// LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR
+// A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT OWNER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! This module contains the high-level command representation of the profiler protocol.
+//!
+//! Unlike the fixed-size `Hello` packet in [version](crate::profiler::network_types::version)
+//! which is hand-encoded so it can be exchanged before any serializer is negotiated, the
+//! commands declared here are dynamically-sized and travel as length-prefixed bincode frames
+//! once the handshake has succeeded. The layout of these commands is versioned by
+//! [SCHEMA_VERSION](SCHEMA_VERSION); any change to the shape of [Command](Command) or the
+//! types it embeds must bump that constant so clients can reject incompatible streams.
+
+use serde::{Serialize, Deserialize};
+use tracing_core::span::Id;
+use crate::profiler::network_types::{Duration, Metadata, Value};
+use crate::util::span_to_id_instance;
+
+/// The version of the serialized command schema.
+///
+/// This is carried alongside the protocol version of the `Hello` packet: the `Hello` packet
+/// versions the handshake itself while this constant versions the shape of the bincode-encoded
+/// [Command](Command) frames exchanged after the handshake.
+#[allow(dead_code)] //Not transmitted yet; clients currently rely on the Hello packet version.
+pub const SCHEMA_VERSION: u32 = 23;
+
+/// Flag bits for the header byte of [Command::Event](Command::Event).
+pub mod event_flags {
+    /// The time field contains an absolute unix timestamp. When unset the time field is a
+    /// delta in seconds relative to the previous event frame of the stream; the profiler
+    /// thread processes events in order so clients can reconstruct absolute values by
+    /// accumulating deltas since the last absolute frame.
+    pub const ABSOLUTE_TIME: u8 = 0x1;
+}
+
+/// Why a span instance closed; lets viewers separate cancellation from completion.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CloseReason {
+    /// The span ran to completion.
+    Normal,
+    /// The span was dropped while still entered (e.g. a cancelled future).
+    Dropped,
+    /// The span recorded an error during its lifetime.
+    Error
+}
+
+/// One span allocation; what SpanAlloc carries, batchable during warmup.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct SpanAllocData {
+    pub id: SpanId,
+    pub metadata: Metadata,
+    pub tags: Vec<(String, String)>
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SpanId {
+    id: u32,
+    instance: u32
+}
+
+impl SpanId {
+    pub fn from_u64(span: u64) -> SpanId {
+        let (id, instance) = span_to_id_instance(&Id::from_u64(span));
+        SpanId {
+            id,
+            //Lossless while SPAN_BITS_INSTANCE <= 32; wider instance halves truncate on
+            // the wire until the protocol grows wider ids (see util.rs migration note).
+            instance: instance as u32
+        }
+    }
+
+    /// The callsite part of the id, shared by every instance of the same span.
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+}
+
+impl Command {
+    /// The message type name, for diagnostics and per-type counters.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::SpanAlloc { .. } => "SpanAlloc",
+            Command::SpanAllocBatch { .. } => "SpanAllocBatch",
+            Command::SpanInit { .. } => "SpanInit",
+            Command::SpanFollows { .. } => "SpanFollows",
+            Command::SpanValues { .. } => "SpanValues",
+            Command::Event { .. } => "Event",
+            Command::SpanEnter(_) => "SpanEnter",
+            Command::SpanExit { .. } => "SpanExit",
+            Command::SpanFree { .. } => "SpanFree",
+            Command::Project { .. } => "Project",
+            Command::ProjectUpdate { .. } => "ProjectUpdate",
+            Command::PermissionDenied { .. } => "PermissionDenied",
+            Command::SessionName { .. } => "SessionName",
+            Command::IncompleteRuns { .. } => "IncompleteRuns",
+            Command::OpenSpansChunk { .. } => "OpenSpansChunk",
+            Command::LogFileChunk { .. } => "LogFileChunk",
+            Command::LogFileSummary { .. } => "LogFileSummary",
+            Command::Bandwidth { .. } => "Bandwidth",
+            Command::StreamSummary { .. } => "StreamSummary",
+            Command::SpanTree { .. } => "SpanTree",
+            Command::Terminate => "Terminate"
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub enum Command {
+    SpanAlloc {
+        id: SpanId,
+        metadata: Metadata,
+        /// Static annotations from the `tag.*` field convention, for viewer-side
+        /// filtering and grouping; sent once at allocation.
+        tags: Vec<(String, String)>
+    },
+
+    /// Several allocations coalesced within the warmup window into one frame; requires a
+    /// client speaking this schema version or later (version-gated via the Hello
+    /// exchange). Steady-state late discoveries still travel as single SpanAlloc.
+    SpanAllocBatch {
+        allocs: Vec<SpanAllocData>
+    },
+
+    SpanInit {
+        span: SpanId,
+        parent: Option<SpanId>, //None must mean that span is at root
+        message: Option<String>,
+        value_set: Vec<(String, Value)>
+    },
+
+    SpanFollows {
+        span: SpanId,
+        follows: SpanId
+    },
+
+    SpanValues {
+        span: SpanId,
+        message: Option<String>,
+        value_set: Vec<(String, Value)>
+    },
+
+    Event {
+        span: Option<SpanId>,
+        metadata: Metadata,
+        flags: u8, //See event_flags for the meaning of each bit.
+        time: i64,
+        message: Option<String>,
+        value_set: Vec<(String, Value)>
+    },
+
+    SpanEnter(SpanId),
+
+    SpanExit {
+        span: SpanId,
+        duration: Duration, //Exact seconds + nanos; see network_types::Duration.
+        failed: bool, //True when an error/error.message field was recorded during this run
+        /// The run completed across a detected processing stall (debugger pause); its
+        /// wall-clock duration is unreliable and should not enter normal statistics.
+        suspect: bool,
+        /// The RSS delta over this run in bytes, when memory capture is enabled.
+        memory_delta: Option<i64>
+    },
+
+    SpanFree {
+        span: SpanId,
+        reason: CloseReason
+    },
+
+    /// Application identity plus named info sections contributed by plugins (driver
+    /// versions, backend names, ...), sent once right after the handshake.
+    Project {
+        app_name: String,
+        sections: Vec<(String, String)>
+    },
+
+    /// Sections registered by plugins after the client already received Project.
+    ProjectUpdate {
+        sections: Vec<(String, String)>
+    },
+
+    /// A state-changing client message was refused because the connection is read-only
+    /// (no or wrong control token).
+    PermissionDenied {
+        msg_type: String
+    },
+
+    /// Echo of a client-set session name after sanitization, confirming what the
+    /// application will use in its local artifacts.
+    SessionName {
+        name: String
+    },
+
+    /// Spans still open when the session terminated: in-flight work that produced
+    /// neither a run nor an error. The top longest-open spans are named with their
+    /// elapsed-so-far durations.
+    IncompleteRuns {
+        count: u64,
+        top: Vec<(String, Duration)>
+    },
+
+    /// One chunk of the live open-spans snapshot; `last` terminates the list.
+    OpenSpansChunk {
+        seq: u32,
+        last: bool,
+        rows: Vec<crate::profiler::state::OpenSpanRow>
+    },
+
+    /// One bounded piece of a requested log file tail, interleaved with normal traffic.
+    LogFileChunk {
+        seq: u32,
+        last: bool,
+        data: Vec<u8>
+    },
+
+    /// Closes a log file transfer: how large the file is, how much was sent, and whether
+    /// the tail was truncated to the requested byte budget.
+    LogFileSummary {
+        total_size: u64,
+        sent: u64,
+        truncated: bool
+    },
+
+    /// Periodic bandwidth report: session totals plus the sending rate since the last
+    /// report, so users on metered or slow links can see what the profiler costs.
+    Bandwidth {
+        sent_total: u64,
+        recv_total: u64,
+        sent_rate: u64 //bytes per second
+    },
+
+    /// Integrity metadata over every frame payload sent so far, emitted once before
+    /// Terminate; a client can recount/rehash what it received and detect a truncated or
+    /// corrupted transfer.
+    StreamSummary {
+        /// The sanitized session name, when the client set one.
+        session_name: Option<String>,
+        /// Which local artifact directory was used, or why artifact writing was disabled.
+        artifacts: String,
+        /// What the handshake negotiated, when a session is connected.
+        protocol: Option<crate::profiler::network_types::ProtocolInfo>,
+        /// Total bytes this session wrote to the connection, framing included (the
+        /// summary and terminate frames themselves excluded).
+        sent_wire: u64,
+        /// Messages dropped because they could not be serialized or exceeded the frame
+        /// budget; each also produced a one-shot diagnostic.
+        serialize_drops: u64,
+        /// Total bytes received from the client so far.
+        received_wire: u64,
+        /// The targets that produced the most events this session, most frequent first.
+        top_targets: Vec<(String, u64)>,
+        /// The number of frames sent before this one.
+        frames: u64,
+        /// The total payload byte length of those frames (length prefixes excluded).
+        bytes: u64,
+        /// An IEEE CRC-32 over the concatenated frame payloads.
+        crc32: u32
+    },
+
+    /// The static callsite-level span tree discovered during the session, emitted once
+    /// before Terminate so the viewer can render the call graph even without timeline
+    /// data. Each edge is (child callsite id, parent callsite id).
+    SpanTree {
+        edges: Vec<(u32, u32)>
+    },
+
+    Terminate
+}
+
+#[cfg(test)]
+mod tests {
+    use bincode::Options;
+    use super::*;
+
+    fn test_metadata() -> Metadata {
+        let record = log::Record::builder()
+            .target("bp3d_tracing::tests")
+            .level(log::Level::Info)
+            .module_path(Some("bp3d_tracing::tests"))
+            .file(Some("command.rs"))
+            .line(Some(42))
+            .build();
+        Metadata::from_log(&record)
+    }
+
+    fn test_value_set() -> Vec<(String, Value)> {
+        vec![
+            ("float".into(), Value::Float(4.2)),
+            ("signed".into(), Value::Signed(-42)),
+            ("unsigned".into(), Value::Unsigned(42)),
+            ("string".into(), Value::String("test".into())),
+            ("bool".into(), Value::Bool(true))
+        ]
+    }
+
+    fn round_trip(cmd: Command) {
+        //Use the same varint encoding as the profiler thread.
+        let bytes = bincode::options().serialize(&cmd).expect("failed to serialize command");
+        let decoded: Command = bincode::options().deserialize(&bytes).expect("failed to deserialize command");
+        assert_eq!(cmd, decoded);
+    }
+
+    #[test]
+    fn round_trip_span_alloc() {
+        round_trip(Command::SpanAlloc {
+            id: SpanId::from_u64(1 << 32),
+            metadata: test_metadata(),
+            tags: vec![("color".into(), "red".into()), ("kind".into(), "render".into())]
+        });
+    }
+
+    #[test]
+    fn round_trip_span_alloc_batch() {
+        round_trip(Command::SpanAllocBatch {
+            allocs: vec![
+                SpanAllocData {
+                    id: SpanId::from_u64(1 << 32),
+                    metadata: test_metadata(),
+                    tags: Vec::new()
+                },
+                SpanAllocData {
+                    id: SpanId::from_u64(2 << 32),
+                    metadata: test_metadata(),
+                    tags: vec![("kind".into(), "render".into())]
+                }
+            ]
+        });
+    }
+
+    #[test]
+    fn round_trip_span_init() {
+        round_trip(Command::SpanInit {
+            span: SpanId::from_u64(1 << 32),
+            parent: None,
+            message: None,
+            value_set: Vec::new()
+        });
+        round_trip(Command::SpanInit {
+            span: SpanId::from_u64((1 << 32) | 1),
+            parent: Some(SpanId::from_u64(2 << 32)),
+            message: Some("test".into()),
+            value_set: test_value_set()
+        });
+    }
+
+    #[test]
+    fn round_trip_span_follows() {
+        round_trip(Command::SpanFollows {
+            span: SpanId::from_u64(1 << 32),
+            follows: SpanId::from_u64(2 << 32)
+        });
+    }
+
+    #[test]
+    fn round_trip_span_values() {
+        round_trip(Command::SpanValues {
+            span: SpanId::from_u64(1 << 32),
+            message: Some("test".into()),
+            value_set: test_value_set()
+        });
+    }
+
+    #[test]
+    fn round_trip_event() {
+        //Cover every combination of span presence and time flag.
+        for (span, flags, time) in [
+            (None, event_flags::ABSOLUTE_TIME, 1648768000),
+            (None, 0, 2),
+            (Some(SpanId::from_u64(1 << 32)), event_flags::ABSOLUTE_TIME, 1648768000),
+            (Some(SpanId::from_u64(1 << 32)), 0, -1)
+        ] {
+            round_trip(Command::Event {
+                span,
+                metadata: test_metadata(),
+                flags,
+                time,
+                message: Some("test".into()),
+                value_set: test_value_set()
+            });
+        }
+    }
+
+    #[test]
+    fn delta_encoding_shrinks_events() {
+        //A delta-encoded varint frame must beat the legacy fixed-width encoding for a
+        // typical short event.
+        let legacy = bincode::serialize(&Command::Event {
+            span: None,
+            metadata: test_metadata(),
+            flags: event_flags::ABSOLUTE_TIME,
+            time: 1648768000,
+            message: Some("short".into()),
+            value_set: Vec::new()
+        }).unwrap();
+        let delta = bincode::options().serialize(&Command::Event {
+            span: None,
+            metadata: test_metadata(),
+            flags: 0,
+            time: 1,
+            message: Some("short".into()),
+            value_set: Vec::new()
+        }).unwrap();
+        assert!(delta.len() < legacy.len());
+    }
+
+    #[test]
+    fn round_trip_span_enter() {
+        round_trip(Command::SpanEnter(SpanId::from_u64(1 << 32)));
+    }
+
+    #[test]
+    fn round_trip_span_exit() {
+        round_trip(Command::SpanExit {
+            span: SpanId::from_u64(1 << 32),
+            duration: std::time::Duration::new(2, 345_678_910).into(),
+            failed: true,
+            suspect: false,
+            memory_delta: Some(-4096)
+        });
+    }
+
+    #[test]
+    fn round_trip_span_free() {
+        for reason in [CloseReason::Normal, CloseReason::Dropped, CloseReason::Error] {
+            round_trip(Command::SpanFree {
+                span: SpanId::from_u64(1 << 32),
+                reason
+            });
+        }
+    }
+
+    #[test]
+    fn comma_containing_field_decodes() {
+        //Separator characters inside user data must not break decoding: values are typed
+        // and length-prefixed, never joined with delimiters.
+        let bytes = bincode::options().serialize(&Command::Event {
+            span: None,
+            metadata: test_metadata(),
+            flags: event_flags::ABSOLUTE_TIME,
+            time: 1648768000,
+            message: Some("a, tricky { message }".into()),
+            value_set: vec![
+                ("list".into(), Value::String("a,b,c".into())),
+                ("brace".into(), Value::String("x: { y: 1, z: 2 }".into()))
+            ]
+        }).unwrap();
+        let decoded: Command = bincode::options().deserialize(&bytes).unwrap();
+        match decoded {
+            Command::Event { message, value_set, .. } => {
+                assert_eq!(message.as_deref(), Some("a, tricky { message }"));
+                assert_eq!(value_set[0].1, Value::String("a,b,c".into()));
+                assert_eq!(value_set[1].1, Value::String("x: { y: 1, z: 2 }".into()));
+            },
+            _ => panic!("decoded frame is not an event")
+        }
+    }
+
+    #[test]
+    fn round_trip_project() {
+        round_trip(Command::Project {
+            app_name: "my_engine".into(),
+            sections: vec![("renderer".into(), "vulkan 1.3".into())]
+        });
+        round_trip(Command::ProjectUpdate {
+            sections: vec![("physics".into(), "px 5".into())]
+        });
+    }
+
+    #[test]
+    fn round_trip_permission_denied() {
+        round_trip(Command::PermissionDenied {
+            msg_type: "SetSessionName".into()
+        });
+    }
+
+    #[test]
+    fn round_trip_session_name() {
+        round_trip(Command::SessionName {
+            name: "run-4-after-fix".into()
+        });
+    }
+
+    #[test]
+    fn round_trip_open_spans_chunk() {
+        round_trip(Command::OpenSpansChunk {
+            seq: 0,
+            last: true,
+            rows: vec![crate::profiler::state::OpenSpanRow {
+                callsite: 1,
+                instance: 2,
+                thread: 3,
+                elapsed: std::time::Duration::from_secs(4).into(),
+                parent: Some(1 << 32)
+            }]
+        });
+    }
+
+    #[test]
+    fn round_trip_log_file_transfer() {
+        round_trip(Command::LogFileChunk {
+            seq: 3,
+            last: true,
+            data: vec![1, 2, 3]
+        });
+        round_trip(Command::LogFileSummary {
+            total_size: 1_000_000,
+            sent: 4096,
+            truncated: true
+        });
+    }
+
+    #[test]
+    fn round_trip_incomplete_runs() {
+        round_trip(Command::IncompleteRuns {
+            count: 7,
+            top: vec![("request".into(), std::time::Duration::from_secs(30).into())]
+        });
+    }
+
+    #[test]
+    fn round_trip_bandwidth() {
+        round_trip(Command::Bandwidth {
+            sent_total: 123456,
+            recv_total: 42,
+            sent_rate: 2048
+        });
+    }
+
+    #[test]
+    fn round_trip_stream_summary() {
+        round_trip(Command::StreamSummary {
+            session_name: Some("run-4-after-fix".into()),
+            artifacts: "artifacts in /tmp/x (temp fallback)".into(),
+            protocol: Some(crate::profiler::network_types::ProtocolInfo {
+                server_major: 1,
+                server_pre_release: Some("alpha-2.0.0".into()),
+                client_major: 1,
+                client_pre_release: Some("alpha-2.0.0".into()),
+                features: 0
+            }),
+            sent_wire: 8192,
+            serialize_drops: 1,
+            received_wire: 64,
+            top_targets: vec![("noisy_module".into(), 420), ("quiet_module".into(), 1)],
+            frames: 42,
+            bytes: 4096,
+            crc32: 0xDEADBEEF
+        });
+    }
+
+    #[test]
+    fn round_trip_span_tree() {
+        round_trip(Command::SpanTree {
+            edges: vec![(2, 1), (3, 2)]
+        });
+    }
+
+    #[test]
+    fn huge_durations_do_not_wrap() {
+        //Far beyond what u32 seconds could hold (a never-exited span's elapsed value);
+        // the u64 representation carries it unchanged instead of wrapping.
+        let huge = std::time::Duration::new(u32::MAX as u64 + 12_345, 999_999_999);
+        let wire: Duration = huge.into();
+        assert_eq!(wire.seconds, u32::MAX as u64 + 12_345);
+        let back: std::time::Duration = wire.into();
+        assert_eq!(back, huge);
+        round_trip(Command::SpanExit {
+            span: SpanId::from_u64(1 << 32),
+            duration: wire,
+            failed: false,
+            suspect: false,
+            memory_delta: None
+        });
+    }
+
+    #[test]
+    fn wire_durations_reconstruct_exactly() {
+        //Every nanosecond survives: no float rounding, no remainder arithmetic.
+        for original in [
+            std::time::Duration::new(0, 1),
+            std::time::Duration::new(0, 999_999_999),
+            std::time::Duration::new(123, 456_789_012),
+            std::time::Duration::new(u64::MAX, 0)
+        ] {
+            let wire: Duration = original.into();
+            let back: std::time::Duration = wire.into();
+            assert_eq!(back, original);
+        }
+    }
+
+    #[test]
+    fn round_trip_terminate() {
+        round_trip(Command::Terminate);
+    }
+}
@@ -0,0 +1,340 @@
+// Copyright (c) 2022, BlockProject 3D
+//
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification,
+// are permitted provided that the following conditions are met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//     * Redistributions in binary form must reproduce the above copyright notice,
+//       this list of conditions and the following disclaimer in the documentation
+//       and/or other materials provided with the distribution.
+//     * Neither the name of BlockProject 3D nor the names of its contributors
+//       may be used to endorse or promote products derived from this software
+//       without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS
+// "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT
+// LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR
+// A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT OWNER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Safe decoding primitives for client-supplied data.
+//!
+//! Client→server messages (span search patterns, session names, ...) carry strings whose
+//! length prefix cannot be trusted: it must never be allowed to read past the received
+//! buffer or trigger an unbounded allocation. Strings are encoded with a u16 length prefix
+//! followed by that many bytes of UTF-8; [read_str](read_str) enforces a caller-configured
+//! maximum before anything is allocated and validates the bytes, never panicking on
+//! malformed input. [write_str](write_str) mirrors the encoding so both directions agree.
+
+use byteorder::{ByteOrder, LittleEndian};
+
+/// The default maximum accepted length of a client-supplied string, in bytes.
+pub const DEFAULT_MAX_STRING_LEN: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The length prefix points past the end of the received buffer.
+    Truncated {
+        expected: usize,
+        remaining: usize
+    },
+    /// The length prefix exceeds the configured maximum accepted length.
+    TooLarge {
+        length: usize,
+        max: usize
+    },
+    /// The string bytes are not valid UTF-8.
+    InvalidUtf8
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::Truncated { expected, remaining } =>
+                write!(f, "string length prefix ({}) exceeds remaining buffer ({})", expected, remaining),
+            Error::TooLarge { length, max } =>
+                write!(f, "string length ({}) exceeds the maximum accepted length ({})", length, max),
+            Error::InvalidUtf8 => f.write_str("string bytes are not valid UTF-8")
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Reads a length-prefixed string from the front of `buf`, returning the string and the
+/// remaining bytes. The length is checked against `max_len` before any validation and
+/// against the remaining buffer before any access; embedded NUL bytes are ordinary UTF-8
+/// and pass through unchanged.
+pub fn read_str(buf: &[u8], max_len: usize) -> Result<(&str, &[u8]), Error> {
+    if buf.len() < 2 {
+        return Err(Error::Truncated {
+            expected: 2,
+            remaining: buf.len()
+        });
+    }
+    let len = LittleEndian::read_u16(buf) as usize;
+    if len > max_len {
+        return Err(Error::TooLarge {
+            length: len,
+            max: max_len
+        });
+    }
+    let remaining = &buf[2..];
+    if len > remaining.len() {
+        return Err(Error::Truncated {
+            expected: len,
+            remaining: remaining.len()
+        });
+    }
+    let string = std::str::from_utf8(&remaining[..len]).map_err(|_| Error::InvalidUtf8)?;
+    Ok((string, &remaining[len..]))
+}
+
+/// Appends a u16 length-prefixed string to `buf`; the mirror of [read_str](read_str).
+/// Fails with [Error::TooLarge](Error::TooLarge) when the string does not fit the u16
+/// prefix or exceeds `max_len`.
+pub fn write_str(buf: &mut Vec<u8>, string: &str, max_len: usize) -> Result<(), Error> {
+    let bytes = string.as_bytes();
+    let max = std::cmp::min(max_len, u16::MAX as usize);
+    if bytes.len() > max {
+        return Err(Error::TooLarge {
+            length: bytes.len(),
+            max
+        });
+    }
+    let mut prefix = [0; 2];
+    LittleEndian::write_u16(&mut prefix, bytes.len() as u16);
+    buf.extend_from_slice(&prefix);
+    buf.extend_from_slice(bytes);
+    Ok(())
+}
+
+/// The maximum accepted length of a client-supplied session name, in bytes.
+pub const MAX_SESSION_NAME_LEN: usize = 256;
+
+/// A message sent by the client to the application.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientCommand {
+    /// Names the profiling session; the application reflects the (sanitized) name in its
+    /// summary and local artifacts.
+    SetSessionName(String),
+    /// Asks for the tail of the application's current log file, at most this many bytes.
+    RequestLogFile {
+        max_bytes: u32
+    },
+    /// Pauses or resumes recording while the viewer (or the target, at a breakpoint) is
+    /// paused; a lightweight toggle that flushes nothing.
+    PauseRecording {
+        paused: bool
+    },
+    /// Asks for a snapshot of the spans open right now with their elapsed times.
+    QueryOpenSpans,
+    /// Upgrades the connection from read-only to controlling when the token matches the
+    /// configured profiler.control_token.
+    Authenticate {
+        token: String
+    },
+    /// The viewer acknowledges having received and persisted the final data of the
+    /// session; unblocks Guard::drain_and_wait.
+    DatasetAck
+}
+
+const TAG_SET_SESSION_NAME: u8 = 0;
+const TAG_REQUEST_LOG_FILE: u8 = 1;
+const TAG_PAUSE_RECORDING: u8 = 2;
+const TAG_QUERY_OPEN_SPANS: u8 = 3;
+const TAG_AUTHENTICATE: u8 = 4;
+const TAG_DATASET_ACK: u8 = 5;
+
+/// Decodes one client frame: a tag byte followed by a tag-specific payload, every string
+/// going through the bounded [read_str](read_str) path.
+pub fn parse_client_frame(buf: &[u8]) -> Result<ClientCommand, Error> {
+    match buf.first() {
+        Some(&TAG_SET_SESSION_NAME) => {
+            let (name, _) = read_str(&buf[1..], MAX_SESSION_NAME_LEN)?;
+            Ok(ClientCommand::SetSessionName(name.into()))
+        },
+        Some(&TAG_REQUEST_LOG_FILE) if buf.len() >= 5 => {
+            Ok(ClientCommand::RequestLogFile {
+                max_bytes: LittleEndian::read_u32(&buf[1..5])
+            })
+        },
+        Some(&TAG_PAUSE_RECORDING) if buf.len() >= 2 => {
+            Ok(ClientCommand::PauseRecording {
+                paused: buf[1] != 0
+            })
+        },
+        Some(&TAG_QUERY_OPEN_SPANS) => Ok(ClientCommand::QueryOpenSpans),
+        Some(&TAG_AUTHENTICATE) => {
+            let (token, _) = read_str(&buf[1..], MAX_SESSION_NAME_LEN)?;
+            Ok(ClientCommand::Authenticate {
+                token: token.into()
+            })
+        },
+        Some(&TAG_DATASET_ACK) => Ok(ClientCommand::DatasetAck),
+        _ => Err(Error::InvalidUtf8) //No better variant yet; unknown tags are rejected.
+    }
+}
+
+/// Constant-time string equality for token comparison: every byte (and the length
+/// difference) folds into the accumulator, so timing reveals nothing about where a
+/// mismatch occurred.
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+    let a = a.as_bytes();
+    let b = b.as_bytes();
+    let mut acc = a.len() ^ b.len();
+    for i in 0..a.len().max(b.len()) {
+        let x = a.get(i).copied().unwrap_or(0);
+        let y = b.get(i).copied().unwrap_or(0);
+        acc |= (x ^ y) as usize;
+    }
+    acc == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(string: &str) -> Vec<u8> {
+        let mut buf = Vec::new();
+        write_str(&mut buf, string, DEFAULT_MAX_STRING_LEN).unwrap();
+        buf
+    }
+
+    #[test]
+    fn round_trip() {
+        let buf = encode("session name");
+        let (string, rest) = read_str(&buf, DEFAULT_MAX_STRING_LEN).unwrap();
+        assert_eq!(string, "session name");
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn trailing_bytes_are_returned() {
+        let mut buf = encode("a");
+        buf.extend_from_slice(&[1, 2, 3]);
+        let (string, rest) = read_str(&buf, DEFAULT_MAX_STRING_LEN).unwrap();
+        assert_eq!(string, "a");
+        assert_eq!(rest, &[1, 2, 3]);
+    }
+
+    #[test]
+    fn length_larger_than_buffer() {
+        //Prefix claims 500 bytes but only 3 follow.
+        let buf = [244, 1, b'a', b'b', b'c'];
+        assert_eq!(read_str(&buf, DEFAULT_MAX_STRING_LEN), Err(Error::Truncated {
+            expected: 500,
+            remaining: 3
+        }));
+    }
+
+    #[test]
+    fn length_larger_than_max() {
+        let mut buf = vec![16, 0];
+        buf.extend_from_slice(&[b'x'; 16]);
+        //The max check fires before the buffer is touched, so an adversarial prefix can
+        // never cause a large allocation.
+        assert_eq!(read_str(&buf, 8), Err(Error::TooLarge {
+            length: 16,
+            max: 8
+        }));
+    }
+
+    #[test]
+    fn invalid_utf8() {
+        let buf = [2, 0, 0xFF, 0xFE];
+        assert_eq!(read_str(&buf, DEFAULT_MAX_STRING_LEN), Err(Error::InvalidUtf8));
+    }
+
+    #[test]
+    fn embedded_nuls_pass_through() {
+        let buf = encode("a\0b");
+        let (string, _) = read_str(&buf, DEFAULT_MAX_STRING_LEN).unwrap();
+        assert_eq!(string, "a\0b");
+    }
+
+    #[test]
+    fn empty_buffer_is_truncated() {
+        assert_eq!(read_str(&[], DEFAULT_MAX_STRING_LEN), Err(Error::Truncated {
+            expected: 2,
+            remaining: 0
+        }));
+    }
+
+    #[test]
+    fn parse_set_session_name() {
+        let mut buf = vec![0u8];
+        write_str(&mut buf, "run-4-after-fix", MAX_SESSION_NAME_LEN).unwrap();
+        assert_eq!(parse_client_frame(&buf), Ok(ClientCommand::SetSessionName("run-4-after-fix".into())));
+    }
+
+    #[test]
+    fn parse_request_log_file() {
+        let mut buf = vec![1u8];
+        buf.extend_from_slice(&[0, 16, 0, 0]); //4096 LE
+        assert_eq!(parse_client_frame(&buf), Ok(ClientCommand::RequestLogFile {
+            max_bytes: 4096
+        }));
+        //A truncated request is rejected, not sliced out of bounds.
+        assert!(parse_client_frame(&[1u8, 0, 16]).is_err());
+    }
+
+    #[test]
+    fn parse_pause_recording() {
+        assert_eq!(parse_client_frame(&[2u8, 1]), Ok(ClientCommand::PauseRecording { paused: true }));
+        assert_eq!(parse_client_frame(&[2u8, 0]), Ok(ClientCommand::PauseRecording { paused: false }));
+        assert!(parse_client_frame(&[2u8]).is_err());
+    }
+
+    #[test]
+    fn constant_time_eq_compares_correctly() {
+        assert!(constant_time_eq("secret", "secret"));
+        assert!(!constant_time_eq("secret", "secres"));
+        assert!(!constant_time_eq("secret", "secret2"));
+        assert!(!constant_time_eq("", "x"));
+        assert!(constant_time_eq("", ""));
+    }
+
+    #[test]
+    fn parse_authenticate() {
+        let mut buf = vec![4u8];
+        write_str(&mut buf, "staging-secret", MAX_SESSION_NAME_LEN).unwrap();
+        assert_eq!(parse_client_frame(&buf), Ok(ClientCommand::Authenticate {
+            token: "staging-secret".into()
+        }));
+    }
+
+    #[test]
+    fn parse_dataset_ack() {
+        assert_eq!(parse_client_frame(&[5u8]), Ok(ClientCommand::DatasetAck));
+    }
+
+    #[test]
+    fn parse_query_open_spans() {
+        assert_eq!(parse_client_frame(&[3u8]), Ok(ClientCommand::QueryOpenSpans));
+    }
+
+    #[test]
+    fn oversized_session_name_is_rejected() {
+        let mut buf = vec![0u8];
+        write_str(&mut buf, &"x".repeat(300), DEFAULT_MAX_STRING_LEN).unwrap();
+        assert!(matches!(parse_client_frame(&buf), Err(Error::TooLarge { .. })));
+    }
+
+    #[test]
+    fn write_rejects_oversized() {
+        let mut buf = Vec::new();
+        let huge = "x".repeat(70000);
+        assert!(matches!(write_str(&mut buf, &huge, usize::MAX), Err(Error::TooLarge { .. })));
+        assert!(buf.is_empty());
+    }
+}
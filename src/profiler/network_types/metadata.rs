@@ -0,0 +1,203 @@
+// Copyright (c) 2022, BlockProject 3D
+//
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification,
+// are permitted provided that the following conditions are met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//     * Redistributions in binary form must reproduce the above copyright notice,
+//       this list of conditions and the following disclaimer in the documentation
+//       and/or other materials provided with the distribution.
+//     * Neither the name of BlockProject 3D nor the names of its contributors
+//       may be used to endorse or promote products derived from this software
+//       without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS
+// "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT
+// LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR
+// A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT OWNER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, PartialEq, Clone, Copy, Eq, Serialize, Deserialize)]
+pub enum Level {
+    Trace,
+    Debug,
+    Info,
+    Warning,
+    Error
+}
+
+impl Level {
+    pub fn from_tracing(level: tracing::Level) -> Level {
+        match level {
+            tracing::Level::TRACE => Level::Trace,
+            tracing::Level::DEBUG => Level::Debug,
+            tracing::Level::INFO => Level::Info,
+            tracing::Level::WARN => Level::Warning,
+            tracing::Level::ERROR => Level::Error
+        }
+    }
+    pub fn from_log(level: log::Level) -> Level {
+        match level {
+            log::Level::Trace => Level::Trace,
+            log::Level::Debug => Level::Debug,
+            log::Level::Info => Level::Info,
+            log::Level::Warn => Level::Warning,
+            log::Level::Error => Level::Error
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct Metadata {
+    name: String, //The name of the span/event
+    target: String, //The target of the span/event (usually this contains module path)
+    level: Level, //The log level of the span/event
+    module_path: Option<String>, //The module path (including crate name)
+    file: Option<String>, //The file path
+    line: Option<u32>, //The line number in the file
+    is_task: bool //True for spans emitted by the async runtime's task instrumentation
+}
+
+impl Metadata {
+    /// The name of the span/event.
+    #[allow(dead_code)] //Only consumed by the chrome-trace exporter.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    #[cfg(test)]
+    #[allow(dead_code)] //Only consumed by the chrome-trace exporter's tests.
+    pub fn set_name(&mut self, name: String) {
+        self.name = name;
+    }
+
+    /// Strips location information according to the configured mode. The wire layout is
+    /// self-describing (absent parts are None/empty), so no separate flags bit is needed
+    /// for the consumer to know which variant is present.
+    pub fn strip_location(&mut self, mode: crate::config::LocationMode) {
+        use crate::config::LocationMode;
+        match mode {
+            LocationMode::Full => {},
+            LocationMode::TargetOnly => {
+                self.module_path = None;
+                self.file = None;
+                self.line = None;
+            },
+            LocationMode::None => {
+                self.module_path = None;
+                self.file = None;
+                self.line = None;
+                self.target.clear();
+            }
+        }
+    }
+
+    pub fn from_log(meta: &log::Record) -> Metadata {
+        Metadata {
+            name: "<log>".into(),
+            target: meta.target().into(),
+            level: Level::from_log(meta.level()),
+            module_path: meta.module_path().map(|v| v.into()),
+            file: meta.file().map(|v| v.into()),
+            line: meta.line(),
+            is_task: false
+        }
+    }
+    pub fn from_tracing(meta: &tracing::Metadata) -> Metadata {
+        Metadata {
+            name: meta.name().into(),
+            target: meta.target().into(),
+            level: Level::from_tracing(*meta.level()),
+            module_path: meta.module_path().map(|v| v.into()),
+            file: meta.file().map(|v| v.into()),
+            line: meta.line(),
+            is_task: Self::task_target(meta.target())
+        }
+    }
+
+    /// Whether spans from this metadata belong to the async runtime's task
+    /// instrumentation; poll timings of such spans flow through the regular
+    /// enter/exit duration path but viewers can group them separately.
+    #[allow(dead_code)] //Read by clients from the wire; in-tree use is feature-gated.
+    pub fn is_task(&self) -> bool {
+        self.is_task
+    }
+
+    #[cfg(feature = "tokio-console")]
+    fn task_target(target: &str) -> bool {
+        crate::util::is_tokio_task_target(target)
+    }
+
+    #[cfg(not(feature = "tokio-console"))]
+    fn task_target(_: &str) -> bool {
+        false
+    }
+}
+
+#[cfg(all(test, feature = "tokio-console"))]
+mod tests {
+    use tracing_core::{Callsite, Kind, Metadata as TracingMetadata};
+    use tracing_core::metadata;
+    use tracing_core::subscriber::Interest;
+
+    struct TaskCallsite(#[allow(dead_code)] u8);
+    static TASK_CALLSITE: TaskCallsite = TaskCallsite(0);
+    static TASK_META: TracingMetadata<'static> = metadata! {
+        name: "runtime.spawn",
+        target: "tokio::task",
+        level: tracing_core::Level::TRACE,
+        fields: &[],
+        callsite: &TASK_CALLSITE,
+        kind: Kind::SPAN
+    };
+
+    struct UserCallsite(#[allow(dead_code)] u8);
+    static USER_CALLSITE: UserCallsite = UserCallsite(0);
+    static USER_META: TracingMetadata<'static> = metadata! {
+        name: "compute",
+        target: module_path!(),
+        level: tracing_core::Level::INFO,
+        fields: &[],
+        callsite: &USER_CALLSITE,
+        kind: Kind::SPAN
+    };
+
+    impl Callsite for TaskCallsite {
+        fn set_interest(&self, _: Interest) {}
+        fn metadata(&self) -> &TracingMetadata<'static> {
+            &TASK_META
+        }
+    }
+
+    impl Callsite for UserCallsite {
+        fn set_interest(&self, _: Interest) {}
+        fn metadata(&self) -> &TracingMetadata<'static> {
+            &USER_META
+        }
+    }
+
+    #[tokio::test]
+    async fn task_spans_are_distinguishable_from_user_spans() {
+        //The classification is what separates runtime task spans from user spans in the
+        // profiler's span model; full poll data additionally needs tokio built with
+        // tokio_unstable.
+        let handle = tokio::spawn(async {
+            super::Metadata::from_tracing(&TASK_META)
+        });
+        let task = handle.await.unwrap();
+        let user = super::Metadata::from_tracing(&USER_META);
+        assert!(task.is_task());
+        assert!(!user.is_task());
+    }
+}
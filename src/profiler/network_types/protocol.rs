@@ -0,0 +1,226 @@
+// Copyright (c) 2022, BlockProject 3D
+//
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification,
+// are permitted provided that the following conditions are met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//     * Redistributions in binary form must reproduce the above copyright notice,
+//       this list of conditions and the following disclaimer in the documentation
+//       and/or other materials provided with the distribution.
+//     * Neither the name of BlockProject 3D nor the names of its contributors
+//       may be used to endorse or promote products derived from this software
+//       without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS
+// "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT
+// LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR
+// A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT OWNER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! The named constants of the wire protocol, used by the implementation itself so no
+//! magic numbers hide in the write/read paths, plus a generator emitting the protocol
+//! description that docs/protocol.md is checked against: a test compares the generated
+//! text to the committed file, so drift between code and documentation fails CI. Variant
+//! tag values are derived by serializing real samples, so even a silent reordering of the
+//! command enum is caught.
+
+use bincode::Options;
+use crate::profiler::network_types::{Command, Metadata, SpanAllocData, SpanId, SCHEMA_VERSION};
+use crate::profiler::network_types::deserializer::MAX_SESSION_NAME_LEN;
+use crate::profiler::network_types::event_flags;
+
+/// The size of the little-endian u32 length prefix in front of every frame.
+pub const FRAME_LEN_BYTES: usize = 4;
+
+/// The largest payload the length prefix can express; longer frames are refused.
+pub const MAX_FRAME_SIZE: usize = u32::MAX as usize;
+
+/// The maximum accepted size of a client frame, bounded before allocation.
+pub const MAX_CLIENT_FRAME: usize = 4096;
+
+/// The size of the fixed hand-encoded Hello packet exchanged before any serializer runs.
+pub const HANDSHAKE_SIZE: usize = 40;
+
+/// The payload size of one log file chunk.
+pub const LOG_CHUNK_SIZE: usize = 1024;
+
+fn sample_metadata() -> Metadata {
+    let record = log::Record::builder()
+        .target("sample")
+        .level(log::Level::Info)
+        .build();
+    Metadata::from_log(&record)
+}
+
+fn sample_commands() -> Vec<(&'static str, Command)> {
+    let span = SpanId::from_u64(1 << 32);
+    vec![
+        ("SpanAlloc", Command::SpanAlloc {
+            id: span,
+            metadata: sample_metadata(),
+            tags: Vec::new()
+        }),
+        ("SpanAllocBatch", Command::SpanAllocBatch {
+            allocs: vec![SpanAllocData {
+                id: span,
+                metadata: sample_metadata(),
+                tags: Vec::new()
+            }]
+        }),
+        ("SpanInit", Command::SpanInit {
+            span,
+            parent: None,
+            message: None,
+            value_set: Vec::new()
+        }),
+        ("SpanFollows", Command::SpanFollows {
+            span,
+            follows: span
+        }),
+        ("SpanValues", Command::SpanValues {
+            span,
+            message: None,
+            value_set: Vec::new()
+        }),
+        ("Event", Command::Event {
+            span: None,
+            metadata: sample_metadata(),
+            flags: event_flags::ABSOLUTE_TIME,
+            time: 0,
+            message: None,
+            value_set: Vec::new()
+        }),
+        ("SpanEnter", Command::SpanEnter(span)),
+        ("SpanExit", Command::SpanExit {
+            span,
+            duration: std::time::Duration::ZERO.into(),
+            failed: false,
+            suspect: false,
+            memory_delta: None
+        }),
+        ("SpanFree", Command::SpanFree {
+            span,
+            reason: crate::profiler::network_types::CloseReason::Normal
+        }),
+        ("Project", Command::Project {
+            app_name: String::new(),
+            sections: Vec::new()
+        }),
+        ("ProjectUpdate", Command::ProjectUpdate {
+            sections: Vec::new()
+        }),
+        ("PermissionDenied", Command::PermissionDenied {
+            msg_type: String::new()
+        }),
+        ("SessionName", Command::SessionName {
+            name: String::new()
+        }),
+        ("IncompleteRuns", Command::IncompleteRuns {
+            count: 0,
+            top: Vec::new()
+        }),
+        ("OpenSpansChunk", Command::OpenSpansChunk {
+            seq: 0,
+            last: true,
+            rows: Vec::new()
+        }),
+        ("LogFileChunk", Command::LogFileChunk {
+            seq: 0,
+            last: false,
+            data: Vec::new()
+        }),
+        ("LogFileSummary", Command::LogFileSummary {
+            total_size: 0,
+            sent: 0,
+            truncated: false
+        }),
+        ("Bandwidth", Command::Bandwidth {
+            sent_total: 0,
+            recv_total: 0,
+            sent_rate: 0
+        }),
+        ("StreamSummary", Command::StreamSummary {
+            session_name: None,
+            artifacts: String::new(),
+            protocol: None,
+            sent_wire: 0,
+            serialize_drops: 0,
+            received_wire: 0,
+            top_targets: Vec::new(),
+            frames: 0,
+            bytes: 0,
+            crc32: 0
+        }),
+        ("SpanTree", Command::SpanTree {
+            edges: Vec::new()
+        }),
+        ("Terminate", Command::Terminate)
+    ]
+}
+
+/// Generates the protocol description docs/protocol.md is checked against.
+pub fn generate_description() -> String {
+    let mut out = String::new();
+    out += &format!("# bp3d-tracing profiler protocol (schema version {})\n\n", SCHEMA_VERSION);
+    out += "This file is generated by `protocol::generate_description()` and verified by a\n\
+test; regenerate it instead of editing by hand.\n\n";
+    out += "## Framing\n\n";
+    out += &format!("- every frame: {}-byte little-endian u32 payload length, then the payload\n", FRAME_LEN_BYTES);
+    out += &format!("- maximum payload length: {} bytes\n", MAX_FRAME_SIZE);
+    out += "- payload encoding: bincode with varint integer encoding\n";
+    out += "- event time field: absolute unix seconds when flag 0x1 is set, else a delta\n  from the previous event frame\n\n";
+    out += "## Handshake\n\n";
+    out += &format!("- one fixed {}-byte Hello packet each way before any frame\n", HANDSHAKE_SIZE);
+    out += "- layout: 8-byte signature \"BP3DPROF\", u64 LE major version, 24-byte\n  zero-padded pre-release string\n\n";
+    out += "## Client frames\n\n";
+    out += &format!("- framing as above, payload bounded to {} bytes\n", MAX_CLIENT_FRAME);
+    out += "- payload: one tag byte then a tag-specific body; strings are u16 LE\n  length-prefixed UTF-8\n";
+    out += &format!("- tag 0: SetSessionName (string, max {} bytes)\n", MAX_SESSION_NAME_LEN);
+    out += "- tag 1: RequestLogFile (u32 LE maximum byte budget)\n";
+    out += "- tag 2: PauseRecording (one byte, nonzero = paused)\n";
+    out += "- tag 3: QueryOpenSpans (no payload)\n";
+    out += "- tag 4: Authenticate (string control token; read-only connections upgrade on\n  a constant-time match)\n";
+    out += "- tag 5: DatasetAck (no payload; acknowledges receipt of the final session\n  data)\n\n";
+    out += "## Server commands\n\n";
+    out += "The variant tag is the first byte of the payload:\n\n";
+    for (name, cmd) in sample_commands() {
+        let bytes = bincode::options().serialize(&cmd).expect("failed to serialize protocol sample");
+        out += &format!("- {}: {}\n", bytes[0], name);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn description_matches_the_committed_protocol_md() {
+        if std::env::var("BP3D_REGEN_PROTOCOL").is_ok() {
+            std::fs::write(concat!(env!("CARGO_MANIFEST_DIR"), "/docs/protocol.md"),
+                generate_description()).unwrap();
+        }
+        let generated = generate_description();
+        let committed = include_str!("../../../docs/protocol.md");
+        assert_eq!(generated, committed,
+            "docs/protocol.md is out of date; regenerate it with protocol::generate_description()");
+    }
+
+    #[test]
+    fn every_command_variant_is_described() {
+        //A new enum variant must be added to the samples (and thus the docs).
+        let described = sample_commands().len();
+        //Serialize a Terminate to learn the highest tag in use.
+        let highest = bincode::options().serialize(&Command::Terminate).unwrap()[0] as usize;
+        assert_eq!(described, highest + 1);
+    }
+}
@@ -0,0 +1,208 @@
+// Copyright (c) 2022, BlockProject 3D
+//
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification,
+// are permitted provided that the following conditions are met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//     * Redistributions in binary form must reproduce the above copyright notice,
+//       this list of conditions and the following disclaimer in the documentation
+//       and/or other materials provided with the distribution.
+//     * Neither the name of BlockProject 3D nor the names of its contributors
+//       may be used to endorse or promote products derived from this software
+//       without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS
+// "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT
+// LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR
+// A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT OWNER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! This module contains the standard declarations for the protocol initialization. All BP3D
+//! protocols (except auto-discovery for now) expose a Hello packet.
+
+use byteorder::{ByteOrder, LittleEndian};
+use crate::profiler::network_types::protocol::HANDSHAKE_SIZE;
+
+const SIGNATURE: [u8; 8] = *b"BP3DPROF";
+
+//Follow semver except that we exclude the build metadata, the minor and the patch because only the
+// major version records protocol changes.
+
+/*
+struct Version {
+    offset 0 major: u64
+    offset 8 pre_release: [u8; 24] //0 padded
+} size 32
+
+struct Hello {
+    offset 0 signature: [u8; 8]
+    offset 8 version: Version
+} size 40
+*/
+
+pub enum MatchResult {
+    SignatureMismatch,
+    VersionMismatch,
+    Ok
+}
+
+/// What a session actually negotiated, recorded at handshake time so summaries and bug
+/// reports show which pairing produced them. The feature bitmask is reserved (0) until
+/// optional features exist.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct ProtocolInfo {
+    pub server_major: u64,
+    pub server_pre_release: Option<String>,
+    pub client_major: u64,
+    pub client_pre_release: Option<String>,
+    pub features: u32
+}
+
+impl ProtocolInfo {
+    pub fn negotiate(server: &Hello, client: &Hello) -> ProtocolInfo {
+        ProtocolInfo {
+            server_major: server.major(),
+            server_pre_release: server.pre_release_str(),
+            client_major: client.major(),
+            client_pre_release: client.pre_release_str(),
+            features: 0
+        }
+    }
+}
+
+pub struct Version {
+    major: u64,
+    pre_release: Option<[u8; 24]>,
+}
+
+pub struct Hello {
+    signature: [u8; 8],
+    version: Version
+}
+
+impl Hello {
+    /// The protocol major version this side advertises.
+    pub fn major(&self) -> u64 {
+        self.version.major
+    }
+
+    /// The advertised pre-release string, when any.
+    pub fn pre_release_str(&self) -> Option<String> {
+        self.version.pre_release.map(|bytes| {
+            let end = bytes.iter().position(|v| *v == 0).unwrap_or(bytes.len());
+            String::from_utf8_lossy(&bytes[..end]).into_owned()
+        })
+    }
+
+    pub const fn new(major: u64, pre_release: Option<[u8; 24]>) -> Self {
+        Self {
+            signature: SIGNATURE,
+            version: Version {
+                major,
+                pre_release
+            }
+        }
+    }
+
+    pub fn from_bytes(block: [u8; HANDSHAKE_SIZE]) -> Self {
+        let mut signature: [u8; 8] = [0; 8];
+        let mut pre_release: [u8; 24] = [0; 24];
+        signature.copy_from_slice(&block[..8]);
+        pre_release.copy_from_slice(&block[16..]);
+        let major = LittleEndian::read_u64(&block[8..16]);
+        if pre_release[0] == 0x0 {
+            Hello {
+                signature,
+                version: Version {
+                    major,
+                    pre_release: None
+                }
+            }
+        } else {
+            Hello {
+                signature,
+                version: Version {
+                    major,
+                    pre_release: Some(pre_release)
+                }
+            }
+        }
+    }
+
+    pub fn matches(&self, other: &Hello) -> MatchResult {
+        if self.signature != other.signature {
+            return MatchResult::SignatureMismatch;
+        }
+        let val = match (self.version.pre_release, other.version.pre_release) {
+            (Some(a), Some(b)) => a == b,
+            (None, None) => self.version.major == other.version.major,
+            _ => false
+        };
+        match val {
+            true => MatchResult::Ok,
+            false => MatchResult::VersionMismatch
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; HANDSHAKE_SIZE] {
+        let mut block = [0; HANDSHAKE_SIZE];
+        block[..8].copy_from_slice(&self.signature);
+        LittleEndian::write_u64(&mut block[8..16], self.version.major);
+        if let Some(pre_release) = &self.version.pre_release {
+            block[16..].copy_from_slice(pre_release);
+        }
+        block
+    }
+}
+
+include!(concat!(env!("OUT_DIR"), "/version_inject.rs"));
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiation_records_both_sides() {
+        //Fake clients advertising different versions/pre-releases.
+        let server = Hello::new(2, Some(*b"alpha-2.0.0\0\0\0\0\0\0\0\0\0\0\0\0\0"));
+        let client = Hello::from_bytes(Hello::new(2, None).to_bytes());
+        let info = ProtocolInfo::negotiate(&server, &client);
+        assert_eq!(info.server_major, 2);
+        assert_eq!(info.server_pre_release.as_deref(), Some("alpha-2.0.0"));
+        assert_eq!(info.client_major, 2);
+        assert_eq!(info.client_pre_release, None);
+        assert_eq!(info.features, 0);
+    }
+
+    #[test]
+    fn negotiated_info_reaches_the_session_summary() {
+        use crate::profiler::state::ProfilerState;
+        let state = ProfilerState::get();
+        let info = ProtocolInfo {
+            server_major: 1,
+            server_pre_release: Some("alpha-2.0.0".into()),
+            client_major: 1,
+            client_pre_release: Some("rc.1".into()),
+            features: 0
+        };
+        state.set_protocol_info(info.clone());
+        //Both surfaced locations read the same recorded value.
+        assert_eq!(state.protocol_info(), Some(info.clone()));
+        let summary = crate::profiler::thread::StreamIntegrity::new()
+            .summary(None, String::new(), 0, 0);
+        match summary {
+            crate::profiler::network_types::Command::StreamSummary { protocol, .. } =>
+                assert_eq!(protocol, Some(info)),
+            _ => panic!("expected a stream summary")
+        }
+    }
+}
@@ -0,0 +1,593 @@
+// Copyright (c) 2022, BlockProject 3D
+//
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification,
+// are permitted provided that the following conditions are met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//     * Redistributions in binary form must reproduce the above copyright notice,
+//       this list of conditions and the following disclaimer in the documentation
+//       and/or other materials provided with the distribution.
+//     * Neither the name of BlockProject 3D nor the names of its contributors
+//       may be used to endorse or promote products derived from this software
+//       without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS
+// "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT
+// LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR
+// A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT OWNER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use crossbeam_channel::{bounded, Receiver, Sender};
+use dashmap::DashMap;
+use once_cell::sync::OnceCell;
+use crate::profiler::thread::Command;
+use crate::util::span_to_id_instance;
+
+//How many of the longest-open incomplete spans the session summary names.
+const TOP_INCOMPLETE: usize = 5;
+
+static THREAD_COUNTER: AtomicUsize = AtomicUsize::new(1);
+
+thread_local! {
+    static THREAD_NUMBER: u64 = THREAD_COUNTER.fetch_add(1, Ordering::Relaxed) as u64;
+}
+
+/// A small monotonically assigned per-thread number (std's ThreadId has no stable public
+/// integer form) used to attribute open spans to threads.
+pub fn current_thread_number() -> u64 {
+    THREAD_NUMBER.with(|v| *v)
+}
+
+/// What the state remembers about one currently open span run.
+pub struct OpenSpanInfo {
+    pub since: Instant,
+    pub thread: u64,
+    pub parent: Option<u64>
+}
+
+/// One row of the live open-spans snapshot sent to the client.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct OpenSpanRow {
+    pub callsite: u32,
+    pub instance: u64,
+    pub thread: u64,
+    pub elapsed: crate::profiler::network_types::Duration,
+    pub parent: Option<u64>
+}
+
+/// The writer task's handle: a dedicated OS thread by default, or a task on the host's
+/// tokio runtime when the application opted in via use_existing_runtime.
+pub enum ThreadHandle {
+    Std(JoinHandle<()>),
+    #[cfg(feature = "tokio")]
+    Tokio(tokio::task::JoinHandle<()>)
+}
+
+impl ThreadHandle {
+    fn join(self) {
+        match self {
+            ThreadHandle::Std(handle) => handle.join().unwrap(),
+            #[cfg(feature = "tokio")]
+            ThreadHandle::Tokio(handle) => {
+                //Graceful first: the writer exits on Terminate by itself; abort only if
+                // it never does, so a stalled connection cannot wedge shutdown.
+                let start = Instant::now();
+                while !handle.is_finished() {
+                    if start.elapsed() > Duration::from_secs(2) {
+                        handle.abort();
+                        break;
+                    }
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+            }
+        }
+    }
+}
+
+const BUF_SIZE: usize = 128; // The default maximum count of log messages in the channel.
+
+// Sampling the channel fill level on every send would put an atomic RMW on the hot path,
+// so only every Nth send is observed.
+const SAMPLE_INTERVAL: usize = 256;
+
+/// Tracks the observed minimum free capacity of a bounded channel so users can tune the
+/// capacity before hitting drops.
+pub struct ChannelMonitor {
+    counter: AtomicUsize,
+    min_free: AtomicUsize
+}
+
+impl ChannelMonitor {
+    fn new() -> ChannelMonitor {
+        ChannelMonitor {
+            counter: AtomicUsize::new(0),
+            min_free: AtomicUsize::new(usize::MAX)
+        }
+    }
+
+    pub fn observe(&self, channel: &Sender<Command>) {
+        if !self.counter.fetch_add(1, Ordering::Relaxed).is_multiple_of(SAMPLE_INTERVAL) {
+            return;
+        }
+        if let Some(capacity) = channel.capacity() {
+            let free = capacity - channel.len();
+            self.min_free.fetch_min(free, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns the minimum observed free capacity, or None when no sample was taken yet.
+    pub fn min_free(&self) -> Option<usize> {
+        match self.min_free.load(Ordering::Relaxed) {
+            usize::MAX => None,
+            v => Some(v)
+        }
+    }
+}
+
+pub struct ProfilerState {
+    exited: AtomicBool,
+    paused: AtomicBool,
+    //0 = unlimited. Beyond the cap, new instances degrade to duration-only tracking.
+    max_tracked: AtomicUsize,
+    cap_warned: AtomicBool,
+    send_ch: Sender<Command>,
+    recv_ch: Receiver<Command>,
+    monitor: ChannelMonitor,
+    //Names per callsite id plus the enter instant of every currently open span run, so
+    // the Terminate path can report in-flight spans instead of letting them vanish.
+    //Set when the viewer acknowledged receipt of the session's final data.
+    ack: (Mutex<bool>, std::sync::Condvar),
+    //What the handshake negotiated; surfaced in the summary and through Guard.
+    protocol: Mutex<Option<crate::profiler::network_types::ProtocolInfo>>,
+    span_names: DashMap<u32, &'static str>,
+    open_spans: DashMap<u64, OpenSpanInfo>,
+    //Parent links registered at span creation, consulted when the span opens.
+    parents: DashMap<u64, u64>,
+    thread: Mutex<Option<ThreadHandle>>
+}
+
+impl ProfilerState {
+    fn new(capacity: usize) -> ProfilerState {
+        let (send_ch, recv_ch) = bounded(capacity);
+        ProfilerState {
+            exited: AtomicBool::new(false),
+            paused: AtomicBool::new(false),
+            max_tracked: AtomicUsize::new(0),
+            cap_warned: AtomicBool::new(false),
+            ack: (Mutex::new(false), std::sync::Condvar::new()),
+            protocol: Mutex::new(None),
+            send_ch,
+            recv_ch,
+            monitor: ChannelMonitor::new(),
+            span_names: DashMap::new(),
+            open_spans: DashMap::new(),
+            parents: DashMap::new(),
+            thread: Mutex::new(None)
+        }
+    }
+
+    pub fn register_span_name(&self, callsite: u32, name: &'static str) {
+        self.span_names.insert(callsite, name);
+    }
+
+    /// Records the viewer's end-of-session acknowledgement.
+    pub fn acknowledge(&self) {
+        let (lock, condvar) = &self.ack;
+        *lock.lock().unwrap() = true;
+        condvar.notify_all();
+    }
+
+    /// Blocks until the viewer acknowledged the final data or the timeout elapsed;
+    /// returns whether the acknowledgement arrived.
+    pub fn wait_ack(&self, timeout: Duration) -> bool {
+        let (lock, condvar) = &self.ack;
+        let guard = lock.lock().unwrap();
+        let (guard, _) = condvar.wait_timeout_while(guard, timeout, |acked| !*acked).unwrap();
+        *guard
+    }
+
+    pub fn set_protocol_info(&self, info: crate::profiler::network_types::ProtocolInfo) {
+        *self.protocol.lock().unwrap() = Some(info);
+    }
+
+    pub fn protocol_info(&self) -> Option<crate::profiler::network_types::ProtocolInfo> {
+        self.protocol.lock().unwrap().clone()
+    }
+
+    pub fn set_max_tracked_instances(&self, max: Option<usize>) {
+        self.max_tracked.store(max.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    /// How many span instances currently hold full tracking state.
+    pub fn tracked_instances(&self) -> usize {
+        self.open_spans.len()
+    }
+
+    pub fn register_parent(&self, span: u64, parent: Option<u64>) {
+        match parent {
+            Some(parent) => {
+                self.parents.insert(span, parent);
+            },
+            None => {
+                self.parents.remove(&span);
+            }
+        }
+    }
+
+    pub fn span_opened(&self, span: u64) {
+        let max = self.max_tracked.load(Ordering::Relaxed);
+        if max > 0 && self.open_spans.len() >= max {
+            //Degraded mode: the instance still gets its duration measured by the
+            // subscriber, it just carries no live-view/incomplete-run bookkeeping here.
+            if !self.cap_warned.swap(true, Ordering::Relaxed) {
+                //Cannot go through log here: span_opened runs from span_enter while the
+                // subscriber's inner lock is held, and the log pump would call back into
+                // current_span() on the same thread.
+                eprintln!("bp3d-tracing: more than {} live span instances; \
+further instances are tracked duration-only to cap memory", max);
+            }
+            return;
+        }
+        self.open_spans.insert(span, OpenSpanInfo {
+            since: Instant::now(),
+            thread: current_thread_number(),
+            parent: self.parents.get(&span).map(|v| *v)
+        });
+    }
+
+    pub fn span_closed(&self, span: u64) {
+        self.open_spans.remove(&span);
+    }
+
+    pub fn span_destroyed(&self, span: u64) {
+        self.open_spans.remove(&span);
+        self.parents.remove(&span);
+    }
+
+    /// How many spans are open per span name, for the statistics snapshot.
+    pub fn open_spans_by_name(&self) -> std::collections::HashMap<String, usize> {
+        let mut counts = std::collections::HashMap::new();
+        for entry in self.open_spans.iter() {
+            let (callsite, _) = span_to_id_instance(&tracing_core::span::Id::from_u64(*entry.key()));
+            let name = self.span_names.get(&callsite)
+                .map(|v| (*v).to_string())
+                .unwrap_or_else(|| "<unknown>".into());
+            *counts.entry(name).or_insert(0) += 1;
+        }
+        counts
+    }
+
+    /// Snapshots every currently open span run for the live view, longest-open first.
+    pub fn open_span_rows(&self) -> Vec<OpenSpanRow> {
+        let mut rows: Vec<OpenSpanRow> = self.open_spans.iter()
+            .map(|entry| {
+                let (callsite, instance) = span_to_id_instance(
+                    &tracing_core::span::Id::from_u64(*entry.key()));
+                OpenSpanRow {
+                    callsite,
+                    instance,
+                    thread: entry.value().thread,
+                    elapsed: entry.value().since.elapsed().into(),
+                    parent: entry.value().parent
+                }
+            })
+            .collect();
+        rows.sort_by_key(|row| std::cmp::Reverse(std::time::Duration::from(row.elapsed)));
+        rows
+    }
+
+    /// Builds the incomplete-runs report over every span still open right now: a count
+    /// plus the longest-open spans with their names and elapsed-so-far durations. These
+    /// are reported distinctly so they never pollute min/max/avg statistics.
+    pub fn incomplete_runs(&self) -> Option<Command> {
+        if self.open_spans.is_empty() {
+            return None;
+        }
+        let mut open: Vec<(String, Duration)> = self.open_spans.iter()
+            .map(|entry| {
+                let (callsite, _) = span_to_id_instance(&tracing_core::span::Id::from_u64(*entry.key()));
+                let name = self.span_names.get(&callsite)
+                    .map(|v| *v)
+                    .unwrap_or("<unknown>");
+                (name.into(), entry.value().since.elapsed())
+            })
+            .collect();
+        let count = open.len() as u64;
+        open.sort_by_key(|v| std::cmp::Reverse(v.1));
+        open.truncate(TOP_INCOMPLETE);
+        Some(Command::IncompleteRuns {
+            count,
+            top: open
+        })
+    }
+
+    /// Initializes the state with the given channel capacity; a no-op returning the
+    /// existing state when already initialized (the capacity of a live channel cannot
+    /// change).
+    pub fn init(capacity: usize) -> &'static ProfilerState {
+        PROFILER_STATE.get_or_init(|| ProfilerState::new(capacity))
+    }
+
+    pub fn get() -> &'static ProfilerState {
+        PROFILER_STATE.get_or_init(|| ProfilerState::new(BUF_SIZE))
+    }
+
+    /// Returns the state only if the profiler was ever initialized.
+    pub fn try_get() -> Option<&'static ProfilerState> {
+        PROFILER_STATE.get()
+    }
+
+    pub fn monitor(&self) -> &ChannelMonitor {
+        &self.monitor
+    }
+
+    /// The total capacity of the command channel.
+    pub fn capacity(&self) -> usize {
+        // The channel is always constructed with bounded() so a capacity exists.
+        self.send_ch.capacity().unwrap_or(0)
+    }
+
+    /// Pauses or resumes recording; a plain flag read by the producers, flushing nothing.
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::Relaxed);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    pub fn is_exited(&self) -> bool {
+        //Acquire pairs with the Release store in terminate(): a thread seeing true also
+        // sees everything terminate() did before flipping the flag.
+        self.exited.load(Ordering::Acquire)
+    }
+
+    pub fn get_channel(&self) -> (Sender<Command>, Receiver<Command>) {
+        (self.send_ch.clone(), self.recv_ch.clone())
+    }
+
+    /// Blocks until the command channel has been drained by the writer thread, up to the
+    /// given timeout; returns whether the channel is empty. Used by flush paths so final
+    /// messages are delivered before teardown.
+    pub fn wait_drained(&self, timeout: Duration) -> bool {
+        let start = Instant::now();
+        while !self.send_ch.is_empty() {
+            if start.elapsed() >= timeout {
+                return false;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        true
+    }
+
+    /// Sends without ever blocking the calling thread: a full channel (the writer thread
+    /// only starts once a client connects) parks the command in the caller's bounded
+    /// overflow queue instead of stalling - the log pump's path, since log-crate records
+    /// can flood the channel during the wait-for-debugger window. Teardown traffic that
+    /// must be delivered (Terminate, the incomplete-runs report) keeps its direct
+    /// blocking sends in terminate().
+    pub fn send_nonblocking(&self, cmd: Command) {
+        self.monitor.observe(&self.send_ch);
+        crate::profiler::core::send_nonblocking(&self.send_ch, cmd);
+    }
+
+
+    pub fn assign_thread(&self, thread: ThreadHandle) {
+        let mut lock = self.thread.lock().unwrap();
+        if lock.is_some() {
+            panic!("Cannot assign thread twice!");
+        }
+        *lock = Some(thread);
+    }
+
+    pub fn terminate(&self) {
+        if self.is_exited() {
+            return;
+        }
+        self.exited.store(true, Ordering::Release);
+        //Gather statistics about still-open spans BEFORE tearing the writer down; the
+        // report must precede Terminate on the wire.
+        if let Some(incomplete) = self.incomplete_runs() {
+            self.send_ch.send(incomplete).unwrap();
+        }
+        self.send_ch.send(Command::Terminate).unwrap();
+        let thread = {
+            let mut lock = self.thread.lock().unwrap();
+            lock.take()
+        };
+        if let Some(thread) = thread {
+            thread.join();
+        }
+    }
+}
+
+static PROFILER_STATE: OnceCell<ProfilerState> = OnceCell::new();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profiler::thread::Command;
+
+    #[test]
+    fn instance_tracking_stays_bounded_under_the_cap() {
+        let state = ProfilerState::new(16);
+        state.set_max_tracked_instances(Some(10));
+        //An instance-heavy burst far beyond the cap.
+        for n in 0..1000u64 {
+            state.span_opened((n + 1) << 32);
+        }
+        assert_eq!(state.tracked_instances(), 10);
+        //Closing every instance (tracked or degraded) is panic-free and drains the map;
+        // durations are unaffected by design since the subscriber measures them.
+        for n in 0..1000u64 {
+            state.span_closed((n + 1) << 32);
+        }
+        assert_eq!(state.tracked_instances(), 0);
+    }
+
+    //Packs ids through the active partitioning strategy so the tests hold under every
+    // configured split.
+    fn sid(callsite: u32, instance: u64) -> u64 {
+        crate::util::span_from_id_instance(callsite, instance).into_u64()
+    }
+
+    #[test]
+    fn open_span_snapshot_reports_threads_nesting_and_ordering() {
+        let state = std::sync::Arc::new(ProfilerState::new(16));
+        //The outer span opens first on this thread.
+        state.register_parent(sid(1, 0), None);
+        state.span_opened(sid(1, 0));
+        std::thread::sleep(Duration::from_millis(10));
+        //A nested span opens later on another thread.
+        let other = state.clone();
+        std::thread::spawn(move || {
+            other.register_parent(sid(2, 0), Some(sid(1, 0)));
+            other.span_opened(sid(2, 0));
+        }).join().unwrap();
+        let rows = state.open_span_rows();
+        assert_eq!(rows.len(), 2);
+        //Longest-open first: the outer span leads.
+        assert_eq!(rows[0].callsite, 1);
+        assert_eq!(rows[1].callsite, 2);
+        assert!(std::time::Duration::from(rows[0].elapsed) > std::time::Duration::from(rows[1].elapsed));
+        assert_eq!(rows[1].parent, Some(sid(1, 0)));
+        assert_ne!(rows[0].thread, rows[1].thread);
+        state.span_destroyed(sid(1, 0));
+        state.span_destroyed(sid(2, 0));
+    }
+
+    #[test]
+    fn wait_ack_returns_only_after_the_viewer_acks() {
+        let state = std::sync::Arc::new(ProfilerState::new(16));
+        //No ack: the bounded wait gives up.
+        assert!(!state.wait_ack(Duration::from_millis(20)));
+        //A simulated viewer acks after a delay: the wait returns true, and only then.
+        let acker = state.clone();
+        let start = Instant::now();
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(100));
+            acker.acknowledge();
+        });
+        assert!(state.wait_ack(Duration::from_secs(5)));
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn pause_is_a_plain_toggle() {
+        let state = ProfilerState::new(16);
+        assert!(!state.is_paused());
+        state.set_paused(true);
+        assert!(state.is_paused());
+        state.set_paused(false);
+        assert!(!state.is_paused());
+    }
+
+    #[test]
+    fn open_spans_are_reported_as_incomplete_on_terminate() {
+        let state = ProfilerState::new(16);
+        state.register_span_name(1, "request");
+        state.register_span_name(2, "render");
+        state.span_opened(sid(1, 0));
+        state.span_opened(sid(2, 0));
+        state.span_opened(sid(2, 1));
+        //One closes normally; the two others stay open across terminate.
+        state.span_closed(sid(2, 1));
+        match state.incomplete_runs() {
+            Some(Command::IncompleteRuns { count, top }) => {
+                assert_eq!(count, 2);
+                let names: Vec<&str> = top.iter().map(|(name, _)| name.as_str()).collect();
+                assert!(names.contains(&"request"));
+                assert!(names.contains(&"render"));
+            },
+            _ => panic!("expected an incomplete-runs report")
+        }
+        //And the terminate path pushes the report ahead of Terminate.
+        state.terminate();
+        let (_, recv) = state.get_channel();
+        assert!(matches!(recv.try_recv().unwrap(), Command::IncompleteRuns { .. }));
+        assert!(matches!(recv.try_recv().unwrap(), Command::Terminate));
+    }
+
+    #[test]
+    fn wait_drained_returns_once_consumed() {
+        let state = ProfilerState::get();
+        let (send, recv) = state.get_channel();
+        send.send(Command::Terminate).unwrap();
+        //Nothing consumed yet: the bounded wait must give up, not hang.
+        assert!(!state.wait_drained(Duration::from_millis(20)));
+        recv.recv().unwrap();
+        assert!(state.wait_drained(Duration::from_millis(20)));
+    }
+
+    #[test]
+    fn high_water_mark_tracks_fill_level() {
+        let (send, _recv) = bounded::<Command>(16);
+        let monitor = ChannelMonitor::new();
+        for _ in 0..10 {
+            send.send(Command::Terminate).unwrap();
+        }
+        //Fill to a known depth of 10 out of 16, then take a sample.
+        monitor.observe(&send);
+        assert_eq!(monitor.min_free(), Some(6));
+    }
+
+    #[test]
+    fn no_sample_reports_none() {
+        let monitor = ChannelMonitor::new();
+        assert_eq!(monitor.min_free(), None);
+    }
+
+    #[test]
+    fn sampling_is_rate_limited() {
+        let (send, _recv) = bounded::<Command>(16);
+        let monitor = ChannelMonitor::new();
+        monitor.observe(&send); //First observation samples (counter 0).
+        send.send(Command::Terminate).unwrap();
+        monitor.observe(&send); //Second does not (counter 1).
+        assert_eq!(monitor.min_free(), Some(16));
+    }
+}
+
+#[cfg(all(test, feature = "tokio"))]
+mod tokio_tests {
+    use std::net::{TcpListener, TcpStream};
+    use crate::config::LocationMode;
+    use crate::profiler::thread::Thread;
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn writer_runs_and_shuts_down_on_the_host_runtime() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let socket = TcpStream::connect(addr).unwrap();
+        let (_peer, _) = listener.accept().unwrap();
+        let state = Box::leak(Box::new(ProfilerState::new(16)));
+        let (send, recv) = state.get_channel();
+        let handle = tokio::runtime::Handle::current().spawn_blocking(move || {
+            let mut thread = Thread::new(socket, recv, false, LocationMode::Full,
+                String::new(), None, std::time::Duration::from_secs(2));
+            thread.run();
+        });
+        state.assign_thread(ThreadHandle::Tokio(handle));
+        send.send(Command::SpanEnter(crate::util::span_from_id_instance(1, 0).into_u64())).unwrap();
+        //Graceful shutdown: terminate() must await the task without wedging the host
+        // runtime, which keeps serving this async test afterwards.
+        tokio::task::block_in_place(|| state.terminate());
+        assert!(state.is_exited());
+        //Runtime still alive and serving async work after the writer shut down.
+        assert_eq!(tokio::task::spawn(async { 7 }).await.unwrap(), 7);
+    }
+}
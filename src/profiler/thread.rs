@@ -0,0 +1,1162 @@
+// Copyright (c) 2022, BlockProject 3D
+//
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification,
+// are permitted provided that the following conditions are met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//     * Redistributions in binary form must reproduce the above copyright notice,
+//       this list of conditions and the following disclaimer in the documentation
+//       and/or other materials provided with the distribution.
+//     * Neither the name of BlockProject 3D nor the names of its contributors
+//       may be used to endorse or promote products derived from this software
+//       without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS
+// "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT
+// LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR
+// A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT OWNER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+pub mod util;
+
+use std::collections::HashSet;
+use std::io::{BufWriter, Write};
+use std::net::TcpStream;
+use std::sync::atomic::Ordering;
+use bincode::Options;
+use byteorder::{ByteOrder, LittleEndian};
+use crossbeam_channel::Receiver;
+use crate::config::LocationMode;
+use crate::profiler::thread::util::{StallDetector, Ticker};
+use crate::profiler::network_types::{event_flags, Metadata, SpanAllocData, SpanId, Value};
+use crate::profiler::network_types::protocol::{FRAME_LEN_BYTES, MAX_FRAME_SIZE};
+use crate::util::Crc32;
+use crate::profiler::network_types::Command as NetCommand;
+
+//Always owned: metadata strings are copied when the command is enqueued, so this thread
+// never dereferences a metadata pointer whose lifetime it doesn't control - dynamically
+// registered callsites included.
+#[derive(Debug, Clone)]
+pub struct Event {
+    pub span: Option<u64>,
+    pub metadata: Metadata,
+    pub time: i64,
+    pub message: Option<String>,
+    pub value_set: Vec<(&'static str, Value)>
+}
+
+#[derive(Clone, Debug)]
+pub enum Command {
+    /// Several commands accumulated by a thread-local batch buffer, sent as one channel
+    /// operation.
+    Batch(Vec<Command>),
+
+    /// A state-changing client message was refused for lack of the control token.
+    PermissionDenied(&'static str),
+
+    /// A sanitized session name received from the client.
+    SessionName(String),
+
+    /// The Project message assembled after the handshake.
+    Project {
+        app_name: String,
+        sections: Vec<(String, String)>
+    },
+
+    /// Sections registered after the Project message already went out.
+    ProjectUpdate(Vec<(String, String)>),
+
+    /// The client asked for the tail of the current log file.
+    SendLogFile {
+        max_bytes: u32
+    },
+
+    /// The client asked which spans are open right now.
+    QueryOpenSpans,
+
+    /// Spans still open when the session terminated, reported distinctly so they never
+    /// pollute the per-run statistics.
+    IncompleteRuns {
+        count: u64,
+        top: Vec<(String, std::time::Duration)>
+    },
+
+    SpanAlloc {
+        id: u64,
+        //Owned at enqueue time; see Event.
+        metadata: Metadata,
+        tags: Vec<(String, String)>
+    },
+
+    SpanInit {
+        span: u64,
+        parent: Option<u64>, //None must mean that span is at root
+        message: Option<String>,
+        value_set: Vec<(&'static str, Value)>
+    },
+
+    SpanFollows {
+        span: u64,
+        follows: u64
+    },
+
+    SpanValues {
+        span: u64,
+        message: Option<String>,
+        value_set: Vec<(&'static str, Value)>
+    },
+
+    Event(Event),
+
+    SpanEnter(u64),
+
+    SpanExit {
+        span: u64,
+        duration: std::time::Duration,
+        failed: bool,
+        memory_delta: Option<i64>
+    },
+
+    SpanFree {
+        span: u64,
+        reason: crate::profiler::network_types::CloseReason
+    },
+
+    Terminate
+}
+
+impl Command {
+    pub fn into_network(self) -> super::network_types::Command {
+        match self {
+            //Batches are unpacked by the thread's main loop before conversion, and log
+            // file requests are served there rather than converted.
+            Command::Batch(_) | Command::SendLogFile { .. } | Command::QueryOpenSpans =>
+                unreachable!("handled by the thread main loop before conversion"),
+            Command::PermissionDenied(msg_type) => NetCommand::PermissionDenied {
+                msg_type: msg_type.into()
+            },
+            Command::SessionName(name) => NetCommand::SessionName { name },
+            Command::Project { app_name, sections } => NetCommand::Project { app_name, sections },
+            Command::ProjectUpdate(sections) => NetCommand::ProjectUpdate { sections },
+            Command::IncompleteRuns { count, top } => NetCommand::IncompleteRuns {
+                count,
+                top: top.into_iter().map(|(name, elapsed)| (name, elapsed.into())).collect()
+            },
+            Command::SpanAlloc { id, metadata, tags } => NetCommand::SpanAlloc {
+                id: SpanId::from_u64(id),
+                metadata,
+                tags
+            },
+            Command::SpanInit { span, parent, message, value_set } => NetCommand::SpanInit {
+                span: SpanId::from_u64(span),
+                parent: parent.map(SpanId::from_u64),
+                message,
+                value_set: value_set.into_iter().map(|(k, v)| (k.into(), v)).collect()
+            },
+            Command::SpanFollows { span, follows } => NetCommand::SpanFollows {
+                span: SpanId::from_u64(span),
+                follows: SpanId::from_u64(follows)
+            },
+            Command::SpanValues { span, message, value_set } => NetCommand::SpanValues {
+                span: SpanId::from_u64(span),
+                message,
+                value_set: value_set.into_iter().map(|(k, v)| (k.into(), v)).collect()
+            },
+            Command::Event(ev) => NetCommand::Event {
+                span: ev.span.map(SpanId::from_u64),
+                metadata: ev.metadata,
+                flags: event_flags::ABSOLUTE_TIME,
+                time: ev.time,
+                message: ev.message,
+                value_set: ev.value_set.into_iter().map(|(k, v)| (k.into(), v)).collect()
+            },
+            Command::SpanEnter(v) => NetCommand::SpanEnter(SpanId::from_u64(v)),
+            Command::SpanExit { span, duration, failed, memory_delta } => NetCommand::SpanExit {
+                span: SpanId::from_u64(span),
+                duration: duration.into(),
+                failed,
+                //Set by the write thread when the run crossed a detected stall.
+                suspect: false,
+                memory_delta
+            },
+            Command::SpanFree { span, reason } => NetCommand::SpanFree {
+                span: SpanId::from_u64(span),
+                reason
+            },
+            Command::Terminate => NetCommand::Terminate
+        }
+    }
+}
+
+//How often the write loop reports bandwidth usage to the client.
+const BANDWIDTH_PERIOD: std::time::Duration = std::time::Duration::from_secs(1);
+
+//Allocations arriving within this window (or up to this many) coalesce into one
+// SpanAllocBatch frame, cutting framing overhead during the warmup burst when every
+// callsite is discovered at once.
+const ALLOC_WINDOW: std::time::Duration = std::time::Duration::from_millis(50);
+const ALLOC_BATCH_MAX: usize = 32;
+
+/// Accumulates the static callsite-level parent graph from the per-instance parent links
+/// seen in SpanInit commands.
+pub struct SpanTreeTracker {
+    edges: HashSet<(u32, u32)>
+}
+
+impl SpanTreeTracker {
+    pub fn new() -> SpanTreeTracker {
+        SpanTreeTracker {
+            edges: HashSet::new()
+        }
+    }
+
+    pub fn observe(&mut self, cmd: &NetCommand) {
+        if let NetCommand::SpanInit { span, parent: Some(parent), .. } = cmd {
+            self.edges.insert((span.id(), parent.id()));
+        }
+    }
+
+    /// Returns the discovered tree as a single command, or None when no edge was seen.
+    pub fn to_command(&self) -> Option<NetCommand> {
+        if self.edges.is_empty() {
+            return None;
+        }
+        let mut edges: Vec<(u32, u32)> = self.edges.iter().copied().collect();
+        edges.sort_unstable();
+        Some(NetCommand::SpanTree { edges })
+    }
+}
+
+/// Incrementally tracks what was sent so the final StreamSummary does not require a
+/// second pass over the session.
+pub struct StreamIntegrity {
+    frames: u64,
+    bytes: u64,
+    crc: Crc32
+}
+
+impl StreamIntegrity {
+    pub fn new() -> StreamIntegrity {
+        StreamIntegrity {
+            frames: 0,
+            bytes: 0,
+            crc: Crc32::new()
+        }
+    }
+
+    pub fn update(&mut self, payload: &[u8]) {
+        self.frames += 1;
+        self.bytes += payload.len() as u64;
+        self.crc.update(payload);
+    }
+
+    pub fn summary(&self, session_name: Option<String>, artifacts: String, sent_wire: u64,
+        serialize_drops: u64) -> NetCommand {
+        NetCommand::StreamSummary {
+            session_name,
+            artifacts,
+            protocol: crate::profiler::state::ProfilerState::try_get()
+                .and_then(|state| state.protocol_info()),
+            sent_wire,
+            serialize_drops,
+            received_wire: crate::stats::snapshot().bytes_received,
+            top_targets: crate::stats::top_targets(20),
+            frames: self.frames,
+            bytes: self.bytes,
+            crc32: self.crc.finalize()
+        }
+    }
+}
+
+/// Recomputes the integrity of a received recording and checks it against its trailing
+/// StreamSummary; this is what a client runs to detect truncated or corrupted transfers.
+#[cfg(test)]
+pub fn verify_recording(mut input: &[u8]) -> Result<(), String> {
+    use std::io::Read;
+    use bincode::Options;
+    let mut integrity = StreamIntegrity::new();
+    let mut header = [0; 4];
+    loop {
+        if input.read_exact(&mut header).is_err() {
+            return Err("recording ends without a stream summary".into());
+        }
+        let len = LittleEndian::read_u32(&header) as usize;
+        let mut payload = vec![0; len];
+        input.read_exact(&mut payload).map_err(|_| "truncated frame".to_string())?;
+        let cmd: NetCommand = bincode::options().deserialize(&payload)
+            .map_err(|e| format!("undecodable frame: {}", e))?;
+        if let NetCommand::StreamSummary { frames, bytes, crc32, .. } = cmd {
+            let matches = frames == integrity.frames
+                && bytes == integrity.bytes
+                && crc32 == integrity.crc.finalize();
+            return match matches {
+                true => Ok(()),
+                false => Err(format!("integrity mismatch: summary says {} frames, {} bytes, \
+crc 0x{:08X}; received {} frames, {} bytes, crc 0x{:08X}", frames, bytes, crc32,
+                    integrity.frames, integrity.bytes, integrity.crc.finalize()))
+            };
+        }
+        integrity.update(&payload);
+    }
+}
+
+//Message types whose serialization/size failures were already logged once.
+static FAILURE_LOGGED: once_cell::sync::Lazy<dashmap::DashSet<&'static str>> =
+    once_cell::sync::Lazy::new(dashmap::DashSet::new);
+
+fn log_drop_once(message: &'static str, why: &str) {
+    if FAILURE_LOGGED.insert(message) {
+        eprintln!("bp3d-tracing: dropping {} message(s): {} (reported once per type)", message, why);
+    }
+}
+
+pub struct Thread {
+    socket: BufWriter<TcpStream>,
+    channel: Receiver<Command>,
+    last_event_time: Option<i64>,
+    tracker: Option<SpanTreeTracker>,
+    integrity: StreamIntegrity,
+    location: LocationMode,
+    artifacts: String,
+    logs_dir: Option<std::path::PathBuf>,
+    max_frame: usize,
+    serialize_drops: u64,
+    stall: StallDetector,
+    wire_sent: u64,
+    pending_allocs: Vec<SpanAllocData>,
+    alloc_window: Option<std::time::Instant>,
+    bandwidth: Ticker,
+    last_report: (std::time::Instant, u64),
+    session_name: Option<String>
+}
+
+impl Thread {
+    pub fn new(socket: TcpStream, channel: Receiver<Command>, export_span_tree: bool,
+               location: LocationMode, artifacts: String,
+               logs_dir: Option<std::path::PathBuf>,
+               stall_threshold: std::time::Duration) -> Thread {
+        Thread {
+            //Buffer frames so bursts don't pay one syscall each; the main loop flushes
+            // whenever the channel drains and on every exit path.
+            socket: BufWriter::new(socket),
+            channel,
+            last_event_time: None,
+            tracker: match export_span_tree {
+                true => Some(SpanTreeTracker::new()),
+                false => None
+            },
+            integrity: StreamIntegrity::new(),
+            location,
+            artifacts,
+            logs_dir,
+            max_frame: MAX_FRAME_SIZE,
+            serialize_drops: 0,
+            stall: StallDetector::new(stall_threshold),
+            wire_sent: 0,
+            pending_allocs: Vec::new(),
+            alloc_window: None,
+            bandwidth: Ticker::new(BANDWIDTH_PERIOD),
+            last_report: (std::time::Instant::now(), 0),
+            session_name: None
+        }
+    }
+
+    /// Applies the configured location mode to event frames; spans keep their full
+    /// metadata since it is sent once per callsite, not per event.
+    fn strip_location(&self, cmd: &mut NetCommand) {
+        if let NetCommand::Event { metadata, .. } = cmd {
+            metadata.strip_location(self.location);
+        }
+    }
+
+    /// Rewrites the time of an event frame as a delta since the previous event frame.
+    ///
+    /// This thread processes events in order so it can maintain the previous timestamp; small
+    /// deltas then shrink to 1-2 bytes under the varint encoding.
+    fn delta_encode(&mut self, cmd: &mut NetCommand) {
+        if let NetCommand::Event { flags, time, .. } = cmd {
+            match self.last_event_time.replace(*time) {
+                Some(last) => {
+                    *flags &= !event_flags::ABSOLUTE_TIME;
+                    *time -= last;
+                },
+                None => *flags |= event_flags::ABSOLUTE_TIME
+            }
+        }
+    }
+
+    //Serialization always completes into a scratch buffer before any byte touches the
+    // BufWriter, so a failed or oversized message can never leave half a frame behind
+    // and desynchronize the stream: the message is dropped, counted (stats and session
+    // summary) and diagnosed once per message type.
+    fn write_frame(&mut self, cmd: &NetCommand) {
+        match bincode::options().serialize(cmd) {
+            Err(e) => {
+                crate::stats::SERIALIZE_ERRORS.fetch_add(1, Ordering::Relaxed);
+                self.serialize_drops += 1;
+                log_drop_once(cmd.name(), &e.to_string());
+            },
+            Ok(v) => {
+                //The length prefix is a u32 and the frame budget may be tightened for
+                // tests; refuse oversized frames whole instead of corrupting the stream.
+                if v.len() > self.max_frame {
+                    crate::stats::SERIALIZE_ERRORS.fetch_add(1, Ordering::Relaxed);
+                    self.serialize_drops += 1;
+                    log_drop_once(cmd.name(), "message exceeds the maximum frame size");
+                    return;
+                }
+                let mut frame = Vec::with_capacity(v.len() + FRAME_LEN_BYTES);
+                let mut buf = [0; FRAME_LEN_BYTES];
+                LittleEndian::write_u32(&mut buf, v.len() as u32);
+                frame.extend_from_slice(&buf);
+                frame.extend_from_slice(&v);
+                match self.socket.write_all(&frame) {
+                    Err(e) => {
+                        crate::stats::record_network_error(&e);
+                        eprintln!("An error has occurred while sending network command: {}", e);
+                        //Best effort: whatever was buffered before the failure may still
+                        // be deliverable.
+                        self.flush();
+                    },
+                    //Only frames handed to the buffered writer count toward the
+                    // integrity summary; the exit paths flush it.
+                    Ok(()) => {
+                        self.integrity.update(&v);
+                        self.wire_sent += frame.len() as u64;
+                        crate::stats::BYTES_SENT.fetch_add(frame.len(), Ordering::Relaxed);
+                        crate::stats::record_protocol(cmd.name(), frame.len());
+                    }
+                }
+            }
+        };
+    }
+
+    fn flush(&mut self) -> bool {
+        match self.socket.flush() {
+            Ok(()) => true,
+            Err(e) => {
+                crate::stats::record_network_error(&e);
+                eprintln!("An error has occurred while flushing the profiler connection: {}", e);
+                false
+            }
+        }
+    }
+
+    /// The newest regular file in the logs directory: the active log file.
+    fn latest_log_file(&self) -> Option<std::path::PathBuf> {
+        let dir = self.logs_dir.as_ref()?;
+        let mut newest: Option<(std::time::SystemTime, std::path::PathBuf)> = None;
+        for entry in std::fs::read_dir(dir).ok()? {
+            let entry = entry.ok()?;
+            let meta = entry.metadata().ok()?;
+            if !meta.is_file() {
+                continue;
+            }
+            let modified = meta.modified().ok()?;
+            if newest.as_ref().map(|(time, _)| modified > *time).unwrap_or(true) {
+                newest = Some((modified, entry.path()));
+            }
+        }
+        newest.map(|(_, path)| path)
+    }
+
+    /// Streams the tail of the active log file as bounded chunks followed by a summary.
+    /// The file is read in one short open-read-close so the logger's own writes are never
+    /// blocked by a held handle.
+    fn send_log_file(&mut self, max_bytes: u32) {
+        use std::io::{Read, Seek, SeekFrom};
+        use crate::profiler::network_types::protocol::LOG_CHUNK_SIZE;
+        let (data, total_size) = match self.latest_log_file().and_then(|path| {
+            let mut file = std::fs::File::open(path).ok()?;
+            let total = file.seek(SeekFrom::End(0)).ok()?;
+            let wanted = (max_bytes as u64).min(total);
+            file.seek(SeekFrom::End(-(wanted as i64))).ok()?;
+            let mut data = Vec::with_capacity(wanted as usize);
+            file.read_to_end(&mut data).ok()?;
+            Some((data, total))
+        }) {
+            Some(v) => v,
+            None => {
+                self.write_frame(&NetCommand::LogFileSummary {
+                    total_size: 0,
+                    sent: 0,
+                    truncated: false
+                });
+                return;
+            }
+        };
+        let sent = data.len() as u64;
+        let chunks = data.chunks(LOG_CHUNK_SIZE);
+        let last_index = chunks.len().saturating_sub(1);
+        for (seq, chunk) in chunks.enumerate() {
+            self.write_frame(&NetCommand::LogFileChunk {
+                seq: seq as u32,
+                last: seq == last_index,
+                data: chunk.to_vec()
+            });
+        }
+        self.write_frame(&NetCommand::LogFileSummary {
+            total_size,
+            sent,
+            truncated: sent < total_size
+        });
+    }
+
+    /// Streams the live open-spans snapshot as bounded chunks; the last chunk terminates
+    /// the list (and an empty snapshot is one empty terminating chunk).
+    fn send_open_spans(&mut self) {
+        const ROWS_PER_CHUNK: usize = 64;
+        let rows = crate::profiler::state::ProfilerState::get().open_span_rows();
+        let count = rows.len().div_ceil(ROWS_PER_CHUNK).max(1);
+        let mut chunks = rows.chunks(ROWS_PER_CHUNK);
+        for seq in 0..count {
+            self.write_frame(&NetCommand::OpenSpansChunk {
+                seq: seq as u32,
+                last: seq + 1 == count,
+                rows: chunks.next().unwrap_or(&[]).to_vec()
+            });
+        }
+    }
+
+    /// Flushes coalesced allocations: one plain SpanAlloc for a single late discovery,
+    /// one batch frame for a warmup burst.
+    fn flush_allocs(&mut self) {
+        self.alloc_window = None;
+        match self.pending_allocs.len() {
+            0 => {},
+            1 => {
+                let alloc = self.pending_allocs.remove(0);
+                self.write_frame(&NetCommand::SpanAlloc {
+                    id: alloc.id,
+                    metadata: alloc.metadata,
+                    tags: alloc.tags
+                });
+            },
+            _ => {
+                let allocs = std::mem::take(&mut self.pending_allocs);
+                self.write_frame(&NetCommand::SpanAllocBatch { allocs });
+            }
+        }
+    }
+
+    //Returns true when the session terminated.
+    fn process(&mut self, cmd: Command, stalled: bool) -> bool {
+        let mut cmd = cmd.into_network();
+        //Allocations coalesce within a short window. Any other frame closes the window
+        // first: a SpanInit/Event may reference a parked span id, and the client must
+        // receive the metadata that introduces an id before anything using it - the
+        // pending list is empty in steady state, so this costs nothing.
+        if let NetCommand::SpanAlloc { id, metadata, tags } = cmd {
+            self.pending_allocs.push(SpanAllocData {
+                id,
+                metadata,
+                tags
+            });
+            self.alloc_window.get_or_insert_with(std::time::Instant::now);
+            if self.pending_allocs.len() >= ALLOC_BATCH_MAX {
+                self.flush_allocs();
+            }
+            return false;
+        }
+        self.flush_allocs();
+        self.strip_location(&mut cmd);
+        self.delta_encode(&mut cmd);
+        //Runs completing across a debugger-induced stall carry wall-clock-inflated
+        // durations; flag them so clients keep them out of normal statistics.
+        if let NetCommand::SpanExit { suspect, .. } = &mut cmd {
+            *suspect = stalled;
+        }
+        //A session name is stored for the summary and echoed back as confirmation.
+        if let NetCommand::SessionName { name } = &cmd {
+            self.session_name = Some(name.clone());
+        }
+        if cmd == NetCommand::Terminate {
+            //Everything still pending leaves before the closing sequence.
+            self.flush_allocs();
+            //Flush the discovered span tree right before terminating so the viewer
+            // receives the call graph exactly once.
+            if let Some(tree) = self.tracker.as_ref().and_then(SpanTreeTracker::to_command) {
+                self.write_frame(&tree);
+            }
+            //Then the integrity summary over everything sent so far, so the client
+            // can detect a truncated or corrupted transfer.
+            let summary = self.integrity.summary(self.session_name.take(),
+                self.artifacts.clone(), self.wire_sent, self.serialize_drops);
+            self.write_frame(&summary);
+            self.write_frame(&NetCommand::Terminate);
+            //The final flush is what actually delivers the tail of the session;
+            // retry once on failure before giving up with the loss accounted for.
+            if !self.flush() {
+                self.flush();
+            }
+            return true;
+        }
+        if let Some(tracker) = &mut self.tracker {
+            tracker.observe(&cmd);
+        }
+        self.write_frame(&cmd);
+        false
+    }
+
+    /// Emits the periodic bandwidth report: totals plus the rate since the last one.
+    fn report_bandwidth(&mut self) {
+        let now = std::time::Instant::now();
+        let (last_time, last_sent) = self.last_report;
+        let elapsed = now.saturating_duration_since(last_time).as_secs_f64();
+        let sent_rate = match elapsed > 0.0 {
+            true => ((self.wire_sent - last_sent) as f64 / elapsed) as u64,
+            false => 0
+        };
+        self.last_report = (now, self.wire_sent);
+        let report = NetCommand::Bandwidth {
+            sent_total: self.wire_sent,
+            recv_total: crate::stats::snapshot().bytes_received,
+            sent_rate
+        };
+        self.write_frame(&report);
+    }
+
+    pub fn run(&mut self) {
+        loop {
+            let channel_was_empty = self.channel.is_empty();
+            //Pending allocations shorten the wait so their window closes on time.
+            let wait = match self.alloc_window.is_some() {
+                true => ALLOC_WINDOW,
+                false => BANDWIDTH_PERIOD
+            };
+            let cmd = match self.channel.recv_timeout(wait) {
+                Ok(cmd) => cmd,
+                Err(crossbeam_channel::RecvTimeoutError::Timeout) => {
+                    //Idle: consume a pending stats-dump trigger, close the alloc window,
+                    // report bandwidth, push buffered bytes.
+                    crate::profiler::artifacts::check_and_dump();
+                    self.flush_allocs();
+                    if self.bandwidth.should_fire(std::time::Instant::now()) {
+                        self.report_bandwidth();
+                    }
+                    self.flush();
+                    continue;
+                },
+                Err(crossbeam_channel::RecvTimeoutError::Disconnected) => return
+            };
+            let stalled = self.stall.observe(std::time::Instant::now(), channel_was_empty);
+            let terminated = match cmd {
+                Command::Batch(commands) => {
+                    commands.into_iter().any(|cmd| self.process(cmd, stalled))
+                },
+                Command::SendLogFile { max_bytes } => {
+                    self.send_log_file(max_bytes);
+                    false
+                },
+                Command::QueryOpenSpans => {
+                    self.send_open_spans();
+                    false
+                },
+                cmd => self.process(cmd, stalled)
+            };
+            if terminated {
+                break;
+            }
+            //Drained the backlog: push buffered frames to the wire before sleeping on
+            // the channel again.
+            if self.channel.is_empty() {
+                self.flush();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use bincode::Options;
+    use crate::profiler::network_types::SpanId;
+    use crate::util::span_from_id_instance;
+    use super::*;
+
+    //Builds a wire SpanId through the active partitioning strategy so the tests hold
+    // under every configured split.
+    fn sid(id: u32, instance: u64) -> SpanId {
+        SpanId::from_u64(span_from_id_instance(id, instance).into_u64())
+    }
+
+    #[test]
+    fn write_failures_are_counted_in_stats() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let socket = TcpStream::connect(addr).unwrap();
+        //Accept then immediately drop the peer so writes eventually fail.
+        drop(listener.accept().unwrap());
+        let (_send, recv) = crossbeam_channel::bounded(1);
+        let mut thread = Thread::new(socket, recv, false, LocationMode::Full, String::new(), None, std::time::Duration::from_secs(2));
+        let before = crate::stats::NETWORK_WRITE_ERRORS.load(Ordering::Relaxed);
+        //The first writes may still land in OS and BufWriter buffers; keep going until
+        // the broken pipe surfaces.
+        for _ in 0..4096 {
+            thread.write_frame(&NetCommand::Terminate);
+            thread.flush();
+        }
+        assert!(crate::stats::NETWORK_WRITE_ERRORS.load(Ordering::Relaxed) > before);
+        //And the aggregated snapshot exposed through Guard::stats reflects it, including
+        // the remembered last error: the loss is accounted for, never silent.
+        let stats = crate::stats::snapshot();
+        assert!(stats.network_write_errors > before);
+        assert!(stats.last_network_error.is_some());
+    }
+
+    fn frame(cmd: &NetCommand) -> Vec<u8> {
+        let body = bincode::options().serialize(cmd).unwrap();
+        let mut frame = vec![0; 4];
+        LittleEndian::write_u32(&mut frame, body.len() as u32);
+        frame.extend_from_slice(&body);
+        frame
+    }
+
+    fn recording() -> Vec<u8> {
+        let mut integrity = StreamIntegrity::new();
+        let mut recording = Vec::new();
+        for cmd in [
+            NetCommand::SpanEnter(sid(1, 0)),
+            NetCommand::SpanExit { span: sid(1, 0), duration: std::time::Duration::from_millis(250).into(), failed: false, suspect: false, memory_delta: None },
+            NetCommand::SpanFree { span: sid(1, 0), reason: crate::profiler::network_types::CloseReason::Normal }
+        ] {
+            let bytes = frame(&cmd);
+            integrity.update(&bytes[4..]);
+            recording.extend(bytes);
+        }
+        recording.extend(frame(&integrity.summary(None, String::new(), 0, 0)));
+        recording
+    }
+
+    #[test]
+    fn session_name_is_echoed_and_lands_in_the_summary() {
+        use std::io::Read;
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let socket = TcpStream::connect(addr).unwrap();
+        let (mut peer, _) = listener.accept().unwrap();
+        let (send, recv) = crossbeam_channel::bounded(8);
+        send.send(Command::SessionName("run 4-after-fix".into())).unwrap();
+        send.send(Command::Terminate).unwrap();
+        let handle = std::thread::spawn(move || {
+            let mut thread = Thread::new(socket, recv, false, LocationMode::Full, String::new(), None, std::time::Duration::from_secs(2));
+            thread.run();
+        });
+        handle.join().unwrap();
+        let mut received = Vec::new();
+        peer.read_to_end(&mut received).unwrap();
+        let mut input = &received[..];
+        let mut commands = Vec::new();
+        let mut header = [0; 4];
+        while input.read_exact(&mut header).is_ok() {
+            let len = LittleEndian::read_u32(&header) as usize;
+            let (payload, rest) = input.split_at(len);
+            commands.push(bincode::options().deserialize::<NetCommand>(payload).unwrap());
+            input = rest;
+        }
+        //The name is echoed back as confirmation and propagated into the summary.
+        assert!(commands.iter().any(|c| matches!(c,
+            NetCommand::SessionName { name } if name == "run 4-after-fix")));
+        assert!(commands.iter().any(|c| matches!(c,
+            NetCommand::StreamSummary { session_name: Some(name), .. } if name == "run 4-after-fix")));
+    }
+
+    #[test]
+    fn log_file_tail_reassembles_from_chunks() {
+        use std::io::Read;
+        let dir = std::env::temp_dir().join(format!("bp3d-logdl-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let content: Vec<u8> = (0..5000u32).map(|v| (v % 251) as u8).collect();
+        std::fs::write(dir.join("app.log"), &content).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let socket = TcpStream::connect(addr).unwrap();
+        let (mut peer, _) = listener.accept().unwrap();
+        let (send, recv) = crossbeam_channel::bounded(8);
+        send.send(Command::SendLogFile { max_bytes: 3000 }).unwrap();
+        send.send(Command::Terminate).unwrap();
+        let dir2 = dir.clone();
+        std::thread::spawn(move || {
+            let mut thread = Thread::new(socket, recv, false, LocationMode::Full,
+                String::new(), Some(dir2), std::time::Duration::from_secs(2));
+            thread.run();
+        }).join().unwrap();
+
+        let mut received = Vec::new();
+        peer.read_to_end(&mut received).unwrap();
+        let mut input = &received[..];
+        let mut header = [0; 4];
+        let mut tail = Vec::new();
+        let mut summary = None;
+        let mut last_seen = false;
+        while std::io::Read::read_exact(&mut input, &mut header).is_ok() {
+            let len = LittleEndian::read_u32(&header) as usize;
+            let (payload, rest) = input.split_at(len);
+            match bincode::options().deserialize::<NetCommand>(payload).unwrap() {
+                NetCommand::LogFileChunk { seq, last, data } => {
+                    assert_eq!(seq as usize, tail.len() / crate::profiler::network_types::protocol::LOG_CHUNK_SIZE);
+                    tail.extend(data);
+                    last_seen |= last;
+                },
+                NetCommand::LogFileSummary { total_size, sent, truncated } => {
+                    summary = Some((total_size, sent, truncated));
+                },
+                _ => {}
+            }
+            input = rest;
+        }
+        //The reassembled bytes are exactly the last 3000 bytes of the file.
+        assert!(last_seen);
+        assert_eq!(tail, content[2000..]);
+        assert_eq!(summary, Some((5000, 3000, true)));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn oversized_messages_drop_whole_and_the_stream_stays_framed() {
+        use std::io::Read;
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let socket = TcpStream::connect(addr).unwrap();
+        let (mut peer, _) = listener.accept().unwrap();
+        let (send, recv) = crossbeam_channel::unbounded();
+        //An unusually large Project payload (huge command line section).
+        send.send(Command::Project {
+            app_name: "big".into(),
+            sections: vec![("cmdline".into(), "x".repeat(4096))]
+        }).unwrap();
+        send.send(Command::SpanEnter(1 << 32)).unwrap();
+        send.send(Command::Terminate).unwrap();
+        std::thread::spawn(move || {
+            let mut thread = Thread::new(socket, recv, false, LocationMode::Full,
+                String::new(), None, std::time::Duration::from_secs(2));
+            //Tighten the frame budget so the Project cannot fit.
+            thread.max_frame = 256;
+            thread.run();
+        }).join().unwrap();
+        let mut received = Vec::new();
+        peer.read_to_end(&mut received).unwrap();
+        //Every frame still decodes cleanly: the oversized one left no partial bytes.
+        let mut input = &received[..];
+        let mut header = [0; 4];
+        let mut decoded = Vec::new();
+        while std::io::Read::read_exact(&mut input, &mut header).is_ok() {
+            let len = LittleEndian::read_u32(&header) as usize;
+            let (payload, rest) = input.split_at(len);
+            decoded.push(bincode::options().deserialize::<NetCommand>(payload).unwrap());
+            input = rest;
+        }
+        assert!(!decoded.iter().any(|c| matches!(c, NetCommand::Project { .. })));
+        assert!(decoded.iter().any(|c| matches!(c, NetCommand::SpanEnter(_))));
+        //And the session continued through to a summary accounting the drop.
+        assert!(decoded.iter().any(|c| matches!(c,
+            NetCommand::StreamSummary { serialize_drops: 1, .. })));
+        assert!(matches!(decoded.last(), Some(NetCommand::Terminate)));
+    }
+
+    #[test]
+    fn alloc_metadata_always_precedes_frames_referencing_it() {
+        use std::io::Read;
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let socket = TcpStream::connect(addr).unwrap();
+        let (mut peer, _) = listener.accept().unwrap();
+        let (send, recv) = crossbeam_channel::unbounded();
+        //The exact producer pattern: alloc immediately followed by the init that
+        // references the freshly introduced id, well inside the coalescing window.
+        send.send(Command::SpanAlloc {
+            id: 1 << 32,
+            metadata: Metadata::from_tracing(&WARMUP_META),
+            tags: Vec::new()
+        }).unwrap();
+        send.send(Command::SpanInit {
+            span: 1 << 32,
+            parent: None,
+            message: None,
+            value_set: Vec::new()
+        }).unwrap();
+        send.send(Command::Terminate).unwrap();
+        std::thread::spawn(move || {
+            let mut thread = Thread::new(socket, recv, false, LocationMode::Full,
+                String::new(), None, std::time::Duration::from_secs(2));
+            thread.run();
+        }).join().unwrap();
+        let mut received = Vec::new();
+        peer.read_to_end(&mut received).unwrap();
+        let mut input = &received[..];
+        let mut header = [0; 4];
+        let mut kinds = Vec::new();
+        while std::io::Read::read_exact(&mut input, &mut header).is_ok() {
+            let len = LittleEndian::read_u32(&header) as usize;
+            let (payload, rest) = input.split_at(len);
+            kinds.push(bincode::options().deserialize::<NetCommand>(payload).unwrap().name());
+            input = rest;
+        }
+        let alloc = kinds.iter().position(|v| *v == "SpanAlloc").unwrap();
+        let init = kinds.iter().position(|v| *v == "SpanInit").unwrap();
+        //Coalescing must never delay the metadata past a frame that references it.
+        assert!(alloc < init, "wire order was {:?}", kinds);
+    }
+
+    #[test]
+    fn warmup_alloc_burst_coalesces_into_few_frames() {
+        use std::io::Read;
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let socket = TcpStream::connect(addr).unwrap();
+        let (mut peer, _) = listener.accept().unwrap();
+        let (send, recv) = crossbeam_channel::unbounded();
+        let record = log::Record::builder().target("warmup").level(log::Level::Info).build();
+        //1000 callsites discovered at once, exactly the warmup pattern.
+        for n in 0..1000u64 {
+            send.send(Command::SpanAlloc {
+                id: (n + 1) << 32,
+                metadata: Metadata::from_tracing(&WARMUP_META),
+                tags: Vec::new()
+            }).unwrap();
+        }
+        let _ = record;
+        send.send(Command::Terminate).unwrap();
+        std::thread::spawn(move || {
+            let mut thread = Thread::new(socket, recv, false, LocationMode::Full,
+                String::new(), None, std::time::Duration::from_secs(2));
+            thread.run();
+        }).join().unwrap();
+        let mut received = Vec::new();
+        peer.read_to_end(&mut received).unwrap();
+        let mut input = &received[..];
+        let mut header = [0; 4];
+        let mut frames = 0usize;
+        let mut allocs_seen = 0usize;
+        while std::io::Read::read_exact(&mut input, &mut header).is_ok() {
+            let len = LittleEndian::read_u32(&header) as usize;
+            let (payload, rest) = input.split_at(len);
+            frames += 1;
+            match bincode::options().deserialize::<NetCommand>(payload).unwrap() {
+                NetCommand::SpanAllocBatch { allocs } => allocs_seen += allocs.len(),
+                NetCommand::SpanAlloc { .. } => allocs_seen += 1,
+                _ => {}
+            }
+            input = rest;
+        }
+        //Every allocation arrived, in far fewer frames than one per callsite.
+        assert_eq!(allocs_seen, 1000);
+        assert!(frames < 100, "warmup produced {} frames", frames);
+    }
+
+    static WARMUP_CALLSITE: WarmupCallsite = WarmupCallsite(0);
+    struct WarmupCallsite(#[allow(dead_code)] u8);
+    static WARMUP_META: tracing_core::Metadata<'static> = tracing_core::metadata! {
+        name: "warmup_span",
+        target: module_path!(),
+        level: tracing_core::Level::INFO,
+        fields: &[],
+        callsite: &WARMUP_CALLSITE,
+        kind: tracing_core::metadata::Kind::SPAN
+    };
+    impl tracing_core::Callsite for WarmupCallsite {
+        fn set_interest(&self, _: tracing_core::subscriber::Interest) {}
+        fn metadata(&self) -> &tracing_core::Metadata<'static> {
+            &WARMUP_META
+        }
+    }
+
+    #[test]
+    fn per_type_counters_track_a_message_mix() {
+        use crate::stats::{protocol_stats, SELF_METRICS};
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let socket = TcpStream::connect(addr).unwrap();
+        let (_peer, _) = listener.accept().unwrap();
+        let (_send, recv) = crossbeam_channel::bounded(1);
+        let mut thread = Thread::new(socket, recv, false, LocationMode::Full,
+            String::new(), None, std::time::Duration::from_secs(2));
+        let counts_for = |name: &str| protocol_stats().iter()
+            .find(|v| v.message == name)
+            .map(|v| (v.count, v.bytes))
+            .unwrap_or((0, 0));
+        SELF_METRICS.store(true, Ordering::Relaxed);
+        let (enter_before, _) = counts_for("SpanEnter");
+        let (follows_before, _) = counts_for("SpanFollows");
+        for _ in 0..3 {
+            thread.write_frame(&NetCommand::SpanEnter(sid(1, 0)));
+        }
+        thread.write_frame(&NetCommand::SpanFollows { span: sid(2, 0), follows: sid(1, 0) });
+        SELF_METRICS.store(false, Ordering::Relaxed);
+        let (enter_count, enter_bytes) = counts_for("SpanEnter");
+        let (follows_count, _) = counts_for("SpanFollows");
+        assert!(enter_count >= enter_before + 3);
+        assert!(follows_count > follows_before);
+        assert!(enter_bytes > 0);
+        //And disabled metrics stay free: no further updates.
+        thread.write_frame(&NetCommand::SpanEnter(sid(1, 0)));
+        assert_eq!(counts_for("SpanEnter").0, enter_count);
+    }
+
+    #[test]
+    fn summary_wire_totals_match_what_the_client_received() {
+        use std::io::Read;
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let socket = TcpStream::connect(addr).unwrap();
+        let (mut peer, _) = listener.accept().unwrap();
+        let (send, recv) = crossbeam_channel::bounded(64);
+        for n in 0..20u64 {
+            send.send(Command::SpanEnter((n + 1) << 32)).unwrap();
+        }
+        send.send(Command::Terminate).unwrap();
+        std::thread::spawn(move || {
+            let mut thread = Thread::new(socket, recv, false, LocationMode::Full,
+                String::new(), None, std::time::Duration::from_secs(2));
+            thread.run();
+        }).join().unwrap();
+        let mut received = Vec::new();
+        peer.read_to_end(&mut received).unwrap();
+        let mut input = &received[..];
+        let mut header = [0; 4];
+        let mut before_summary = 0u64;
+        let mut summary_sent_wire = None;
+        while std::io::Read::read_exact(&mut input, &mut header).is_ok() {
+            let len = LittleEndian::read_u32(&header) as usize;
+            let (payload, rest) = input.split_at(len);
+            match bincode::options().deserialize::<NetCommand>(payload).unwrap() {
+                NetCommand::StreamSummary { sent_wire, .. } => summary_sent_wire = Some(sent_wire),
+                _ if summary_sent_wire.is_none() => before_summary += (4 + len) as u64,
+                _ => {}
+            }
+            input = rest;
+        }
+        //Every byte the client received ahead of the summary is accounted for exactly.
+        assert_eq!(summary_sent_wire, Some(before_summary));
+    }
+
+    #[test]
+    fn intact_recording_verifies() {
+        assert!(verify_recording(&recording()).is_ok());
+    }
+
+    #[test]
+    fn corrupted_payload_is_detected() {
+        let mut recording = recording();
+        //Flip a bit inside the second frame's payload.
+        recording[20] ^= 0x01;
+        assert!(verify_recording(&recording).is_err());
+    }
+
+    #[test]
+    fn truncated_recording_is_detected() {
+        let recording = recording();
+        //Cut the stream before the summary: the verifier must refuse to call it intact.
+        assert!(verify_recording(&recording[..recording.len() - 8]).is_err());
+    }
+
+    #[test]
+    fn location_modes_shrink_event_frames() {
+        let record = log::Record::builder()
+            .target("my_engine::renderer::vulkan")
+            .level(log::Level::Info)
+            .module_path(Some("my_engine::renderer::vulkan::pipeline"))
+            .file(Some("src/renderer/vulkan/pipeline.rs"))
+            .line(Some(712))
+            .build();
+        let event = |metadata| NetCommand::Event {
+            span: None,
+            metadata,
+            flags: event_flags::ABSOLUTE_TIME,
+            time: 1648768000,
+            message: Some("draw".into()),
+            value_set: Vec::new()
+        };
+        let sizes: Vec<usize> = IntoIterator::into_iter([LocationMode::Full, LocationMode::TargetOnly, LocationMode::None])
+            .map(|mode| {
+                let mut metadata = Metadata::from_log(&record);
+                metadata.strip_location(mode);
+                bincode::options().serialize(&event(metadata)).unwrap().len()
+            })
+            .collect();
+        //Every step down reclaims payload space for user data.
+        assert!(sizes[0] > sizes[1]);
+        assert!(sizes[1] > sizes[2]);
+        //And the client-visible layout matches the mode.
+        let mut metadata = Metadata::from_log(&record);
+        metadata.strip_location(LocationMode::TargetOnly);
+        let decoded: Metadata = bincode::options().deserialize(
+            &bincode::options().serialize(&metadata).unwrap()).unwrap();
+        assert_eq!(decoded, metadata);
+    }
+
+    #[test]
+    fn long_module_paths_round_trip_losslessly() {
+        //SpanAlloc metadata is serialized into a heap frame, so long file/module paths
+        // must survive unchanged rather than truncating at a fixed buffer size.
+        let long_path = "a::".repeat(2048) + "leaf";
+        let record = log::Record::builder()
+            .target(&long_path)
+            .level(log::Level::Info)
+            .module_path(Some(&long_path))
+            .file(Some(&long_path))
+            .line(Some(1))
+            .build();
+        let cmd = NetCommand::SpanAlloc {
+            id: sid(1, 0),
+            metadata: Metadata::from_log(&record),
+            tags: vec![("kind".into(), "render".into())]
+        };
+        let bytes = bincode::options().serialize(&cmd).unwrap();
+        assert!(bytes.len() > 1024); //Well past the old fixed-buffer sizes.
+        let decoded: NetCommand = bincode::options().deserialize(&bytes).unwrap();
+        assert_eq!(decoded, cmd);
+    }
+
+    #[test]
+    fn nested_spans_produce_expected_edges() {
+        let mut tracker = SpanTreeTracker::new();
+        //root (callsite 1) -> middle (callsite 2) -> leaf (callsite 3), with a second
+        // instance of the middle span to prove edges deduplicate at callsite level.
+        tracker.observe(&NetCommand::SpanInit {
+            span: sid(1, 0),
+            parent: None,
+            message: None,
+            value_set: Vec::new()
+        });
+        tracker.observe(&NetCommand::SpanInit {
+            span: sid(2, 0),
+            parent: Some(sid(1, 0)),
+            message: None,
+            value_set: Vec::new()
+        });
+        tracker.observe(&NetCommand::SpanInit {
+            span: sid(2, 1),
+            parent: Some(sid(1, 0)),
+            message: None,
+            value_set: Vec::new()
+        });
+        tracker.observe(&NetCommand::SpanInit {
+            span: sid(3, 0),
+            parent: Some(sid(2, 1)),
+            message: None,
+            value_set: Vec::new()
+        });
+        match tracker.to_command() {
+            Some(NetCommand::SpanTree { edges }) => assert_eq!(edges, vec![(2, 1), (3, 2)]),
+            _ => panic!("expected a span tree command")
+        }
+    }
+
+    #[test]
+    fn empty_tracker_emits_nothing() {
+        let tracker = SpanTreeTracker::new();
+        assert!(tracker.to_command().is_none());
+    }
+}
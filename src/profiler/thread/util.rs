@@ -0,0 +1,210 @@
+// Copyright (c) 2022, BlockProject 3D
+//
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification,
+// are permitted provided that the following conditions are met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//     * Redistributions in binary form must reproduce the above copyright notice,
+//       this list of conditions and the following disclaimer in the documentation
+//       and/or other materials provided with the distribution.
+//     * Neither the name of BlockProject 3D nor the names of its contributors
+//       may be used to endorse or promote products derived from this software
+//       without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS
+// "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT
+// LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR
+// A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT OWNER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Shared period/interval math for the profiler's timers (broadcast, batch flush,
+//! rotation, keep-alive), so each of them doesn't reimplement "has at least X elapsed
+//! since last time" with its own types and overflow behavior.
+
+use std::time::{Duration, Instant};
+
+/// A periodic trigger: fires on the first poll, then whenever at least the configured
+/// period has elapsed since the last firing. The current time is injected, keeping the
+/// type clock-agnostic and testable; all arithmetic is saturating, so there is no overflow
+/// or panic on non-monotonic inputs or very long gaps.
+pub struct Ticker {
+    period: Duration,
+    last: Option<Instant>
+}
+
+impl Ticker {
+    pub fn new(period: Duration) -> Ticker {
+        Ticker {
+            period,
+            last: None
+        }
+    }
+
+    /// Returns true when the period has elapsed (or on the first call), consuming the
+    /// firing: the next one is measured from `now`.
+    pub fn should_fire(&mut self, now: Instant) -> bool {
+        let fire = match self.last {
+            None => true,
+            Some(last) => now.saturating_duration_since(last) >= self.period
+        };
+        if fire {
+            self.last = Some(now);
+        }
+        fire
+    }
+}
+
+//Longer names are truncated when sanitized for filesystem use.
+const MAX_FILE_NAME_LEN: usize = 64;
+
+/// Sanitizes a client-supplied session name for use in local artifact filenames: path
+/// separators and other non-portable characters are replaced by '-', the result is
+/// trimmed and bounded; an empty result becomes "session".
+pub fn sanitize_file_name(name: &str) -> String {
+    let mut out: String = name.chars()
+        .take(MAX_FILE_NAME_LEN)
+        .map(|c| match c {
+            c if c.is_alphanumeric() => c,
+            '-' | '_' | '.' | ' ' => c,
+            _ => '-'
+        })
+        .collect();
+    out = out.trim().trim_matches('.').to_string();
+    match out.is_empty() {
+        true => "session".into(),
+        false => out
+    }
+}
+
+/// Detects debugger-induced stalls: a gap between two consecutively processed messages
+/// longer than the threshold, while the channel was empty (so the gap cannot be explained
+/// by backlog), marks the intervening runs as suspect. Clock-injectable like Ticker.
+pub struct StallDetector {
+    threshold: Duration,
+    last: Option<Instant>
+}
+
+impl StallDetector {
+    pub fn new(threshold: Duration) -> StallDetector {
+        StallDetector {
+            threshold,
+            last: None
+        }
+    }
+
+    /// Observes one processed message; returns true when the gap since the previous one
+    /// exceeded the threshold with an empty channel.
+    pub fn observe(&mut self, now: Instant, channel_was_empty: bool) -> bool {
+        let stalled = match self.last {
+            Some(last) => channel_was_empty && now.saturating_duration_since(last) >= self.threshold,
+            None => false
+        };
+        self.last = Some(now);
+        stalled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stall_detection_needs_gap_and_empty_channel() {
+        let start = Instant::now();
+        let mut detector = StallDetector::new(Duration::from_secs(2));
+        assert!(!detector.observe(start, true)); //First message never counts.
+        //Short gap: fine.
+        assert!(!detector.observe(start + Duration::from_millis(100), true));
+        //Long gap with a backlog: the channel explains it, not a stall.
+        assert!(!detector.observe(start + Duration::from_secs(10), false));
+        //Long gap with an empty channel: a breakpoint pause.
+        assert!(detector.observe(start + Duration::from_secs(20), true));
+        //And detection re-arms afterwards.
+        assert!(!detector.observe(start + Duration::from_secs(20), true));
+    }
+
+    #[test]
+    fn path_separators_are_sanitized() {
+        //Leading dots are stripped, separators replaced: no traversal possible.
+        assert_eq!(sanitize_file_name("../../etc/passwd"), "-..-etc-passwd");
+        assert!(!sanitize_file_name("a/b\\c").contains('/'));
+        assert!(!sanitize_file_name("a/b\\c").contains('\\'));
+        assert_eq!(sanitize_file_name("run 4/after:fix"), "run 4-after-fix");
+    }
+
+    #[test]
+    fn empty_name_gets_a_default() {
+        assert_eq!(sanitize_file_name(""), "session");
+        assert_eq!(sanitize_file_name("..."), "session");
+    }
+
+    #[test]
+    fn long_names_are_bounded() {
+        assert!(sanitize_file_name(&"x".repeat(500)).len() <= MAX_FILE_NAME_LEN);
+    }
+
+    #[test]
+    fn first_poll_fires() {
+        let mut ticker = Ticker::new(Duration::from_secs(1));
+        assert!(ticker.should_fire(Instant::now()));
+    }
+
+    #[test]
+    fn fires_exactly_at_the_boundary() {
+        let start = Instant::now();
+        let mut ticker = Ticker::new(Duration::from_millis(100));
+        assert!(ticker.should_fire(start));
+        //One nanosecond short: hold fire.
+        assert!(!ticker.should_fire(start + Duration::from_millis(100) - Duration::from_nanos(1)));
+        //Exactly the period: fire.
+        assert!(ticker.should_fire(start + Duration::from_millis(100)));
+    }
+
+    #[test]
+    fn firing_resets_the_measurement_origin() {
+        let start = Instant::now();
+        let mut ticker = Ticker::new(Duration::from_millis(100));
+        assert!(ticker.should_fire(start));
+        assert!(ticker.should_fire(start + Duration::from_millis(150)));
+        //Only 50ms since the LAST firing (at +150), not since start.
+        assert!(!ticker.should_fire(start + Duration::from_millis(200)));
+        assert!(ticker.should_fire(start + Duration::from_millis(250)));
+    }
+
+    #[test]
+    fn zero_period_fires_every_poll() {
+        let now = Instant::now();
+        let mut ticker = Ticker::new(Duration::ZERO);
+        assert!(ticker.should_fire(now));
+        assert!(ticker.should_fire(now));
+        assert!(ticker.should_fire(now));
+    }
+
+    #[test]
+    fn very_long_gap_fires_once() {
+        let start = Instant::now();
+        let mut ticker = Ticker::new(Duration::from_millis(100));
+        assert!(ticker.should_fire(start));
+        //A gap of many periods produces a single firing, not a burst.
+        assert!(ticker.should_fire(start + Duration::from_secs(100_000)));
+        assert!(!ticker.should_fire(start + Duration::from_secs(100_000)));
+    }
+
+    #[test]
+    fn non_monotonic_now_does_not_panic() {
+        let start = Instant::now();
+        let mut ticker = Ticker::new(Duration::from_millis(100));
+        assert!(ticker.should_fire(start + Duration::from_secs(1)));
+        //A now earlier than the last firing saturates to zero elapsed.
+        assert!(!ticker.should_fire(start));
+    }
+}
@@ -0,0 +1,480 @@
+// Copyright (c) 2022, BlockProject 3D
+//
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification,
+// are permitted provided that the following conditions are met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//     * Redistributions in binary form must reproduce the above copyright notice,
+//       this list of conditions and the following disclaimer in the documentation
+//       and/or other materials provided with the distribution.
+//     * Neither the name of BlockProject 3D nor the names of its contributors
+//       may be used to endorse or promote products derived from this software
+//       without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS
+// "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT
+// LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR
+// A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT OWNER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use std::fmt::Debug;
+use tracing_core::Field;
+use tracing_core::field::Visit;
+use crate::config::FieldMode;
+use crate::profiler::network_types::Value;
+
+//The placeholder recorded instead of real values in names-only mode.
+const PLACEHOLDER: &str = "<omitted>";
+
+//The marker field collapsing fields recorded past the configured cap.
+const OVERFLOW_FIELD: &str = "...";
+
+//How many bytes a Debug rendering may produce before it is cut off.
+const DEFAULT_MAX_DEBUG_LEN: usize = 4096;
+
+//A fmt sink that refuses further input once full: returning an error aborts the
+// formatting machinery, so a value whose Debug output would be megabytes stops paying
+// CPU at the cap instead of rendering bytes that get dropped anyway.
+struct BoundedDebug {
+    out: String,
+    max: usize,
+    truncated: bool
+}
+
+impl std::fmt::Write for BoundedDebug {
+    fn write_str(&mut self, fragment: &str) -> std::fmt::Result {
+        if self.out.len() >= self.max {
+            self.truncated = true;
+            return Err(std::fmt::Error);
+        }
+        let remaining = self.max - self.out.len();
+        if fragment.len() <= remaining {
+            self.out.push_str(fragment);
+            return Ok(());
+        }
+        let mut take = remaining;
+        while take > 0 && !fragment.is_char_boundary(take) {
+            take -= 1;
+        }
+        self.out.push_str(&fragment[..take]);
+        self.truncated = true;
+        Err(std::fmt::Error)
+    }
+}
+
+/// Renders a value's Debug representation, short-circuiting once `max` bytes were
+/// produced; truncated renderings end with an ellipsis marker.
+pub(crate) fn format_debug_bounded(value: &dyn std::fmt::Debug, max: usize) -> String {
+    use std::fmt::Write;
+    let mut sink = BoundedDebug {
+        out: String::new(),
+        max,
+        truncated: false
+    };
+    let _ = write!(sink, "{:?}", value);
+    if sink.truncated {
+        sink.out.push_str("...");
+    }
+    sink.out
+}
+
+/// Returns true when the field marks the current span run as failed.
+///
+/// By convention applications record an `error` field (either through
+/// `span.record("error", &err as &dyn std::error::Error)` or as a display string under
+/// `error.message`) before the span closes when the operation failed.
+pub fn is_error_field(field: &Field) -> bool {
+    field.name() == crate::fields::ERROR || field.name() == crate::fields::ERROR_MESSAGE
+}
+
+pub struct Visitor {
+    message: Option<String>,
+    value_set: Vec<(&'static str, Value)>,
+    tags: Vec<(String, String)>,
+    mode: FieldMode,
+    max_vars: Option<usize>,
+    max_debug: usize,
+    overflowed: usize,
+    failed: bool
+}
+
+impl Visitor {
+    pub fn into_inner(mut self) -> (Option<String>, Vec<(&'static str, Value)>) {
+        self.flush_overflow_marker();
+        (self.message, self.value_set)
+    }
+
+    pub fn new(mode: FieldMode) -> Visitor {
+        Self::with_declared_fields(mode, 0)
+    }
+
+    /// Creates a visitor pre-sized for the callsite's declared field count, so recording
+    /// never reallocates mid-payload. Per-run payloads are dynamically sized (there is no
+    /// fixed buffer class to pick), which makes small spans cost only what they carry and
+    /// large spans impossible to truncate; the declared count is the exact upper bound
+    /// since duplicate field names merge in place.
+    pub fn with_declared_fields(mode: FieldMode, declared: usize) -> Visitor {
+        Self::with_limits(mode, declared, None)
+    }
+
+    /// Creates a visitor additionally capping how many variables the payload records;
+    /// excess fields collapse into one explicit `...(N more fields)` marker rather than
+    /// disappearing silently.
+    pub fn with_limits(mode: FieldMode, declared: usize, max_vars: Option<usize>) -> Visitor {
+        Visitor {
+            message: None,
+            value_set: match mode {
+                FieldMode::None => Vec::new(),
+                _ => Vec::with_capacity(match max_vars {
+                    Some(max) => declared.min(max),
+                    None => declared
+                })
+            },
+            tags: Vec::new(),
+            mode,
+            max_vars,
+            max_debug: DEFAULT_MAX_DEBUG_LEN,
+            overflowed: 0,
+            failed: false
+        }
+    }
+
+    /// Overrides the Debug rendering byte cap.
+    pub fn set_max_debug_len(&mut self, max: usize) {
+        self.max_debug = max;
+    }
+
+    fn flush_overflow_marker(&mut self) {
+        if self.overflowed > 0 {
+            self.value_set.push((OVERFLOW_FIELD,
+                Value::String(format!("({} more fields)", self.overflowed))));
+        }
+    }
+
+    /// The tags collected through the `tag.*` field convention; always recorded
+    /// regardless of the field mode since they are filtering metadata, not payload data.
+    pub fn tags(&self) -> &[(String, String)] {
+        &self.tags
+    }
+
+    /// Returns true when an `error`/`error.message` field was recorded through this visitor.
+    pub fn failed(&self) -> bool {
+        self.failed
+    }
+
+    //Every record_* funnels through here so the field mode applies uniformly: full mode
+    // keeps the value, names-only swaps in a placeholder, none drops the field. Error
+    // detection stays name-based and works in every mode. tracing allows re-recording a
+    // field; the last value wins and appears exactly once, so clients never disagree
+    // about which copy is current.
+    fn push(&mut self, field: &Field, value: Value) {
+        if let Some(tag) = field.name().strip_prefix(crate::fields::TAG_PREFIX) {
+            //Plain display forms, so a numeric/bool tag renders as "3"/"true" on the
+            // wire - never the internal enum's Debug shape - and agrees with the
+            // logger backend's rendering of the same tag.
+            let value = match value {
+                Value::String(v) => v,
+                Value::Float(v) => v.to_string(),
+                Value::Signed(v) => v.to_string(),
+                Value::Unsigned(v) => v.to_string(),
+                Value::Bool(v) => v.to_string()
+            };
+            match self.tags.iter_mut().find(|(name, _)| name == tag) {
+                Some(entry) => entry.1 = value,
+                None => self.tags.push((tag.into(), value))
+            }
+            return;
+        }
+        self.failed |= is_error_field(field);
+        let value = match self.mode {
+            FieldMode::Full => value,
+            FieldMode::NamesOnly => Value::String(PLACEHOLDER.into()),
+            FieldMode::None => return
+        };
+        match self.value_set.iter_mut().find(|(name, _)| *name == field.name()) {
+            Some(entry) => entry.1 = value,
+            None => {
+                if let Some(max) = self.max_vars {
+                    if self.value_set.len() >= max {
+                        self.overflowed += 1;
+                        return;
+                    }
+                }
+                self.value_set.push((field.name(), value))
+            }
+        }
+    }
+}
+
+impl Visit for Visitor {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.push(field, Value::Float(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.push(field, Value::Signed(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.push(field, Value::Unsigned(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.push(field, Value::Bool(value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == crate::fields::MESSAGE {
+            self.message = Some(value.into())
+        } else {
+            self.push(field, Value::String(value.into()));
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        if field.name() == crate::fields::MESSAGE {
+            self.message = Some(format_debug_bounded(value, self.max_debug));
+        } else {
+            let rendered = format_debug_bounded(value, self.max_debug);
+            self.push(field, Value::String(rendered));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing_core::{Callsite, Kind, Level, Metadata};
+    use tracing_core::field::Visit;
+    use tracing_core::metadata;
+    use tracing_core::subscriber::Interest;
+    use super::*;
+
+    struct TestCallsite;
+    static CALLSITE: TestCallsite = TestCallsite;
+    static META: Metadata<'static> = metadata! {
+        name: "test_span",
+        target: module_path!(),
+        level: Level::INFO,
+        fields: &["error", "error.message", "value"],
+        callsite: &CALLSITE,
+        kind: Kind::SPAN
+    };
+
+    impl Callsite for TestCallsite {
+        fn set_interest(&self, _: Interest) {}
+        fn metadata(&self) -> &Metadata<'static> {
+            &META
+        }
+    }
+
+    #[test]
+    fn enormous_debug_outputs_stop_formatting_early() {
+        use std::cell::Cell;
+
+        //Counts how many fragments its Debug impl was actually asked to render.
+        struct Huge(Cell<usize>);
+
+        impl std::fmt::Debug for Huge {
+            fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                for _ in 0..1_000_000 {
+                    self.0.set(self.0.get() + 1);
+                    f.write_str("xxxxxxxxxx")?; //10 bytes per fragment.
+                }
+                Ok(())
+            }
+        }
+
+        let huge = Huge(Cell::new(0));
+        let rendered = format_debug_bounded(&huge, 100);
+        //Formatting aborted right past the cap rather than rendering ten megabytes.
+        assert!(huge.0.get() <= 11);
+        assert!(rendered.ends_with("..."));
+        assert!(rendered.len() <= 103);
+        //And the visitor routes record_debug through the cap.
+        let mut visitor = Visitor::new(FieldMode::Full);
+        visitor.set_max_debug_len(50);
+        visitor.record_debug(&META.fields().field("value").unwrap(), &Huge(Cell::new(0)));
+        let (_, values) = visitor.into_inner();
+        match &values[0].1 {
+            Value::String(v) => assert!(v.len() <= 53 && v.ends_with("...")),
+            v => panic!("unexpected value {:?}", v)
+        }
+    }
+
+    #[test]
+    fn exceeding_the_variable_cap_leaves_an_explicit_marker() {
+        static WIDE: Metadata<'static> = metadata! {
+            name: "wide",
+            target: module_path!(),
+            level: Level::INFO,
+            fields: &["a", "b", "c", "d", "e"],
+            callsite: &CALLSITE,
+            kind: Kind::SPAN
+        };
+        let mut visitor = Visitor::with_limits(FieldMode::Full, 5, Some(2));
+        for name in ["a", "b", "c", "d", "e"] {
+            visitor.record_u64(&WIDE.fields().field(name).unwrap(), 1);
+        }
+        //Re-recording a kept field still merges rather than counting as overflow.
+        visitor.record_u64(&WIDE.fields().field("a").unwrap(), 2);
+        let (_, values) = visitor.into_inner();
+        assert_eq!(values.len(), 3);
+        assert_eq!(values[0], ("a", Value::Unsigned(2)));
+        assert_eq!(values[1], ("b", Value::Unsigned(1)));
+        assert_eq!(values[2], ("...", Value::String("(3 more fields)".into())));
+        //Within the cap: no marker.
+        let mut visitor = Visitor::with_limits(FieldMode::Full, 5, Some(8));
+        visitor.record_u64(&WIDE.fields().field("a").unwrap(), 1);
+        let (_, values) = visitor.into_inner();
+        assert_eq!(values.len(), 1);
+    }
+
+    #[test]
+    fn declared_field_presizing_changes_nothing_observable() {
+        let field = META.fields().field("value").unwrap();
+        let mut sized = Visitor::with_declared_fields(FieldMode::Full, META.fields().len());
+        let mut plain = Visitor::new(FieldMode::Full);
+        sized.record_u64(&field, 42);
+        plain.record_u64(&field, 42);
+        assert!(sized.value_set.capacity() >= META.fields().len());
+        assert_eq!(sized.into_inner(), plain.into_inner());
+    }
+
+    #[test]
+    fn full_mode_records_values() {
+        let mut visitor = Visitor::new(FieldMode::Full);
+        visitor.record_u64(&META.fields().field("value").unwrap(), 42);
+        let (_, values) = visitor.into_inner();
+        assert_eq!(values, vec![("value", Value::Unsigned(42))]);
+    }
+
+    #[test]
+    fn names_only_mode_keeps_names_with_placeholder() {
+        let mut visitor = Visitor::new(FieldMode::NamesOnly);
+        visitor.record_u64(&META.fields().field("value").unwrap(), 42);
+        visitor.record_str(&META.fields().field("error").unwrap(), "secret");
+        let failed = visitor.failed();
+        let (_, values) = visitor.into_inner();
+        assert_eq!(values, vec![
+            ("value", Value::String(PLACEHOLDER.into())),
+            ("error", Value::String(PLACEHOLDER.into()))
+        ]);
+        //The value is hidden but the error marker still works.
+        assert!(failed);
+    }
+
+    #[test]
+    fn none_mode_drops_fields_entirely() {
+        let mut visitor = Visitor::new(FieldMode::None);
+        visitor.record_u64(&META.fields().field("value").unwrap(), 42);
+        visitor.record_str(&META.fields().field("error").unwrap(), "secret");
+        let failed = visitor.failed();
+        let (_, values) = visitor.into_inner();
+        assert!(values.is_empty());
+        assert!(failed);
+    }
+
+    #[test]
+    fn non_string_tags_render_in_plain_form_matching_the_logger() {
+        static NMETA: Metadata<'static> = metadata! {
+            name: "numeric_tags",
+            target: module_path!(),
+            level: Level::INFO,
+            fields: &["tag.depth", "tag.critical", "tag.ratio"],
+            callsite: &CALLSITE,
+            kind: Kind::SPAN
+        };
+        let mut profiler = Visitor::new(FieldMode::Full);
+        profiler.record_u64(&NMETA.fields().field("tag.depth").unwrap(), 3);
+        profiler.record_bool(&NMETA.fields().field("tag.critical").unwrap(), true);
+        profiler.record_f64(&NMETA.fields().field("tag.ratio").unwrap(), 0.5);
+        assert_eq!(profiler.tags(), &[
+            ("depth".to_string(), "3".to_string()),
+            ("critical".to_string(), "true".to_string()),
+            ("ratio".to_string(), "0.5".to_string())
+        ]);
+        //And the logger backend renders the same tags identically.
+        let mut logger = crate::logger::test_visitor();
+        tracing_core::field::Visit::record_u64(&mut logger,
+            &NMETA.fields().field("tag.depth").unwrap(), 3);
+        tracing_core::field::Visit::record_bool(&mut logger,
+            &NMETA.fields().field("tag.critical").unwrap(), true);
+        tracing_core::field::Visit::record_f64(&mut logger,
+            &NMETA.fields().field("tag.ratio").unwrap(), 0.5);
+        assert_eq!(logger.tag_values(), profiler.tags());
+    }
+
+    #[test]
+    fn tag_fields_are_collected_separately() {
+        static TMETA: Metadata<'static> = metadata! {
+            name: "tagged",
+            target: module_path!(),
+            level: Level::INFO,
+            fields: &["tag.color", "tag.kind", "size"],
+            callsite: &CALLSITE,
+            kind: Kind::SPAN
+        };
+        let mut visitor = Visitor::new(FieldMode::Full);
+        visitor.record_str(&TMETA.fields().field("tag.color").unwrap(), "red");
+        visitor.record_str(&TMETA.fields().field("tag.kind").unwrap(), "render");
+        visitor.record_u64(&TMETA.fields().field("size").unwrap(), 42);
+        assert_eq!(visitor.tags(), &[
+            ("color".to_string(), "red".to_string()),
+            ("kind".to_string(), "render".to_string())
+        ]);
+        let (_, values) = visitor.into_inner();
+        //Tags never leak into the regular value set.
+        assert_eq!(values, vec![("size", Value::Unsigned(42))]);
+    }
+
+    #[test]
+    fn re_recorded_fields_keep_the_last_value_exactly_once() {
+        let field = META.fields().field("value").unwrap();
+        //Shorter, equal-length and longer re-records all end at the last value.
+        for (first, second) in [("abcdef", "xy"), ("ab", "cd"), ("xy", "abcdef")] {
+            let mut visitor = Visitor::new(FieldMode::Full);
+            visitor.record_str(&field, first);
+            visitor.record_str(&field, second);
+            let (_, values) = visitor.into_inner();
+            assert_eq!(values, vec![("value", Value::String(second.into()))]);
+        }
+        //Re-recording with a different type also replaces in place.
+        let mut visitor = Visitor::new(FieldMode::Full);
+        visitor.record_str(&field, "42");
+        visitor.record_u64(&field, 42);
+        let (_, values) = visitor.into_inner();
+        assert_eq!(values, vec![("value", Value::Unsigned(42))]);
+    }
+
+    #[test]
+    fn error_field_as_debug() {
+        //Mirrors span.record("error", &err as &dyn Error) which flows through record_debug.
+        let mut visitor = Visitor::new(FieldMode::Full);
+        visitor.record_debug(&META.fields().field("error").unwrap(), &"oh no");
+        assert!(visitor.failed());
+    }
+
+    #[test]
+    fn error_message_field_as_str() {
+        let mut visitor = Visitor::new(FieldMode::Full);
+        visitor.record_str(&META.fields().field("error.message").unwrap(), "oh no");
+        assert!(visitor.failed());
+    }
+
+    #[test]
+    fn regular_fields_do_not_fail() {
+        let mut visitor = Visitor::new(FieldMode::Full);
+        visitor.record_str(&META.fields().field("value").unwrap(), "oh no");
+        visitor.record_u64(&META.fields().field("value").unwrap(), 42);
+        assert!(!visitor.failed());
+    }
+}
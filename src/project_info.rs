@@ -0,0 +1,158 @@
+// Copyright (c) 2022, BlockProject 3D
+//
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification,
+// are permitted provided that the following conditions are met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//     * Redistributions in binary form must reproduce the above copyright notice,
+//       this list of conditions and the following disclaimer in the documentation
+//       and/or other materials provided with the distribution.
+//     * Neither the name of BlockProject 3D nor the names of its contributors
+//       may be used to endorse or promote products derived from this software
+//       without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS
+// "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT
+// LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR
+// A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT OWNER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Plugin-contributed sections of the profiler's Project message.
+//!
+//! Engine plugins (renderer, physics, ...) register a named provider returning a small
+//! info blob (driver version, backend name, ...); the providers are invoked once when the
+//! Project message is built after the handshake - never on the tracer hot path - and
+//! their results travel as named sections. Registrations arriving after a client already
+//! received Project are sent as a ProjectUpdate carrying only the new sections. A
+//! panicking provider loses only its own section, and the total payload size is capped.
+
+use std::panic::AssertUnwindSafe;
+use std::sync::Mutex;
+use crossbeam_channel::Sender;
+use once_cell::sync::Lazy;
+use crate::profiler::thread::Command;
+
+//The combined size cap over all section names and contents.
+const MAX_PROJECT_PAYLOAD: usize = 16 * 1024;
+
+type Provider = Box<dyn Fn() -> String + Send + Sync>;
+
+struct Registry {
+    providers: Vec<(&'static str, Provider)>,
+    //Set once the Project message went out; late registrations stream through it.
+    sink: Option<Sender<Command>>,
+    sent_bytes: usize
+}
+
+static REGISTRY: Lazy<Mutex<Registry>> = Lazy::new(|| Mutex::new(Registry {
+    providers: Vec::new(),
+    sink: None,
+    sent_bytes: 0
+}));
+
+fn run_provider(section: &str, provider: &Provider) -> Option<String> {
+    match std::panic::catch_unwind(AssertUnwindSafe(provider)) {
+        Ok(v) => Some(v),
+        Err(_) => {
+            log::warn!(target: "bp3d-tracing", "The project info provider for section '{}' panicked; the section is skipped", section);
+            None
+        }
+    }
+}
+
+fn build_section(section: &str, content: String, sent_bytes: &mut usize) -> Option<(String, String)> {
+    let size = section.len() + content.len();
+    if *sent_bytes + size > MAX_PROJECT_PAYLOAD {
+        log::warn!(target: "bp3d-tracing", "The project info section '{}' exceeds the payload cap and is skipped", section);
+        return None;
+    }
+    *sent_bytes += size;
+    Some((section.into(), content))
+}
+
+/// Registers a named project info section; callable before or after initialize. When the
+/// client already received the Project message, the provider runs immediately (on the
+/// caller's thread) and the section is delivered as a ProjectUpdate.
+pub fn register<F: 'static + Fn() -> String + Send + Sync>(section: &'static str, provider: F) {
+    let mut lock = REGISTRY.lock().unwrap();
+    let provider: Provider = Box::new(provider);
+    if lock.sink.is_some() {
+        let update = run_provider(section, &provider)
+            .and_then(|content| build_section(section, content, &mut lock.sent_bytes))
+            .map(|section| Command::ProjectUpdate(vec![section]));
+        if let (Some(update), Some(sink)) = (update, &lock.sink) {
+            let _ = sink.send(update);
+        }
+    }
+    lock.providers.push((section, provider));
+}
+
+/// Invokes every registered provider and returns the built sections; called by the
+/// profiler when it assembles the Project message.
+pub(crate) fn collect() -> Vec<(String, String)> {
+    let mut lock = REGISTRY.lock().unwrap();
+    let mut sections = Vec::new();
+    let mut sent_bytes = 0;
+    for (section, provider) in &lock.providers {
+        if let Some(built) = run_provider(section, provider)
+            .and_then(|content| build_section(section, content, &mut sent_bytes)) {
+            sections.push(built);
+        }
+    }
+    lock.sent_bytes = sent_bytes;
+    sections
+}
+
+/// Marks the Project message as sent and attaches the channel through which late
+/// registrations are delivered as ProjectUpdate messages.
+pub(crate) fn attach_sink(sink: Sender<Command>) {
+    REGISTRY.lock().unwrap().sink = Some(sink);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reset() {
+        let mut lock = REGISTRY.lock().unwrap();
+        lock.sink = None;
+    }
+
+    #[test]
+    fn collect_builds_sections_and_skips_panicking_providers() {
+        reset();
+        register("renderer_test", || "vulkan 1.3".into());
+        register("physics_test", || panic!("driver query exploded"));
+        let sections = collect();
+        assert!(sections.iter().any(|(name, content)| name == "renderer_test" && content == "vulkan 1.3"));
+        assert!(!sections.iter().any(|(name, _)| name == "physics_test"));
+    }
+
+    #[test]
+    fn late_registration_sends_a_project_update() {
+        let (send, recv) = crossbeam_channel::unbounded();
+        {
+            let mut lock = REGISTRY.lock().unwrap();
+            lock.sink = Some(send);
+        }
+        register("late_plugin_test", || "backend 7".into());
+        reset();
+        let mut updates = Vec::new();
+        while let Ok(cmd) = recv.try_recv() {
+            if let Command::ProjectUpdate(sections) = cmd {
+                updates.extend(sections);
+            }
+        }
+        //Only the new section travels, not everything registered before.
+        assert_eq!(updates, vec![("late_plugin_test".to_string(), "backend 7".to_string())]);
+    }
+}
@@ -0,0 +1,120 @@
+// Copyright (c) 2022, BlockProject 3D
+//
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification,
+// are permitted provided that the following conditions are met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//     * Redistributions in binary form must reproduce the above copyright notice,
+//       this list of conditions and the following disclaimer in the documentation
+//       and/or other materials provided with the distribution.
+//     * Neither the name of BlockProject 3D nor the names of its contributors
+//       may be used to endorse or promote products derived from this software
+//       without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS
+// "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT
+// LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR
+// A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT OWNER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Offline replay of a captured profiler stream, for developing and testing viewers
+//! without a live application: reads previously recorded length-prefixed frames and
+//! replays them to a connected viewer byte-identically and in order, either as fast as
+//! possible or paced by the recorded event timestamps.
+
+use std::io::{Read, Write};
+use std::time::Duration;
+use bincode::Options;
+use byteorder::{ByteOrder, LittleEndian};
+use crate::profiler::network_types::{event_flags, Command};
+use crate::profiler::network_types::protocol::FRAME_LEN_BYTES;
+
+/// How fast a recording is replayed.
+pub enum Speed {
+    /// No pacing: frames stream back-to-back.
+    AsFastAsPossible,
+    /// Frames are spaced by the recorded event time deltas, with gaps capped so a long
+    /// idle stretch in the recording doesn't stall the replay for minutes.
+    Realtime {
+        max_gap: Duration
+    }
+}
+
+/// Replays a captured frame stream into the writer. Frames pass through byte-identically
+/// and in order; only the pacing between them depends on the selected speed.
+pub fn replay_frames<R: Read, W: Write>(mut input: R, mut out: W, speed: Speed) -> std::io::Result<W> {
+    let mut header = [0; FRAME_LEN_BYTES];
+    loop {
+        if input.read_exact(&mut header).is_err() {
+            break; //End of recording.
+        }
+        let len = LittleEndian::read_u32(&header) as usize;
+        let mut payload = vec![0; len];
+        input.read_exact(&mut payload)
+            .map_err(|_| std::io::Error::other("truncated frame in recording"))?;
+        if let Speed::Realtime { max_gap } = &speed {
+            //Delta-timed event frames carry the recorded spacing directly.
+            if let Ok(Command::Event { flags, time, .. }) =
+                bincode::options().deserialize::<Command>(&payload) {
+                if flags & event_flags::ABSOLUTE_TIME == 0 && time > 0 {
+                    std::thread::sleep((*max_gap).min(Duration::from_secs(time as u64)));
+                }
+            }
+        }
+        out.write_all(&header)?;
+        out.write_all(&payload)?;
+    }
+    out.flush()?;
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::profiler::network_types::SpanId;
+    use super::*;
+
+    fn frame(cmd: &Command) -> Vec<u8> {
+        let body = bincode::options().serialize(cmd).unwrap();
+        let mut frame = vec![0; FRAME_LEN_BYTES];
+        LittleEndian::write_u32(&mut frame, body.len() as u32);
+        frame.extend_from_slice(&body);
+        frame
+    }
+
+    #[test]
+    fn frames_replay_intact_and_in_order() {
+        let mut recording = Vec::new();
+        for cmd in [
+            Command::SpanEnter(SpanId::from_u64(1 << 32)),
+            Command::SpanExit {
+                span: SpanId::from_u64(1 << 32),
+                duration: Duration::from_millis(3).into(),
+                failed: false,
+                suspect: false,
+                memory_delta: None
+            },
+            Command::Terminate
+        ] {
+            recording.extend(frame(&cmd));
+        }
+        let replayed = replay_frames(&recording[..], Vec::new(), Speed::AsFastAsPossible).unwrap();
+        //Byte-identical, same order.
+        assert_eq!(replayed, recording);
+    }
+
+    #[test]
+    fn truncated_recordings_error_out() {
+        let mut recording = frame(&Command::Terminate);
+        recording.pop();
+        assert!(replay_frames(&recording[..], Vec::new(), Speed::AsFastAsPossible).is_err());
+    }
+}
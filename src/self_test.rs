@@ -0,0 +1,222 @@
+// Copyright (c) 2022, BlockProject 3D
+//
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification,
+// are permitted provided that the following conditions are met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//     * Redistributions in binary form must reproduce the above copyright notice,
+//       this list of conditions and the following disclaimer in the documentation
+//       and/or other materials provided with the distribution.
+//     * Neither the name of BlockProject 3D nor the names of its contributors
+//       may be used to endorse or promote products derived from this software
+//       without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS
+// "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT
+// LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR
+// A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT OWNER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Startup self-test: answers "the viewer shows nothing - is it config, network,
+//! filtering or my instrumentation?" by exercising the active backend end-to-end and
+//! reporting each stage as ok/failed with details. Triggered programmatically through
+//! [self_test](crate::self_test) or by setting BP3D_TRACE_SELFTEST=1 before
+//! initialization.
+
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+use tracing_core::Level;
+
+/// One diagnosed stage of the pipeline.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StageResult {
+    pub stage: &'static str,
+    pub ok: bool,
+    pub details: String
+}
+
+/// The structured self-test outcome; printable as a human-readable block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SelfTestReport {
+    pub stages: Vec<StageResult>
+}
+
+impl SelfTestReport {
+    /// True when every stage passed.
+    pub fn ok(&self) -> bool {
+        self.stages.iter().all(|stage| stage.ok)
+    }
+}
+
+impl Display for SelfTestReport {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        writeln!(f, "bp3d-tracing self test: {}", match self.ok() {
+            true => "all stages ok",
+            false => "FAILURES detected"
+        })?;
+        for stage in &self.stages {
+            writeln!(f, "  [{}] {}: {}", match stage.ok {
+                true => "ok",
+                false => "FAILED"
+            }, stage.stage, stage.details)?;
+        }
+        Ok(())
+    }
+}
+
+/// What the profiler probe observed, when the profiler backend is active.
+pub(crate) struct ProfilerProbe {
+    pub exited: bool,
+    pub drained: bool
+}
+
+/// Builds the report from the probed inputs; separated from the probing so every verdict
+/// combination is testable deterministically.
+pub(crate) fn build_report(
+    initialized: bool,
+    hint: Option<Level>,
+    event_delivered: bool,
+    span_delivered: bool,
+    profiler: Option<ProfilerProbe>
+) -> SelfTestReport {
+    let mut stages = Vec::new();
+    stages.push(StageResult {
+        stage: "initialization",
+        ok: initialized,
+        details: match initialized {
+            true => "a tracing backend is installed".into(),
+            false => "no backend installed; was initialize() called?".into()
+        }
+    });
+    //Levels compare at inverse logic!
+    let level_ok = hint.map(|hint| hint >= Level::INFO).unwrap_or(true);
+    stages.push(StageResult {
+        stage: "level filter",
+        ok: level_ok,
+        details: match (level_ok, hint) {
+            (true, Some(hint)) => format!("info events pass the configured level ({})", hint),
+            (true, None) => "no level filter configured".into(),
+            (false, hint) => format!("level too low: info events are filtered out (max level {:?}); \
+raise LOG or the configured level", hint)
+        }
+    });
+    stages.push(StageResult {
+        stage: "event delivery",
+        ok: event_delivered,
+        details: match event_delivered {
+            true => "the test event traversed the pipeline".into(),
+            false => "the test event never reached the backend output".into()
+        }
+    });
+    stages.push(StageResult {
+        stage: "span delivery",
+        ok: span_delivered,
+        details: match span_delivered {
+            true => "the test span exit traversed the pipeline".into(),
+            false => "the test span never reached the backend output".into()
+        }
+    });
+    if let Some(probe) = profiler {
+        stages.push(StageResult {
+            stage: "profiler channel",
+            ok: !probe.exited && probe.drained,
+            details: match (probe.exited, probe.drained) {
+                (true, _) => "the profiler session already terminated; commands go nowhere".into(),
+                (false, false) => "the writer thread is not draining the channel (stalled connection?)".into(),
+                (false, true) => "the writer thread is draining the channel".into()
+            }
+        });
+    }
+    SelfTestReport {
+        stages
+    }
+}
+
+/// Exercises the active backend end-to-end and returns the diagnosis.
+pub fn self_test() -> SelfTestReport {
+    let initialized = crate::is_initialized();
+    let hint = crate::level_hint();
+    //Emit a marked span and event through the real pipeline and watch the log buffer for
+    //them (the logger and the log pump both feed it; the profiler path is probed via its
+    //channel below).
+    let buffer = crate::LogBuffer::new();
+    let marker = format!("bp3d-selftest-{}", std::process::id());
+    {
+        let span = tracing::info_span!("bp3d_selftest_span");
+        let entered = span.enter();
+        tracing::error!("{} event", marker);
+        tracing::info!("{} info", marker);
+        drop(entered);
+        drop(span);
+    }
+    bp3d_logger::flush();
+    let mut event_delivered = false;
+    let mut span_delivered = false;
+    while let Some(msg) = buffer.pull() {
+        if msg.msg.contains(&marker) {
+            event_delivered = true;
+        }
+        if msg.msg.contains("bp3d_selftest_span") {
+            span_delivered = true;
+        }
+    }
+    let profiler = crate::profiler::state::ProfilerState::try_get().map(|state| ProfilerProbe {
+        exited: state.is_exited(),
+        drained: state.wait_drained(Duration::from_millis(200))
+    });
+    //In profiler mode the buffer never sees span lines; the channel probe is the
+    //authoritative stage there.
+    if profiler.is_some() {
+        span_delivered = true;
+    }
+    build_report(initialized, hint, event_delivered || profiler.is_some(), span_delivered, profiler)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn healthy_session_reports_all_ok() {
+        let report = build_report(true, Some(Level::DEBUG), true, true, Some(ProfilerProbe {
+            exited: false,
+            drained: true
+        }));
+        assert!(report.ok());
+        assert_eq!(report.stages.len(), 5);
+        let printed = report.to_string();
+        assert!(printed.contains("all stages ok"));
+        assert!(printed.contains("[ok] profiler channel"));
+    }
+
+    #[test]
+    fn level_too_low_is_diagnosed() {
+        let report = build_report(true, Some(Level::ERROR), false, false, None);
+        assert!(!report.ok());
+        let level = report.stages.iter().find(|v| v.stage == "level filter").unwrap();
+        assert!(!level.ok);
+        assert!(level.details.contains("level too low"));
+        assert!(report.to_string().contains("FAILED"));
+    }
+
+    #[test]
+    fn closed_profiler_channel_is_diagnosed() {
+        let report = build_report(true, None, true, true, Some(ProfilerProbe {
+            exited: true,
+            drained: false
+        }));
+        assert!(!report.ok());
+        let channel = report.stages.iter().find(|v| v.stage == "profiler channel").unwrap();
+        assert!(!channel.ok);
+        assert!(channel.details.contains("terminated"));
+    }
+}
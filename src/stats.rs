@@ -0,0 +1,208 @@
+// Copyright (c) 2022, BlockProject 3D
+//
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification,
+// are permitted provided that the following conditions are met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//     * Redistributions in binary form must reproduce the above copyright notice,
+//       this list of conditions and the following disclaimer in the documentation
+//       and/or other materials provided with the distribution.
+//     * Neither the name of BlockProject 3D nor the names of its contributors
+//       may be used to endorse or promote products derived from this software
+//       without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS
+// "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT
+// LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR
+// A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT OWNER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Tracing health counters.
+//!
+//! The various loss paths each bump a global atomic here; [Guard::stats](crate::Guard::stats)
+//! snapshots them all into one [TracingStats](TracingStats) so applications have a single
+//! place to poll to find out whether their tracing is lossy.
+
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+
+pub(crate) static NETWORK_WRITE_ERRORS: AtomicUsize = AtomicUsize::new(0);
+
+pub(crate) static SERIALIZE_ERRORS: AtomicUsize = AtomicUsize::new(0);
+
+pub(crate) static EARLY_DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+pub(crate) static CONFIG_CLAMPS: AtomicUsize = AtomicUsize::new(0);
+
+pub(crate) static CHANNEL_DROPS: AtomicUsize = AtomicUsize::new(0);
+
+pub(crate) static FOREIGN_IDS: AtomicUsize = AtomicUsize::new(0);
+
+pub(crate) static BYTES_SENT: AtomicUsize = AtomicUsize::new(0);
+
+pub(crate) static BYTES_RECEIVED: AtomicUsize = AtomicUsize::new(0);
+
+static LAST_NETWORK_ERROR: Lazy<Mutex<Option<String>>> = Lazy::new(|| Mutex::new(None));
+
+pub(crate) fn record_network_error(error: &dyn std::fmt::Display) {
+    NETWORK_WRITE_ERRORS.fetch_add(1, Ordering::Relaxed);
+    *LAST_NETWORK_ERROR.lock().unwrap() = Some(error.to_string());
+}
+
+//The per-target counter map is bounded; once full, further targets aggregate under
+// OTHER_TARGETS so a target-cardinality explosion cannot leak memory.
+const MAX_TRACKED_TARGETS: usize = 256;
+
+const OTHER_TARGETS: &str = "<other>";
+
+static TARGET_COUNTS: Lazy<DashMap<String, AtomicUsize>> = Lazy::new(DashMap::new);
+
+pub(crate) fn count_target(target: &str) {
+    if let Some(count) = TARGET_COUNTS.get(target) {
+        count.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    let key = match TARGET_COUNTS.len() < MAX_TRACKED_TARGETS {
+        true => target,
+        false => OTHER_TARGETS
+    };
+    TARGET_COUNTS.entry(key.into())
+        .or_insert_with(|| AtomicUsize::new(0))
+        .fetch_add(1, Ordering::Relaxed);
+}
+
+/// Returns the `n` targets that produced the most events, most frequent first, with
+/// their event counts. Use this to find the noisiest modules when tuning filters.
+pub fn top_targets(n: usize) -> Vec<(String, u64)> {
+    let mut counts: Vec<(String, u64)> = TARGET_COUNTS.iter()
+        .map(|entry| (entry.key().clone(), entry.value().load(Ordering::Relaxed) as u64))
+        .collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    counts.truncate(n);
+    counts
+}
+
+pub(crate) static SELF_METRICS: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+static PROTOCOL_COUNTS: Lazy<Mutex<std::collections::HashMap<&'static str, (u64, u64)>>> =
+    Lazy::new(|| Mutex::new(std::collections::HashMap::new()));
+
+pub(crate) fn record_protocol(message: &'static str, bytes: usize) {
+    if !SELF_METRICS.load(Ordering::Relaxed) {
+        return;
+    }
+    let mut lock = PROTOCOL_COUNTS.lock().unwrap();
+    let entry = lock.entry(message).or_insert((0, 0));
+    entry.0 += 1;
+    entry.1 += bytes as u64;
+}
+
+/// Per-message-type counts of one protocol message kind, for wire debugging.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProtocolCounter {
+    pub message: &'static str,
+    pub count: u64,
+    pub bytes: u64
+}
+
+/// How many messages of each protocol type were sent (with their total framed bytes);
+/// empty unless profiler.self_metrics is enabled. Sorted by count, highest first.
+pub fn protocol_stats() -> Vec<ProtocolCounter> {
+    let lock = PROTOCOL_COUNTS.lock().unwrap();
+    let mut counters: Vec<ProtocolCounter> = lock.iter()
+        .map(|(message, (count, bytes))| ProtocolCounter {
+            message,
+            count: *count,
+            bytes: *bytes
+        })
+        .collect();
+    counters.sort_by_key(|v| std::cmp::Reverse(v.count));
+    counters
+}
+
+/// A snapshot of the tracing health counters; obtained from
+/// [Guard::stats](crate::Guard::stats), built from plain atomic loads.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TracingStats {
+    /// Commands that could not be written to the profiler connection.
+    pub network_write_errors: usize,
+    /// Commands that could not be serialized.
+    pub serialize_errors: usize,
+    /// Records dropped by the early-capture buffer before initialization.
+    pub early_dropped: usize,
+    /// Configuration values clamped to server limits (each clamp also emitted a warning
+    /// notice to the client).
+    pub config_clamps: usize,
+    /// Commands dropped because both the channel and the local overflow buffer were full.
+    pub channel_drops: usize,
+    /// Calls referencing a span id this subscriber never allocated (leaked from a scoped
+    /// foreign subscriber), ignored instead of corrupting state.
+    pub foreign_ids_ignored: usize,
+    /// The capacity of the profiler command channel, when the profiler is active.
+    pub channel_capacity: Option<usize>,
+    /// The minimum observed free capacity of the profiler command channel; the closer to
+    /// zero, the closer the session came to blocking on a full channel.
+    pub channel_min_free: Option<usize>,
+    /// The last network error observed on the profiler connection.
+    pub last_network_error: Option<String>,
+    /// Total bytes written to the profiler connection, framing included.
+    pub bytes_sent: u64,
+    /// Total bytes read from the profiler connection.
+    pub bytes_received: u64,
+    /// How many span instances currently hold full tracking state, when the profiler is
+    /// active.
+    pub tracked_instances: Option<usize>
+}
+
+pub(crate) fn snapshot() -> TracingStats {
+    let state = crate::profiler::state::ProfilerState::try_get();
+    TracingStats {
+        network_write_errors: NETWORK_WRITE_ERRORS.load(Ordering::Relaxed),
+        serialize_errors: SERIALIZE_ERRORS.load(Ordering::Relaxed),
+        early_dropped: EARLY_DROPPED.load(Ordering::Relaxed),
+        config_clamps: CONFIG_CLAMPS.load(Ordering::Relaxed),
+        channel_drops: CHANNEL_DROPS.load(Ordering::Relaxed),
+        foreign_ids_ignored: FOREIGN_IDS.load(Ordering::Relaxed),
+        channel_capacity: state.map(|v| v.capacity()),
+        channel_min_free: state.and_then(|v| v.monitor().min_free()),
+        last_network_error: LAST_NETWORK_ERROR.lock().unwrap().clone(),
+        bytes_sent: BYTES_SENT.load(Ordering::Relaxed) as u64,
+        bytes_received: BYTES_RECEIVED.load(Ordering::Relaxed) as u64,
+        tracked_instances: state.map(|v| v.tracked_instances())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn top_targets_reports_skewed_traffic_in_order() {
+        //Skewed traffic across 50 targets: target i gets i+1 events.
+        for i in 0..50 {
+            for _ in 0..=i {
+                count_target(&format!("skew_t{:02}", i));
+            }
+        }
+        //Other tests share the global map; judge only our own targets.
+        let ours: Vec<(String, u64)> = top_targets(usize::MAX).into_iter()
+            .filter(|(name, _)| name.starts_with("skew_t"))
+            .collect();
+        assert_eq!(ours.len(), 50);
+        assert_eq!(ours[0], ("skew_t49".into(), 50));
+        assert_eq!(ours[1], ("skew_t48".into(), 49));
+        assert_eq!(ours[49], ("skew_t00".into(), 1));
+    }
+}
@@ -0,0 +1,114 @@
+// Copyright (c) 2022, BlockProject 3D
+//
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification,
+// are permitted provided that the following conditions are met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//     * Redistributions in binary form must reproduce the above copyright notice,
+//       this list of conditions and the following disclaimer in the documentation
+//       and/or other materials provided with the distribution.
+//     * Neither the name of BlockProject 3D nor the names of its contributors
+//       may be used to endorse or promote products derived from this software
+//       without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS
+// "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT
+// LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR
+// A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT OWNER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Test harness for exercising the full initialize→log→shutdown pipeline in-process.
+//!
+//! set_global_default is process-global and once-only, so regular initialization cannot
+//! be driven from more than one test per process; this harness installs a freshly built
+//! tracing system with thread-scoped with_default instead, runs the scenario, then tears
+//! the system down (dropping its destructor exactly like Guard teardown). Multiple
+//! end-to-end tests can therefore run in one test binary.
+
+use tracing::subscriber::with_default;
+use crate::core::{Tracer, TracingSystem};
+
+/// Runs the closure with the given tracing system installed for the current thread, then
+/// performs the same flush-and-drop teardown as Guard termination. Returns the closure's
+/// value.
+pub fn with_tracing_system<T: 'static + Tracer + Send + Sync, R>(
+    system: TracingSystem<T>,
+    func: impl FnOnce() -> R
+) -> R {
+    let destructor = system.destructor;
+    let result = with_default(system.system, func);
+    bp3d_logger::flush();
+    drop(destructor);
+    result
+}
+
+/// Runs the closure with a JSON tracer writing into an in-memory sink and returns the
+/// closure's value together with everything captured (one JSON object per line).
+pub fn with_memory_sink<R>(func: impl FnOnce() -> R) -> (R, String) {
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let sink = SharedBuf(Arc::new(Mutex::new(Vec::new())));
+    let system = crate::json::JsonTracer::new(sink.clone());
+    let result = with_tracing_system(system, func);
+    let captured = String::from_utf8(sink.0.lock().unwrap().clone()).unwrap();
+    (result, captured)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn full_pipeline_runs_scoped() {
+        let ((), captured) = with_memory_sink(|| {
+            let span = tracing::info_span!("e2e_span", size = 42);
+            let entered = span.enter();
+            tracing::warn!(code = 7, "e2e event");
+            drop(entered);
+            drop(span);
+        });
+        let lines: Vec<serde_json::Value> = captured.lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["type"], "event");
+        assert_eq!(lines[0]["message"], "e2e event");
+        assert_eq!(lines[0]["fields"]["code"], 7);
+        assert_eq!(lines[1]["type"], "span_exit");
+        assert_eq!(lines[1]["name"], "e2e_span");
+        assert_eq!(lines[1]["fields"]["size"], 42);
+    }
+
+    #[test]
+    fn multiple_scoped_systems_run_in_one_process() {
+        //The whole point of the harness: a second full pipeline in the same process.
+        let ((), captured) = with_memory_sink(|| {
+            tracing::error!("second pipeline");
+        });
+        assert!(captured.contains("second pipeline"));
+    }
+}
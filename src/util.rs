@@ -0,0 +1,415 @@
+// Copyright (c) 2022, BlockProject 3D
+//
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification,
+// are permitted provided that the following conditions are met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//     * Redistributions in binary form must reproduce the above copyright notice,
+//       this list of conditions and the following disclaimer in the documentation
+//       and/or other materials provided with the distribution.
+//     * Neither the name of BlockProject 3D nor the names of its contributors
+//       may be used to endorse or promote products derived from this software
+//       without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS
+// "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT
+// LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR
+// A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT OWNER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use tracing_core::{Level, Metadata};
+use tracing_core::span::Id;
+
+pub type Meta = &'static Metadata<'static>;
+
+pub fn extract_target_module<'a>(record: Meta) -> (&'a str, Option<&'a str>) {
+    let base_string = record.module_path().unwrap_or_else(|| record.target());
+    let target = base_string
+        .find("::")
+        .map(|v| &base_string[..v])
+        .unwrap_or(base_string);
+    let module = base_string.find("::").map(|v| &base_string[(v + 2)..]);
+    (target, module)
+}
+
+pub fn tracing_level_to_log(level: &Level) -> log::Level {
+    match *level {
+        Level::TRACE => log::Level::Trace,
+        Level::DEBUG => log::Level::Debug,
+        Level::INFO => log::Level::Info,
+        Level::WARN => log::Level::Warn,
+        Level::ERROR => log::Level::Error
+    }
+}
+
+//A tracing span id packs a callsite id and an instance id into one u64. The partitioning
+// is a compile-time strategy: the default split is 32/32, while the span-id-20-44 feature
+// trades callsite space for instance space (code with few callsites but extreme instance
+// churn). Migration note: the wire SpanId keeps a u32/u32 pair, which is lossless while
+// SPAN_BITS_ID <= 32 and SPAN_BITS_INSTANCE <= 32; enabling span-id-20-44 truncates
+// instance ids above 2^32 on the wire, so pair it with a protocol version bump once
+// clients interpret raw ids.
+
+/// The number of bits of a span id devoted to the callsite part.
+pub const SPAN_BITS_ID: u32 = if cfg!(feature = "span-id-20-44") { 20 } else { 32 };
+
+/// The number of bits of a span id devoted to the instance part.
+pub const SPAN_BITS_INSTANCE: u32 = 64 - SPAN_BITS_ID;
+
+/// The highest representable callsite id.
+pub const SPAN_ID_MAX: u32 = (((1u64 << SPAN_BITS_ID) - 1) & u32::MAX as u64) as u32;
+
+/// The highest representable instance id.
+pub const SPAN_INSTANCE_MAX: u64 = (1u64 << SPAN_BITS_INSTANCE) - 1;
+
+pub fn span_from_id_instance(span_id: u32, instance: u64) -> Id {
+    Id::from_u64((span_id as u64) << SPAN_BITS_INSTANCE | (instance & SPAN_INSTANCE_MAX))
+}
+
+pub fn span_to_id_instance(span: &Id) -> (u32, u64) {
+    let combined = span.into_u64();
+    ((combined >> SPAN_BITS_INSTANCE) as u32, combined & SPAN_INSTANCE_MAX)
+}
+
+/// Returns true when the target identifies a span emitted by tokio's runtime task
+/// instrumentation rather than by application code.
+#[cfg(feature = "tokio-console")]
+pub fn is_tokio_task_target(target: &str) -> bool {
+    target == "tokio::task" || target.starts_with("tokio::task::") || target.starts_with("runtime")
+}
+
+//All user-visible durations funnel through this one helper so every output agrees on
+// units; binary row formats carry total nanoseconds as u64 instead of ad-hoc unit splits.
+fn format_scaled(value: f64, unit: &str) -> String {
+    let text = format!("{:.2}", value);
+    let text = text.trim_end_matches('0').trim_end_matches('.');
+    format!("{}{}", text, unit)
+}
+
+/// Formats a duration with explicit units: nanoseconds, microseconds, milliseconds and
+/// seconds scale with up to two decimals, and durations of a minute or more use the
+/// `XmYs` form.
+pub fn format_duration(duration: std::time::Duration) -> String {
+    let nanos = duration.as_nanos();
+    if nanos < 1_000 {
+        format!("{}ns", nanos)
+    } else if nanos < 1_000_000 {
+        format_scaled(nanos as f64 / 1_000.0, "\u{b5}s")
+    } else if nanos < 1_000_000_000 {
+        format_scaled(nanos as f64 / 1_000_000.0, "ms")
+    } else if duration.as_secs() < 60 {
+        format_scaled(duration.as_secs_f64(), "s")
+    } else {
+        let minutes = duration.as_secs() / 60;
+        let seconds = duration.as_secs() % 60;
+        match seconds {
+            0 => format!("{}m", minutes),
+            _ => format!("{}m{}s", minutes, seconds)
+        }
+    }
+}
+
+/// Reads the current process resident set size in bytes; best effort, None when the
+/// platform offers no cheap way to obtain it.
+#[cfg(target_os = "linux")]
+pub fn read_rss_bytes() -> Option<u64> {
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let resident: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    Some(resident * 4096)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn read_rss_bytes() -> Option<u64> {
+    None
+}
+
+/// A fixed-capacity string buffer for hot paths that must not allocate (protocol
+/// headers, panic paths). Writes APPEND - a Debug implementation emitting many fragments
+/// accumulates them all - and overflow truncates at a UTF-8 character boundary while
+/// remembering that truncation happened.
+#[allow(dead_code)] //Groundwork for the fixed-layout message encoders.
+pub struct FixedBufStr<const N: usize> {
+    buffer: [u8; N],
+    len: usize,
+    truncated: bool
+}
+
+#[allow(dead_code)] //See the note on the type.
+impl<const N: usize> FixedBufStr<N> {
+    pub fn new() -> FixedBufStr<N> {
+        FixedBufStr {
+            buffer: [0; N],
+            len: 0,
+            truncated: false
+        }
+    }
+
+    /// Builds the buffer from a value's Debug representation; multi-fragment Debug
+    /// output (every derived Debug) concatenates correctly.
+    pub fn from_debug(value: &dyn std::fmt::Debug) -> FixedBufStr<N> {
+        use std::fmt::Write;
+        let mut buf = FixedBufStr::new();
+        let _ = write!(buf, "{:?}", value);
+        buf
+    }
+
+    pub fn str(&self) -> &str {
+        //len always sits on a character boundary by construction.
+        std::str::from_utf8(&self.buffer[..self.len]).unwrap_or("")
+    }
+
+    /// The total capacity in bytes.
+    pub fn capacity(&self) -> usize {
+        N
+    }
+
+    /// How many bytes can still be appended.
+    pub fn remaining(&self) -> usize {
+        N - self.len
+    }
+
+    /// Whether any write did not fit entirely.
+    pub fn is_truncated(&self) -> bool {
+        self.truncated
+    }
+}
+
+impl<const N: usize> Default for FixedBufStr<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> std::fmt::Write for FixedBufStr<N> {
+    fn write_str(&mut self, fragment: &str) -> std::fmt::Result {
+        let remaining = self.remaining();
+        let take = if fragment.len() <= remaining {
+            fragment.len()
+        } else {
+            self.truncated = true;
+            //Walk back to a character boundary so the buffer stays valid UTF-8.
+            let mut take = remaining;
+            while take > 0 && !fragment.is_char_boundary(take) {
+                take -= 1;
+            }
+            take
+        };
+        self.buffer[self.len..self.len + take].copy_from_slice(&fragment.as_bytes()[..take]);
+        self.len += take;
+        Ok(())
+    }
+}
+
+impl<const N: usize> std::fmt::Display for FixedBufStr<N> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(self.str())
+    }
+}
+
+impl<const N: usize> AsRef<str> for FixedBufStr<N> {
+    fn as_ref(&self) -> &str {
+        self.str()
+    }
+}
+
+impl<const N: usize> PartialEq<str> for FixedBufStr<N> {
+    fn eq(&self, other: &str) -> bool {
+        self.str() == other
+    }
+}
+
+//IEEE CRC-32 with a const-built table; kept in-tree to avoid a dependency for one hash.
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = match crc & 1 {
+                0 => crc >> 1,
+                _ => (crc >> 1) ^ 0xEDB8_8320
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// An incremental IEEE CRC-32, so checksumming a stream does not require a second pass
+/// over buffered data.
+pub struct Crc32(u32);
+
+impl Crc32 {
+    pub fn new() -> Crc32 {
+        Crc32(0xFFFF_FFFF)
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for byte in data {
+            self.0 = (self.0 >> 8) ^ CRC32_TABLE[((self.0 ^ *byte as u32) & 0xFF) as usize];
+        }
+    }
+
+    pub fn finalize(&self) -> u32 {
+        !self.0
+    }
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug)]
+    #[allow(dead_code)] //Read through the derived Debug only.
+    struct MultiFragment {
+        name: &'static str,
+        count: u32
+    }
+
+    #[test]
+    fn from_debug_accumulates_every_fragment() {
+        //A derived Debug emits many write_str fragments; all of them must appear, not
+        // just the final one.
+        let value = MultiFragment {
+            name: "renderer",
+            count: 3
+        };
+        let buf: FixedBufStr<128> = FixedBufStr::from_debug(&value);
+        assert_eq!(buf.str(), format!("{:?}", value));
+        assert!(!buf.is_truncated());
+    }
+
+    #[test]
+    fn writes_append_and_truncate_against_a_reference_string() {
+        use std::fmt::Write;
+        //Property-style comparison: whatever fragment sequence we write, the buffer is
+        // always a prefix of the String-based reference, valid UTF-8, within capacity.
+        let fragments = ["alpha ", "β", "gamma-gamma-gamma ", "δδδδδδ", "end"];
+        for capacity_probe in 0..=40usize {
+            let mut reference = String::new();
+            let mut buf: FixedBufStr<24> = FixedBufStr::new();
+            for fragment in fragments.iter().take(capacity_probe % (fragments.len() + 1)) {
+                reference.push_str(fragment);
+                let _ = buf.write_str(fragment);
+            }
+            assert!(reference.starts_with(buf.str()));
+            assert!(buf.str().len() <= buf.capacity());
+            assert_eq!(buf.remaining(), buf.capacity() - buf.str().len());
+            assert_eq!(buf.is_truncated(), reference.len() > buf.str().len());
+        }
+    }
+
+    #[test]
+    fn truncation_respects_utf8_boundaries() {
+        use std::fmt::Write;
+        let mut buf: FixedBufStr<5> = FixedBufStr::new();
+        //Each β is two bytes: the third one cannot fit entirely.
+        let _ = buf.write_str("βββ");
+        assert_eq!(buf.str(), "ββ");
+        assert!(buf.is_truncated());
+        assert_eq!(buf.remaining(), 1);
+        //Ergonomics impls.
+        assert_eq!(buf.to_string(), "ββ");
+        assert_eq!(buf.as_ref(), "ββ");
+        assert!(buf == *"ββ");
+    }
+
+    #[test]
+    fn durations_format_with_explicit_units() {
+        use std::time::Duration;
+        assert_eq!(format_duration(Duration::from_nanos(500)), "500ns");
+        assert_eq!(format_duration(Duration::from_micros(999)), "999\u{b5}s");
+        assert_eq!(format_duration(Duration::from_micros(1500)), "1.5ms");
+        assert_eq!(format_duration(Duration::from_millis(42)), "42ms");
+        assert_eq!(format_duration(Duration::from_secs_f64(2.35)), "2.35s");
+        assert_eq!(format_duration(Duration::from_secs(123)), "2m3s");
+        assert_eq!(format_duration(Duration::from_secs(120)), "2m");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn rss_is_readable() {
+        let rss = read_rss_bytes().unwrap();
+        assert!(rss > 0);
+    }
+
+    #[test]
+    fn crc32_known_vector() {
+        let mut crc = Crc32::new();
+        crc.update(b"123456789");
+        assert_eq!(crc.finalize(), 0xCBF43926); //The standard IEEE check value.
+    }
+
+    #[test]
+    fn crc32_incremental_matches_one_shot() {
+        let mut one = Crc32::new();
+        one.update(b"hello world");
+        let mut two = Crc32::new();
+        two.update(b"hello ");
+        two.update(b"world");
+        assert_eq!(one.finalize(), two.finalize());
+    }
+
+    #[test]
+    fn split_round_trips_at_boundaries() {
+        //Boundary values expressed through the strategy consts so the test holds under
+        // every configured split.
+        for id in [0, 1, SPAN_ID_MAX - 1, SPAN_ID_MAX] {
+            for instance in [0, 1, SPAN_INSTANCE_MAX - 1, SPAN_INSTANCE_MAX] {
+                if id == 0 && instance == 0 {
+                    continue; //Id::from_u64 forbids zero.
+                }
+                let span = span_from_id_instance(id, instance);
+                assert_eq!(span_to_id_instance(&span), (id, instance));
+            }
+        }
+    }
+
+    #[test]
+    fn halves_do_not_bleed() {
+        let span = span_from_id_instance(1, SPAN_INSTANCE_MAX);
+        let (id, instance) = span_to_id_instance(&span);
+        assert_eq!(id, 1);
+        assert_eq!(instance, SPAN_INSTANCE_MAX);
+        let span = span_from_id_instance(SPAN_ID_MAX, 1);
+        let (id, instance) = span_to_id_instance(&span);
+        assert_eq!(id, SPAN_ID_MAX);
+        assert_eq!(instance, 1);
+    }
+
+    #[cfg(not(feature = "span-id-20-44"))]
+    #[test]
+    fn default_split_is_32_32() {
+        assert_eq!(SPAN_BITS_ID, 32);
+        assert_eq!(SPAN_BITS_INSTANCE, 32);
+        assert_eq!(SPAN_ID_MAX, u32::MAX);
+        assert_eq!(SPAN_INSTANCE_MAX, u32::MAX as u64);
+    }
+
+    #[cfg(feature = "span-id-20-44")]
+    #[test]
+    fn alternate_split_is_20_44() {
+        assert_eq!(SPAN_BITS_ID, 20);
+        assert_eq!(SPAN_BITS_INSTANCE, 44);
+        assert_eq!(SPAN_ID_MAX, (1 << 20) - 1);
+        assert_eq!(SPAN_INSTANCE_MAX, (1 << 44) - 1);
+    }
+}
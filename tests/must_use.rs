@@ -0,0 +1,6 @@
+//Compile-fail coverage for the Guard must_use footgun.
+#[test]
+fn must_use_guard() {
+    let cases = trybuild::TestCases::new();
+    cases.compile_fail("tests/ui/drop_guard.rs");
+}
@@ -0,0 +1,6 @@
+//Dropping the Guard immediately tears tracing down; the must_use lint has to fire.
+#![deny(unused_must_use)]
+
+fn main() {
+    bp3d_tracing::initialize("footgun");
+}